@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::mpsc::Sender;
@@ -6,12 +7,18 @@ use chidori_static_analysis::language::Report;
 use crate::cells::{CellTypes, CodeCell, SupportedLanguage, TextRange};
 use crate::execution::execution::execution_graph::ExecutionNodeId;
 use crate::execution::execution::ExecutionState;
-use crate::execution::primitives::operation::{AsyncRPCCommunication, InputItemConfiguration, InputSignature, InputType, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature};
-use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::execution::primitives::operation::{AsyncRPCCommunication, InputItemConfiguration, InputSignature, InputType, LogLine, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature, EXCEPTION_TYPE_METADATA_KEY, RANDOM_SEED_METADATA_KEY, TRACEBACK_METADATA_KEY};
+use crate::execution::primitives::serialized_value::{serialized_value_to_json_value, RkyvSerializedValue};
+use crate::library::std::ai::llm::validation::validate_against_schema;
+use crate::library::std::code::runtime_pyo3::{denied_import, NETWORK_MODULES};
+use crate::library::std::code::transpile::transpile_typescript;
 
 /// Code cells allow notebooks to evaluate source code in a variety of languages.
 #[tracing::instrument]
 pub fn code_cell(execution_state_id: ExecutionNodeId, cell: &CodeCell, range: &TextRange) -> anyhow::Result<OperationNode> {
+    // Invocations of a function defined by the cell run once, rather than over the mapped input
+    let map = cell.map.clone().filter(|_| cell.function_invocation.is_none());
     match cell.language {
         SupportedLanguage::PyO3 => {
             let paths =
@@ -19,7 +26,7 @@ pub fn code_cell(execution_state_id: ExecutionNodeId, cell: &CodeCell, range: &T
                     &cell.source_code,
                 )?;
             let report = chidori_static_analysis::language::python::parse::build_report(&paths);
-            let (input_signature, output_signature) = signatures_from_report(&report);
+            let (input_signature, output_signature) = signatures_from_report(&report, cell.optional_inputs.as_deref());
 
             let cell = cell.clone();
             Ok(OperationNode::new(
@@ -28,16 +35,18 @@ pub fn code_cell(execution_state_id: ExecutionNodeId, cell: &CodeCell, range: &T
                 input_signature,
                 output_signature,
                 CellTypes::Code(cell, Default::default()),
-            ))
+            ).with_map(map))
         }
         SupportedLanguage::Deno => {
+            // TypeScript cells are analyzed as the JavaScript they are transpiled to, as they are executed
+            let transpiled = transpile_typescript(&cell.source_code)?;
             let paths =
                 chidori_static_analysis::language::javascript::parse::extract_dependencies_js(
-                    &cell.source_code,
+                    &transpiled.code,
                 )?;
             let report = chidori_static_analysis::language::javascript::parse::build_report(&paths);
 
-            let (input_signature, output_signature) = signatures_from_report(&report);
+            let (input_signature, output_signature) = signatures_from_report(&report, cell.optional_inputs.as_deref());
 
             let cell = cell.clone();
             Ok(OperationNode::new(
@@ -46,7 +55,25 @@ pub fn code_cell(execution_state_id: ExecutionNodeId, cell: &CodeCell, range: &T
                 input_signature,
                 output_signature,
                 CellTypes::Code(cell, Default::default()),
-            ))
+            ).with_map(map))
+        }
+        SupportedLanguage::Lua => {
+            let paths =
+                chidori_static_analysis::language::lua::parse::extract_dependencies_lua(
+                    &cell.source_code,
+                )?;
+            let report = chidori_static_analysis::language::lua::parse::build_report(&paths);
+
+            let (input_signature, output_signature) = signatures_from_report(&report, cell.optional_inputs.as_deref());
+
+            let cell = cell.clone();
+            Ok(OperationNode::new(
+                cell.name.clone(),
+                execution_state_id,
+                input_signature,
+                output_signature,
+                CellTypes::Code(cell, Default::default()),
+            ).with_map(map))
         }
     }
 }
@@ -62,46 +89,310 @@ pub(crate) fn code_cell_exec_deno(cell: CodeCell) -> Box<OperationFn> {
                 &s,
                 &cell.source_code,
                 &x,
-                &cell.function_invocation,
+                &invoked_function(&x, &cell.function_invocation),
+                &cell.permissions,
+                working_directory(&s, &cell),
+            ).await?;
+            let mut stderr = result.2;
+            let output = check_output_schema(&cell, result.0, &mut stderr);
+            let metadata = output.as_ref().err().map(exception_metadata).unwrap_or_default();
+            Ok(OperationFnOutput {
+                has_error: output.is_err(),
+                execution_state: Some(result.3),
+                output,
+                stdout: result.1,
+                stderr,
+                metadata,
+            })
+        }.boxed()
+    })
+}
+
+/// Deny the cell the modules its configuration denies, along with the networking modules when the
+/// run denies network access.
+fn deny_imports_of_cell(s: &mut ExecutionState, cell: &CodeCell) {
+    if let Some(denied_imports) = &cell.denied_imports {
+        s.denied_imports.extend(denied_imports.iter().cloned());
+    }
+    if s.deny_network {
+        s.denied_imports.extend(NETWORK_MODULES.iter().map(|module| module.to_string()));
+    }
+}
+
+pub(crate) fn code_cell_exec_lua(cell: CodeCell) -> Box<OperationFn> {
+    Box::new(move |s, x, _, _| {
+        let closure_span = tracing::span!(tracing::Level::INFO, "lua_code_cell");
+        let _enter = closure_span.enter();
+        let mut s = s.clone();
+        let cell = cell.clone();
+        async move {
+            deny_imports_of_cell(&mut s, &cell);
+            let result = crate::library::std::code::runtime_lua::source_code_run_lua(
+                &s,
+                &cell.source_code,
+                &x,
+                &invoked_function(&x, &cell.function_invocation),
             ).await?;
+            let mut stderr = result.2;
+            let output = check_output_schema(&cell, result.0, &mut stderr);
             Ok(OperationFnOutput {
-                has_error: false,
+                has_error: output.is_err(),
                 execution_state: Some(result.3),
-                output: result.0,
+                output,
                 stdout: result.1,
-                stderr: result.2,
+                stderr,
+                metadata: Default::default(),
             })
         }.boxed()
     })
 }
 
 pub fn code_cell_exec_python(cell: CodeCell) -> Box<OperationFn> {
+    let functions = declared_functions(&cell.source_code);
     Box::new(move |s, x, _, _| {
         let closure_span = tracing::span!(tracing::Level::INFO, "pyo3_code_cell");
         let _enter = closure_span.enter();
         let cell = cell.clone();
         let s = s.clone();
+        let functions = functions.clone();
         async move {
+            let mut s = s;
+            let function_invocation = invoked_function(&x, &cell.function_invocation);
+            if let (Some(function_name), Some(functions)) = (&function_invocation, &functions) {
+                if !functions.contains_key(function_name) {
+                    let mut defined: Vec<&String> = functions.keys().collect();
+                    defined.sort();
+                    return Ok(OperationFnOutput {
+                        has_error: true,
+                        execution_state: None,
+                        output: Err(ExecutionStateErrors::Unknown(format!(
+                            "Function {:?} is not defined by this cell, it defines {:?}", function_name, defined
+                        ))),
+                        stdout: vec![],
+                        stderr: vec![],
+                        metadata: Default::default(),
+                    });
+                }
+            }
+            deny_imports_of_cell(&mut s, &cell);
+            if s.strict_imports && !s.denied_imports.is_empty() {
+                let denied = chidori_static_analysis::language::python::parse::denied_imports_python(&cell.source_code, &s.denied_imports)?;
+                if let Some(module) = denied.first() {
+                    return Ok(OperationFnOutput {
+                        has_error: true,
+                        execution_state: None,
+                        output: Err(denied_import(module, s.deny_network)),
+                        stdout: vec![],
+                        stderr: vec![],
+                        metadata: Default::default(),
+                    });
+                }
+            }
+            let random_seed = s.cell_random_seed(s.evaluating_operation_id);
             let result = crate::library::std::code::runtime_pyo3::source_code_run_python(
                 &s,
                 &cell.source_code,
                 &x,
-                &cell.function_invocation,
+                &function_invocation,
                 &None,
                 &None,
-            ).await?;
+                working_directory(&s, &cell),
+            ).await;
+            // Exceptions raised by the cell fail the cell rather than the execution
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => match e.downcast::<ExecutionStateErrors>() {
+                    Ok(error @ ExecutionStateErrors::PythonException { .. }) => {
+                        return Ok(OperationFnOutput {
+                            has_error: true,
+                            execution_state: None,
+                            metadata: with_random_seed(exception_metadata(&error), random_seed),
+                            stderr: match &error {
+                                ExecutionStateErrors::PythonException { traceback, .. } => vec![LogLine::stderr(traceback.clone())],
+                                _ => vec![],
+                            },
+                            output: Err(error),
+                            stdout: vec![],
+                        });
+                    }
+                    Ok(error) => return Err(error.into()),
+                    Err(e) => return Err(e),
+                },
+            };
+            let declared_outputs = function_invocation.as_ref()
+                .and_then(|function_name| functions.as_ref()?.get(function_name))
+                .filter(|outputs| !outputs.is_empty());
+            let output = match (&function_invocation, declared_outputs) {
+                (Some(function_name), Some(outputs)) => result.0.and_then(|value| split_named_outputs(function_name, value, outputs)),
+                _ => result.0,
+            };
+            let mut stderr = result.2;
+            let output = check_output_schema(&cell, output, &mut stderr);
+            let metadata = with_random_seed(output.as_ref().err().map(exception_metadata).unwrap_or_default(), random_seed);
             Ok(OperationFnOutput {
-                has_error: false,
+                has_error: output.is_err(),
                 execution_state: Some(result.3),
-                output: result.0,
+                output,
                 stdout: result.1,
-                stderr: result.2,
+                stderr,
+                metadata,
             })
         }.boxed()
     })
 }
 
-fn signatures_from_report(report: &Report) -> (InputSignature, OutputSignature) {
+/// Record the seed a Python cell's random number generators were seeded with, so that it can be replayed.
+fn with_random_seed(mut metadata: HashMap<String, String>, random_seed: Option<u64>) -> HashMap<String, String> {
+    if let Some(seed) = random_seed {
+        metadata.insert(RANDOM_SEED_METADATA_KEY.to_string(), seed.to_string());
+    }
+    metadata
+}
+
+/// Record the class and traceback of an exception raised by a Python or JavaScript cell in its output's metadata.
+fn exception_metadata(error: &ExecutionStateErrors) -> HashMap<String, String> {
+    match error {
+        ExecutionStateErrors::PythonException { exception_type, traceback, .. } => HashMap::from([
+            (EXCEPTION_TYPE_METADATA_KEY.to_string(), exception_type.clone()),
+            (TRACEBACK_METADATA_KEY.to_string(), traceback.clone()),
+        ]),
+        ExecutionStateErrors::JavaScriptException { name, stack, .. } => HashMap::from([
+            (EXCEPTION_TYPE_METADATA_KEY.to_string(), name.clone()),
+            (TRACEBACK_METADATA_KEY.to_string(), stack.clone()),
+        ]),
+        _ => HashMap::new(),
+    }
+}
+
+/// Validate the output of a cell against the schema it declares, an output that violates it fails the
+/// cell with the violation appended to its stderr.
+fn check_output_schema(
+    cell: &CodeCell,
+    output: Result<RkyvSerializedValue, ExecutionStateErrors>,
+    stderr: &mut Vec<LogLine>,
+) -> Result<RkyvSerializedValue, ExecutionStateErrors> {
+    let (Some(schema), Ok(value)) = (&cell.output_schema, &output) else {
+        return output;
+    };
+    let validated = serde_json::from_str::<serde_json::Value>(schema)
+        .map_err(|e| format!("Invalid output schema: {}", e))
+        .and_then(|schema| validate_against_schema(&serialized_value_to_json_value(value), &schema, "$"));
+    match validated {
+        Ok(()) => output,
+        Err(reason) => {
+            stderr.push(LogLine::stderr(&reason));
+            Err(ExecutionStateErrors::OutputSchemaViolation(reason))
+        }
+    }
+}
+
+/// The directory a code cell executes in, its own configuration takes precedence over the run's.
+fn working_directory<'a>(execution_state: &'a ExecutionState, cell: &'a CodeCell) -> Option<&'a str> {
+    cell.cwd.as_deref().or(execution_state.cwd.as_deref())
+}
+
+/// Key of an invocation payload naming the function of a code cell to invoke, taking precedence
+/// over the function the cell was constructed to invoke. This lets one cell serve as a module of
+/// functions invoked from different call sites.
+pub const INVOKED_FUNCTION_KEY: &str = "function_name";
+
+/// The function an invocation of a code cell targets, None when the cell is executed as a whole.
+pub(crate) fn invoked_function(payload: &RkyvSerializedValue, function_invocation: &Option<String>) -> Option<String> {
+    match payload {
+        RkyvSerializedValue::Object(payload) => match payload.get(INVOKED_FUNCTION_KEY) {
+            Some(RkyvSerializedValue::String(name)) => Some(name.clone()),
+            _ => function_invocation.clone(),
+        },
+        _ => function_invocation.clone(),
+    }
+}
+
+/// The functions defined by python source mapped to the named outputs each declares, empty for
+/// functions that return a single value. None when the source cannot be analyzed.
+fn declared_functions(source_code: &str) -> Option<HashMap<String, Vec<String>>> {
+    let paths = chidori_static_analysis::language::python::parse::extract_dependencies_python(source_code).ok()?;
+    let report = chidori_static_analysis::language::python::parse::build_report(&paths);
+    let (_, output_signature) = signatures_from_report(&report, None);
+    Some(output_signature.functions
+        .into_iter()
+        .map(|(name, config)| match config {
+            OutputItemConfiguration::Function { outputs, .. } => (name, outputs),
+            OutputItemConfiguration::Value => (name, vec![]),
+        })
+        .collect())
+}
+
+/// Split the value returned by a function invocation into its declared named outputs, a tuple is
+/// matched to the names by position and a dictionary by key.
+fn split_named_outputs(function_name: &str, value: RkyvSerializedValue, outputs: &[String]) -> Result<RkyvSerializedValue, ExecutionStateErrors> {
+    let mismatch = |found: String| ExecutionStateErrors::Unknown(format!(
+        "Function {} declares outputs {:?} but returned {}", function_name, outputs, found
+    ));
+    match value {
+        RkyvSerializedValue::Array(items) => {
+            if items.len() != outputs.len() {
+                return Err(mismatch(format!("a tuple of {} values", items.len())));
+            }
+            Ok(RkyvSerializedValue::Object(outputs.iter().cloned().zip(items).collect()))
+        }
+        RkyvSerializedValue::Object(map) => {
+            let mut keys = map.keys().cloned().collect::<Vec<_>>();
+            keys.sort();
+            let mut expected = outputs.to_vec();
+            expected.sort();
+            if keys != expected {
+                return Err(mismatch(format!("a dictionary with keys {:?}", keys)));
+            }
+            Ok(RkyvSerializedValue::Object(map))
+        }
+        other => Err(mismatch(format!("a single value {:?}", other))),
+    }
+}
+
+/// Evaluates the source of a default argument value when it is a literal, defaults computed by
+/// an expression are left for the language to apply when the function is called.
+fn python_literal_to_rkyv(source: &str) -> Option<RkyvSerializedValue> {
+    let source = source.trim();
+    match source {
+        "None" => return Some(RkyvSerializedValue::Null),
+        "True" => return Some(RkyvSerializedValue::Boolean(true)),
+        "False" => return Some(RkyvSerializedValue::Boolean(false)),
+        _ => {}
+    }
+    if let Ok(n) = source.parse::<i64>() {
+        return Some(RkyvSerializedValue::from_i64(n));
+    }
+    // Parsing as a float also accepts names such as inf and nan
+    let numeric = source.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.');
+    if let (true, Ok(f)) = (numeric, source.parse::<f64>()) {
+        return Some(RkyvSerializedValue::Float(f));
+    }
+    let quoted = source.len() >= 2
+        && ((source.starts_with('"') && source.ends_with('"')) || (source.starts_with('\'') && source.ends_with('\'')));
+    if quoted && !source[1..source.len() - 1].contains(['\\', '"', '\'']) {
+        return Some(RkyvSerializedValue::String(source[1..source.len() - 1].to_string()));
+    }
+    None
+}
+
+/// Infers a constraint from the source of an argument's type annotation, only `Literal` annotations
+/// listing strings are understood, e.g. `Literal["red", "green"]`.
+fn python_annotation_to_input_type(source: &str) -> Option<InputType> {
+    let source = source.trim();
+    let inner = source.strip_prefix("typing.Literal[")
+        .or_else(|| source.strip_prefix("Literal["))?
+        .strip_suffix(']')?;
+    let values = inner.split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| match python_literal_to_rkyv(item) {
+            Some(RkyvSerializedValue::String(s)) => Some(s),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(InputType::Enum(values))
+}
+
+fn signatures_from_report(report: &Report, optional_inputs: Option<&[String]>) -> (InputSignature, OutputSignature) {
     let mut input_signature = InputSignature::new();
     for (key, value) in &report.cell_depended_values {
         input_signature.globals.insert(
@@ -109,6 +400,8 @@ fn signatures_from_report(report: &Report) -> (InputSignature, OutputSignature)
             InputItemConfiguration {
                 ty: Some(InputType::String),
                 default: None,
+                variadic: false,
+                optional: optional_inputs.is_some_and(|inputs| inputs.contains(key)),
             },
         );
     }
@@ -125,8 +418,28 @@ fn signatures_from_report(report: &Report) -> (InputSignature, OutputSignature)
         let mut input_signature = InputSignature::new();
         for (i, arg) in value.arguments.iter().enumerate() {
             input_signature.args.insert(arg.clone(), InputItemConfiguration {
-                ty: Some(InputType::String),
+                ty: Some(value.argument_annotations.get(arg)
+                    .and_then(|source| python_annotation_to_input_type(source))
+                    .unwrap_or(InputType::String)),
+                default: value.argument_defaults.get(arg).and_then(|source| python_literal_to_rkyv(source)),
+                variadic: false,
+                optional: false,
+            });
+        }
+        if let Some(arg) = &value.variadic_args {
+            input_signature.args.insert(arg.clone(), InputItemConfiguration {
+                ty: None,
                 default: None,
+                variadic: true,
+                optional: false,
+            });
+        }
+        if let Some(arg) = &value.variadic_kwargs {
+            input_signature.kwargs.insert(arg.clone(), InputItemConfiguration {
+                ty: None,
+                default: None,
+                variadic: true,
+                optional: false,
             });
         }
 
@@ -134,8 +447,9 @@ fn signatures_from_report(report: &Report) -> (InputSignature, OutputSignature)
             key.clone(),
             OutputItemConfiguration::Function {
                 input_signature,
-                emit_event: vec![],
-                trigger_on: vec![],
+                emit_event: value.emit_event.clone(),
+                trigger_on: value.trigger_on.clone(),
+                outputs: value.returned_names.clone(),
             },
         );
     }
@@ -145,9 +459,347 @@ fn signatures_from_report(report: &Report) -> (InputSignature, OutputSignature)
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use indoc::indoc;
+    use uuid::Uuid;
+    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+
     #[tokio::test]
     async fn test_code_cell() {
 
 
     }
+
+    #[test]
+    fn test_signatures_report_dependencies_and_outputs() {
+        let op = code_cell(Uuid::nil(), &CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: String::from(indoc! { r#"
+                total = price * quantity
+                def discounted(rate):
+                    return total * rate
+                "#}),
+            ..Default::default()
+        }, &TextRange::default()).unwrap();
+        let mut inputs: Vec<&String> = op.input_signature().globals.keys().collect();
+        inputs.sort();
+        assert_eq!(inputs, vec!["price", "quantity"]);
+        assert!(op.output_signature().globals.contains_key("total"));
+        assert!(op.output_signature().functions.contains_key("discounted"));
+    }
+
+    #[test]
+    fn test_function_signature_includes_defaults_and_variadics() {
+        let op = code_cell(Uuid::nil(), &CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: String::from(indoc! { r#"
+                def f(x, y=10, **opts):
+                    return x + y
+                "#}),
+            ..Default::default()
+        }, &TextRange::default()).unwrap();
+        let Some(OutputItemConfiguration::Function { input_signature, .. }) = op.signature.output_signature.functions.get("f") else {
+            panic!("f should be exposed as a function");
+        };
+        assert_eq!(input_signature.args["x"].default, None);
+        assert_eq!(input_signature.args["y"].default, Some(RkyvSerializedValue::Number(10)));
+        assert!(input_signature.kwargs["opts"].variadic);
+
+        // Omitted arguments with defaults are filled in when invoked with keyword arguments
+        let payload = input_signature.fill_invocation_defaults(RkyvObjectBuilder::new()
+            .insert_object("kwargs", RkyvObjectBuilder::new().insert_number("x", 1))
+            .build());
+        assert_eq!(payload, RkyvObjectBuilder::new()
+            .insert_object("kwargs", RkyvObjectBuilder::new().insert_number("x", 1).insert_number("y", 10))
+            .build());
+    }
+
+    #[test]
+    fn test_literal_annotations_constrain_arguments() {
+        let op = code_cell(Uuid::nil(), &CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: String::from(indoc! { r#"
+                def paint(color: Literal["red", "green"], times: int):
+                    return color * times
+                "#}),
+            ..Default::default()
+        }, &TextRange::default()).unwrap();
+        let Some(OutputItemConfiguration::Function { input_signature, .. }) = op.signature.output_signature.functions.get("paint") else {
+            panic!("paint should be exposed as a function");
+        };
+        assert!(matches!(&input_signature.args["color"].ty, Some(InputType::Enum(values)) if values == &vec!["red".to_string(), "green".to_string()]));
+        assert!(matches!(input_signature.args["times"].ty, Some(InputType::String)));
+
+        let invocation = |color: &str| RkyvObjectBuilder::new()
+            .insert_object("kwargs", RkyvObjectBuilder::new().insert_string("color", color.to_string()).insert_number("times", 2))
+            .build();
+        assert!(input_signature.validate_invocation_values(&invocation("red")).is_ok());
+        assert!(input_signature.validate_invocation_values(&invocation("blue")).unwrap_err().contains("color"));
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_runs_in_configured_working_directory() {
+        let dir = std::env::temp_dir().join(format!("chidori_cwd_{}", Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let previous = std::env::current_dir().unwrap();
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: String::from(indoc! { r#"
+                with open("./out.txt", "w") as f:
+                    f.write("written")
+                "#}),
+            cwd: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let output = code_cell_exec_python(cell)(&ExecutionState::new_with_random_id(), RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "written");
+        assert_eq!(std::env::current_dir().unwrap(), previous);
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_output_schema() {
+        let configuration: crate::cells::CodeCellConfiguration = serde_yaml::from_str(indoc! { r#"
+            output_schema:
+              type: object
+              properties:
+                count:
+                  type: integer
+              required: [count]
+            "#}).unwrap();
+        let cell = |source_code: &str, output_schema: Option<String>| CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: source_code.to_string(),
+            output_schema,
+            ..Default::default()
+        };
+        let state = ExecutionState::new_with_random_id();
+
+        let output = code_cell_exec_python(cell("count = 3", configuration.output_schema.clone()))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output, Ok(RkyvObjectBuilder::new().insert_number("count", 3).build()));
+
+        let output = code_cell_exec_python(cell("count = \"three\"", configuration.output_schema.clone()))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        let Err(ExecutionStateErrors::OutputSchemaViolation(reason)) = &output.output else {
+            panic!("Expected a schema violation, got {:?}", output.output);
+        };
+        assert!(reason.contains("$.count expected type integer"), "{}", reason);
+        assert!(output.stderr_text().contains(reason.as_str()));
+
+        // Without a schema the output is passed along unchecked
+        let output = code_cell_exec_python(cell("count = \"three\"", None))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_reports_exception_type() {
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "values = {}\nmissing = values[\"missing\"]".to_string(),
+            ..Default::default()
+        };
+        let output = code_cell_exec_python(cell)(&ExecutionState::new_with_random_id(), RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.exception_type(), Some("KeyError"));
+        assert!(output.traceback().unwrap().contains("KeyError: 'missing'"), "{:?}", output.traceback());
+        let Err(ExecutionStateErrors::PythonException { exception_type, message, .. }) = &output.output else {
+            panic!("Expected a python exception, got {:?}", output.output);
+        };
+        assert_eq!(exception_type, "KeyError");
+        assert_eq!(message, "'missing'");
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_denied_imports() {
+        let cell = |source_code: &str| CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: source_code.to_string(),
+            denied_imports: Some(vec!["subprocess".to_string()]),
+            ..Default::default()
+        };
+        let state = ExecutionState::new_with_random_id().with_denied_imports(vec!["socket".to_string()], false);
+
+        let output = code_cell_exec_python(cell("import json\nx = json.dumps(1)"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+
+        for source_code in ["import socket", "from subprocess import run"] {
+            let output = code_cell_exec_python(cell(source_code))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+            assert!(output.has_error);
+            let module = source_code.split_whitespace().nth(1).unwrap();
+            assert_eq!(output.output, Err(ExecutionStateErrors::Unknown(format!("Import of module '{}' is denied", module))));
+        }
+
+        // Strict mode rejects the cell before any of it runs
+        let dir = std::env::temp_dir().join(format!("chidori_strict_imports_{}", Uuid::now_v7()));
+        let source_code = format!("open({:?}, \"w\").close()\nimport socket", dir.to_string_lossy());
+        let strict_state = state.clone().with_denied_imports(vec!["socket".to_string()], true);
+        let output = code_cell_exec_python(cell(&source_code))(&strict_state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_network_denied() {
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "import urllib.request\nbody = urllib.request.urlopen(\"http://localhost:9\").read()".to_string(),
+            ..Default::default()
+        };
+        let state = ExecutionState::new_with_random_id().with_deny_network(true);
+        let output = code_cell_exec_python(cell.clone())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.output, Err(ExecutionStateErrors::NetworkDenied("urllib.request".to_string())));
+
+        let strict_state = state.with_denied_imports(vec![], true);
+        let output = code_cell_exec_python(cell)(&strict_state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output, Err(ExecutionStateErrors::NetworkDenied("urllib.request".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_lua_cell_network_denied() {
+        let cell = CodeCell {
+            language: SupportedLanguage::Lua,
+            source_code: "local http = require('socket.http')".to_string(),
+            ..Default::default()
+        };
+        let state = ExecutionState::new_with_random_id().with_deny_network(true);
+        let output = code_cell_exec_lua(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.output, Err(ExecutionStateErrors::NetworkDenied("socket.http".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_network_denied_through_allowed_modules() {
+        let state = ExecutionState::new_with_random_id().with_deny_network(true);
+        // asyncio is allowed, and reaches the socket module it loaded before the cell ran
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: indoc! {r#"
+                import asyncio
+                async def connect():
+                    await asyncio.open_connection("127.0.0.1", 9)
+                asyncio.run(connect())
+                "#}.to_string(),
+            ..Default::default()
+        };
+        let output = code_cell_exec_python(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output, Err(ExecutionStateErrors::NetworkDenied("socket".to_string())));
+
+        // The denylist is lifted once the cell completes
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "import socket\nx = 1".to_string(),
+            ..Default::default()
+        };
+        let output = code_cell_exec_python(cell)(&ExecutionState::new_with_random_id(), RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error, "{:?}", output.output);
+    }
+
+    #[tokio::test]
+    async fn test_python_cell_randomness_is_replayed_from_its_recorded_seed() {
+        let cell = CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "import random\nfirst = random.random()\nsecond = random.random()".to_string(),
+            ..Default::default()
+        };
+        let operation_id = Uuid::now_v7();
+        let mut state = ExecutionState::new_with_random_id().with_random_seed(42);
+        state.evaluating_operation_id = operation_id;
+        let recorded = code_cell_exec_python(cell.clone())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!recorded.has_error);
+        let seed: u64 = recorded.metadata[RANDOM_SEED_METADATA_KEY].parse().unwrap();
+        assert_eq!(Some(seed), state.cell_random_seed(operation_id));
+
+        let mut replay = ExecutionState::new_with_random_id().with_replayed_seed(operation_id, seed);
+        replay.evaluating_operation_id = operation_id;
+        let replayed = code_cell_exec_python(cell.clone())(&replay, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(replayed.output, recorded.output);
+        assert_eq!(replayed.metadata[RANDOM_SEED_METADATA_KEY], seed.to_string());
+
+        let mut reseeded = ExecutionState::new_with_random_id().with_random_seed(43);
+        reseeded.evaluating_operation_id = operation_id;
+        let reseeded = code_cell_exec_python(cell)(&reseeded, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_ne!(reseeded.output, recorded.output);
+    }
+
+    #[test]
+    fn test_split_named_outputs() {
+        let outputs = vec!["total".to_string(), "count".to_string()];
+        let expected = RkyvObjectBuilder::new().insert_number("total", 6).insert_number("count", 3).build();
+        let tuple = RkyvSerializedValue::Array(vec![RkyvSerializedValue::Number(6), RkyvSerializedValue::Number(3)]);
+        assert_eq!(split_named_outputs("stats", tuple, &outputs).unwrap(), expected);
+        assert_eq!(split_named_outputs("stats", expected.clone(), &outputs).unwrap(), expected);
+
+        let err = split_named_outputs("stats", RkyvSerializedValue::Array(vec![RkyvSerializedValue::Number(6)]), &outputs).unwrap_err();
+        assert!(err.to_string().contains("a tuple of 1 values"), "{}", err);
+        let err = split_named_outputs("stats", RkyvObjectBuilder::new().insert_number("sum", 6).build(), &outputs).unwrap_err();
+        assert!(err.to_string().contains("[\"sum\"]"), "{}", err);
+        assert!(split_named_outputs("stats", RkyvSerializedValue::Number(6), &outputs).is_err());
+    }
+
+    #[test]
+    fn test_declared_function_outputs() {
+        let functions = declared_functions(indoc! { r#"
+            def stats(values):
+                return sum(values), len(values)
+
+            def pair(values):
+                total = sum(values)
+                count = len(values)
+                return total, count
+            "#}).unwrap();
+        assert_eq!(functions["pair"], vec!["total".to_string(), "count".to_string()]);
+        assert!(functions["stats"].is_empty());
+        assert_eq!(functions.len(), 2);
+    }
+
+    #[test]
+    fn test_invoked_function_is_selected_by_the_payload() {
+        let fixed = Some("stats".to_string());
+        let payload = RkyvObjectBuilder::new().insert_string(INVOKED_FUNCTION_KEY, "pair".to_string()).build();
+        assert_eq!(invoked_function(&payload, &fixed), Some("pair".to_string()));
+        assert_eq!(invoked_function(&RkyvObjectBuilder::new().build(), &fixed), fixed);
+        assert_eq!(invoked_function(&RkyvSerializedValue::Null, &None), None);
+    }
+
+    #[tokio::test]
+    async fn test_code_cell_dispatches_to_the_named_function() {
+        let op = code_cell(Uuid::nil(), &CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: String::from(indoc! { r#"
+                def double(x):
+                    return x * 2
+
+                def negate(x):
+                    return -x
+                "#}),
+            ..Default::default()
+        }, &TextRange::default()).unwrap();
+        let state = ExecutionState::new_with_random_id();
+        let invoke = |function_name: &str| RkyvObjectBuilder::new()
+            .insert_string(INVOKED_FUNCTION_KEY, function_name.to_string())
+            .insert_object("args", RkyvObjectBuilder::new().insert_number("0", 5))
+            .build();
+
+        let output = op.execute(&state, invoke("double"), None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvSerializedValue::Number(10));
+        let output = op.execute(&state, invoke("negate"), None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvSerializedValue::Number(-5));
+
+        let output = op.execute(&state, invoke("triple"), None, None).await.unwrap();
+        assert!(output.has_error);
+        let err = output.output.unwrap_err().to_string();
+        assert!(err.contains(r#"Function "triple" is not defined by this cell, it defines ["double", "negate"]"#), "{}", err);
+    }
+
+    #[test]
+    fn test_python_literal_defaults() {
+        assert_eq!(python_literal_to_rkyv("None"), Some(RkyvSerializedValue::Null));
+        assert_eq!(python_literal_to_rkyv("-3"), Some(RkyvSerializedValue::Number(-3)));
+        assert_eq!(python_literal_to_rkyv("1.5"), Some(RkyvSerializedValue::Float(1.5)));
+        assert_eq!(python_literal_to_rkyv("'hi'"), Some(RkyvSerializedValue::String("hi".to_string())));
+        assert_eq!(python_literal_to_rkyv("inf"), None);
+        assert_eq!(python_literal_to_rkyv("compute()"), None);
+    }
 }
\ No newline at end of file
@@ -1,13 +1,19 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::mpsc::Sender;
-use crate::cells::{CellTypes, LLMCodeGenCell, LLMCodeGenCellChatConfiguration, LLMPromptCell, SupportedModelProviders, TextRange};
+use crate::cells::{CellTypes, CodeCell, LLMCodeGenCell, LLMCodeGenCellChatConfiguration, LLMPromptCell, SupportedLanguage, SupportedModelProviders, TextRange};
 use crate::execution::primitives::operation::{AsyncRPCCommunication, InputItemConfiguration, InputSignature, InputType, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature};
 use crate::execution::primitives::serialized_value::{RkyvSerializedValue as RKV, RkyvSerializedValue, serialized_value_to_json_value};
 use futures_util::FutureExt;
 use chidori_prompt_format::templating::templates::{ChatModelRoles, TemplateWithSource};
 use crate::execution::execution::execution_graph::ExecutionNodeId;
 use crate::execution::execution::ExecutionState;
+use crate::execution::primitives::operation::LogLine;
+use crate::library::std::ai::llm::{ai_llm_generate_code, apply_generated_code, GeneratedCode, MessageRole, TemplateMessage};
+use crate::library::std::code::runtime_pyo3::source_code_run_python;
+use crate::cells::llm_prompt_cell::run_within_deadlines;
+use crate::sdk::md::interpret_markdown_code_block;
+use tracing::{warn, Instrument};
 
 #[tracing::instrument]
 pub fn code_gen_cell(execution_state_id: ExecutionNodeId, cell: &LLMCodeGenCell, range: &TextRange) -> anyhow::Result<OperationNode> {
@@ -57,8 +63,10 @@ pub fn code_gen_cell(execution_state_id: ExecutionNodeId, cell: &LLMCodeGenCell,
             input_signature.globals.insert(
                 key.clone(),
                 InputItemConfiguration {
-                    ty: Some(InputType::String),
+                    ty: Some(InputType::from(&value.ty)),
                     default: None,
+                    variadic: false,
+                    optional: false,
                 },
             );
         }
@@ -72,7 +80,8 @@ pub fn code_gen_cell(execution_state_id: ExecutionNodeId, cell: &LLMCodeGenCell,
     }
 
     match provider {
-        SupportedModelProviders::OpenAI => Ok(OperationNode::new(
+        None => Err(anyhow::anyhow!("Code generation cell does not declare a provider and no default_provider is configured")),
+        Some(SupportedModelProviders::OpenAI) => Ok(OperationNode::new(
             name.clone(),
             execution_state_id,
             input_signature,
@@ -117,21 +126,212 @@ pub fn code_gen_cell_exec_openai(cell: LLMCodeGenCell) -> Box<OperationFn> {
         let s = s.clone();
         let configuration = configuration.clone();
         async move {
-            let (value, state) = crate::library::std::ai::llm::ai_llm_code_generation_chat_model(
-                &s,
-                payload,
-                role_blocks,
-                name,
-                is_function_invocation,
-                configuration.clone()
-            ).await?;
-            Ok(OperationFnOutput {
-                has_error: false,
-                execution_state: state,
-                output: Ok(value),
-                stdout: vec![],
-                stderr: vec![],
-            })
+            let execution = async {
+                if let Some(max_attempts) = configuration.max_repair_attempts {
+                    return generate_with_repair(&s, payload, role_blocks, configuration.clone(), max_attempts).await;
+                }
+                let (value, state) = crate::library::std::ai::llm::ai_llm_code_generation_chat_model(
+                    &s,
+                    payload,
+                    role_blocks,
+                    name,
+                    is_function_invocation,
+                    configuration.clone()
+                ).await?;
+                Ok(OperationFnOutput {
+                    has_error: false,
+                    execution_state: state,
+                    output: Ok(value),
+                    stdout: vec![],
+                    stderr: vec![],
+                    metadata: Default::default(),
+                })
+            };
+            run_within_deadlines(&s, None, s.default_llm_timeout_ms(), execution).await
         }.boxed()
     })
 }
+
+/// Generate code, executing the Python it contains and feeding any error back to the model until
+/// it runs cleanly or `max_attempts` have been made. Each attempt is recorded as a span of the
+/// cell's trace. Once the attempts are exhausted the last generation is returned with `has_error`
+/// set and the error of each attempt in stderr, without adding its cells to the graph.
+async fn generate_with_repair(
+    execution_state: &ExecutionState,
+    payload: RkyvSerializedValue,
+    role_blocks: Vec<(ChatModelRoles, Option<TemplateWithSource>)>,
+    configuration: LLMCodeGenCellChatConfiguration,
+    max_attempts: usize,
+) -> anyhow::Result<OperationFnOutput> {
+    let mut feedback = vec![];
+    let mut failures = vec![];
+    let mut last_output = None;
+    for attempt in 1..=max_attempts.max(1) {
+        let span = tracing::info_span!("code_generation_attempt", attempt);
+        let (text, error) = async {
+            let text = ai_llm_generate_code(execution_state, &payload, &role_blocks, feedback.clone(), &configuration).await?;
+            let error = match &text {
+                Some(text) => execute_generated_python(execution_state, &GeneratedCode::from_response(text, &configuration).source).await,
+                None => Some("the model did not produce any code".to_string()),
+            };
+            anyhow::Ok((text, error))
+        }.instrument(span).await?;
+
+        match (text, error) {
+            (Some(text), None) => {
+                let generated = GeneratedCode::from_response(&text, &configuration);
+                let state = apply_generated_code(execution_state, &generated.source).await?;
+                return Ok(OperationFnOutput {
+                    has_error: false,
+                    execution_state: Some(state),
+                    output: Ok(generated.output(&configuration)),
+                    stdout: vec![],
+                    stderr: failures,
+                    metadata: Default::default(),
+                });
+            }
+            (text, error) => {
+                let error = error.unwrap_or_default();
+                warn!("Code generation attempt {} failed: {}", attempt, error);
+                failures.push(LogLine::stderr(format!("Attempt {} failed: {}", attempt, error)));
+                if let Some(text) = &text {
+                    feedback.push(TemplateMessage {
+                        role: MessageRole::Assistant,
+                        content: text.clone(),
+                        name: None,
+                        function_call: None,
+                    });
+                }
+                feedback.push(TemplateMessage {
+                    role: MessageRole::User,
+                    content: format!("The code failed when executed with the following error, respond with corrected code.\n{}", error),
+                    name: None,
+                    function_call: None,
+                });
+                last_output = text
+                    .map(|text| GeneratedCode::from_response(&text, &configuration).output(&configuration))
+                    .or(last_output);
+            }
+        }
+    }
+    Ok(OperationFnOutput {
+        has_error: true,
+        execution_state: None,
+        output: Ok(last_output.unwrap_or(RkyvSerializedValue::Null)),
+        stdout: vec![],
+        stderr: failures,
+        metadata: Default::default(),
+    })
+}
+
+/// Execute the Python cells of generated code, returning the error of the first that fails.
+async fn execute_generated_python(execution_state: &ExecutionState, text: &str) -> Option<String> {
+    let cells = crate::sdk::md::extract_code_blocks(text)
+        .iter()
+        .filter_map(|block| interpret_markdown_code_block(block, None).ok().flatten())
+        .collect::<Vec<_>>();
+    for cell in cells {
+        let CellTypes::Code(CodeCell { language: SupportedLanguage::PyO3, source_code, .. }, _) = cell else {
+            continue;
+        };
+        match source_code_run_python(execution_state, &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await {
+            Ok((Ok(_), ..)) => {}
+            Ok((Err(e), ..)) => return Some(e.to_string()),
+            Err(e) => return Some(e.to_string()),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+    use crate::library::std::ai::llm::mock::{MockChatModel, RequestMatcher};
+
+    fn code_gen_cell_with_attempts(max_attempts: usize) -> LLMCodeGenCell {
+        LLMCodeGenCell {
+            backing_file_reference: None,
+            function_invocation: false,
+            configuration: Default::default(),
+            name: Some("generated".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            req: "Write a function that adds two numbers".to_string(),
+            complete_body: format!("---\nmax_repair_attempts: {}\n---\nWrite a function that adds two numbers", max_attempts),
+        }
+    }
+
+    const FAILING_CODE: &str = "```python\nraise ValueError(\"boom\")\n```";
+    const PASSING_CODE: &str = "```python\ndef add(a, b):\n    return a + b\n```";
+
+    #[tokio::test]
+    async fn test_repair_loop_feeds_back_errors() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("boom".to_string()), PASSING_CODE)
+            .respond_when(RequestMatcher::Any, FAILING_CODE)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = code_gen_cell_exec_openai(code_gen_cell_with_attempts(3))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvSerializedValue::String(PASSING_CODE.to_string()));
+        assert_eq!(output.stderr.len(), 1);
+        assert!(output.execution_state.unwrap().function_name_to_metadata.contains_key("add"));
+        assert_eq!(model.calls(0), 1);
+        assert_eq!(model.calls(1), 1);
+    }
+
+    fn code_gen_cell_with_explanation() -> LLMCodeGenCell {
+        LLMCodeGenCell {
+            complete_body: "---\nfn: add\nexplanation: true\n---\nWrite a function that adds two numbers".to_string(),
+            function_invocation: true,
+            ..code_gen_cell_with_attempts(1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_structured_code_and_explanation() {
+        let requests_structured_output = RequestMatcher::custom("request with a json_schema response format", |req| {
+            req.extra["response_format"]["type"] == "json_schema"
+        });
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(requests_structured_output, r#"{"code": "def add(a, b):\n    return a + b", "explanation": "Sums its arguments."}"#)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = code_gen_cell_exec_openai(code_gen_cell_with_explanation())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new()
+            .insert_string("code", "def add(a, b):\n    return a + b".to_string())
+            .insert_string("explanation", "Sums its arguments.".to_string())
+            .insert_string("add", "def add(a, b):\n    return a + b".to_string())
+            .build());
+        assert!(output.execution_state.unwrap().function_name_to_metadata.contains_key("add"));
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_unstructured_response_falls_back_to_fenced_code() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, PASSING_CODE)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = code_gen_cell_exec_openai(code_gen_cell_with_explanation())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        let RkyvSerializedValue::Object(output) = output.output.unwrap() else { panic!("expected an object") };
+        assert_eq!(output["code"], RkyvSerializedValue::String("def add(a, b):\n    return a + b".to_string()));
+        assert_eq!(output["explanation"], RkyvSerializedValue::String(String::new()));
+    }
+
+    #[tokio::test]
+    async fn test_repair_loop_returns_last_attempt_when_exhausted() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, FAILING_CODE)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = code_gen_cell_exec_openai(code_gen_cell_with_attempts(2))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvSerializedValue::String(FAILING_CODE.to_string()));
+        assert_eq!(output.stderr.len(), 2);
+        assert!(output.stderr[1].text.contains("boom"), "{}", output.stderr[1].text);
+        assert!(output.execution_state.is_none());
+        assert_eq!(model.calls(0), 2);
+    }
+}
@@ -0,0 +1,136 @@
+use futures_util::FutureExt;
+
+use crate::cells::{CellTypes, LLMEmbeddingCell, TextRange};
+use crate::execution::execution::execution_graph::ExecutionNodeId;
+use crate::execution::primitives::operation::{InputItemConfiguration, InputSignature, InputType, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature};
+use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+use crate::library::std::ai::llm::ai_llm_run_embedding_model;
+
+/// Embedding cells render their body as a template against the globals it references, and embed
+/// the result with the model and dimensions of their frontmatter.
+#[tracing::instrument]
+pub fn embedding_cell(execution_state_id: ExecutionNodeId, cell: &LLMEmbeddingCell, range: &TextRange) -> anyhow::Result<OperationNode> {
+    let schema = chidori_prompt_format::templating::templates::analyze_referenced_partials(&cell.req)?;
+
+    let mut input_signature = InputSignature::new();
+    for (key, value) in &schema.items {
+        input_signature.globals.insert(
+            key.clone(),
+            InputItemConfiguration {
+                ty: Some(InputType::from(&value.ty)),
+                default: None,
+                variadic: false,
+                optional: false,
+            },
+        );
+    }
+
+    let mut output_signature = OutputSignature::new();
+    if let Some(name) = &cell.name {
+        output_signature.globals.insert(
+            name.clone(),
+            OutputItemConfiguration::Value,
+        );
+    }
+
+    Ok(OperationNode::new(
+        cell.name.clone(),
+        execution_state_id,
+        input_signature,
+        output_signature,
+        CellTypes::Embedding(cell.clone(), Default::default())
+    ))
+}
+
+pub fn embedding_cell_exec(cell: LLMEmbeddingCell) -> Box<OperationFn> {
+    Box::new(move |s, payload, _, _| {
+        let s = s.clone();
+        let cell = cell.clone();
+        async move {
+            let result = ai_llm_run_embedding_model(
+                &s,
+                payload,
+                &cell.req,
+                cell.name.clone(),
+                cell.function_invocation,
+                &cell.configuration,
+            ).await;
+            Ok(match result {
+                Ok(value) => OperationFnOutput::with_value(value),
+                Err(e) => OperationFnOutput {
+                    has_error: true,
+                    output: Err(e),
+                    ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+                },
+            })
+        }.boxed()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use indoc::indoc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::execution::execution::ExecutionState;
+    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+    use crate::library::std::ai::llm::{EmbeddingModel, EmbeddingReq};
+    use crate::sdk::md::{extract_code_blocks, interpret_markdown_code_block};
+
+    /// Responds with vectors of the requested length, recording the requests it receives.
+    #[derive(Default)]
+    struct RecordingEmbeddingModel {
+        requests: Mutex<Vec<(String, String, Option<u32>)>>,
+    }
+
+    #[async_trait]
+    impl EmbeddingModel for RecordingEmbeddingModel {
+        async fn embed(&self, embedding_req: EmbeddingReq) -> Result<Vec<f32>, String> {
+            let length = embedding_req.dimensions.unwrap_or(4) as usize;
+            self.requests.lock().unwrap().push((embedding_req.content, embedding_req.model, embedding_req.dimensions));
+            Ok(vec![0.5; length])
+        }
+    }
+
+    fn embedding_cell_from_markdown(contents: &str) -> CellTypes {
+        let blocks = extract_code_blocks(contents);
+        interpret_markdown_code_block(&blocks[0], None).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embedding_cell_embeds_its_rendered_body() {
+        let model = Arc::new(RecordingEmbeddingModel::default());
+        let mut state = ExecutionState::new_with_random_id().with_embedding_model(model.clone());
+        let cell = embedding_cell_from_markdown(indoc! { r#"
+            ```embedding (vector)
+            ---
+            model: text-embedding-3-large
+            dimensions: 8
+            ---
+            A document about {{topic}}
+            ```
+            "#});
+        let CellTypes::Embedding(embedding, _) = &cell else {
+            panic!("Expected an embedding cell");
+        };
+        assert_eq!(embedding.configuration.model.as_deref(), Some("text-embedding-3-large"));
+        assert_eq!(embedding.configuration.dimensions, Some(8));
+
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        state = state.upsert_operation(op, Uuid::now_v7()).unwrap().1;
+        let state = state.with_initial_globals(RkyvObjectBuilder::new().insert_string("topic", "whales".to_string()).build()).unwrap();
+        let (state, _) = state.step_execution().await.unwrap();
+
+        let expected = RkyvSerializedValue::Array(vec![RkyvSerializedValue::Float(0.5); 8]);
+        assert_eq!(state.get_output_by_name("vector").unwrap(), Some(RkyvObjectBuilder::new().insert_value("vector", expected).build()));
+        assert_eq!(*model.requests.lock().unwrap(), vec![(
+            "A document about whales".to_string(),
+            "text-embedding-3-large".to_string(),
+            Some(8),
+        )]);
+    }
+}
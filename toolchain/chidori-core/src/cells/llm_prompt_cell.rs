@@ -4,20 +4,27 @@ use std::env;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::mpsc::Sender;
-use tokio::runtime;
+use std::time::Duration;
 use crate::cells::{llm_prompt_cell, CellTypes, LLMPromptCell, LLMPromptCellChatConfiguration, SupportedModelProviders, TextRange};
-use crate::execution::primitives::operation::{AsyncRPCCommunication, InputItemConfiguration, InputSignature, InputType, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature};
+use crate::execution::primitives::operation::{AsyncRPCCommunication, InputItemConfiguration, InputSignature, InputType, LogLine, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature, TEMPLATE_HASH_METADATA_KEY};
 use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue as RKV, RkyvSerializedValue, serialized_value_to_json_value};
 use futures_util::FutureExt;
+use sha1::{Digest, Sha1};
 use crate::execution::execution::execution_graph::ExecutionNodeId;
 use crate::execution::execution::ExecutionState;
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::execution::execution::progress::ProgressEvent;
 use crate::library::std::ai::llm::ai_llm_run_embedding_model;
+use crate::library::std::ai::llm::examples::load_examples;
+use crate::library::std::ai::llm::validation::{retry_until_valid, validator_from_configuration, Attempt};
+use crate::library::std::ai::llm::tokenizer::validate_logit_bias;
 
 
 
-/// LLM Prompt Cells allow notebooks to invoke language models to generate text.
+/// LLM Prompt Cells allow notebooks to invoke language models to generate text. A relative examples
+/// file of the cell is resolved against `cwd`.
 #[tracing::instrument]
-pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell, range: &TextRange) -> anyhow::Result<OperationNode> {
+pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell, range: &TextRange, cwd: Option<&str>) -> anyhow::Result<OperationNode> {
     match cell {
         llm_prompt_cell @ LLMPromptCell::Chat {
             is_function_invocation: function_invocation,
@@ -30,6 +37,11 @@ pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell
                 anyhow::Error::msg(e.to_string())
             })?;
             let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(&frontmatter)?;
+            if let Some(validation) = &configuration.validation {
+                validator_from_configuration(validation).map_err(anyhow::Error::msg)?;
+            }
+            validate_logit_bias(&configuration).map_err(anyhow::Error::msg)?;
+            let examples = load_examples(&configuration, cwd)?;
             let role_blocks =
                 chidori_prompt_format::templating::templates::extract_roles_from_template(&&req);
 
@@ -51,21 +63,33 @@ pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell
             }
 
             let mut input_signature = InputSignature::new();
-            let schema =
-                chidori_prompt_format::templating::templates::analyze_referenced_partials(&&req);
             // We only require the globals to be passed in if the user has not specified this prompt as a function
             if configuration.function_name.is_none() {
-                for (key, value) in &schema.unwrap().items {
-                    input_signature.globals.insert(
-                        key.clone(),
-                        InputItemConfiguration {
-                            ty: Some(InputType::String),
+                // The inputs and outputs of examples are templates rendered against the same globals
+                let example_templates = examples.iter().flat_map(|example| [&example.input, &example.output]);
+                for template in std::iter::once(&req).chain(example_templates) {
+                    let schema =
+                        chidori_prompt_format::templating::templates::analyze_referenced_partials(template)?;
+                    for (key, value) in &schema.items {
+                        input_signature.globals.entry(key.clone()).or_insert(InputItemConfiguration {
+                            ty: Some(InputType::from(&value.ty)),
                             default: None,
-                        },
-                    );
+                            variadic: false,
+                            optional: false,
+                        });
+                    }
                 }
             }
 
+            for (key, constraint) in configuration.inputs.iter().flatten() {
+                let Some(input) = input_signature.globals.get_mut(key) else {
+                    return Err(anyhow::anyhow!("Prompt cell declares a constraint on input '{}' which it does not reference", key));
+                };
+                input.ty = Some(InputType::try_from(constraint).map_err(|e| {
+                    anyhow::anyhow!("Prompt cell declares an invalid pattern on input '{}': {}", key, e)
+                })?);
+            }
+
             let name = name.clone();
             let configuration = configuration.clone();
             let is_function_invocation = function_invocation.clone();
@@ -81,20 +105,31 @@ pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell
                         InputItemConfiguration {
                             ty: Some(InputType::String),
                             default: None,
+                            variadic: false,
+                            optional: false,
                         },
                     );
                 }
             }
 
+            // The cell executed carries the examples of its examples file, so that the file is only
+            // read once
+            let mut built_cell = llm_prompt_cell.clone();
+            if let LLMPromptCell::Chat { configuration: built_configuration, .. } = &mut built_cell {
+                built_configuration.examples = Some(examples);
+                built_configuration.examples_file = None;
+            }
+
             match provider {
-                SupportedModelProviders::OpenAI => Ok(OperationNode::new(
+                None => Err(anyhow::anyhow!("Prompt cell does not declare a provider and no default_provider is configured")),
+                Some(SupportedModelProviders::OpenAI) => Ok(OperationNode::new(
                     name.clone(),
                     execution_state_id,
                     input_signature,
                     output_signature,
-                    CellTypes::Prompt(llm_prompt_cell.clone(), Default::default())
+                    CellTypes::Prompt(built_cell, Default::default())
                     // llm_prompt_cell_exec_chat_openai(),
-                )),
+                ).with_map(configuration.map.clone().filter(|_| !is_function_invocation))),
             }
         }
         LLMPromptCell::Completion { .. } => {
@@ -103,20 +138,43 @@ pub fn llm_prompt_cell(execution_state_id: ExecutionNodeId, cell: &LLMPromptCell
     }
 }
 
+/// Hex encoded hash of a template before it is rendered. Line endings and trailing whitespace are
+/// normalized so that the hash only changes when the content of the template does.
+pub fn template_hash(template: &str) -> String {
+    let normalized = template
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Sha1::digest(normalized.trim().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn llm_prompt_cell_exec_chat_openai(llm_prompt_cell: LLMPromptCell) -> Box<OperationFn> {
     let LLMPromptCell::Chat {
         is_function_invocation,
         name,
         provider,
         complete_body,
+        configuration: cell_configuration,
         ..
     } = llm_prompt_cell else { unreachable!() };
     let (frontmatter, req) = chidori_prompt_format::templating::templates::split_frontmatter(&complete_body).map_err(|e| {
         anyhow::Error::msg(e.to_string())
     }).unwrap();
-    let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(&frontmatter).unwrap();
+    let mut configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(&frontmatter).unwrap();
+    // A built cell carries its examples, including those loaded from its examples file
+    if let Some(examples) = cell_configuration.examples {
+        configuration.examples = Some(examples);
+        configuration.examples_file = None;
+    }
     let role_blocks =
         chidori_prompt_format::templating::templates::extract_roles_from_template(&&req);
+    // An invalid validation configuration fails the cell when it executes rather than panicking
+    let validator = configuration.validation.as_ref()
+        .map(validator_from_configuration)
+        .transpose()
+        .map(Option::flatten);
+    let template_hash = template_hash(&req);
 
     Box::new(move |s, payload, _, _| {
         let role_blocks = role_blocks.clone();
@@ -133,22 +191,806 @@ pub fn llm_prompt_cell_exec_chat_openai(llm_prompt_cell: LLMPromptCell) -> Box<O
         }
         let s = s.clone();
         let configuration = configuration.clone();
+        let validator = validator.clone();
+        let template_hash = template_hash.clone();
         async move {
-            let (value, state) = crate::library::std::ai::llm::ai_llm_run_chat_model(
-                &s,
-                payload,
-                role_blocks,
-                name,
-                is_function_invocation,
-                configuration.clone()
-            ).await?;
-            Ok(OperationFnOutput {
-                has_error: false,
-                execution_state: state,
-                output: value,
-                stdout: vec![],
-                stderr: vec![],
-            })
+            let validator = match validator {
+                Ok(validator) => validator,
+                Err(e) => return Ok(OperationFnOutput {
+                    has_error: true,
+                    output: Err(ExecutionStateErrors::Unknown(e)),
+                    ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+                }),
+            };
+            let run = |attempt: Attempt| {
+                let s = s.clone();
+                let template_hash = template_hash.clone();
+                let payload = payload.clone();
+                let role_blocks = role_blocks.clone();
+                let name = name.clone();
+                let mut configuration = configuration.clone();
+                // Double the completion budget for every earlier attempt that was truncated by it
+                if attempt.truncated_attempts > 0 {
+                    configuration.max_tokens = configuration.max_tokens
+                        .map(|max_tokens| max_tokens.saturating_mul(2i64.saturating_pow(attempt.truncated_attempts as u32)));
+                }
+                async move {
+                    let (value, state, mut metadata) = crate::library::std::ai::llm::ai_llm_run_chat_model(
+                        &s,
+                        payload,
+                        role_blocks,
+                        name,
+                        is_function_invocation,
+                        configuration,
+                        // A retry sends the request that produced the invalid output again, which
+                        // the response cache would answer with that same output
+                        attempt.number == 1,
+                    ).await?;
+                    metadata.insert(TEMPLATE_HASH_METADATA_KEY.to_string(), template_hash);
+                    Ok(OperationFnOutput {
+                        has_error: value.is_err(),
+                        execution_state: state,
+                        output: value,
+                        stdout: vec![],
+                        stderr: vec![],
+                        metadata,
+                    })
+                }
+            };
+            let execution = async {
+                match validator {
+                    Some(validator) => {
+                        let max_attempts = configuration.validation.as_ref().and_then(|v| v.max_attempts);
+                        retry_until_valid(max_attempts, validator, run).await
+                    }
+                    None => run(Attempt { number: 1, truncated_attempts: 0 }).await,
+                }
+            };
+            let hard_timeout_ms = configuration.hard_timeout_ms.or_else(|| s.default_llm_timeout_ms());
+            run_within_deadlines(&s, configuration.soft_timeout_ms, hard_timeout_ms, execution).await
         }.boxed()
     })
 }
+
+/// Run the execution of a cell within its deadlines. Passing the soft deadline reports the cell as
+/// slow through the progress channel and leaves it running, passing the hard deadline abandons the
+/// execution and fails the cell.
+pub(crate) async fn run_within_deadlines(
+    execution_state: &ExecutionState,
+    soft_timeout_ms: Option<u64>,
+    hard_timeout_ms: Option<u64>,
+    execution: impl Future<Output = anyhow::Result<OperationFnOutput>>,
+) -> anyhow::Result<OperationFnOutput> {
+    let deadline = |timeout_ms: Option<u64>| async move {
+        match timeout_ms {
+            Some(timeout_ms) => tokio::time::sleep(Duration::from_millis(timeout_ms)).await,
+            None => std::future::pending().await,
+        }
+    };
+    let soft_deadline = deadline(soft_timeout_ms);
+    let hard_deadline = deadline(hard_timeout_ms);
+    tokio::pin!(execution, soft_deadline, hard_deadline);
+    let mut reported_slow = false;
+    loop {
+        tokio::select! {
+            output = &mut execution => return output,
+            _ = &mut soft_deadline, if !reported_slow => {
+                reported_slow = true;
+                execution_state.emit_progress(ProgressEvent::CellSlow {
+                    operation_id: execution_state.evaluating_operation_id,
+                    name: execution_state.evaluating_name.clone(),
+                    elapsed_ms: soft_timeout_ms.unwrap_or_default(),
+                });
+            }
+            _ = &mut hard_deadline => {
+                let error = ExecutionStateErrors::CellTimedOut(hard_timeout_ms.unwrap_or_default());
+                return Ok(OperationFnOutput {
+                    has_error: true,
+                    execution_state: None,
+                    stderr: vec![LogLine::stderr(error.to_string())],
+                    output: Err(error),
+                    stdout: vec![],
+                    metadata: Default::default(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use futures_util::StreamExt;
+    use indoc::indoc;
+    use crate::cells::{CodeCell, SupportedLanguage};
+    use crate::library::std::ai::llm::{LlmError, MessageRole, ToolCallSource};
+    use crate::library::std::ai::llm::cache::InMemoryResponseCache;
+    use crate::library::std::ai::llm::mock::{MockChatModel, RequestMatcher};
+    use crate::library::std::ai::llm::openai::OpenAIChatModel;
+    use crate::sdk::config::{ChidoriConfig, MessageOrdering, ProviderConfiguration, OPENAI_PROVIDER};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_chat_cell_with_mock_model() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("France".to_string()), "Paris")
+            .build());
+        let cell = LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("capital".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: indoc! {r#"
+                ---
+                model: gpt-4o
+                ---
+                What is the capital of {{country}}?
+                "#}.to_string(),
+            req: "What is the capital of {{country}}?".to_string(),
+        };
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new().insert_string("country", "France".to_string()))
+            .build();
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, payload, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("capital", "Paris".to_string()).build());
+        model.assert_all_called();
+    }
+
+    fn chat_cell_with_frontmatter(frontmatter: &str) -> LLMPromptCell {
+        LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("greeting".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: format!("---\n{}\n---\nSay hello", frontmatter),
+            req: "Say hello".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_examples_precede_the_user_turn() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("two examples before the user turn", |req| {
+                let turns: Vec<(MessageRole, &str)> = req.template_messages.iter()
+                    .map(|m| (m.role.clone(), m.content.as_str()))
+                    .collect();
+                turns == vec![
+                    (MessageRole::User, "Translate dog to French"),
+                    (MessageRole::Assistant, "chien"),
+                    (MessageRole::User, "Translate bird to French"),
+                    (MessageRole::Assistant, "oiseau"),
+                    (MessageRole::User, "Say hello"),
+                ]
+            }), "bonjour")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4o
+            examples:
+              - input: Translate dog to {{language}}
+                output: chien
+              - input: Translate bird to {{language}}
+                output: oiseau"#});
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new().insert_string("language", "French".to_string()))
+            .build();
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, payload, None, None).await.unwrap();
+        assert!(!output.has_error, "{:?}", output.output);
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_examples_file_is_loaded_when_the_cell_is_built() {
+        let dir = std::env::temp_dir().join(format!("chidori_examples_{}", Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("examples.yaml"), "- input: Translate bird to {{language}}\n  output: oiseau").unwrap();
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("example of the examples file", |req| {
+                req.template_messages.iter().any(|m| m.content == "Translate bird to French")
+            }), "bonjour")
+            .build());
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(model.clone())
+            .with_cwd(dir.to_str().unwrap());
+        let cell = CellTypes::Prompt(chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4o
+            examples_file: examples.yaml
+            examples:
+              - input: Translate dog to {{animal_language}}
+                output: chien"#}), TextRange::default());
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let mut inputs: Vec<&String> = op.signature.input_signature.globals.keys().collect();
+        inputs.sort();
+        assert_eq!(inputs, vec!["animal_language", "language"]);
+
+        // The examples file is not read again when the cell executes
+        std::fs::remove_dir_all(&dir).unwrap();
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new()
+                .insert_string("language", "French".to_string())
+                .insert_string("animal_language", "French".to_string()))
+            .build();
+        let output = op.execute(&state, payload, None, None).await.unwrap();
+        assert!(!output.has_error, "{:?}", output.output);
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_named_role_blocks_are_sent_with_their_names() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("messages named by their participant", |req| {
+                let openai_req = OpenAIChatModel::chat_completion_req_to_openai_req(req);
+                let names: Vec<Option<&str>> = openai_req.messages.iter().map(|m| m.name.as_deref()).collect();
+                names == vec![Some("alice"), Some("bob"), None]
+            }), "Hello both.")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let req = indoc! {r#"
+            {{#user name="alice"}}Hi Bob.{{/user}}
+            {{#user name="bob"}}Hi Alice.{{/user}}
+            {{#user}}Greet them both.{{/user}}
+        "#}.to_string();
+        let cell = LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("greeting".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: req.clone(),
+            req,
+        };
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error, "{:?}", output.output);
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_request_user() {
+        let requires_user = |user: &'static str| RequestMatcher::custom(
+            &format!("request with user {}", user),
+            move |req| req.config.user.as_deref() == Some(user),
+        );
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(requires_user("run-user"), "hello run")
+            .respond_when(requires_user("cell-user"), "hello cell")
+            .build());
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(model.clone())
+            .with_user("run-user");
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello run".to_string()).build());
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o\nuser: cell-user"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello cell".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_routes_to_model_by_rule() {
+        let requires_model = |model: &'static str| RequestMatcher::custom(
+            &format!("request for {}", model),
+            move |req| req.config.model.as_deref() == Some(model),
+        );
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(requires_model("o1"), "hello from o1")
+            .respond_when(requires_model("gpt-4o-mini"), "hello from mini")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4o
+            routes:
+              - model: o1
+                when_input: complex
+              - model: gpt-4o-mini
+                max_prompt_tokens: 100"#});
+
+        let output = llm_prompt_cell_exec_chat_openai(cell.clone())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.model(), Some("gpt-4o-mini"));
+
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new().insert_boolean("complex", true))
+            .build();
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, payload, None, None).await.unwrap();
+        assert_eq!(output.model(), Some("o1"));
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello from o1".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_falls_back_when_the_provider_fails() {
+        let requires_model = |model: &'static str| RequestMatcher::custom(
+            &format!("request for {}", model),
+            move |req| req.config.model.as_deref() == Some(model),
+        );
+        let model = Arc::new(MockChatModel::builder()
+            .fail_when(requires_model("gpt-4o"), LlmError::Provider { status: 503, message: "overloaded".to_string() })
+            .fail_when(requires_model("gpt-4"), LlmError::Provider { status: 400, message: "invalid messages".to_string() })
+            .respond_when(requires_model("gpt-4o-mini"), "hello from mini")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4o
+            fallbacks:
+              - model: gpt-4o-mini"#}))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.model(), Some("gpt-4o-mini"));
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello from mini".to_string()).build());
+        assert_eq!(model.calls(0), 1);
+
+        // A rejected request is not sent to the fallback
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4
+            fallbacks:
+              - model: gpt-4o-mini"#}))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.model(), Some("gpt-4"));
+        assert_eq!(model.calls(1), 1);
+        assert_eq!(model.calls(2), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_resolves_logit_bias_for_its_model() {
+        let bias_for = |model: &str| -> HashMap<String, i32> {
+            tiktoken_rs::get_bpe_from_model(model).unwrap()
+                .encode_ordinary(" delve")
+                .into_iter()
+                .map(|token| (token.to_string(), -100))
+                .collect()
+        };
+        let (gpt_4o_bias, gpt_4_bias) = (bias_for("gpt-4o"), bias_for("gpt-4"));
+        let model = Arc::new(MockChatModel::builder()
+            .fail_when(RequestMatcher::custom("request for gpt-4o", move |req| {
+                req.config.model.as_deref() == Some("gpt-4o") && req.config.logit_bias.as_ref() == Some(&gpt_4o_bias)
+            }), LlmError::RateLimited("slow down".to_string()))
+            .respond_when(RequestMatcher::custom("request for gpt-4", move |req| {
+                req.config.model.as_deref() == Some("gpt-4") && req.config.logit_bias.as_ref() == Some(&gpt_4_bias)
+            }), "hello from gpt-4")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter(indoc! {r#"
+            model: gpt-4o
+            logit_bias_text:
+              " delve": -100
+            fallbacks:
+              - model: gpt-4"#}))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello from gpt-4".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_includes_rendered_messages() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("any request", |_| true), "hello")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model);
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.rendered_messages(), None);
+
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\ninclude_rendered_messages: true");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        let messages = output.rendered_messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, MessageRole::User);
+        assert_eq!(messages[0].content.trim(), "Say hello");
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello".to_string()).build());
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_normalizes_whitespace() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "hello")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model);
+        let cell = |frontmatter: &str| LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("greeting".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: format!(
+                "---\n{}\ninclude_rendered_messages: true\n---\n{{{{#system}}}}\n    You are terse.\n\n\n\n    Answer briefly.\n{{{{/system}}}}\n{{{{#user}}}}\n    Say hello\n{{{{/user}}}}",
+                frontmatter
+            ),
+            req: "Say hello".to_string(),
+        };
+
+        let output = llm_prompt_cell_exec_chat_openai(cell("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        let messages = output.rendered_messages().unwrap();
+        assert!(messages[0].content.contains("    You are terse.\n\n\n\n"), "{:?}", messages[0].content);
+
+        let output = llm_prompt_cell_exec_chat_openai(cell("model: gpt-4o\nnormalize_whitespace: true"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        let messages = output.rendered_messages().unwrap();
+        assert_eq!(messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["You are terse.\n\nAnswer briefly.", "Say hello"]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_merges_consecutive_user_blocks_for_strict_providers() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("a single user message", |req| {
+                let [message] = req.template_messages.as_slice() else { return false };
+                message.role == MessageRole::User && message.content.contains("Say hello") && message.content.contains("to everyone")
+            }), "hello everyone")
+            .build());
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let ordering = MessageOrdering { alternate_roles: true, single_system_message: true, drop_message_names: false };
+        state.configuration = Arc::new(ChidoriConfig {
+            providers: HashMap::from([(OPENAI_PROVIDER.to_string(), ProviderConfiguration {
+                api_key: Some("sk-test".to_string()),
+                message_ordering: Some(ordering),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        });
+        let cell = LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("greeting".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: "---\nmodel: gpt-4o\n---\n{{#user}}Say hello{{/user}}\n{{#user}}to everyone{{/user}}".to_string(),
+            req: "Say hello".to_string(),
+        };
+
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello everyone".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_routes_tool_results_back_to_model() {
+        let last_function_message = |content: &'static str| RequestMatcher::custom(
+            &format!("function message {}", content),
+            move |req| req.template_messages.last().is_some_and(|m| {
+                m.role == MessageRole::Function && m.name.as_deref() == Some("add") && m.content == content
+            }),
+        );
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(last_function_message("5"), "The sum is 5")
+            .call_tool_when(
+                RequestMatcher::LastUserMessageContains("Add".to_string()),
+                "add",
+                RkyvObjectBuilder::new().insert_number("a", 2).insert_number("b", 3).build(),
+            )
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let (state, _) = state.update_operation(CellTypes::Code(CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "def add(a, b):\n    return a + b".to_string(),
+            ..Default::default()
+        }, TextRange::default()), Uuid::now_v7()).await.unwrap();
+
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nimport:\n  - add");
+        let LLMPromptCell::Chat { complete_body, req, .. } = &cell else { unreachable!() };
+        let cell = LLMPromptCell::Chat {
+            complete_body: complete_body.replace("Say hello", "Add 2 and 3"),
+            req: req.replace("Say hello", "Add 2 and 3"),
+            ..cell.clone()
+        };
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.sources(), vec![ToolCallSource {
+            name: "add".to_string(),
+            arguments: serde_json::json!({"a": 2, "b": 3}),
+            result: "5".to_string(),
+        }]);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "The sum is 5".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_tool_rounds_are_bounded() {
+        let model = Arc::new(MockChatModel::builder()
+            .call_tool_when(RequestMatcher::Any, "missing", RkyvSerializedValue::Null)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o\nmax_tool_rounds: 2"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        // The initial request and one for each round of tool results
+        assert_eq!(model.calls(0), 3);
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_reports_finish_reason() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "Hello there, it is nice to meet you")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.finish_reason(), Some("stop"));
+
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o\nmax_tokens: 1"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.finish_reason(), Some("length"));
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "Hell".to_string()).build());
+    }
+
+    #[tokio::test]
+    async fn test_chat_cell_reports_template_hash() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "hello")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.template_hash(), Some(template_hash("Say hello").as_str()));
+
+        // The hash identifies the template, not the configuration it is run with
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o-mini"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.template_hash(), Some(template_hash("Say hello").as_str()));
+    }
+
+    #[test]
+    fn test_template_hash_is_normalized() {
+        assert_eq!(template_hash("Hello {{name}}\r\nBye  \n"), template_hash("Hello {{name}}\nBye"));
+        assert_ne!(template_hash("Hello {{name}}"), template_hash("Hello {{person}}"));
+        assert_eq!(template_hash("Say hello").len(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_chat_cell_closes_its_connection() {
+        // A provider that accepts the request and never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, accepted_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = accepted_tx.send(socket);
+        });
+
+        let state = ExecutionState::new_with_random_id();
+        let cell = chat_cell_with_frontmatter(&format!("model: gpt-4o\napi_url: http://{}/v1", addr));
+        let execution = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None);
+        let mut execution = Box::pin(execution);
+        let mut socket = tokio::select! {
+            _ = &mut execution => panic!("the provider never responds"),
+            socket = accepted_rx => socket.unwrap(),
+        };
+        // Abandoning the execution, as cancelling the run does, drops the request
+        drop(execution);
+
+        let mut buf = vec![0u8; 4096];
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                match tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            }
+        }).await;
+        assert!(closed.is_ok(), "the connection remained open after the execution was dropped");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_output_is_retried_with_more_max_tokens() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, r#"{"greeting": "hello there"}"#)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nmax_tokens: 2\nvalidation:\n  json: true");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.finish_reason(), Some("stop"));
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", r#"{"greeting": "hello there"}"#.to_string()).build());
+        // max_tokens of 2 and then 4 are both too short for the response
+        assert_eq!(model.calls(0), 3);
+    }
+
+    #[tokio::test]
+    async fn test_validation_retries_are_not_answered_from_the_response_cache() {
+        let first_call = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::custom("the first request", move |_| first_call.swap(false, std::sync::atomic::Ordering::SeqCst)), "not json")
+            .respond_when(RequestMatcher::Any, r#"{"greeting": "hello"}"#)
+            .build());
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(model.clone())
+            .with_response_cache(Arc::new(InMemoryResponseCache::default()));
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nvalidation:\n  json: true");
+        let output = llm_prompt_cell_exec_chat_openai(cell.clone())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(model.calls(0), 1);
+        assert_eq!(model.calls(1), 1);
+
+        // The valid response replaced the invalid one in the cache
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(model.calls(1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_content_filter_block_is_a_distinct_error() {
+        let model = Arc::new(MockChatModel::builder()
+            .block_when(RequestMatcher::Any)
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nvalidation:\n  json: true");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert!(output.blocked_by_content_filter());
+        assert!(matches!(output.output, Err(ExecutionStateErrors::BlockedByContentFilter)));
+        // Blocked responses are not retried by validation
+        assert_eq!(model.calls(0), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_cell_reports_token_deltas() {
+        let model = Arc::new(MockChatModel::builder().respond_when(RequestMatcher::Any, "hello").build());
+        let (sender, mut receiver) = crate::execution::execution::progress::progress_channel(16);
+        let mut state = ExecutionState::new_with_random_id()
+            .with_chat_model(model.clone())
+            .with_response_cache(Arc::new(InMemoryResponseCache::default()));
+        state.progress_sender = Some(sender);
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nstream: true");
+        for _ in 0..2 {
+            let output = llm_prompt_cell_exec_chat_openai(cell.clone())(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+            assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello".to_string()).build());
+            assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::TokenDelta {
+                operation_id: state.evaluating_operation_id,
+                delta: "hello".to_string(),
+            });
+        }
+        // Streamed responses are not answered from the response cache
+        assert_eq!(model.calls(0), 2);
+    }
+
+    /// Serve the chat completions endpoint of a provider, responding to the n-th request with `respond(n)`.
+    async fn serve_chat_completions(respond: impl Fn(usize) -> axum::body::Body + Clone + Send + Sync + 'static) -> std::net::SocketAddr {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let app = axum::Router::new().route("/v1/chat/completions", axum::routing::post(move || async move {
+            respond(requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst))
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_streamed_cell_fails_once_idle_past_its_timeout() {
+        // Responds with a single chunk and then holds the connection open without sending more
+        let addr = serve_chat_completions(|_| {
+            let chunks = futures_util::stream::once(async { Ok::<_, std::io::Error>("data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n") })
+                .chain(futures_util::stream::pending());
+            axum::body::Body::from_stream(chunks)
+        }).await;
+        let state = ExecutionState::new_with_random_id();
+        let cell = chat_cell_with_frontmatter(&format!("model: gpt-4o\napi_url: http://{}/v1\nstream: true\nstream_idle_timeout: 1", addr));
+        let output = tokio::time::timeout(Duration::from_secs(10), llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None))
+            .await
+            .expect("the stalled response should time out")
+            .unwrap();
+        assert!(output.has_error);
+        assert!(matches!(&output.output, Err(ExecutionStateErrors::AnyhowError(e)) if *e == LlmError::Timeout(Duration::from_secs(1)).to_string()), "{:?}", output.output);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_cell_assembles_fragmented_tool_calls() {
+        let addr = serve_chat_completions(|request| {
+            let chunks: Vec<&'static str> = match request {
+                0 => vec![
+                    "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"add\",\"arguments\":\"{\\\"a\\\": 2, \"}}]}}]}\n\n",
+                    "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"b\\\": 3}\"}}]}}]}\n\n",
+                    "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\ndata: [DONE]\n\n",
+                ],
+                _ => vec![
+                    "data: {\"choices\":[{\"delta\":{\"content\":\"The sum \"}}]}\n\n",
+                    "data: {\"choices\":[{\"delta\":{\"content\":\"is 5\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n",
+                ],
+            };
+            axum::body::Body::from_stream(futures_util::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>)))
+        }).await;
+        let state = ExecutionState::new_with_random_id();
+        let (state, _) = state.update_operation(CellTypes::Code(CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: "def add(a, b):\n    return a + b".to_string(),
+            ..Default::default()
+        }, TextRange::default()), Uuid::now_v7()).await.unwrap();
+
+        let cell = chat_cell_with_frontmatter(&format!("model: gpt-4o\napi_url: http://{}/v1\nstream: true\nimport:\n  - add", addr));
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert_eq!(output.sources(), vec![ToolCallSource {
+            name: "add".to_string(),
+            arguments: serde_json::json!({"a": 2, "b": 3}),
+            result: "5".to_string(),
+        }]);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "The sum is 5".to_string()).build());
+    }
+
+    #[test]
+    fn test_out_of_range_logit_bias_fails_construction() {
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nlogit_bias_text:\n  certainly: -150");
+        let err = llm_prompt_cell(Uuid::nil(), &cell, &TextRange::default(), None).unwrap_err();
+        assert!(err.to_string().contains("-100..=100"), "{}", err);
+        assert!(llm_prompt_cell(Uuid::nil(), &chat_cell_with_frontmatter("model: gpt-4o\nlogit_bias_text:\n  certainly: -100"), &TextRange::default(), None).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_input_pattern_fails_construction() {
+        let cell_with_pattern = |pattern: &str| LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("greeting".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: format!("---\nmodel: gpt-4o\ninputs:\n  code:\n    pattern: '{}'\n---\nSay {{{{code}}}}", pattern),
+            req: "Say {{code}}".to_string(),
+        };
+        let err = llm_prompt_cell(Uuid::nil(), &cell_with_pattern("^[a-z+$"), &TextRange::default(), None).unwrap_err();
+        assert!(err.to_string().contains("invalid pattern on input 'code'"), "{}", err);
+
+        let op = llm_prompt_cell(Uuid::nil(), &cell_with_pattern("^[a-z]+$"), &TextRange::default(), None).unwrap();
+        assert!(matches!(&op.signature.input_signature.globals["code"].ty, Some(InputType::Pattern(re)) if re.as_str() == "^[a-z]+$"));
+    }
+
+    /// Answers like the wrapped model once `delay` has passed.
+    struct SlowChatModel {
+        delay: std::time::Duration,
+        model: MockChatModel,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::library::std::ai::llm::ChatModelBatch for SlowChatModel {
+        async fn batch(&self, chat_completion_req: crate::library::std::ai::llm::ChatCompletionReq) -> Result<crate::library::std::ai::llm::ChatCompletionRes, crate::library::std::ai::llm::LlmError> {
+            tokio::time::sleep(self.delay).await;
+            self.model.batch(chat_completion_req).await
+        }
+    }
+
+    fn slow_model(delay_ms: u64) -> Arc<SlowChatModel> {
+        Arc::new(SlowChatModel {
+            delay: std::time::Duration::from_millis(delay_ms),
+            model: MockChatModel::builder().respond_when(RequestMatcher::Any, "hello").build(),
+        })
+    }
+
+    // The timeout tests run on a paused clock, which advances straight to the next timer due, so
+    // that the model's delay and the timeouts fire in order regardless of the machine's load
+    #[tokio::test(start_paused = true)]
+    async fn test_cell_past_its_soft_timeout_is_reported_and_completes() {
+        let (sender, mut receiver) = crate::execution::execution::progress::progress_channel(16);
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(slow_model(200));
+        state.progress_sender = Some(sender);
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nsoft_timeout_ms: 20\nhard_timeout_ms: 5000");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_string("greeting", "hello".to_string()).build());
+        assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::CellSlow {
+            operation_id: state.evaluating_operation_id,
+            name: state.evaluating_name.clone(),
+            elapsed_ms: 20,
+        });
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cell_past_its_hard_timeout_fails() {
+        let state = ExecutionState::new_with_random_id().with_chat_model(slow_model(5000));
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nhard_timeout_ms: 20");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert!(matches!(output.output, Err(ExecutionStateErrors::CellTimedOut(20))));
+        assert!(output.stderr_text().contains("hard timeout of 20ms"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_default_timeout_applies_to_cells_without_one() {
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(slow_model(5000))
+            .with_llm_timeout_ms(20);
+        let output = llm_prompt_cell_exec_chat_openai(chat_cell_with_frontmatter("model: gpt-4o"))(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(matches!(output.output, Err(ExecutionStateErrors::CellTimedOut(20))));
+
+        // The cell's own timeout takes precedence
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(slow_model(50))
+            .with_llm_timeout_ms(20);
+        let cell = chat_cell_with_frontmatter("model: gpt-4o\nhard_timeout_ms: 5000");
+        let output = llm_prompt_cell_exec_chat_openai(cell)(&state, RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+    }
+}
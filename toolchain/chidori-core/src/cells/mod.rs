@@ -2,11 +2,17 @@ pub mod template_cell;
 pub mod code_cell;
 pub mod llm_prompt_cell;
 pub mod code_gen_cell;
+pub mod transform_cell;
+pub mod embedding_cell;
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use rkyv::{Archive, Deserialize, Serialize};
 use serde_json::Value;
+use uuid::Uuid;
+use crate::execution::primitives::identifiers::OperationId;
 use crate::library::std::ai::llm::ChatModelBatch;
 
 #[derive(
@@ -16,6 +22,7 @@ use crate::library::std::ai::llm::ChatModelBatch;
     Serialize,
     Deserialize,
     Debug,
+    Default,
     PartialEq,
     Clone,
 )]
@@ -26,8 +33,10 @@ use crate::library::std::ai::llm::ChatModelBatch;
 ))]
 #[archive_attr(derive(Debug))]
 pub enum SupportedLanguage {
+    #[default]
     PyO3,
     Deno,
+    Lua,
 }
 
 
@@ -59,6 +68,7 @@ pub struct BackingFileReference {
     Serialize,
     Deserialize,
     Debug,
+    Default,
     PartialEq,
     Clone,
 )]
@@ -74,6 +84,108 @@ pub struct CodeCell {
     pub language: SupportedLanguage,
     pub source_code: String,
     pub function_invocation: Option<String>,
+    /// Working directory the cell executes in, relative paths are resolved against the process's
+    /// working directory at the time the cell runs. Falls back to the run's working directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Modules the cell may not import, in addition to those denied for the run. Only enforced for Python.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denied_imports: Option<Vec<String>>,
+    /// Capabilities granted to the cell, nothing is granted when unset. Only enforced for JavaScript.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<CodeCellPermissions>,
+    /// JSON schema the output of the cell must satisfy, the object of the values it exposes or the
+    /// value returned by the function it invokes. Outputs that violate it fail the cell.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "json_text")]
+    pub output_schema: Option<String>,
+    /// Values the cell depends upon that are bound to `None` in Python or `null` in JavaScript when
+    /// no other cell provides them, rather than preventing the cell from running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub optional_inputs: Option<Vec<String>>,
+    /// Run the cell once for each element of an input array.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map: Option<MapConfiguration>,
+    /// Tags supplied by the author, such as an owner or category, copied into the metadata of the cell's outputs.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Options of a code cell, declared in frontmatter at the start of its block.
+#[derive(serde::Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct CodeCellConfiguration {
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub denied_imports: Option<Vec<String>>,
+    #[serde(default)]
+    pub permissions: Option<CodeCellPermissions>,
+    #[serde(default, with = "json_text")]
+    pub output_schema: Option<String>,
+    #[serde(default)]
+    pub optional_inputs: Option<Vec<String>>,
+    #[serde(default)]
+    pub map: Option<MapConfiguration>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Marks a cell as a map over one of its inputs, the cell is executed once per element of the
+/// array and the outputs are collected in the order of the elements.
+#[derive(
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct MapConfiguration {
+    /// Name of the global holding the array that is mapped over.
+    pub over: String,
+    /// Number of elements executed at once, defaults to 4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// Fail the cell on the first failing element rather than collecting the errors of every element.
+    #[serde(default)]
+    pub fail_fast: bool,
+}
+
+/// Capabilities a code cell is granted, each an allowlist where a `"*"` entry grants the
+/// capability without restriction and an absent or empty list grants nothing.
+#[derive(
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+Default,
+PartialEq,
+Clone,
+)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct CodeCellPermissions {
+    /// Hosts the cell may connect to, optionally with a port, e.g. `api.example.com:443`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net: Option<Vec<String>>,
+    /// Paths the cell may read from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read: Option<Vec<String>>,
+    /// Paths the cell may write to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<Vec<String>>,
+    /// Environment variables the cell may access.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
 }
 
 
@@ -143,6 +255,45 @@ pub struct TemplateCell {
     pub body: String,
 }
 
+#[derive(
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct TransformCell {
+    pub backing_file_reference: Option<BackingFileReference>,
+    pub name: Option<String>,
+    /// Globals the expression is applied to, as an object keyed by their names. When empty the
+    /// expression is applied to every global.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// A jq expression.
+    pub body: String,
+    /// Tags supplied by the author, such as an owner or category, copied into the metadata of the cell's outputs.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Options of a transform cell, declared in frontmatter at the start of its block.
+#[derive(serde::Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct TransformCellConfiguration {
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
 #[derive(
 Archive,
 serde::Serialize,
@@ -227,6 +378,7 @@ pub struct ScheduleCell {
 ))]
 #[archive_attr(derive(Debug))]
 pub enum SupportedModelProviders {
+    #[serde(alias = "openai")]
     OpenAI,
 }
 
@@ -255,6 +407,64 @@ pub struct EjectionConfig {
 
 
 
+/// Headers attached to outgoing requests to a model provider, such as those required by a gateway.
+/// Values of headers carrying credentials are redacted from debug output.
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+#[serde(transparent)]
+pub struct RequestHeaders(pub HashMap<String, String>);
+
+impl RequestHeaders {
+    /// Whether the value of a header is a credential, judged by its name.
+    pub fn is_sensitive(name: &str) -> bool {
+        let name = name.to_lowercase();
+        ["authorization", "cookie", "key", "token", "secret", "password"]
+            .iter()
+            .any(|sensitive| name.contains(sensitive))
+    }
+
+    /// These headers with those of `overrides` taking precedence, header names are compared
+    /// case insensitively.
+    pub fn merged_with(&self, overrides: Option<&RequestHeaders>) -> RequestHeaders {
+        let mut merged = self.0.clone();
+        for (name, value) in overrides.map(|o| &o.0).into_iter().flatten() {
+            merged.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+            merged.insert(name.clone(), value.clone());
+        }
+        RequestHeaders(merged)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Debug for RequestHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<_> = self.0.keys().collect();
+        names.sort();
+        f.debug_map()
+            .entries(names.into_iter().map(|name| {
+                let value = if Self::is_sensitive(name) { "<redacted>" } else { self.0[name].as_str() };
+                (name, value)
+            }))
+            .finish()
+    }
+}
 
 #[derive(
 Default,
@@ -277,6 +487,9 @@ pub struct LLMPromptCellChatConfiguration {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) import: Option<Vec<String>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<SupportedModelProviders>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "fn")]
     pub(crate) function_name: Option<String>,
@@ -295,14 +508,330 @@ pub struct LLMPromptCellChatConfiguration {
     pub stop: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    /// Biases of the likelihood of tokens keyed by token id, each within -100..=100.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub logit_bias: Option<HashMap<String, i32>>,
+    /// Biases keyed by literal text rather than token id, every token the text encodes to with
+    /// the model's tokenizer receives the bias.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias_text: Option<HashMap<String, i32>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f64>,
+
+    /// When set, prior exchanges of this conversation are stored on the ExecutionState and
+    /// prepended to the messages of subsequent executions of the cell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// Approximate number of tokens of conversation history to retain, oldest turns are dropped first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_token_budget: Option<usize>,
+
+    /// Headers attached to the outgoing request, taking precedence over the provider's headers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<RequestHeaders>,
+
+    /// Provider parameters that are not otherwise modeled, merged into the outgoing request body.
+    /// Held as JSON text so that the configuration remains archivable.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "json_text")]
+    pub extra: Option<String>,
+
+    /// Reasoning effort of o-series models, e.g. "low", "medium" or "high". Sent along with `extra`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+    /// Whether the model is a reasoning model, which takes no sampling parameters and a
+    /// `max_completion_tokens` budget. When unset this is inferred from an o-series model name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_model: Option<bool>,
+
+    /// Validation applied to the output of the cell, failing outputs are re-requested from the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<LLMOutputValidationConfiguration>,
+
+    /// Context window of the model in tokens, when the prompt is estimated to exceed it (less max_tokens)
+    /// the oldest messages are trimmed according to the context_trim_strategy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_trim_strategy: Option<ContextTrimStrategy>,
+
+    /// Receive the response as it is generated, reporting its content as `TokenDelta` progress
+    /// events. Streamed responses are not cached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// Seconds a streamed response may go without delivering a chunk before it fails with a timeout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_idle_timeout: Option<u64>,
+
+    /// Rounds of tool calls serviced before the cell fails for want of a final answer, defaults to 8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tool_rounds: Option<usize>,
+
+    /// Attach the messages sent to the model, after templates are rendered and history is applied,
+    /// to the metadata of the cell's output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_rendered_messages: Option<bool>,
+
+    /// Normalize the whitespace of each rendered role block, removing the indentation of the template
+    /// and collapsing runs of blank lines. Rendered content is sent as is by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize_whitespace: Option<bool>,
+
+    /// Constraints on the values referenced by the prompt, inputs that violate them are rejected
+    /// before the cell runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<HashMap<String, InputConstraint>>,
+
+    /// Run the prompt once for each element of an input array.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map: Option<MapConfiguration>,
+
+    /// Rules selecting the model and provider a request is sent to, the first matching route is
+    /// used and when none match the request falls back to `model`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routes: Option<Vec<ModelRoute>>,
+
+    /// Models tried in order when the request fails for reasons another model may not share, such
+    /// as rate limiting, server errors or an unreachable provider. Rejected requests are not retried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallbacks: Option<Vec<ModelFallback>>,
+
+    /// Few-shot examples, each sent as a user message of its input followed by an assistant message
+    /// of its output ahead of the final user message. Both are templates rendered against the globals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples: Option<Vec<PromptExample>>,
+
+    /// A YAML or JSON file of further examples, relative to the working directory of the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples_file: Option<String>,
+
+    /// Milliseconds after which the cell is reported as slow with a `CellSlow` progress event, it
+    /// continues to run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soft_timeout_ms: Option<u64>,
+
+    /// Milliseconds after which the cell is abandoned and fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hard_timeout_ms: Option<u64>,
+
+    /// Tags supplied by the author, such as an owner or category, copied into the metadata of the cell's outputs.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+#[serde(rename_all = "snake_case")]
+pub enum InputConstraint {
+    /// The value must be one of the given strings.
+    Enum(Vec<String>),
+    /// The value must be an integer within the inclusive bounds.
+    IntRange {
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    /// The value must be a string matching the regular expression.
+    Pattern(String),
+}
+
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+#[serde(rename_all = "snake_case")]
+pub enum ContextTrimStrategy {
+    /// Remove the oldest messages until the prompt fits.
+    #[default]
+    DropOldest,
+    /// Replace the oldest messages with a summary produced by the model.
+    SummarizeOldest,
+}
+
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct LLMOutputValidationConfiguration {
+    /// Total number of requests made before giving up, defaults to 3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<usize>,
+    /// Require the output to parse as JSON.
+    #[serde(default)]
+    pub json: bool,
+    /// JSON schema the output must satisfy, implies `json`.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "json_text")]
+    pub schema: Option<String>,
+    /// Repair nearly valid JSON, such as JSON with trailing commas or wrapped in a code fence,
+    /// before failing the attempt. The output is replaced by the repaired JSON.
+    #[serde(default)]
+    pub repair: bool,
+}
+
+/// A rule of a prompt cell's model router. Every condition that is set must hold for the route to match.
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct ModelRoute {
+    pub model: String,
+    /// Name of the configured provider to send the request to, defaults to openai.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Matches prompts estimated to be at least this many tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_prompt_tokens: Option<usize>,
+    /// Matches prompts estimated to be at most this many tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_prompt_tokens: Option<usize>,
+    /// Matches when the named input is present and is neither null nor false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when_input: Option<String>,
+}
+
+/// A few-shot example of a chat cell, an input and the output the model is expected to give for it.
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct PromptExample {
+    pub input: String,
+    pub output: String,
+}
+
+/// A model a chat cell's request is sent to when the models before it fail.
+#[derive(
+Default,
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+PartialEq,
+Clone,
+)]
+#[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+#[archive(check_bytes)]
+#[archive_attr(check_bytes(
+bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"
+))]
+#[archive_attr(derive(Debug))]
+pub struct ModelFallback {
+    pub model: String,
+    /// Name of the configured provider to send the request to, defaults to openai.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+impl LLMPromptCellChatConfiguration {
+    pub fn extra_value(&self) -> Value {
+        let mut extra = self.extra
+            .as_ref()
+            .and_then(|extra| serde_json::from_str(extra).ok())
+            .unwrap_or(Value::Null);
+        if let Some(reasoning_effort) = &self.reasoning_effort {
+            if extra.is_null() {
+                extra = Value::Object(Default::default());
+            }
+            if let Value::Object(extra) = &mut extra {
+                extra.insert("reasoning_effort".to_string(), Value::String(reasoning_effort.clone()));
+            }
+        }
+        extra
+    }
+}
+
+/// (De)serializes a JSON text field as the structured value it contains.
+mod json_text {
+    use serde_json::Value;
+
+    pub fn serialize<S: serde::Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+        let value: Option<Value> = value
+            .as_ref()
+            .map(|v| serde_json::from_str(v))
+            .transpose()
+            .map_err(serde::ser::Error::custom)?;
+        serde::Serialize::serialize(&value, serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+        let value: Option<Value> = serde::Deserialize::deserialize(deserializer)?;
+        value
+            .map(|v| serde_json::to_string(&v))
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(
@@ -327,7 +856,8 @@ pub enum LLMPromptCell {
         is_function_invocation: bool,
         configuration: LLMPromptCellChatConfiguration,
         name: Option<String>,
-        provider: SupportedModelProviders,
+        /// When omitted the project's default provider is used
+        provider: Option<SupportedModelProviders>,
         complete_body: String,
         req: String,
     },
@@ -358,6 +888,8 @@ pub struct LLMCodeGenCellChatConfiguration {
     #[serde(rename = "fn")]
     pub function_name: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<SupportedModelProviders>,
     pub api_url: Option<String>,
     pub model: Option<String>,
     pub frequency_penalty: Option<f64>,
@@ -371,6 +903,25 @@ pub struct LLMCodeGenCellChatConfiguration {
     pub top_p: Option<f64>,
 
     pub language: Option<String>,
+
+    /// When set, generated Python is executed and, if it fails, regenerated with its error until it
+    /// runs cleanly or this many attempts have been made.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_repair_attempts: Option<usize>,
+
+    /// Request the code and an explanation of it as a structured response, the cell then outputs an
+    /// object with `code` and `explanation` fields rather than the generated text.
+    #[serde(default)]
+    pub explanation: bool,
+
+    /// Normalize the whitespace of each rendered role block, removing the indentation of the template
+    /// and collapsing runs of blank lines. Rendered content is sent as is by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize_whitespace: Option<bool>,
+
+    /// Tags supplied by the author, such as an owner or category, copied into the metadata of the cell's outputs.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
 }
 
 #[derive(
@@ -394,7 +945,8 @@ pub struct LLMCodeGenCell {
     pub function_invocation: bool,
     pub configuration: LLMCodeGenCellChatConfiguration,
     pub name: Option<String>,
-    pub provider: SupportedModelProviders,
+    /// When omitted the project's default provider is used
+    pub provider: Option<SupportedModelProviders>,
     pub req: String,
     pub complete_body: String,
 }
@@ -417,12 +969,37 @@ bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error:
 ))]
 #[archive_attr(derive(Debug))]
 pub struct LLMEmbeddingCell {
+    pub backing_file_reference: Option<BackingFileReference>,
     pub function_invocation: bool,
-    pub configuration: HashMap<String, String>,
+    pub configuration: LLMEmbeddingCellConfiguration,
     pub name: Option<String>,
     pub req: String,
 }
 
+/// Options of an embedding cell, declared in frontmatter at the start of its block.
+#[derive(
+Archive,
+serde::Serialize,
+serde::Deserialize,
+Serialize,
+Deserialize,
+Debug,
+Default,
+PartialEq,
+Clone,
+)]
+#[archive(check_bytes)]
+#[archive_attr(derive(Debug))]
+pub struct LLMEmbeddingCellConfiguration {
+    /// Embedding model to use, defaults to text-embedding-3-small.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Length of the vectors to request, for models that support shortening their embeddings.
+    /// Vectors of any other length are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+}
+
 
 #[derive(
 Archive,
@@ -488,6 +1065,8 @@ pub enum CellTypes {
     CodeGen(LLMCodeGenCell, TextRange),
     Prompt(LLMPromptCell, TextRange),
     Template(TemplateCell, TextRange),
+    Transform(TransformCell, TextRange),
+    Embedding(LLMEmbeddingCell, TextRange),
 }
 
 impl Eq for CellTypes {
@@ -496,13 +1075,16 @@ impl Eq for CellTypes {
 
 impl PartialOrd for CellTypes {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(Ordering::Equal)
+        Some(self.cmp(other))
     }
 }
 
+/// Cells are ordered by the path of the file they were loaded from and then by their position in
+/// it, so that loading the same files yields the same cell order.
 impl Ord for CellTypes {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        self.backing_file_path().cmp(&other.backing_file_path())
+            .then_with(|| self.text_range().cmp(other.text_range()))
     }
 }
 
@@ -515,8 +1097,201 @@ impl CellTypes {
                 LLMPromptCell::Completion { .. } => &None,
             },
             CellTypes::Template(c, _) => &c.name,
+            CellTypes::Transform(c, _) => &c.name,
+            CellTypes::Embedding(c, _) => &c.name,
             CellTypes::CodeGen(c, _) => &c.name
         }
     }
+
+    /// The source text of the cell, the code of code cells and the prompt body of the others.
+    pub fn source(&self) -> &str {
+        match &self {
+            CellTypes::Code(c, _) => &c.source_code,
+            CellTypes::Prompt(c, _) => match c {
+                LLMPromptCell::Chat { req, .. } => req,
+                LLMPromptCell::Completion { req } => req,
+            },
+            CellTypes::Template(c, _) => &c.body,
+            CellTypes::Transform(c, _) => &c.body,
+            CellTypes::Embedding(c, _) => &c.req,
+            CellTypes::CodeGen(c, _) => &c.req
+        }
+    }
+
+    /// Tags the author attached to the cell, prompt and codegen cells declare theirs in frontmatter.
+    pub fn metadata(&self) -> Option<&HashMap<String, String>> {
+        match &self {
+            CellTypes::Code(c, _) => Some(&c.metadata),
+            CellTypes::Prompt(LLMPromptCell::Chat { configuration, .. }, _) => Some(&configuration.metadata),
+            CellTypes::Prompt(LLMPromptCell::Completion { .. }, _) => None,
+            CellTypes::Template(_, _) => None,
+            CellTypes::Transform(c, _) => Some(&c.metadata),
+            CellTypes::Embedding(_, _) => None,
+            CellTypes::CodeGen(c, _) => Some(&c.configuration.metadata),
+        }
+    }
+
+    /// The cell with an omitted model provider filled from `default`, the project's default provider.
+    pub fn with_default_provider(&self, default: Option<&SupportedModelProviders>) -> CellTypes {
+        let mut cell = self.clone();
+        match &mut cell {
+            CellTypes::Prompt(LLMPromptCell::Chat { provider, .. }, _) |
+            CellTypes::CodeGen(LLMCodeGenCell { provider, .. }, _) => {
+                if provider.is_none() {
+                    *provider = default.cloned();
+                }
+            }
+            _ => {}
+        }
+        cell
+    }
+
+    /// The path of the file the cell was loaded from, if any.
+    pub fn backing_file_path(&self) -> Option<&str> {
+        let reference = match &self {
+            CellTypes::Code(c, _) => &c.backing_file_reference,
+            CellTypes::CodeGen(c, _) => &c.backing_file_reference,
+            CellTypes::Prompt(c, _) => match c {
+                LLMPromptCell::Chat { backing_file_reference, .. } => backing_file_reference,
+                LLMPromptCell::Completion { .. } => &None,
+            },
+            CellTypes::Template(c, _) => &c.backing_file_reference,
+            CellTypes::Transform(c, _) => &c.backing_file_reference,
+            CellTypes::Embedding(c, _) => &c.backing_file_reference,
+        };
+        reference.as_ref().map(|r| r.path.as_str())
+    }
+
+    /// The range of the cell within the file it was loaded from.
+    pub fn text_range(&self) -> &TextRange {
+        match &self {
+            CellTypes::Code(_, range) |
+            CellTypes::CodeGen(_, range) |
+            CellTypes::Prompt(_, range) |
+            CellTypes::Template(_, range) |
+            CellTypes::Transform(_, range) |
+            CellTypes::Embedding(_, range) => range,
+        }
+    }
+
+    /// An id derived from the cell's kind, file and name, so that the same cell keeps its id
+    /// across runs and across edits to its body. `position` distinguishes the cells sharing
+    /// these, the nth of them in cell order being at position n, which identifies unnamed cells
+    /// by their position among the unnamed cells of their kind in their file.
+    pub fn stable_id(&self, position: usize) -> OperationId {
+        let kind = match &self {
+            CellTypes::Code(..) => "code",
+            CellTypes::CodeGen(..) => "codegen",
+            CellTypes::Prompt(..) => "prompt",
+            CellTypes::Template(..) => "template",
+            CellTypes::Transform(..) => "transform",
+            CellTypes::Embedding(..) => "embedding",
+        };
+        let key = format!(
+            "{}\0{}\0{}\0{}",
+            kind,
+            self.backing_file_path().unwrap_or(""),
+            self.name().as_deref().unwrap_or(""),
+            position
+        );
+        Uuid::new_v5(&CELL_ID_NAMESPACE, key.as_bytes())
+    }
+
+    /// The stable ids of the given cells, in the same order. Cells are expected in cell order.
+    pub fn stable_ids(cells: &[CellTypes]) -> Vec<OperationId> {
+        let mut positions: HashMap<OperationId, usize> = HashMap::new();
+        cells.iter().map(|cell| {
+            // Cells sharing a kind, file and name share the id at position 0
+            let position = positions.entry(cell.stable_id(0)).or_insert(0);
+            let id = cell.stable_id(*position);
+            *position += 1;
+            id
+        }).collect()
+    }
+
+    /// The cell's definition excluding its position in the file, serialized with the keys of maps
+    /// in a canonical order, which changes whenever an edit to the cell could change what it produces.
+    pub fn canonical_definition(&self) -> String {
+        let mut cell = self.clone();
+        match &mut cell {
+            CellTypes::Code(_, range) |
+            CellTypes::CodeGen(_, range) |
+            CellTypes::Prompt(_, range) |
+            CellTypes::Template(_, range) |
+            CellTypes::Transform(_, range) |
+            CellTypes::Embedding(_, range) => *range = TextRange::default(),
+        }
+        // Round trip through a Value so that the keys of maps in the configuration are ordered
+        serde_json::to_value(&cell).map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    /// A hash of the cell's canonical definition, for comparing cells within a process.
+    pub fn source_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.canonical_definition().hash(&mut hasher);
+        hasher.finish()
+    }
 }
 
+const CELL_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6f1c_2a9e_4b7d_4c1a_9e3f_5d8b_2c7a_1e04);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_cell(name: Option<&str>, source_code: &str) -> CellTypes {
+        CellTypes::Code(CodeCell {
+            name: name.map(|n| n.to_string()),
+            language: SupportedLanguage::PyO3,
+            source_code: source_code.to_string(),
+            ..Default::default()
+        }, TextRange::default())
+    }
+
+    #[test]
+    fn test_stable_id_is_deterministic() {
+        let a = code_cell(Some("a"), "x = 1\ny = 2");
+        assert_eq!(a.stable_id(0), code_cell(Some("a"), "x = 1\ny = 2").stable_id(0));
+        // Edits to the body keep the id
+        assert_eq!(a.stable_id(0), code_cell(Some("a"), "x = 2\ny = 2").stable_id(0));
+        assert_ne!(a.stable_id(0), code_cell(Some("b"), "x = 1\ny = 2").stable_id(0));
+        assert_ne!(a.stable_id(0), a.stable_id(1));
+    }
+
+    #[test]
+    fn test_stable_ids_distinguish_unnamed_cells_by_position() {
+        let cells = vec![code_cell(None, "x = 1"), code_cell(Some("a"), "y = 1"), code_cell(None, "x = 1")];
+        let ids = CellTypes::stable_ids(&cells);
+        assert_eq!(ids, vec![cells[0].stable_id(0), cells[1].stable_id(0), cells[2].stable_id(1)]);
+        // Editing an unnamed cell keeps the ids of every cell
+        let edited = vec![code_cell(None, "x = 2"), code_cell(Some("a"), "y = 1"), code_cell(None, "x = 1")];
+        assert_eq!(CellTypes::stable_ids(&edited), ids);
+    }
+
+    #[test]
+    fn test_cells_are_ordered_by_file_and_position() {
+        let at = |path: &str, start: usize| {
+            let mut cell = code_cell(None, "x = 1");
+            if let CellTypes::Code(c, range) = &mut cell {
+                c.backing_file_reference = Some(BackingFileReference { path: path.to_string(), text_range: None });
+                *range = TextRange { start, end: start + 5 };
+            }
+            cell
+        };
+        let mut cells = vec![at("b.md", 0), at("a.md", 20), at("a.md", 3)];
+        cells.sort();
+        assert_eq!(cells, vec![at("a.md", 3), at("a.md", 20), at("b.md", 0)]);
+    }
+
+    #[test]
+    fn test_source_hash_ignores_position() {
+        let a = code_cell(Some("a"), "x = 1");
+        let mut moved = a.clone();
+        if let CellTypes::Code(_, range) = &mut moved {
+            *range = TextRange { start: 10, end: 20 };
+        }
+        assert_eq!(a.canonical_definition(), moved.canonical_definition());
+        assert_eq!(a.source_hash(), moved.source_hash());
+        assert_ne!(a.source_hash(), code_cell(Some("a"), "x = 2").source_hash());
+    }
+}
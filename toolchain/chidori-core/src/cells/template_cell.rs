@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::mpsc::Sender;
@@ -10,6 +9,8 @@ use futures_util::FutureExt;
 use chidori_prompt_format::templating::templates::{ChatModelRoles, TemplateWithSource};
 use crate::execution::execution::execution_graph::ExecutionNodeId;
 use crate::execution::execution::ExecutionState;
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::library::std::ai::llm::tokenizer::template_tokenizer;
 
 /// Template cells leverage the same tooling as LLM Prompt Cells, but are used for more general templating.
 #[tracing::instrument]
@@ -22,8 +23,10 @@ pub fn template_cell(execution_state_id: ExecutionNodeId, cell: &TemplateCell, r
         input_signature.globals.insert(
             key.clone(),
             InputItemConfiguration {
-                ty: Some(InputType::String),
+                ty: Some(InputType::from(&value.ty)),
                 default: None,
+                variadic: false,
+                optional: false,
             },
         );
     }
@@ -37,6 +40,7 @@ pub fn template_cell(execution_state_id: ExecutionNodeId, cell: &TemplateCell, r
                 input_signature: InputSignature::new(),
                 emit_event: vec![],
                 trigger_on: vec![],
+                outputs: vec![],
             },
         );
     }
@@ -53,8 +57,10 @@ pub fn template_cell(execution_state_id: ExecutionNodeId, cell: &TemplateCell, r
 
 
 pub fn template_cell_exec(body: String) -> Box<OperationFn> {
-    Box::new(move |_, x, _, _| {
+    Box::new(move |s, x, _, _| {
         let body = body.clone();
+        let partials = s.partials();
+        let compiled_templates = s.compiled_templates.clone();
         async move {
             let data = if let RKV::Object(m) = x {
                 if let Some(m) = m.get("globals") {
@@ -65,8 +71,18 @@ pub fn template_cell_exec(body: String) -> Box<OperationFn> {
             } else {
                 serialized_value_to_json_value(&x)
             };
-            let rendered = chidori_prompt_format::templating::templates::render_template_prompt(&body, &data, &HashMap::new()).unwrap();
-            Ok(OperationFnOutput::with_value(RKV::String(rendered)))
+            let rendered = partials
+                .map_err(anyhow::Error::from)
+                .and_then(|partials| Ok((partials, template_tokenizer(None)?)))
+                .and_then(|(partials, tokenizer)| compiled_templates.render(&body, &data, &partials, Some(&tokenizer)));
+            Ok(match rendered {
+                Ok(rendered) => OperationFnOutput::with_value(RKV::String(rendered)),
+                Err(e) => OperationFnOutput {
+                    has_error: true,
+                    output: Err(ExecutionStateErrors::Unknown(e.to_string())),
+                    ..OperationFnOutput::with_value(RKV::Null)
+                },
+            })
         }.boxed()
     })
 }
@@ -92,4 +108,30 @@ mod test {
         assert_eq!(output.output, Ok(crate::execution::primitives::serialized_value::RkyvSerializedValue::String("Hello, !".to_string())));
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_template_cell_iterates_array_of_objects() -> anyhow::Result<()> {
+        use crate::execution::primitives::operation::InputType;
+        use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue as RKV};
+
+        let cell = crate::cells::TemplateCell {
+            backing_file_reference: None,
+            name: Some("test".to_string()),
+            body: "{{#each items}}[{{name}}: {{count}}]{{/each}}".to_string(),
+        };
+        let op = crate::cells::template_cell::template_cell(Uuid::nil(), &cell, &TextRange::default())?;
+        assert!(matches!(op.signature.input_signature.globals["items"].ty, Some(InputType::Array)));
+
+        let item = |name: &str, count: i32| RkyvObjectBuilder::new()
+            .insert_string("name", name.to_string())
+            .insert_number("count", count)
+            .build();
+        let input = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new()
+                .insert_value("items", RKV::Array(vec![item("a", 1), item("b", 2), item("c", 3)])))
+            .build();
+        let output = op.execute(&ExecutionState::new_with_random_id(), input, None, None).await?;
+        assert_eq!(output.output, Ok(RKV::String("[a: 1][b: 2][c: 3]".to_string())));
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures_util::FutureExt;
+use jaq_interpret::{Ctx, Filter, FilterT, ParseCtx, RcIter, Val};
+use once_cell::sync::Lazy;
+
+use crate::cells::{CellTypes, TextRange, TransformCell};
+use crate::execution::execution::execution_graph::ExecutionNodeId;
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::execution::primitives::operation::{InputItemConfiguration, InputSignature, OperationFn, OperationFnOutput, OperationNode, OutputItemConfiguration, OutputSignature};
+use crate::execution::primitives::serialized_value::{json_value_to_serialized_value, serialized_value_to_json_value, RkyvObjectBuilder, RkyvSerializedValue};
+
+/// The number of compiled expressions kept, beyond which the least recently used is evicted.
+const COMPILED_EXPRESSIONS_CAPACITY: usize = 256;
+
+/// Expressions of transform cells, compiled when their cells are constructed.
+static COMPILED_EXPRESSIONS: Lazy<Mutex<CompiledExpressions>> =
+    Lazy::new(|| Mutex::new(CompiledExpressions::with_capacity(COMPILED_EXPRESSIONS_CAPACITY)));
+
+/// Compiled expressions keyed by their source, holding at most `capacity` of them.
+struct CompiledExpressions {
+    capacity: usize,
+    /// Each filter with the tick of its last use.
+    filters: HashMap<String, (Arc<Filter>, u64)>,
+    tick: u64,
+}
+
+impl CompiledExpressions {
+    fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, filters: HashMap::new(), tick: 0 }
+    }
+
+    fn get(&mut self, expression: &str) -> Option<Arc<Filter>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.filters.get_mut(expression).map(|(filter, last_used)| {
+            *last_used = tick;
+            filter.clone()
+        })
+    }
+
+    fn insert(&mut self, expression: String, filter: Arc<Filter>) {
+        if self.filters.len() >= self.capacity && !self.filters.contains_key(&expression) {
+            let least_recently_used = self.filters.iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(expression, _)| expression.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.filters.remove(&least_recently_used);
+            }
+        }
+        self.tick += 1;
+        self.filters.insert(expression, (filter, self.tick));
+    }
+}
+
+/// Transform cells reshape the values of other cells with a jq expression, evaluated by jaq. The
+/// expression is applied to an object of the cell's declared inputs, or of every global when it
+/// declares none.
+#[tracing::instrument]
+pub fn transform_cell(execution_state_id: ExecutionNodeId, cell: &TransformCell, range: &TextRange) -> anyhow::Result<OperationNode> {
+    compiled_expression(&cell.body)?;
+
+    let mut input_signature = InputSignature::new();
+    input_signature.all_globals = cell.inputs.is_empty();
+    for input in &cell.inputs {
+        input_signature.globals.insert(
+            input.clone(),
+            InputItemConfiguration {
+                ty: None,
+                default: None,
+                variadic: false,
+                optional: false,
+            },
+        );
+    }
+
+    let mut output_signature = OutputSignature::new();
+    if let Some(name) = &cell.name {
+        output_signature.globals.insert(
+            name.clone(),
+            OutputItemConfiguration::Value,
+        );
+    }
+
+    Ok(OperationNode::new(
+        cell.name.clone(),
+        execution_state_id,
+        input_signature,
+        output_signature,
+        CellTypes::Transform(cell.clone(), Default::default())
+    ))
+}
+
+/// The compiled form of an expression, compiling it on first use.
+fn compiled_expression(expression: &str) -> anyhow::Result<Arc<Filter>> {
+    if let Some(filter) = COMPILED_EXPRESSIONS.lock().unwrap().get(expression) {
+        return Ok(filter);
+    }
+    let filter = Arc::new(compile_expression(expression)?);
+    COMPILED_EXPRESSIONS.lock().unwrap().insert(expression.to_string(), filter.clone());
+    Ok(filter)
+}
+
+fn compile_expression(expression: &str) -> anyhow::Result<Filter> {
+    let mut definitions = ParseCtx::new(Vec::new());
+    definitions.insert_natives(jaq_core::core());
+    definitions.insert_defs(jaq_std::std());
+    let (filter, errors) = jaq_parse::parse(expression, jaq_parse::main());
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        return Err(anyhow::anyhow!("Invalid jq expression {:?}: {}", expression, errors.join("; ")));
+    }
+    let Some(filter) = filter else {
+        return Err(anyhow::anyhow!("Invalid jq expression {:?}", expression));
+    };
+    let filter = definitions.compile(filter);
+    if !definitions.errs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Invalid jq expression {:?}: it refers to {} undefined filters or variables",
+            expression,
+            definitions.errs.len()
+        ));
+    }
+    Ok(filter)
+}
+
+/// Apply a jq expression to a value. An expression producing a single value yields that value,
+/// one producing several yields an array of them and one producing none yields Null.
+pub fn transform(expression: &str, input: &RkyvSerializedValue) -> Result<RkyvSerializedValue, String> {
+    let filter = compiled_expression(expression).map_err(|e| e.to_string())?;
+    apply(&filter, input)
+}
+
+fn apply(filter: &Filter, input: &RkyvSerializedValue) -> Result<RkyvSerializedValue, String> {
+    let inputs = RcIter::new(core::iter::empty());
+    let mut outputs = filter
+        .run((Ctx::new([], &inputs), Val::from(serialized_value_to_json_value(input))))
+        .map(|output| {
+            output
+                .map(|value| json_value_to_serialized_value(&serde_json::Value::from(value)))
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(match outputs.len() {
+        0 => RkyvSerializedValue::Null,
+        1 => outputs.remove(0),
+        _ => RkyvSerializedValue::Array(outputs),
+    })
+}
+
+pub fn transform_cell_exec(cell: TransformCell) -> Box<OperationFn> {
+    let filter = compiled_expression(&cell.body);
+    Box::new(move |_, payload, _, _| {
+        let globals = match &payload {
+            RkyvSerializedValue::Object(payload) => payload.get("globals").cloned(),
+            _ => None,
+        };
+        let input = match (&globals, cell.inputs.is_empty()) {
+            (Some(globals), true) => globals.clone(),
+            (Some(globals), false) => {
+                let mut input = RkyvObjectBuilder::new();
+                for name in &cell.inputs {
+                    let value = globals.get_path(name).cloned().unwrap_or(RkyvSerializedValue::Null);
+                    input = input.insert_value(name, value);
+                }
+                input.build()
+            }
+            (None, _) => RkyvSerializedValue::Null,
+        };
+        let result = match &filter {
+            Ok(filter) => apply(filter, &input),
+            Err(e) => Err(e.to_string()),
+        };
+        let output = match result {
+            Ok(value) => {
+                let value = match &cell.name {
+                    Some(name) => RkyvObjectBuilder::new().insert_value(name, value).build(),
+                    None => value,
+                };
+                OperationFnOutput::with_value(value)
+            }
+            Err(e) => OperationFnOutput {
+                has_error: true,
+                output: Err(ExecutionStateErrors::Unknown(format!("Transform failed: {}", e))),
+                ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+            },
+        };
+        async move { Ok(output) }.boxed()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::execution::execution::ExecutionState;
+
+    fn cell(inputs: Vec<&str>, body: &str) -> TransformCell {
+        TransformCell {
+            backing_file_reference: None,
+            name: Some("reshaped".to_string()),
+            inputs: inputs.into_iter().map(|i| i.to_string()).collect(),
+            body: body.to_string(),
+            metadata: Default::default(),
+        }
+    }
+
+    fn payload(globals: RkyvObjectBuilder) -> RkyvSerializedValue {
+        RkyvObjectBuilder::new().insert_object("globals", globals).build()
+    }
+
+    #[test]
+    fn test_compiled_expressions_evict_the_least_recently_used() {
+        let mut expressions = CompiledExpressions::with_capacity(2);
+        for expression in [".a", ".b"] {
+            expressions.insert(expression.to_string(), Arc::new(compile_expression(expression).unwrap()));
+        }
+        assert!(expressions.get(".a").is_some());
+        expressions.insert(".c".to_string(), Arc::new(compile_expression(".c").unwrap()));
+        assert_eq!(expressions.filters.len(), 2);
+        assert!(expressions.get(".a").is_some());
+        assert!(expressions.get(".b").is_none());
+        assert!(expressions.get(".c").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_transform_cell_reshapes_its_inputs() {
+        let op = transform_cell(Uuid::nil(), &cell(vec!["orders"], "[.orders[] | .total] | add"), &TextRange::default()).unwrap();
+        assert!(op.signature.input_signature.globals.contains_key("orders"));
+        let order = |total: i32| RkyvObjectBuilder::new().insert_number("total", total).build();
+        let input = payload(RkyvObjectBuilder::new()
+            .insert_value("orders", RkyvSerializedValue::Array(vec![order(3), order(4)])));
+        let output = op.execute(&ExecutionState::new_with_random_id(), input, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new().insert_number("reshaped", 7).build());
+    }
+
+    #[tokio::test]
+    async fn test_transform_cell_without_inputs_receives_every_global() {
+        let doubled = TransformCell { name: Some("doubled".to_string()), ..cell(vec!["a"], ".a * 2") };
+        let state = ExecutionState::new_with_random_id();
+        let (_, state) = state.upsert_operation(state.get_operation_from_cell_type(&CellTypes::Transform(doubled, TextRange::default())).unwrap(), Uuid::now_v7()).unwrap();
+        let (_, state) = state.upsert_operation(state.get_operation_from_cell_type(&CellTypes::Transform(cell(vec![], ".a + .doubled"), TextRange::default())).unwrap(), Uuid::now_v7()).unwrap();
+        let state = state.with_initial_globals(RkyvObjectBuilder::new().insert_number("a", 2).build()).unwrap();
+        let (state, _) = state.step_execution().await.unwrap();
+        let (state, _) = state.step_execution().await.unwrap();
+        assert_eq!(state.get_output_by_name("reshaped").unwrap(), Some(RkyvObjectBuilder::new().insert_number("reshaped", 6).build()));
+    }
+
+    #[test]
+    fn test_transform_multiple_outputs() {
+        let input = RkyvSerializedValue::Array(vec![RkyvSerializedValue::Number(1), RkyvSerializedValue::Number(2)]);
+        assert_eq!(transform(".[]", &input), Ok(input.clone()));
+        assert_eq!(transform("empty", &input), Ok(RkyvSerializedValue::Null));
+    }
+
+    #[test]
+    fn test_invalid_expression_fails_construction() {
+        assert!(transform_cell(Uuid::nil(), &cell(vec![], ".orders | map("), &TextRange::default()).is_err());
+        assert!(transform_cell(Uuid::nil(), &cell(vec![], "undefined_filter(.)"), &TextRange::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_runtime_type_error_is_an_output_error() {
+        let op = transform_cell(Uuid::nil(), &cell(vec!["count"], ".count.field"), &TextRange::default()).unwrap();
+        let input = payload(RkyvObjectBuilder::new().insert_number("count", 1));
+        let output = op.execute(&ExecutionState::new_with_random_id(), input, None, None).await.unwrap();
+        assert!(output.has_error);
+        assert!(output.output.unwrap_err().to_string().contains("Transform failed"));
+    }
+}
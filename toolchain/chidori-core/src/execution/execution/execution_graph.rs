@@ -32,6 +32,9 @@ use tokio::sync::Notify;
 use tokio::sync::oneshot::Receiver;
 use uuid::Uuid;
 use crate::cells::CellTypes;
+use crate::sdk::config::ChidoriConfig;
+use crate::library::std::code::runtime_pyo3::warm_start_configured_python;
+use crate::execution::execution::progress::ProgressSender;
 use crate::execution::primitives::operation::OperationFnOutput;
 use tokio::sync::mpsc::{Sender, channel};
 use tracing::debug;
@@ -99,6 +102,13 @@ impl std::fmt::Debug for ExecutionGraph {
 impl ExecutionGraph {
     #[tracing::instrument]
     pub fn new() -> Self {
+        Self::new_with_runtime_handle(None)
+    }
+
+    /// An execution graph whose states run their asynchronous work, and the graph its own
+    /// background task, on the given runtime. Without one the caller's runtime is used.
+    #[tracing::instrument(skip(runtime_handle))]
+    pub fn new_with_runtime_handle(runtime_handle: Option<tokio::runtime::Handle>) -> Self {
         debug!("Initializing ExecutionGraph");
         let (sender_new_execution_states, mut receiver_new_execution_states) = tokio::sync::mpsc::channel::<ExecutionGraphSendPayload>(1028);
 
@@ -106,10 +116,12 @@ impl ExecutionGraph {
 
         // Initialization of the execution graph at Uuid::nil - this is always the root of the execution graph
         let init_id = Uuid::nil();
-        state_id_to_state.insert(init_id, ExecutionState::new_with_graph_sender(
+        let mut root = ExecutionState::new_with_graph_sender(
             init_id,
             Arc::new(sender_new_execution_states)
-        ));
+        );
+        root.runtime_handle = runtime_handle;
+        state_id_to_state.insert(init_id, root);
 
         // Graph of execution states
         let mut execution_graph = Arc::new(Mutex::new(DiGraphMap::new()));
@@ -136,7 +148,8 @@ impl ExecutionGraph {
         // Those branches will continue to evaluate independently.
         debug!("Initializing background thread for handling async updates to our execution graph");
         let state_id_to_state_clone  = state_id_to_state.clone();
-        let handle = tokio::spawn(async move {
+        let root = state_id_to_state.get(&init_id).expect("The root of the execution graph was inserted above");
+        let handle = root.spawn(async move {
             // Signal that the task has started, we can continue initialization
             initialization_notify_clone.notify_one();
             // Pushing this state into the graph
@@ -173,6 +186,7 @@ impl ExecutionGraph {
                 }
             }
         });
+        drop(root);
         ExecutionGraph {
             cancellation_notify,
             execution_depth_orchestration_initialized_notify: initialization_notify,
@@ -185,6 +199,28 @@ impl ExecutionGraph {
         }
     }
 
+    /// Apply the project configuration to the root of the execution graph, states derived from it
+    /// inherit it. The Python interpreter is warm started when the configuration asks for it.
+    pub fn set_configuration(&self, configuration: Arc<ChidoriConfig>) -> anyhow::Result<()> {
+        warm_start_configured_python(&configuration)?;
+        if let Some(mut root) = self.execution_node_id_to_state.get_mut(&Uuid::nil()) {
+            root.configuration = configuration;
+        }
+        Ok(())
+    }
+
+    /// The runtime the execution graph was created to run on, if any.
+    pub fn runtime_handle(&self) -> Option<tokio::runtime::Handle> {
+        self.execution_node_id_to_state.get(&Uuid::nil()).and_then(|root| root.runtime_handle.clone())
+    }
+
+    /// Attach a progress channel to the root of the execution graph, states derived from it emit to it.
+    pub fn set_progress_sender(&self, progress_sender: ProgressSender) {
+        if let Some(mut root) = self.execution_node_id_to_state.get_mut(&Uuid::nil()) {
+            root.progress_sender = Some(progress_sender);
+        }
+    }
+
     pub fn take_execution_event_receiver(&mut self) -> tokio::sync::mpsc::Receiver<ExecutionState> {
         self.execution_state_receiver.take().expect("Execution event receiver may only be taken once by a new owner")
     }
@@ -336,6 +372,7 @@ mod tests {
             output: Ok(arg0),
             stdout: vec![],
             stderr: vec![],
+            metadata: Default::default(),
         });
         state.state_insert(id_b, OperationFnOutput {
             has_error: false,
@@ -343,6 +380,7 @@ mod tests {
             output: Ok(arg1),
             stdout: vec![],
             stderr: vec![],
+            metadata: Default::default(),
         });
         let (_, new_state, _) = ExecutionGraph::immutable_external_step_execution(state.clone()).await?;
         assert!(new_state.state_get_value(&id_c).is_some());
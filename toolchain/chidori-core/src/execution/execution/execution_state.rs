@@ -1,6 +1,6 @@
 use crate::execution::primitives::identifiers::{DependencyReference, OperationId};
-use crate::execution::primitives::operation::{InputSignature, OperationFnOutput, OperationNode, OutputItemConfiguration};
-use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue};
+use crate::execution::primitives::operation::{InputSignature, InputType, LogLine, OperationFnOutput, OperationNode, OutputItemConfiguration};
+use crate::execution::primitives::serialized_value::{serialize_to_vec, RkyvObjectBuilder, RkyvSerializedValue};
 use im::{HashMap as ImHashMap, HashSet as ImHashSet};
 
 use indexmap::set::IndexSet;
@@ -8,11 +8,14 @@ use indoc::indoc;
 use petgraph::dot::Dot;
 use petgraph::graphmap::DiGraphMap;
 use petgraph::Direction;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::fmt::{Debug, Formatter};
 use std::ops::{Deref};
 use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
 use no_deadlocks::{Mutex, MutexGuard};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{MapAccess, Visitor};
@@ -24,9 +27,24 @@ use anyhow::Error;
 use tokio::sync::oneshot;
 use futures_util::FutureExt;
 use tokio::sync::oneshot::error::TryRecvError;
-use tracing::debug;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn, Instrument};
 use uuid::Uuid;
 use crate::cells::{CellTypes, CodeCell, LLMPromptCell};
+use crate::library::std::ai::llm::{ChatModelBatch, EmbeddingModel, TemplateMessage};
+use crate::library::std::ai::llm::rate_limit::RateLimiters;
+use crate::library::std::ai::llm::single_flight::InFlightRequests;
+use crate::library::std::ai::llm::cache::ResponseCache;
+use chidori_prompt_format::templating::templates::{PromptLibraryRecord, TemplateCache};
+use crate::execution::execution::graph_export::GraphExport;
+use sha1::{Digest, Sha1};
+use crate::library::std::ai::llm::usage::UsageSummary;
+use crate::sdk::config::{ChidoriConfig, ProviderConfiguration};
+use crate::sdk::partials::{PartialsCache, PartialsError};
+use crate::library::std::code::runtime_pyo3::warm_start_configured_python;
+use crate::sdk::secrets::{EnvSecretResolver, SecretError, SecretResolver};
+use crate::execution::execution::hooks::{run_after_hooks, run_before_hooks, CellExecution, ExecutionHook};
+use crate::execution::execution::progress::{ProgressEvent, ProgressSender};
 use crate::execution::execution::execution_graph::{ExecutionGraphSendPayload, ExecutionNodeId, ChronologyId};
 
 pub enum OperationExecutionStatusOption {
@@ -61,6 +79,49 @@ pub enum ExecutionStateErrors {
     Unknown(String),
     #[error("Anyhow Error: {0}")]
     AnyhowError(String),
+    /// The run was cancelled, during the execution of the operation when one was in progress.
+    #[error("the run was cancelled{}", .0.map(|operation_id| format!(" during the execution of operation {}", operation_id)).unwrap_or_default())]
+    Cancelled(Option<OperationId>),
+    #[error("operation {0} received invalid inputs: {1}")]
+    InvalidInputs(OperationId, String),
+    #[error("event limit exceeded: {0}")]
+    EventLimitExceeded(String),
+    #[error("{0:?} names more than one cell: {1:?}")]
+    AmbiguousName(String, Vec<OperationId>),
+    #[error("the global {0:?} is provided by more than one cell, refer to it by one of {1:?}")]
+    AmbiguousGlobal(String, Vec<String>),
+    #[error("the response was blocked by the provider's content filter")]
+    BlockedByContentFilter,
+    #[error("invocation of {0:?} exceeds the limit of {1} nested function invocations")]
+    DispatchDepthExceeded(String, usize),
+    #[error("the output does not satisfy the cell's schema: {0}")]
+    OutputSchemaViolation(String),
+    #[error("the output of {size} bytes exceeds the limit of {max_output_bytes} bytes")]
+    OutputTooLarge { size: usize, max_output_bytes: usize },
+    #[error("the cell did not finish within its hard timeout of {0}ms")]
+    CellTimedOut(u64),
+    #[error("the run paused ahead of operation {0}")]
+    PausedAtBreakpoint(OperationId),
+    #[error("no cell is named {0:?}")]
+    UnknownName(String),
+    #[error("no attachment named {0:?} was attached to the run")]
+    MissingAttachment(String),
+    #[error("failed to read attachment {0:?}: {1}")]
+    UnreadableAttachment(String, String),
+    #[error("network access is denied, the cell imported {0:?}")]
+    NetworkDenied(String),
+    #[error("{exception_type}: {message}")]
+    PythonException {
+        exception_type: String,
+        message: String,
+        traceback: String,
+    },
+    #[error("{name}: {message}")]
+    JavaScriptException {
+        name: String,
+        message: String,
+        stack: String,
+    },
 }
 
 impl From<anyhow::Error> for ExecutionStateErrors {
@@ -74,6 +135,34 @@ impl From<anyhow::Error> for ExecutionStateErrors {
 pub struct FunctionMetadata {
     operation_id: OperationId,
     pub(crate) input_signature: InputSignature,
+    /// Events the function publishes its return value as
+    pub(crate) emit_event: Vec<String>,
+    /// Events the function is invoked in response to
+    pub(crate) trigger_on: Vec<String>,
+}
+
+/// Events published by functions that were themselves invoked in response to an event are nested,
+/// those nested deeper than this are rejected so that a cascade of events cannot run forever.
+pub const MAX_EVENT_DEPTH: usize = 16;
+
+/// Functions invoked from within functions, such as a function invoking itself, are nested; by
+/// default invocations nested deeper than this are rejected so that unbounded recursion fails cleanly.
+pub const DEFAULT_MAX_DISPATCH_DEPTH: usize = 32;
+
+/// Most events that may be published over the course of a run.
+pub const MAX_EVENTS_PER_RUN: usize = 1024;
+
+/// Shown in place of the value of a secret global.
+pub const REDACTED_VALUE: &str = "<redacted>";
+
+/// An event published by a function declaring `emit_event`, awaiting delivery to the functions
+/// that trigger on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEvent {
+    pub name: String,
+    pub payload: RkyvSerializedValue,
+    /// Number of events that led to this one, zero when it was not published in response to an event
+    pub depth: usize,
 }
 
 pub struct OperationRunningStatus {
@@ -120,6 +209,9 @@ pub struct ExecutionState {
     pub evaluating_name: Option<String>,
     pub evaluating_fn: Option<String>,
     pub evaluating_arguments: Option<RkyvSerializedValue>,
+    /// Violations of the constraints the evaluating operation declares upon its inputs, which fail
+    /// the operation rather than executing it.
+    pub evaluating_input_violations: Option<String>,
     pub evaluating_cell: Option<CellTypes>,
     pub evaluating_enclosed_state: EnclosedState,
 
@@ -135,6 +227,10 @@ pub struct ExecutionState {
     /// Map of operation_id -> output value of that operation
     pub state: ImHashMap<OperationId, Arc<OperationFnOutput>>,
 
+    /// Tokens consumed by every output inserted into `state`, including outputs since replaced by
+    /// a later execution of the same cell
+    pub usage: UsageSummary,
+
     /// Values that were introduced specifically by this state being evaluated, used to identity most recent changes
     pub fresh_values: IndexSet<OperationId>,
 
@@ -170,6 +266,136 @@ pub struct ExecutionState {
     pub dependency_map: ImHashMap<OperationId, IndexSet<(OperationId, DependencyReference)>>,
 
     pub value_freshness_map: ImHashMap<OperationId, usize>,
+
+    /// Operations whose output may no longer reflect their cell or inputs. An operation is marked
+    /// when its cell is edited or an operation it depends upon produces a different value, and is
+    /// cleared when it next executes. Operations that have been set and are not dirty are not re-run.
+    pub dirty_operations: ImHashSet<OperationId>,
+
+    /// Map of operation_id -> hash of the cell source and inputs of its most recent execution. A dirty
+    /// operation whose hash is unchanged keeps its previous output rather than executing again.
+    pub execution_hashes: ImHashMap<OperationId, u64>,
+
+    /// Map of conversation id -> the prior exchanges of that conversation, used by chat prompt cells
+    /// that declare a conversation_id in order to carry history across executions.
+    pub conversation_histories: ImHashMap<String, Vec<TemplateMessage>>,
+
+    /// Events published during the run that have yet to be delivered, in the order they were published.
+    pub pending_events: VecDeque<PendingEvent>,
+
+    /// Number of events published over the course of the run.
+    pub published_event_count: usize,
+
+    /// Depth of the event the evaluating function was invoked in response to, zero otherwise.
+    pub evaluating_event_depth: usize,
+
+    /// Number of function invocations the evaluating function is nested within, zero otherwise.
+    pub dispatch_depth: usize,
+
+    /// Depth of nested function invocations beyond which invocations are rejected.
+    pub max_dispatch_depth: usize,
+
+    /// Milliseconds after which a cell calling a model fails, for cells that do not set a hard
+    /// timeout of their own. Takes precedence over the `llm_timeout_ms` of the configuration.
+    pub llm_timeout_ms: Option<u64>,
+
+    /// Size in bytes of its serialized output beyond which a cell fails rather than propagating
+    /// its output, unlimited when unset.
+    pub max_output_bytes: Option<usize>,
+
+    /// Project configuration, loaded from chidori.toml, describing the model providers available to cells.
+    pub configuration: Arc<ChidoriConfig>,
+
+    /// Source of the api keys of model providers, the environment unless the embedding application
+    /// registers its own.
+    pub secret_resolver: Arc<dyn SecretResolver>,
+
+    /// Channel observers receive progress events on, such as cells starting and finishing
+    pub progress_sender: Option<ProgressSender>,
+
+    /// Hooks invoked before and after the execution of every cell, in the order they were registered
+    pub hooks: Vec<Arc<dyn ExecutionHook>>,
+
+    /// Chat model used by prompt and code generation cells in place of the configured provider,
+    /// used to substitute an offline model when testing.
+    pub chat_model: Option<Arc<dyn ChatModelBatch + Send + Sync>>,
+
+    /// Embedding model used by embedding cells in place of the configured provider, used to
+    /// substitute an offline model when testing.
+    pub embedding_model: Option<Arc<dyn EmbeddingModel + Send + Sync>>,
+
+    /// Chat requests awaiting a response, shared by every state of the run so that identical
+    /// concurrent requests result in a single call to the model.
+    pub in_flight_requests: Arc<InFlightRequests>,
+
+    /// Rate limiters of the providers that configure `requests_per_minute`, shared by every state
+    /// of the run so that the limit holds across the cells calling a provider.
+    pub rate_limiters: Arc<RateLimiters>,
+
+    /// Store of model responses consulted before every chat request, so that a request answered
+    /// once is not sent again. Responses are not cached unless the embedding application
+    /// registers a cache.
+    pub response_cache: Option<Arc<dyn ResponseCache>>,
+
+    /// Templates of prompt cells compiled on first render, shared by every state of the run so
+    /// that cells executed repeatedly do not parse their templates again.
+    pub compiled_templates: Arc<TemplateCache>,
+
+    /// Partials read from the configured templates directory on first render, shared by every
+    /// state of the run so that the directory is not scanned on every execution.
+    pub partials: Arc<PartialsCache>,
+
+    /// Identifier of the end-user this run acts on behalf of, sent as the `user` of LLM requests
+    /// unless a cell overrides it in its configuration.
+    pub user: Option<String>,
+
+    /// Values supplied from outside of the graph for globals that no cell provides, seeded at the
+    /// start of a run. Values produced by cells take precedence over these.
+    pub initial_globals: ImHashMap<String, RkyvSerializedValue>,
+
+    /// Working directory code cells execute in when they do not configure their own.
+    pub cwd: Option<String>,
+
+    /// Modules Python cells may not import, a denied module's submodules are denied with it.
+    pub denied_imports: Vec<String>,
+
+    /// When set, Python cells that statically import a denied module are rejected without running.
+    pub strict_imports: bool,
+
+    /// When set, code cells may not access the network: Python cells may not import networking
+    /// modules and Deno cells are denied net access whatever their permissions grant.
+    pub deny_network: bool,
+
+    /// Token of the run this state belongs to. Once cancelled no further operations are started
+    /// and the operation in progress is abandoned.
+    pub cancellation: CancellationToken,
+
+    /// Runtime the engine's asynchronous work is run on when it is embedded in an application that
+    /// owns one, otherwise the runtime of the caller or one created for each step of an instance.
+    pub runtime_handle: Option<tokio::runtime::Handle>,
+
+    /// Globals whose values are replaced by `REDACTED_VALUE` wherever the state is logged, traced
+    /// or sent to observers, in addition to the `secret_globals` of the configuration. Cells still
+    /// receive their values.
+    pub secret_globals: Vec<String>,
+
+    /// Data attached to the run by name, which code cells read with `attachment`.
+    pub attachments: ImHashMap<String, Attachment>,
+
+    /// Operations the run pauses ahead of, leaving the caller to inspect their inputs and resume.
+    pub breakpoints: ImHashSet<OperationId>,
+
+    /// The operation a paused run was resumed at, which executes without pausing again, along with
+    /// the inputs it executes with in place of its pending inputs when they were modified.
+    pub resuming: Option<(OperationId, Option<RkyvSerializedValue>)>,
+
+    /// Seed of the run, combined with the id of each code cell to seed the random number generators
+    /// of the cell. Unseeded runs leave them seeded by the runtime.
+    pub random_seed: Option<u64>,
+
+    /// Seeds recorded by an earlier run, reused in place of the seeds derived from `random_seed` so
+    /// that replaying a cell reproduces its randomness.
+    pub replayed_seeds: ImHashMap<OperationId, u64>,
 }
 
 impl std::fmt::Debug for ExecutionState {
@@ -185,7 +411,8 @@ fn render_map_as_table(exec_state: &ExecutionState) -> String {
         |---|---|"));
     for key in exec_state.state.keys() {
         if let Some(val) = exec_state.state_get(key) {
-            table.push_str(&format!(indoc!(r"| {} | {:?} |" ), key, val));
+            let output = val.output.as_ref().map(|value| exec_state.redact(value));
+            table.push_str(&format!(indoc!(r"| {} | {:?} |" ), key, output));
             table.push_str("\n");
         }
     }
@@ -194,6 +421,24 @@ fn render_map_as_table(exec_state: &ExecutionState) -> String {
     table
 }
 
+/// Record the outcome of a cell on the span its execution ran within, along with the model and
+/// tokens of prompt cells, so that exported traces carry them as attributes.
+fn record_cell_span(span: &tracing::Span, output: &OperationFnOutput, duration: Duration) {
+    span.record("has_error", output.has_error);
+    span.record("duration_ms", duration.as_millis() as u64);
+    if let Some(provider) = output.provider() {
+        span.record("provider", provider);
+    }
+    if let Some(model) = output.model() {
+        span.record("model", model);
+    }
+    if let Some(usage) = output.usage() {
+        span.record("prompt_tokens", usage.prompt_tokens);
+        span.record("completion_tokens", usage.completion_tokens);
+        span.record("total_tokens", usage.total_tokens);
+    }
+}
+
 impl Default for ExecutionState {
     fn default() -> Self {
         ExecutionState {
@@ -206,12 +451,14 @@ impl Default for ExecutionState {
             evaluating_name: None,
             evaluating_fn: None,
             evaluating_arguments: None,
+            evaluating_input_violations: None,
             evaluating_cell: None,
             evaluating_enclosed_state: Default::default(),
             evaluated_mutation_of_cell: None,
             graph_sender: None,
             exec_queue: VecDeque::new(),
             state: Default::default(),
+            usage: Default::default(),
             fresh_values: Default::default(),
             operation_name_to_id: Default::default(),
             operation_by_id: Default::default(),
@@ -221,11 +468,117 @@ impl Default for ExecutionState {
             has_been_set: Default::default(),
             dependency_map: Default::default(),
             value_freshness_map: Default::default(),
+            dirty_operations: Default::default(),
+            execution_hashes: Default::default(),
+            conversation_histories: Default::default(),
+            pending_events: Default::default(),
+            published_event_count: 0,
+            evaluating_event_depth: 0,
+            dispatch_depth: 0,
+            max_dispatch_depth: DEFAULT_MAX_DISPATCH_DEPTH,
+            llm_timeout_ms: None,
+            max_output_bytes: None,
+            configuration: Default::default(),
+            secret_resolver: Arc::new(EnvSecretResolver),
+            progress_sender: None,
+            hooks: vec![],
+            chat_model: None,
+            embedding_model: None,
+            in_flight_requests: Default::default(),
+            rate_limiters: Default::default(),
+            response_cache: None,
+            compiled_templates: Default::default(),
+            partials: Default::default(),
+            user: None,
+            initial_globals: Default::default(),
+            cwd: None,
+            denied_imports: vec![],
+            strict_imports: false,
+            deny_network: false,
+            cancellation: CancellationToken::new(),
+            runtime_handle: None,
+            secret_globals: vec![],
+            attachments: Default::default(),
+            breakpoints: Default::default(),
+            resuming: None,
+            random_seed: None,
+            replayed_seeds: Default::default(),
             external_event_queue_head: 0,
         }
     }
 }
 
+/// A run paused ahead of a breakpoint: the state to resume from, and the inputs the operation it
+/// paused ahead of would execute with, in the form `resume` accepts modified inputs in.
+#[derive(Debug, Clone)]
+pub struct PausedRun {
+    pub state: ExecutionState,
+    pub operation_id: OperationId,
+    pub inputs: RkyvSerializedValue,
+}
+
+/// Where a run stopped, either ahead of a breakpoint or once no operation remained to execute.
+#[derive(Debug, Clone)]
+pub enum RunProgress {
+    Paused(PausedRun),
+    Completed(RunResult),
+}
+
+/// Data attached to a run, either read from a file when a cell asks for it or held in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attachment {
+    Path(std::path::PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl Attachment {
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Attachment::Path(path) => std::fs::read(path),
+            Attachment::Bytes(bytes) => Ok(bytes.clone()),
+        }
+    }
+}
+
+/// The state of each cell at the end of a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunOutcome {
+    pub completed: Vec<OperationId>,
+    pub cancelled: Vec<OperationId>,
+    pub not_started: Vec<OperationId>,
+    /// The run was cut short by its deadline, the cells that were cancelled or never started
+    /// produced no output.
+    pub timed_out: bool,
+}
+
+/// A global or function provided by a cell which no other cell of the graph consumes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnusedOutput {
+    pub operation_id: OperationId,
+    pub cell_name: Option<String>,
+    pub name: String,
+    pub is_function: bool,
+}
+
+/// The result of running a graph to completion, the outputs of every cell that executed including
+/// those that failed, along with the cells that were not executed because an input of theirs failed.
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub state: ExecutionState,
+    pub outputs: HashMap<OperationId, OperationFnOutput>,
+    pub failed: Vec<FailedOperation>,
+    pub skipped: Vec<OperationId>,
+}
+
+/// A cell whose most recent execution failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedOperation {
+    pub operation_id: OperationId,
+    pub name: Option<String>,
+    pub error: Option<ExecutionStateErrors>,
+    pub stderr: Vec<LogLine>,
+}
+
 // New struct to encapsulate operation inputs
 #[derive(Debug, Clone)]
 pub struct OperationInputs {
@@ -233,25 +586,36 @@ pub struct OperationInputs {
     pub(crate) kwargs: HashMap<String, RkyvSerializedValue>,
     pub(crate) globals: HashMap<String, RkyvSerializedValue>,
     pub(crate) functions: HashMap<String, RkyvSerializedValue>,
+    /// Globals bound to the namespace of the cell of the same name, the object of its globals.
+    pub(crate) namespaces: HashSet<String>,
 }
 
 impl OperationInputs {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             args: HashMap::new(),
             kwargs: HashMap::new(),
             globals: HashMap::new(),
             functions: HashMap::new(),
+            namespaces: HashSet::new(),
         }
     }
 
     fn to_serialized_value(&self) -> RkyvSerializedValue {
-        RkyvSerializedValue::Object(HashMap::from_iter(vec![
+        let mut payload = HashMap::from_iter(vec![
             ("args".to_string(), RkyvSerializedValue::Object(self.args.clone())),
             ("kwargs".to_string(), RkyvSerializedValue::Object(self.kwargs.clone())),
             ("globals".to_string(), RkyvSerializedValue::Object(self.globals.clone())),
             ("functions".to_string(), RkyvSerializedValue::Object(self.functions.clone())),
-        ]))
+        ]);
+        if !self.namespaces.is_empty() {
+            let mut namespaces: Vec<&String> = self.namespaces.iter().collect();
+            namespaces.sort();
+            payload.insert("namespaces".to_string(), RkyvSerializedValue::Array(
+                namespaces.into_iter().map(|name| RkyvSerializedValue::String(name.clone())).collect()
+            ));
+        }
+        RkyvSerializedValue::Object(payload)
     }
 }
 
@@ -278,6 +642,7 @@ impl ExecutionState {
         new.evaluating_fn = None;
         new.evaluating_name = None;
         new.evaluating_arguments = None;
+        new.evaluating_input_violations = None;
         new.evaluating_cell = None;
         new.parent_state_chronology_id = new.chronology_id;
         new.fresh_values = IndexSet::new();
@@ -303,6 +668,376 @@ impl ExecutionState {
         self.has_been_set.len() == self.operation_by_id.len()
     }
 
+    pub fn with_chat_model(mut self, chat_model: Arc<dyn ChatModelBatch + Send + Sync>) -> Self {
+        self.chat_model = Some(chat_model);
+        self
+    }
+
+    pub fn with_embedding_model(mut self, embedding_model: Arc<dyn EmbeddingModel + Send + Sync>) -> Self {
+        self.embedding_model = Some(embedding_model);
+        self
+    }
+
+    pub fn with_response_cache(mut self, response_cache: Arc<dyn ResponseCache>) -> Self {
+        self.response_cache = Some(response_cache);
+        self
+    }
+
+    /// Apply a project's configuration to this state, warm starting the Python interpreter ahead
+    /// of the first cell when the configuration asks for it.
+    pub fn with_configuration(mut self, configuration: Arc<ChidoriConfig>) -> anyhow::Result<Self> {
+        warm_start_configured_python(&configuration)?;
+        self.configuration = configuration;
+        Ok(self)
+    }
+
+    pub fn with_secret_resolver(mut self, secret_resolver: Arc<dyn SecretResolver>) -> Self {
+        self.secret_resolver = secret_resolver;
+        self
+    }
+
+    /// The partials available to prompts, read from the configured templates directory once per run.
+    pub fn partials(&self) -> Result<Arc<HashMap<String, PromptLibraryRecord>>, PartialsError> {
+        self.partials.partials(self.configuration.templates_dir.as_deref())
+    }
+
+    /// The configuration of a provider, with its api key obtained through the secret resolver
+    /// when chidori.toml does not set one.
+    pub async fn provider_configuration(&self, name: &str) -> Result<ProviderConfiguration, SecretError> {
+        self.configuration.provider(name, self.secret_resolver.as_ref()).await
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn with_cwd(mut self, cwd: impl Into<String>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    pub fn with_denied_imports(mut self, denied_imports: Vec<String>, strict: bool) -> Self {
+        self.denied_imports = denied_imports;
+        self.strict_imports = strict;
+        self
+    }
+
+    pub fn with_deny_network(mut self, deny_network: bool) -> Self {
+        self.deny_network = deny_network;
+        self
+    }
+
+    pub fn with_max_dispatch_depth(mut self, max_dispatch_depth: usize) -> Self {
+        self.max_dispatch_depth = max_dispatch_depth;
+        self
+    }
+
+    pub fn with_llm_timeout_ms(mut self, llm_timeout_ms: u64) -> Self {
+        self.llm_timeout_ms = Some(llm_timeout_ms);
+        self
+    }
+
+    /// The timeout of cells calling a model that do not set one, the run's taking precedence over
+    /// the configuration's. None when neither sets one.
+    pub fn default_llm_timeout_ms(&self) -> Option<u64> {
+        self.llm_timeout_ms.or(self.configuration.llm_timeout_ms)
+    }
+
+    pub fn with_max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    /// Fail an output whose serialized size exceeds `max_output_bytes`.
+    fn limit_output_size(&self, mut output: OperationFnOutput) -> OperationFnOutput {
+        let (Some(max_output_bytes), Ok(value)) = (self.max_output_bytes, &output.output) else {
+            return output;
+        };
+        let size = serialize_to_vec(value).len();
+        if size > max_output_bytes {
+            let error = ExecutionStateErrors::OutputTooLarge { size, max_output_bytes };
+            output.stderr.push(LogLine::stderr(error.to_string()));
+            output.output = Err(error);
+            output.has_error = true;
+        }
+        output
+    }
+
+    pub fn with_hook(mut self, hook: Arc<dyn ExecutionHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn with_runtime_handle(mut self, runtime_handle: tokio::runtime::Handle) -> Self {
+        self.runtime_handle = Some(runtime_handle);
+        self
+    }
+
+    pub fn with_secret_globals(mut self, secret_globals: Vec<String>) -> Self {
+        self.secret_globals = secret_globals;
+        self
+    }
+
+    pub fn is_secret_global(&self, name: &str) -> bool {
+        self.secret_globals.iter().chain(self.configuration.secret_globals.iter()).any(|secret| secret == name)
+    }
+
+    pub fn with_breakpoint(mut self, operation_id: OperationId) -> Self {
+        self.breakpoints.insert(operation_id);
+        self
+    }
+
+    /// As `with_breakpoint`, for the cell with the given name.
+    pub fn with_breakpoint_at(self, name: &str) -> Result<Self, ExecutionStateErrors> {
+        let operation_id = self.operation_id_by_name(name)?
+            .ok_or_else(|| ExecutionStateErrors::UnknownName(name.to_string()))?;
+        Ok(self.with_breakpoint(operation_id))
+    }
+
+    pub fn with_random_seed(mut self, random_seed: u64) -> Self {
+        self.random_seed = Some(random_seed);
+        self
+    }
+
+    pub fn with_replayed_seed(mut self, operation_id: OperationId, seed: u64) -> Self {
+        self.replayed_seeds.insert(operation_id, seed);
+        self
+    }
+
+    /// The seed of the random number generators of a code cell, the seed recorded for it when it is
+    /// replayed, otherwise derived from the seed of the run. None when neither is set.
+    pub fn cell_random_seed(&self, operation_id: OperationId) -> Option<u64> {
+        if let Some(seed) = self.replayed_seeds.get(&operation_id) {
+            return Some(*seed);
+        }
+        let random_seed = self.random_seed?;
+        let mut hasher = Sha1::new();
+        hasher.update(random_seed.to_le_bytes());
+        hasher.update(operation_id.as_bytes());
+        let digest = hasher.finalize();
+        Some(u64::from_le_bytes(digest[..8].try_into().unwrap()))
+    }
+
+    pub fn with_attachment(mut self, name: impl Into<String>, attachment: Attachment) -> Self {
+        self.attachments.insert(name.into(), attachment);
+        self
+    }
+
+    /// The contents of the attachment of the run with the given name.
+    pub fn attachment(&self, name: &str) -> Result<Vec<u8>, ExecutionStateErrors> {
+        self.attachments.get(name)
+            .ok_or_else(|| ExecutionStateErrors::MissingAttachment(name.to_string()))?
+            .read()
+            .map_err(|e| ExecutionStateErrors::UnreadableAttachment(name.to_string(), e.to_string()))
+    }
+
+    /// A copy of a value with the values of secret globals replaced by `REDACTED_VALUE`, so that
+    /// what is shown of the state records that a secret was produced but not what it was. Secrets
+    /// are redacted at any depth, such as within the `globals` of a cell's inputs.
+    pub fn redact(&self, value: &RkyvSerializedValue) -> RkyvSerializedValue {
+        match value {
+            RkyvSerializedValue::Object(fields) => RkyvSerializedValue::Object(fields.iter()
+                .map(|(name, value)| if self.is_secret_global(name) {
+                    (name.clone(), RkyvSerializedValue::String(REDACTED_VALUE.to_string()))
+                } else {
+                    (name.clone(), self.redact(value))
+                })
+                .collect()),
+            RkyvSerializedValue::Array(values) => RkyvSerializedValue::Array(values.iter().map(|value| self.redact(value)).collect()),
+            value => value.clone(),
+        }
+    }
+
+    /// Spawn a task on the runtime provided to the engine, or on the caller's runtime when none was.
+    pub fn spawn<F>(&self, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.runtime_handle {
+            Some(runtime_handle) => runtime_handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+
+    /// Summary of a run that ended at this state, partitioning the cells of the graph by whether
+    /// they produced a value, were abandoned by cancellation, or never started.
+    pub fn run_outcome(&self, cancelled: &[OperationId]) -> RunOutcome {
+        let mut outcome = RunOutcome::default();
+        let mut operation_ids: Vec<OperationId> = self.cells_by_id.keys().copied().collect();
+        operation_ids.sort();
+        for operation_id in operation_ids {
+            if self.has_been_set.contains(&operation_id) {
+                outcome.completed.push(operation_id);
+            } else if cancelled.contains(&operation_id) {
+                outcome.cancelled.push(operation_id);
+            } else {
+                outcome.not_started.push(operation_id);
+            }
+        }
+        outcome
+    }
+
+    /// Step the state until no operation remains to execute. Cells that fail do not end the run,
+    /// the cells depending upon them are skipped and the remainder of the graph still executes.
+    /// Failures of the run itself, such as its cancellation or reaching a breakpoint, are returned as errors.
+    pub async fn run_to_completion(&self) -> anyhow::Result<RunResult> {
+        let mut state = self.clone();
+        loop {
+            match state.step_execution().await {
+                Ok((next, _)) => state = next,
+                Err(e) => return match e.downcast_ref::<ExecutionStateErrors>() {
+                    Some(ExecutionStateErrors::NoFurtherExecutionDetected) => Ok(state.run_result()),
+                    _ => Err(e),
+                },
+            }
+        }
+    }
+
+    /// The outputs and failures of the cells of the graph as of this state, cells that have not
+    /// executed and depend, directly or through other such cells, upon a failed cell are skipped.
+    pub fn run_result(&self) -> RunResult {
+        let mut operation_ids: Vec<OperationId> = self.cells_by_id.keys().copied().collect();
+        operation_ids.sort();
+        let outputs: HashMap<OperationId, OperationFnOutput> = operation_ids.iter()
+            .filter_map(|id| self.state_get(id).map(|output| (*id, output.clone())))
+            .collect();
+        let failed: Vec<FailedOperation> = operation_ids.iter()
+            .filter(|id| self.has_failed(id))
+            .map(|id| FailedOperation {
+                operation_id: *id,
+                name: self.operation_by_id.get(id).and_then(|op| op.name.clone()),
+                error: outputs[id].output.clone().err(),
+                stderr: outputs[id].stderr.clone(),
+            })
+            .collect();
+
+        let dependency_graph = self.get_dependency_graph();
+        let mut unavailable: HashSet<OperationId> = failed.iter().map(|f| f.operation_id).collect();
+        let mut skipped = vec![];
+        loop {
+            let newly_skipped: Vec<OperationId> = operation_ids.iter()
+                .filter(|id| !outputs.contains_key(id) && !unavailable.contains(id))
+                .filter(|id| dependency_graph
+                    .edges_directed(**id, Direction::Incoming)
+                    .any(|(from, _, _)| unavailable.contains(&from)))
+                .copied()
+                .collect();
+            if newly_skipped.is_empty() {
+                break;
+            }
+            unavailable.extend(newly_skipped.iter().copied());
+            skipped.extend(newly_skipped);
+        }
+        skipped.sort();
+
+        RunResult { state: self.clone(), outputs, failed, skipped }
+    }
+
+    /// The tokens consumed by the prompt cells that have executed as of this state, by provider and
+    /// model. Every execution of a cell is counted, not only its latest.
+    pub fn usage_summary(&self) -> UsageSummary {
+        self.usage.clone()
+    }
+
+    /// Step the state until it reaches a breakpoint or no operation remains to execute. Failures of
+    /// the run itself, such as its cancellation, are returned as errors.
+    pub async fn run_to_breakpoint(&self) -> anyhow::Result<RunProgress> {
+        let mut state = self.clone();
+        loop {
+            match state.step_execution().await {
+                Ok((next, _)) => state = next,
+                Err(e) => return match e.downcast_ref::<ExecutionStateErrors>() {
+                    Some(ExecutionStateErrors::PausedAtBreakpoint(operation_id)) => {
+                        let operation_id = *operation_id;
+                        let inputs = state.pending_inputs(operation_id).unwrap_or(RkyvSerializedValue::Null);
+                        Ok(RunProgress::Paused(PausedRun { state, operation_id, inputs }))
+                    }
+                    Some(ExecutionStateErrors::NoFurtherExecutionDetected) => Ok(RunProgress::Completed(state.run_result())),
+                    _ => Err(e),
+                },
+            }
+        }
+    }
+
+    /// Continue a paused run from the operation it paused ahead of, executing it with `inputs` in
+    /// place of its pending inputs when given, until the next breakpoint or the end of the run.
+    pub async fn resume(&self, operation_id: OperationId, inputs: Option<RkyvSerializedValue>) -> anyhow::Result<RunProgress> {
+        let mut state = self.clone();
+        state.resuming = Some((operation_id, inputs));
+        state.run_to_breakpoint().await
+    }
+
+    /// Step the state until no operation remains to execute or `deadline` elapses, whichever is
+    /// first. When the deadline elapses the cell in progress is cancelled, and the returned state
+    /// retains the outputs of the cells that completed beforehand.
+    pub async fn run_with_deadline(&self, deadline: Duration) -> (ExecutionState, RunOutcome) {
+        let deadline_cancellation = self.cancellation.child_token();
+        let timer = {
+            let deadline_cancellation = deadline_cancellation.clone();
+            self.spawn(async move {
+                tokio::time::sleep(deadline).await;
+                deadline_cancellation.cancel();
+            })
+        };
+
+        let mut state = self.clone().with_cancellation(deadline_cancellation.clone());
+        let mut cancelled = vec![];
+        loop {
+            match state.step_execution().await {
+                Ok((next, _)) => state = next,
+                Err(e) => {
+                    if let Some(ExecutionStateErrors::Cancelled(Some(operation_id))) = e.downcast_ref::<ExecutionStateErrors>() {
+                        cancelled.push(*operation_id);
+                    }
+                    break;
+                }
+            }
+        }
+        timer.abort();
+
+        let mut outcome = state.run_outcome(&cancelled);
+        outcome.timed_out = deadline_cancellation.is_cancelled() && !self.cancellation.is_cancelled();
+        (state.with_cancellation(self.cancellation.clone()), outcome)
+    }
+
+    pub fn emit_progress(&self, event: ProgressEvent) {
+        if let Some(sender) = &self.progress_sender {
+            sender.emit(event);
+        }
+    }
+
+    /// The output of the cell with the given name, None when no cell has that name or it has yet
+    /// to produce an output. A cell whose execution failed yields its error. Names shared by more
+    /// than one cell are ambiguous and yield `AmbiguousName` rather than either cell's output.
+    pub fn get_output_by_name(&self, name: &str) -> Result<Option<RkyvSerializedValue>, ExecutionStateErrors> {
+        match self.operation_id_by_name(name)? {
+            Some(operation_id) => self.state_get(&operation_id).map(|output| output.output.clone()).transpose(),
+            None => Ok(None),
+        }
+    }
+
+    /// The operation of the cell with the given name, None when no cell has that name.
+    fn operation_id_by_name(&self, name: &str) -> Result<Option<OperationId>, ExecutionStateErrors> {
+        let mut operation_ids: Vec<OperationId> = self.operation_by_id
+            .iter()
+            .filter(|(_, op)| op.name.as_deref() == Some(name))
+            .map(|(id, _)| *id)
+            .collect();
+        operation_ids.sort();
+        match operation_ids.as_slice() {
+            [] => Ok(None),
+            [operation_id] => Ok(Some(*operation_id)),
+            _ => Err(ExecutionStateErrors::AmbiguousName(name.to_string(), operation_ids)),
+        }
+    }
+
     fn state_get(&self, operation_id: &OperationId) -> Option<&OperationFnOutput> {
         self.state.get(operation_id).map(|x| x.as_ref())
     }
@@ -312,8 +1047,9 @@ impl ExecutionState {
         self.state.get(operation_id).map(|x| x.as_ref()).map(|o| &o.output)
     }
 
-    #[tracing::instrument]
+    #[tracing::instrument(skip(value))]
     pub fn state_insert(&mut self, operation_id: OperationId, value: OperationFnOutput) {
+        self.usage.record(&value);
         self.state.insert(operation_id, Arc::new(value));
         self.has_been_set.insert(operation_id);
     }
@@ -342,6 +1078,47 @@ impl ExecutionState {
         );
     }
 
+    /// The cells of the graph, their signatures and the dependencies between them, for tools that
+    /// consume the graph as JSON.
+    pub fn export_graph(&self) -> GraphExport {
+        GraphExport::from_state(self)
+    }
+
+    /// Hex encoded hash of the whole graph: the definition of every cell and the dependencies between
+    /// them. Cells are identified by their content rather than their operation ids and both are
+    /// hashed in a canonical order, so the hash does not depend on the order cells were added in.
+    /// Cells are hashed with SHA-1 of their canonical definition so that the hash is stable across
+    /// processes and versions of the toolchain, and can be compared against that of a prior run.
+    pub fn graph_hash(&self) -> String {
+        let sha1 = |cell: &CellTypes| -> [u8; 20] { Sha1::digest(cell.canonical_definition().as_bytes()).into() };
+        let cell_hash = |operation_id: &OperationId| self.cells_by_id.get(operation_id).map_or([0; 20], sha1);
+        let mut cells: Vec<[u8; 20]> = self.cells_by_id.values().map(sha1).collect();
+        cells.sort();
+        let mut edges: Vec<([u8; 20], [u8; 20], Vec<String>)> = self.get_dependency_graph_flattened()
+            .into_iter()
+            .map(|(from, to, references)| {
+                let mut references: Vec<String> = references.iter().map(|reference| format!("{:?}", reference)).collect();
+                references.sort();
+                (cell_hash(&from), cell_hash(&to), references)
+            })
+            .collect();
+        edges.sort();
+
+        let mut hasher = Sha1::new();
+        for cell in cells {
+            hasher.update(cell);
+        }
+        for (from, to, references) in edges {
+            hasher.update(from);
+            hasher.update(to);
+            for reference in references {
+                hasher.update(reference.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
     #[tracing::instrument]
     pub fn get_dependency_graph_flattened(&self) -> Vec<(OperationId, OperationId, Vec<DependencyReference>)> {
         let edges = self.get_dependency_graph();
@@ -367,15 +1144,55 @@ impl ExecutionState {
     }
 
     pub fn get_operation_from_cell_type(&self, cell: &CellTypes) -> anyhow::Result<OperationNode> {
+        let cell = &cell.with_default_provider(self.configuration.default_provider.as_ref());
         let op = match cell {
             CellTypes::Code(c, r) => crate::cells::code_cell::code_cell(self.chronology_id.clone(), c, r),
-            CellTypes::Prompt(c, r) => crate::cells::llm_prompt_cell::llm_prompt_cell(self.chronology_id.clone(), c, r),
+            CellTypes::Prompt(c, r) => crate::cells::llm_prompt_cell::llm_prompt_cell(self.chronology_id.clone(), c, r, self.cwd.as_deref()),
             CellTypes::Template(c, r) => crate::cells::template_cell::template_cell(self.chronology_id.clone(), c, r),
             CellTypes::CodeGen(c, r) => crate::cells::code_gen_cell::code_gen_cell(self.chronology_id.clone(), c, r),
+            CellTypes::Transform(c, r) => crate::cells::transform_cell::transform_cell(self.chronology_id.clone(), c, r),
+            CellTypes::Embedding(c, r) => crate::cells::embedding_cell::embedding_cell(self.chronology_id.clone(), c, r),
         }?;
         Ok(op)
     }
 
+    /// A new revision of this state seeded with the given object of globals, for supplying the
+    /// external inputs of the graph to a run. Keys that do not correspond to an input declared by
+    /// any cell are retained but warned about.
+    pub fn with_initial_globals(&self, globals: RkyvSerializedValue) -> anyhow::Result<ExecutionState> {
+        let RkyvSerializedValue::Object(globals) = globals else {
+            return Err(anyhow::anyhow!("Initial globals must be an object, got {:?}", globals));
+        };
+        let declared: HashSet<&String> = self.operation_by_id
+            .values()
+            .flat_map(|operation| operation.signature.input_signature.globals.keys())
+            .collect();
+        let mut new_state = self.create_new_revision_of_execution_state();
+        for (key, value) in globals {
+            if !declared.contains(&key) {
+                warn!("Initial global {:?} does not correspond to an input of any cell", key);
+            }
+            if self.initial_globals.get(&key) != Some(&value) {
+                for (id, operation) in &self.operation_by_id {
+                    if operation.signature.input_signature.globals.contains_key(&key) {
+                        new_state.dirty_operations.insert(*id);
+                    }
+                }
+            }
+            new_state.initial_globals.insert(key, value);
+        }
+        new_state.evaluating_enclosed_state = EnclosedState::SelfContained;
+        Ok(new_state)
+    }
+
+    /// As `with_initial_globals`, recording the seeded state in the execution graph.
+    #[tracing::instrument(skip(globals))]
+    pub async fn seed_initial_globals(&self, globals: RkyvSerializedValue) -> anyhow::Result<ExecutionState> {
+        let mut new_state = self.with_initial_globals(globals)?;
+        self.send_new_state_to_graph_and_pause_with_oneshot(&mut new_state.clone()).await;
+        Ok(new_state)
+    }
+
     #[tracing::instrument]
     pub async fn update_operation(
         &self,
@@ -390,7 +1207,7 @@ impl ExecutionState {
 
     #[tracing::instrument]
     fn assign_dependencies_to_operations(new_state: &ExecutionState) -> anyhow::Result<Vec<DependencyGraphMutation>> {
-        let (available_values, available_functions) = Self::extract_available_values_and_functions(new_state)?;
+        let (available_values, available_functions, available_namespaces) = Self::extract_available_values_and_functions(new_state)?;
 
         // Anywhere there is a matched value, we create a dependency graph edge
         let mut mutations = vec![];
@@ -402,6 +1219,19 @@ impl ExecutionState {
             // The currently running operation will be locked and will fail this condition, but we're not updating it.
             let input_signature = &operation.signature.input_signature;
             let mut accum = vec![];
+            // Operations consuming every global depend on each global a single other cell provides
+            if input_signature.all_globals {
+                for (value_name, source_cell_ids) in available_values.iter() {
+                    if input_signature.globals.contains_key(value_name) {
+                        continue;
+                    }
+                    if let [source_cell_id] = source_cell_ids.as_slice() {
+                        if *source_cell_id != destination_cell_id {
+                            accum.push((**source_cell_id, DependencyReference::Global(value_name.to_string())));
+                        }
+                    }
+                }
+            }
             for (value_name, value) in input_signature.globals.iter() {
 
                 // TODO: we need to handle collisions between the two of these
@@ -414,12 +1244,32 @@ impl ExecutionState {
                     }
                 }
 
-                if let Some(source_cell_id) = available_values.get(value_name) {
-                    if source_cell_id != &destination_cell_id {
-                        accum.push((
-                            *source_cell_id.clone(),
-                            DependencyReference::Global(value_name.to_string()),
-                        ));
+                // Names of cells refer to the namespace of that cell, an object of the globals it
+                // provides, when no cell provides a global of the same name and it is not supplied
+                // externally
+                let Some(source_cell_ids) = available_values.get(value_name)
+                    .or_else(|| available_namespaces.get(value_name).filter(|_| !new_state.initial_globals.contains_key(value_name))) else {
+                    continue;
+                };
+                let source_cell_ids: Vec<&OperationId> = source_cell_ids
+                    .iter()
+                    .copied()
+                    .filter(|source_cell_id| source_cell_id != &destination_cell_id)
+                    .collect();
+                match source_cell_ids.as_slice() {
+                    [] => {}
+                    [source_cell_id] => accum.push((
+                        **source_cell_id,
+                        DependencyReference::Global(value_name.to_string()),
+                    )),
+                    _ => {
+                        let mut qualified_names: Vec<String> = source_cell_ids
+                            .iter()
+                            .filter_map(|id| new_state.operation_by_id.get(*id)?.name.as_ref())
+                            .map(|name| format!("{}.{}", name, value_name))
+                            .collect();
+                        qualified_names.sort();
+                        return Err(ExecutionStateErrors::AmbiguousGlobal(value_name.to_string(), qualified_names).into());
                     }
                 }
                 // unsatisfied_dependencies.push(value_name.clone())
@@ -434,10 +1284,77 @@ impl ExecutionState {
         Ok(mutations)
     }
 
+    /// Globals that cells of this graph depend upon but no cell of the graph provides, along with
+    /// their declared types. Unlike missing inputs these are not errors, they may be supplied
+    /// externally when the graph is run. Inputs with defaults are not included.
+    pub fn unresolved_external_inputs(&self) -> BTreeMap<String, Option<InputType>> {
+        let mut provided: HashSet<String> = HashSet::new();
+        for operation in self.operation_by_id.values() {
+            let output_signature = &operation.signature.output_signature;
+            provided.extend(output_signature.globals.keys().chain(output_signature.functions.keys()).cloned());
+            if let Some(name) = operation.name.as_ref().filter(|_| !output_signature.globals.is_empty()) {
+                provided.insert(name.clone());
+            }
+        }
+
+        let mut unresolved: BTreeMap<String, Option<InputType>> = BTreeMap::new();
+        for operation in self.operation_by_id.values() {
+            for (name, config) in operation.signature.input_signature.globals.iter() {
+                if provided.contains(name) || config.default.is_some() || config.variadic {
+                    continue;
+                }
+                let entry = unresolved.entry(name.clone()).or_insert(None);
+                if entry.is_none() {
+                    *entry = config.ty.clone();
+                }
+            }
+        }
+        unresolved
+    }
+
+    /// Globals and functions that no other cell of this graph consumes, by their name or through
+    /// their cell's namespace, the opposite of `unresolved_external_inputs`. Outputs named in
+    /// `final_outputs`, either by their name, their name qualified by their cell's name or the name
+    /// of their cell, are the results of the graph and are not reported.
+    pub fn unused_outputs(&self, final_outputs: &[&str]) -> Vec<UnusedOutput> {
+        let mut unused = vec![];
+        for (operation_id, operation) in self.operation_by_id.iter() {
+            let output_signature = &operation.signature.output_signature;
+            let outputs = output_signature.globals.keys().map(|name| (name, false))
+                .chain(output_signature.functions.keys().map(|name| (name, true)));
+            for (name, is_function) in outputs {
+                let qualified_name = operation.name.as_ref().map(|cell_name| format!("{}.{}", cell_name, name));
+                let consumed_as = |reference: &str| reference == name.as_str()
+                    || Some(reference) == operation.name.as_deref();
+                if final_outputs.iter().any(|reference| consumed_as(reference) || Some(*reference) == qualified_name.as_deref()) {
+                    continue;
+                }
+                let consumed = self.operation_by_id.iter()
+                    .filter(|(id, _)| *id != operation_id)
+                    .any(|(_, consumer)| consumer.signature.input_signature.globals.keys().any(|input| consumed_as(input.as_str())));
+                if !consumed {
+                    unused.push(UnusedOutput {
+                        operation_id: *operation_id,
+                        cell_name: operation.name.clone(),
+                        name: name.clone(),
+                        is_function,
+                    });
+                }
+            }
+        }
+        unused.sort_by(|a, b| (&a.cell_name, &a.name, a.operation_id).cmp(&(&b.cell_name, &b.name, b.operation_id)));
+        unused
+    }
+
+    /// The cells providing each global, function and namespace. More than one cell may provide a
+    /// global of the same name, these are consumed through the namespace of the cell providing
+    /// them, as `cellname.result`. The namespace of a named cell is the object of the globals it
+    /// provides.
     #[tracing::instrument]
-    fn extract_available_values_and_functions(new_state: &ExecutionState) -> anyhow::Result<(HashMap<String, &OperationId>, HashMap<String, &OperationId>)> {
-        let mut available_values = HashMap::new();
+    fn extract_available_values_and_functions(new_state: &ExecutionState) -> anyhow::Result<(HashMap<String, Vec<&OperationId>>, HashMap<String, &OperationId>, HashMap<String, Vec<&OperationId>>)> {
+        let mut available_values: HashMap<String, Vec<&OperationId>> = HashMap::new();
         let mut available_functions = HashMap::new();
+        let mut available_namespaces: HashMap<String, Vec<&OperationId>> = HashMap::new();
 
         // For all reported cells, add their exposed values to the available values
         for (id, operation) in new_state.operation_by_id.iter() {
@@ -445,10 +1362,10 @@ impl ExecutionState {
 
             // Store values that are available as globals
             for (key, value) in output_signature.globals.iter() {
-                let insert_result = available_values.insert(key.clone(), id);
-                if insert_result.is_some() {
-                    return Err(anyhow::Error::msg(format!("Naming collision detected for value {} when storing op #{}", key, id)));
-                }
+                available_values.entry(key.clone()).or_default().push(id);
+            }
+            if let Some(name) = operation.name.as_ref().filter(|_| !output_signature.globals.is_empty()) {
+                available_namespaces.entry(name.clone()).or_default().push(id);
             }
 
             for (key, value) in output_signature.functions.iter() {
@@ -458,7 +1375,7 @@ impl ExecutionState {
                 }
             }
         }
-        Ok((available_values, available_functions))
+        Ok((available_values, available_functions, available_namespaces))
     }
 
     /// Inserts a new operation into the execution state, returning the operation id and the new state.
@@ -476,6 +1393,11 @@ impl ExecutionState {
                 op_id
             });
         operation_node.id = op_id;
+        let unchanged = self.cells_by_id.get(&op_id)
+            .is_some_and(|previous| previous.source_hash() == operation_node.cell.source_hash());
+        if !unchanged {
+            s.dirty_operations.insert(op_id);
+        }
         s.cells_by_id.insert(op_id, operation_node.cell.clone());
         s.evaluated_mutation_of_cell = Some((op_id, operation_node.cell.clone()));
         s.operation_by_id.insert(op_id, operation_node);
@@ -527,34 +1449,115 @@ impl ExecutionState {
         for (id, op_node) in &self.operation_by_id {
             self.function_name_to_metadata.extend(
                 op_node.signature.output_signature.functions.iter().map(|(name, config)| {
-                    let input_signature = match config {
-                        OutputItemConfiguration::Function { input_signature, .. } => input_signature.clone(),
-                        _ => InputSignature::new(),
+                    let (input_signature, emit_event, trigger_on) = match config {
+                        OutputItemConfiguration::Function { input_signature, emit_event, trigger_on, .. } => {
+                            (input_signature.clone(), emit_event.clone(), trigger_on.clone())
+                        }
+                        _ => (InputSignature::new(), vec![], vec![]),
                     };
 
                     (name.clone(), FunctionMetadata {
                         operation_id: id.clone(),
                         input_signature,
+                        emit_event,
+                        trigger_on,
                     })
                 })
             );
         }
     }
 
+    /// Publish an event for delivery to the functions that trigger on it. Fails when the event would
+    /// exceed the depth of nested events or the number of events allowed in a run.
+    pub fn publish_event(&mut self, name: &str, payload: RkyvSerializedValue) -> Result<(), ExecutionStateErrors> {
+        if self.evaluating_event_depth >= MAX_EVENT_DEPTH {
+            return Err(ExecutionStateErrors::EventLimitExceeded(format!(
+                "event {:?} is nested {} events deep, the limit is {}", name, self.evaluating_event_depth, MAX_EVENT_DEPTH
+            )));
+        }
+        if self.published_event_count >= MAX_EVENTS_PER_RUN {
+            return Err(ExecutionStateErrors::EventLimitExceeded(format!(
+                "event {:?} would exceed the limit of {} events in a run", name, MAX_EVENTS_PER_RUN
+            )));
+        }
+        self.published_event_count += 1;
+        self.pending_events.push_back(PendingEvent {
+            name: name.to_string(),
+            payload,
+            depth: self.evaluating_event_depth,
+        });
+        Ok(())
+    }
+
+    /// Names of the functions that trigger on the named event, in a deterministic order.
+    pub fn event_subscribers(&self, name: &str) -> Vec<String> {
+        let mut subscribers: Vec<String> = self.function_name_to_metadata
+            .iter()
+            .filter(|(_, meta)| meta.trigger_on.iter().any(|event| event == name))
+            .map(|(function_name, _)| function_name.clone())
+            .collect();
+        subscribers.sort();
+        subscribers
+    }
+
+    /// Deliver the earliest pending event to each function that triggers on it, with the event's
+    /// payload bound to the function's inputs. Returns None when no events are pending.
+    async fn deliver_next_event(&self) -> anyhow::Result<Option<(ExecutionState, Vec<(OperationId, OperationFnOutput)>)>> {
+        let mut state = self.clone();
+        let Some(event) = state.pending_events.pop_front() else {
+            return Ok(None);
+        };
+        debug!("Delivering event {:?}", event.name);
+        let mut outputs = vec![];
+        for function_name in self.event_subscribers(&event.name) {
+            let meta = &self.function_name_to_metadata[&function_name];
+            let payload = meta.input_signature.bind_event_payload(event.payload.clone());
+            state.evaluating_event_depth = event.depth + 1;
+            let (output, next_state) = state.dispatch(&function_name, payload, None).await?;
+            state = next_state;
+            outputs.push((meta.operation_id, OperationFnOutput {
+                has_error: output.is_err(),
+                output,
+                ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+            }));
+        }
+        state.evaluating_event_depth = 0;
+        Ok(Some((state, outputs)))
+    }
+
+    /// Carry over what an operation recorded in the state it resolved to: the events published by
+    /// the functions it invoked and the conversation histories of its prompts.
+    fn retain_resolved_effects(&mut self, resolved: &ExecutionState) {
+        if resolved.published_event_count > self.published_event_count {
+            self.pending_events = resolved.pending_events.clone();
+            self.published_event_count = resolved.published_event_count;
+        }
+        self.conversation_histories = resolved.conversation_histories.clone();
+    }
+
     /// Invoke a function made available by the execution state, this accepts arguments derived in the context
     /// of a parent function's scope. This targets a specific function by name that we've identified a dependence on.
     // TODO: this should create a coroutine that yields with the result of the function invocation
     #[tracing::instrument(parent = parent_span_id.clone(), skip(self, payload))]
     pub async fn dispatch(&self, function_name: &str, payload: RkyvSerializedValue, parent_span_id: Option<tracing::Id>) -> anyhow::Result<(Result<RkyvSerializedValue, ExecutionStateErrors>, ExecutionState)> {
         debug!("Running dispatch {:?}", function_name);
+        if self.dispatch_depth >= self.max_dispatch_depth {
+            return Err(ExecutionStateErrors::DispatchDepthExceeded(function_name.to_string(), self.max_dispatch_depth).into());
+        }
 
-        // Store the invocation payload into an execution state and record this before executing
+        // Store the invocation payload into an execution state and record this before executing,
+        // functions the invoked function itself invokes observe the deeper nesting
         let mut before_execution_state = self.create_new_revision_of_execution_state();
         before_execution_state.stack.push_back(self.resolving_execution_node_state_id);
-
-        let meta = self.function_name_to_metadata.get(function_name).map(|meta| {
-            meta
-        }).expect("Failed to find named function");
+        before_execution_state.dispatch_depth = self.dispatch_depth + 1;
+
+        let meta = self.function_name_to_metadata.get(function_name)
+            .ok_or_else(|| anyhow::anyhow!("Failed to find named function {:?}", function_name))?;
+        let payload = meta.input_signature.fill_invocation_defaults(payload);
+        let emit_event = meta.emit_event.clone();
+        if let Err(violations) = meta.input_signature.validate_invocation_values(&payload) {
+            return Err(ExecutionStateErrors::InvalidInputs(meta.operation_id, violations).into());
+        }
 
         let cell = before_execution_state.cells_by_id.get(&meta.operation_id).unwrap();
         // modify code cell to indicate execution of the target function
@@ -578,6 +1581,15 @@ impl ExecutionState {
             .close_and_set_chronological_parent(&result.execution_state.as_ref().unwrap_or(&before_execution_state));
 
         after_execution_state.stack.pop_back();
+        after_execution_state.dispatch_depth = self.dispatch_depth;
+        if let Some(resolved) = &result.execution_state {
+            after_execution_state.retain_resolved_effects(resolved);
+        }
+        if let Ok(value) = &result.output {
+            for event in &emit_event {
+                after_execution_state.publish_event(event, value.clone())?;
+            }
+        }
         after_execution_state.state_insert(Uuid::max(), result.clone());
         after_execution_state.fresh_values.insert(Uuid::max());
         self.send_new_state_to_graph_and_pause_with_oneshot(&mut after_execution_state).await;
@@ -597,10 +1609,10 @@ impl ExecutionState {
                 match c {
                     LLMPromptCell::Chat { is_function_invocation: ref mut function_invocation, .. } => {
                         *function_invocation = true;
-                        crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, &r)?
+                        crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, &r, self.cwd.as_deref())?
                     }
                     _ => {
-                        crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, &r)?
+                        crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, &r, self.cwd.as_deref())?
                     }
                 }
             }
@@ -638,6 +1650,17 @@ impl ExecutionState {
 
         signature.prepopulate_defaults(&mut inputs);
 
+        for key in signature.globals.keys() {
+            if let Some(value) = self.initial_globals.get(key) {
+                inputs.globals.insert(key.clone(), value.clone());
+            }
+        }
+        if signature.all_globals {
+            for (key, value) in self.initial_globals.iter() {
+                inputs.globals.insert(key.clone(), value.clone());
+            }
+        }
+
         for (from, _, argument_indices) in dependency_graph.edges_directed(operation_id, Direction::Incoming) {
             let Some(output) = self.state_get(&from) else { continue; };
             let output_value = &output.output;
@@ -651,7 +1674,20 @@ impl ExecutionState {
                     }
                     DependencyReference::Global(name) => {
                         if let RkyvSerializedValue::Object(value) = &output.output.clone().unwrap() {
-                            inputs.globals.insert(name.clone(), value.get(name).ok_or_else(|| anyhow::anyhow!("Expected value with name: {:?} to be available", name))?.clone());
+                            let source_name = self.operation_by_id.get(&from).and_then(|op| op.name.as_deref());
+                            match value.get(name) {
+                                Some(global) => {
+                                    inputs.globals.insert(name.clone(), global.clone());
+                                }
+                                // The namespace of the cell, unless the global is supplied externally
+                                None if source_name == Some(name.as_str()) => {
+                                    if !self.initial_globals.contains_key(name) {
+                                        inputs.globals.insert(name.clone(), RkyvSerializedValue::Object(value.clone()));
+                                        inputs.namespaces.insert(name.clone());
+                                    }
+                                }
+                                None => return Err(anyhow::anyhow!("Expected value with name: {:?} to be available", name)),
+                            }
                         }
                     }
                     DependencyReference::FunctionInvocation(name) => {
@@ -666,6 +1702,12 @@ impl ExecutionState {
         Ok(inputs)
     }
 
+    /// The inputs the operation would execute with as of this state.
+    fn pending_inputs(&self, operation_id: OperationId) -> anyhow::Result<RkyvSerializedValue> {
+        let signature = &self.get_operation_node(operation_id)?.signature.input_signature;
+        Ok(self.prepare_operation_inputs(signature, operation_id, self.get_dependency_graph())?.to_serialized_value())
+    }
+
     fn has_fresher_inputs(&self, operation_id: OperationId) -> anyhow::Result<bool> {
         let our_freshness = self.value_freshness_map.get(&operation_id).copied().unwrap_or(0);
         let dependency_graph = self.get_dependency_graph();
@@ -679,6 +1721,35 @@ impl ExecutionState {
             }))
     }
 
+    /// Whether the most recent execution of the operation failed.
+    fn has_failed(&self, operation_id: &OperationId) -> bool {
+        self.state_get(operation_id).is_some_and(|output| output.has_error || output.output.is_err())
+    }
+
+    fn has_failed_dependency(&self, operation_id: OperationId) -> bool {
+        self.get_dependency_graph()
+            .edges_directed(operation_id, Direction::Incoming)
+            .any(|(from, _, _)| self.has_failed(&from))
+    }
+
+    /// Mark the operations that consume the output of `operation_id` as dirty.
+    fn mark_dependents_dirty(&mut self, operation_id: OperationId) {
+        let dependents: Vec<OperationId> = self.dependency_map
+            .iter()
+            .filter(|(_, depends_on)| depends_on.iter().any(|(id, _)| *id == operation_id))
+            .map(|(id, _)| *id)
+            .collect();
+        self.dirty_operations.extend(dependents);
+    }
+
+    /// Hash of an operation's cell source together with the inputs it would execute with.
+    fn execution_hash(cell: &CellTypes, inputs: &OperationInputs) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        cell.source_hash().hash(&mut hasher);
+        inputs.to_serialized_value().hash(&mut hasher);
+        hasher.finish()
+    }
+
     #[tracing::instrument]
     pub(crate) fn determine_next_operation(&self) -> anyhow::Result<ExecutionState> {
         let mut exec_queue = self.exec_queue.clone();
@@ -689,7 +1760,7 @@ impl ExecutionState {
             debug!("Looping through queue of executable cells {:?} {:?}", self.exec_queue, count_loops);
 
             if count_loops >= operation_count * 2 {
-                return Err(Error::msg("Looped through all operations without detecting an execution"));
+                return Err(ExecutionStateErrors::NoFurtherExecutionDetected.into());
             }
             count_loops += 1;
 
@@ -708,13 +1779,21 @@ impl ExecutionState {
             let op_node = self.get_operation_node(next_operation_id)?;
             let signature = &op_node.signature.input_signature;
 
-            // Skip if already run with no dependencies
-            if signature.is_empty() && self.has_been_set.contains(&next_operation_id) {
+            // Skip if already run and neither its cell nor anything it depends upon has changed since
+            let has_been_set = self.has_been_set.contains(&next_operation_id);
+            if has_been_set && !self.dirty_operations.contains(&next_operation_id) {
+                continue;
+            }
+
+            // Skip if no new inputs available, unless it has yet to consume the globals the run was seeded with
+            let awaits_initial_globals = !has_been_set
+                && signature.globals.keys().any(|key| self.initial_globals.contains_key(key));
+            if !signature.is_empty() && !has_been_set && !awaits_initial_globals && !self.has_fresher_inputs(next_operation_id)? {
                 continue;
             }
 
-            // Skip if no new inputs available
-            if !signature.is_empty() && !self.has_fresher_inputs(next_operation_id)? {
+            // Skip if an operation it depends upon failed, rather than executing without that input
+            if self.has_failed_dependency(next_operation_id) {
                 continue;
             }
 
@@ -724,12 +1803,21 @@ impl ExecutionState {
                 continue;
             }
 
+            // Skip if dirty but executing again would see the same source and inputs as last time
+            let execution_hash = Self::execution_hash(&op_node.cell, &inputs);
+            if has_been_set && self.execution_hashes.get(&next_operation_id) == Some(&execution_hash) {
+                continue;
+            }
+
             // Create and stage new execution state
             let mut new_state = self.create_new_revision_of_execution_state();
             new_state.evaluating_operation_id = next_operation_id;
             new_state.evaluating_name = op_node.name.clone();
             new_state.evaluating_arguments = Some(inputs.to_serialized_value());
+            new_state.evaluating_input_violations = signature.validate_input_values(&inputs).err();
             new_state.exec_queue = exec_queue;
+            new_state.dirty_operations.remove(&next_operation_id);
+            new_state.execution_hashes.insert(next_operation_id, execution_hash);
             return Ok(new_state);
         }
     }
@@ -739,21 +1827,123 @@ impl ExecutionState {
         &self,
     ) -> anyhow::Result<(ExecutionState, Vec<(OperationId, OperationFnOutput)>)> {
         debug!("Running step_execution for state {:?}", self.chronology_id);
+        if self.cancellation.is_cancelled() {
+            return Err(ExecutionStateErrors::Cancelled(None).into());
+        }
+
+        // 0. Deliver events published by earlier operations before starting another
+        if let Some(delivered) = self.deliver_next_event().await? {
+            return Ok(delivered);
+        }
+
         // 1. Initialize state and prepare for execution
         let mut before_execution_state = self.determine_next_operation()?;
         let operation_id = before_execution_state.evaluating_operation_id.clone();
-        let args = before_execution_state.evaluating_arguments.take().unwrap();
+        let mut args = before_execution_state.evaluating_arguments.take().unwrap();
+
+        // Pause ahead of a breakpoint, unless the run was resumed at it
+        if self.breakpoints.contains(&operation_id) {
+            match &self.resuming {
+                Some((resumed, inputs)) if *resumed == operation_id => {
+                    if let Some(inputs) = inputs {
+                        args = inputs.clone();
+                    }
+                }
+                _ => return Err(ExecutionStateErrors::PausedAtBreakpoint(operation_id).into()),
+            }
+        }
+        before_execution_state.resuming = None;
 
         // 2. Update operation node info
         let op_node = self.get_operation_node(operation_id)?;
         before_execution_state.evaluating_cell = Some(op_node.cell.clone());
 
+        // Inputs violating the constraints the cell declares fail the cell without executing it
+        if let Some(violations) = before_execution_state.evaluating_input_violations.take() {
+            let error = ExecutionStateErrors::InvalidInputs(operation_id, violations);
+            let result = OperationFnOutput {
+                has_error: true,
+                execution_state: None,
+                stderr: vec![LogLine::stderr(error.to_string())],
+                output: Err(error),
+                stdout: vec![],
+                metadata: Default::default(),
+            };
+            return Ok(self.record_operation_result(before_execution_state, operation_id, result).await);
+        }
+
         // 3. Pause if needed, sending in progress execution to the graph
         self.send_new_state_to_graph_and_pause_with_oneshot(&mut before_execution_state).await;
 
         // 4. Execute the operation
-        let result = op_node.execute(&mut before_execution_state, args, None, None).await?;
+        self.emit_progress(ProgressEvent::CellStarted {
+            execution_node_id: before_execution_state.chronology_id,
+            operation_id,
+            name: op_node.name.clone(),
+        });
+        let cell_execution = CellExecution {
+            execution_node_id: before_execution_state.chronology_id,
+            operation_id,
+            name: op_node.name.as_deref(),
+            cell: &op_node.cell,
+        };
+        run_before_hooks(&self.hooks, &cell_execution, &args);
+        let cell_span = tracing::info_span!(
+            "cell",
+            execution_node_id = %before_execution_state.chronology_id,
+            operation_id = %operation_id,
+            name = op_node.name.as_deref().unwrap_or_default(),
+            has_error = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+            provider = tracing::field::Empty,
+            model = tracing::field::Empty,
+            prompt_tokens = tracing::field::Empty,
+            completion_tokens = tracing::field::Empty,
+            total_tokens = tracing::field::Empty,
+        );
+        let started_at = Instant::now();
+        let execution = op_node.execute(&mut before_execution_state, args, None, None).instrument(cell_span.clone());
+        let result = tokio::select! {
+            // Cells interrupted by the cancellation fail, they are reported as cancelled instead
+            biased;
+            _ = self.cancellation.cancelled() => {
+                self.emit_progress(ProgressEvent::CellFinished {
+                    execution_node_id: before_execution_state.chronology_id,
+                    operation_id,
+                    name: op_node.name.clone(),
+                    has_error: true,
+                });
+                return Err(ExecutionStateErrors::Cancelled(Some(operation_id)).into());
+            }
+            // A failing cell is recorded with its error so that the cells not depending upon it can proceed
+            result = execution => result.unwrap_or_else(|e| OperationFnOutput {
+                has_error: true,
+                execution_state: None,
+                output: Err(ExecutionStateErrors::AnyhowError(e.to_string())),
+                stdout: vec![],
+                stderr: vec![LogLine::stderr(e.to_string())],
+                metadata: Default::default(),
+            }),
+        };
+        let result = self.limit_output_size(result);
+        record_cell_span(&cell_span, &result, started_at.elapsed());
+        self.emit_progress(ProgressEvent::CellFinished {
+            execution_node_id: before_execution_state.chronology_id,
+            operation_id,
+            name: op_node.name.clone(),
+            has_error: result.has_error,
+        });
+        run_after_hooks(&self.hooks, &cell_execution, &result);
+
+        Ok(self.record_operation_result(before_execution_state, operation_id, result).await)
+    }
 
+    async fn record_operation_result(
+        &self,
+        before_execution_state: ExecutionState,
+        operation_id: OperationId,
+        result: OperationFnOutput,
+    ) -> (ExecutionState, Vec<(OperationId, OperationFnOutput)>) {
         // 5. Update state with execution results
         // If the result of the execution returned a new execution state
         // make sure that our Close for the step_execution is parented by
@@ -762,21 +1952,30 @@ impl ExecutionState {
             .close_and_set_chronological_parent(&result.execution_state.as_ref().unwrap_or(&before_execution_state));
 
         // 6. Finalize state
+        if let Some(resolved) = &result.execution_state {
+            after_execution_state.retain_resolved_effects(resolved);
+        }
         after_execution_state.fresh_values.insert(operation_id.clone());
+        let previous_output = self.state.get(&operation_id).and_then(|previous| previous.output.as_ref().ok());
+        if previous_output.is_none() || previous_output != result.output.as_ref().ok() {
+            after_execution_state.mark_dependents_dirty(operation_id);
+        }
         after_execution_state.state_insert(operation_id.clone(), result.clone());
         after_execution_state.value_freshness_map.insert(operation_id.clone(), after_execution_state.exec_counter);
 
         self.send_new_state_to_graph_and_pause_with_oneshot(&mut after_execution_state).await;
 
-        Ok((after_execution_state, vec![(operation_id, result)]))
+        (after_execution_state, vec![(operation_id, result)])
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::cells::{CellTypes, SupportedLanguage, TextRange};
+    use crate::cells::{CellTypes, SupportedLanguage, SupportedModelProviders, TextRange};
     use crate::cells::CodeCell;
+    use crate::library::std::ai::llm::mock::{MockChatModel, RequestMatcher};
+    use crate::library::std::ai::llm::{ChatCompletionReq, MessageRole};
     use crate::execution::primitives::operation::{InputItemConfiguration, InputType, OutputSignature, Signature, TriggerConfiguration};
 
     #[test]
@@ -790,6 +1989,7 @@ mod tests {
             output: Ok(value),
             stdout: vec![],
             stderr: vec![],
+            metadata: Default::default(),
         };
         exec_state.state_insert(operation_id, value.clone());
 
@@ -863,11 +2063,10 @@ mod tests {
     fn test_update_op() {
         let state = ExecutionState::new_with_random_id();
         let cell = CellTypes::Code(CodeCell {
-            backing_file_reference: None,
             name: Some(String::from("a")),
             language: SupportedLanguage::PyO3,
             source_code: String::from("y = x + 1"),
-            function_invocation: None,
+            ..Default::default()
         }, Default::default());
 
         let id_a = Uuid::now_v7();
@@ -924,11 +2123,9 @@ mod tests {
         let mut state = ExecutionState::new_with_random_id();
         let mut op_node = OperationNode::default();
         op_node.cell = CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: "def test_fn(): return 2".to_string(),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default());
 
         let id_a = Uuid::now_v7();
@@ -937,6 +2134,8 @@ mod tests {
         new_state.function_name_to_metadata.insert("test_fn".to_string(), FunctionMetadata {
             operation_id: op_id,
             input_signature: InputSignature::new(),
+            emit_event: vec![],
+            trigger_on: vec![],
         });
         
         let payload = RkyvSerializedValue::Null;
@@ -960,6 +2159,193 @@ mod tests {
         assert!(graph.contains_edge(id_c, id_b));
     }
 
+    #[test]
+    fn test_unresolved_external_inputs() {
+        let state = ExecutionState::new_with_random_id();
+        let code_cell = |source_code: &str| CellTypes::Code(CodeCell {
+            language: SupportedLanguage::PyO3,
+            source_code: source_code.to_string(),
+            ..Default::default()
+        }, TextRange::default());
+
+        let op = state.get_operation_from_cell_type(&code_cell("y = seed + 1")).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        let op = state.get_operation_from_cell_type(&code_cell("z = y * 2")).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        let unresolved = state.unresolved_external_inputs();
+        assert_eq!(unresolved.keys().collect::<Vec<_>>(), vec!["seed"]);
+    }
+
+    #[tokio::test]
+    async fn test_initial_globals_are_consumed_by_prompt_cell() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("Hello Alice".to_string()), "Hi!")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = prompt_cell("reply", "model: gpt-4o", "Hello {{name}}");
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (op_id, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        assert_eq!(state.unresolved_external_inputs().keys().collect::<Vec<_>>(), vec!["name"]);
+
+        // Undeclared keys are accepted alongside the declared ones
+        let state = state.with_initial_globals(RkyvObjectBuilder::new()
+            .insert_string("name", "Alice".to_string())
+            .insert_number("unused", 1)
+            .build()).unwrap();
+        let (_, outputs) = state.step_execution().await.unwrap();
+        assert_eq!(outputs[0].0, op_id);
+        assert_eq!(outputs[0].1.output.clone().unwrap(), RkyvObjectBuilder::new().insert_string("reply", "Hi!".to_string()).build());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_inputs_violating_declared_constraints_are_rejected() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "Hi!")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = prompt_cell("reply", "model: gpt-4o\ninputs:\n  name:\n    enum: [Alice, Bob]", "Hello {{name}}");
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        let rejected = state.with_initial_globals(RkyvObjectBuilder::new()
+            .insert_string("name", "Mallory".to_string())
+            .build()).unwrap();
+        // The cell fails without executing, and the run continues
+        let (_, outputs) = rejected.step_execution().await.unwrap();
+        let Err(ExecutionStateErrors::InvalidInputs(_, violations)) = &outputs[0].1.output else {
+            panic!("expected the inputs to be rejected, got {:?}", outputs[0].1.output);
+        };
+        assert!(violations.contains(r#"globals: name: expected one of ["Alice", "Bob"]"#), "{}", violations);
+        assert_eq!(model.calls(0), 0);
+        let result = rejected.run_to_completion().await.unwrap();
+        assert_eq!(result.failed.len(), 1);
+
+        let accepted = state.with_initial_globals(RkyvObjectBuilder::new()
+            .insert_string("name", "Bob".to_string())
+            .build()).unwrap();
+        assert!(accepted.step_execution().await.is_ok());
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_run_starts_no_further_operations() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "Hi!")
+            .build());
+        let cancellation = CancellationToken::new();
+        let state = ExecutionState::new_with_random_id()
+            .with_chat_model(model.clone())
+            .with_cancellation(cancellation.clone());
+        let op = state.get_operation_from_cell_type(&prompt_cell("first", "model: gpt-4o", "Hello")).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        let op = state.get_operation_from_cell_type(&prompt_cell("second", "model: gpt-4o", "Hello")).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        let (state, outputs) = state.step_execution().await.unwrap();
+        let completed = outputs[0].0;
+        cancellation.cancel();
+        let error = state.step_execution().await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ExecutionStateErrors>(), Some(ExecutionStateErrors::Cancelled(None))), "{}", error);
+        let error = state.run_to_completion().await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ExecutionStateErrors>(), Some(ExecutionStateErrors::Cancelled(None))), "{}", error);
+
+        let outcome = state.run_outcome(&[]);
+        assert_eq!(outcome.completed, vec![completed]);
+        assert!(outcome.cancelled.is_empty());
+        assert_eq!(outcome.not_started.len(), 1);
+        assert_ne!(outcome.not_started[0], completed);
+    }
+
+    struct StalledChatModel;
+
+    #[async_trait::async_trait]
+    impl ChatModelBatch for StalledChatModel {
+        async fn batch(&self, _: crate::library::std::ai::llm::ChatCompletionReq) -> Result<crate::library::std::ai::llm::ChatCompletionRes, crate::library::std::ai::llm::LlmError> {
+            futures_util::future::pending().await
+        }
+    }
+
+    // The clock only advances once every cell that can make progress has, so the deadline falls
+    // while the prompt cell is stalled however long the Python cell takes
+    #[tokio::test(start_paused = true)]
+    async fn test_run_with_deadline_returns_partial_state() {
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(Arc::new(StalledChatModel));
+        let op = state.get_operation_from_cell_type(&python_cell("a", "x = 1")).unwrap();
+        let (completed, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        state = next;
+        let op = state.get_operation_from_cell_type(&prompt_cell("slow", "model: gpt-4o", "Count to {{x}}")).unwrap();
+        let (stalled, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        let (state, outcome) = state.run_with_deadline(Duration::from_millis(200)).await;
+        assert!(outcome.timed_out);
+        assert_eq!(outcome.completed, vec![completed]);
+        assert_eq!(outcome.cancelled, vec![stalled]);
+        assert_eq!(state.get_output_by_name("a").unwrap(), Some(RkyvObjectBuilder::new().insert_number("x", 1).build()));
+
+        let (_, outcome) = settled_chain().await.0.run_with_deadline(Duration::from_secs(5)).await;
+        assert!(!outcome.timed_out);
+    }
+
+    /// Cancel the run once the cell is running, from another thread as the cell blocks the runtime.
+    async fn cancel_running_cell(cell: CellTypes) {
+        let cancellation = CancellationToken::new();
+        let state = ExecutionState::new_with_random_id().with_cancellation(cancellation.clone());
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (running, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            cancellation.cancel();
+        });
+        let error = state.step_execution().await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ExecutionStateErrors>(), Some(ExecutionStateErrors::Cancelled(Some(id))) if *id == running));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_interrupts_running_python() {
+        cancel_running_cell(python_cell("spin", indoc! { r#"
+            import time
+            while True:
+                time.sleep(0.01)
+            "#})).await;
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_interrupts_running_javascript() {
+        cancel_running_cell(CellTypes::Code(CodeCell {
+            name: Some("spin".to_string()),
+            language: SupportedLanguage::Deno,
+            source_code: "while (true) {}".to_string(),
+            ..Default::default()
+        }, TextRange::default())).await;
+    }
+
+    #[test]
+    fn test_prompt_cell_provider_falls_back_to_default() {
+        let mut cell = prompt_cell("reply", "model: gpt-4o", "Hello");
+        let CellTypes::Prompt(LLMPromptCell::Chat { provider, .. }, _) = &mut cell else { unreachable!() };
+        *provider = None;
+
+        let mut state = ExecutionState::new_with_random_id();
+        let err = state.get_operation_from_cell_type(&cell).unwrap_err();
+        assert!(err.to_string().contains("default_provider"), "{}", err);
+
+        state.configuration = Arc::new(ChidoriConfig {
+            default_provider: Some(SupportedModelProviders::OpenAI),
+            ..Default::default()
+        });
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let CellTypes::Prompt(LLMPromptCell::Chat { provider, .. }, _) = op.cell else { unreachable!() };
+        assert_eq!(provider, Some(SupportedModelProviders::OpenAI));
+    }
+
+    #[test]
+    fn test_initial_globals_must_be_an_object() {
+        let state = ExecutionState::new_with_random_id();
+        assert!(state.with_initial_globals(RkyvSerializedValue::Number(1)).is_err());
+    }
+
     #[test]
     fn test_input_signature_check() {
         let mut exec_state = ExecutionState::new_with_random_id();
@@ -972,15 +2358,22 @@ mod tests {
                 args: HashMap::from([("0".to_string(), InputItemConfiguration {
                     ty: Some(InputType::String),
                     default: None,
+                    variadic: false,
+                    optional: false,
                 })]),
                 kwargs: HashMap::from([("kwarg1".to_string(), InputItemConfiguration {
                     ty: Some(InputType::String),
                     default: None,
+                    variadic: false,
+                    optional: false,
                 })]),
                 globals: HashMap::from([("global1".to_string(), InputItemConfiguration {
                     ty: Some(InputType::String),
                     default: None,
+                    variadic: false,
+                    optional: false,
                 })]),
+                all_globals: false,
             },
             output_signature: OutputSignature {
                 globals: HashMap::new(),
@@ -1014,5 +2407,600 @@ mod tests {
         extra_inputs.kwargs.insert("extra_kwarg".to_string(), RkyvSerializedValue::Null);
         assert!(signature.check_input_against_signature(&extra_inputs));
     }
-}
 
+    pub(crate) fn python_cell(name: &str, source_code: &str) -> CellTypes {
+        CellTypes::Code(CodeCell {
+            name: Some(name.to_string()),
+            language: SupportedLanguage::PyO3,
+            source_code: source_code.to_string(),
+            ..Default::default()
+        }, TextRange::default())
+    }
+
+    /// A chat prompt cell sending `req`, configured by the `frontmatter` preceding it.
+    fn prompt_cell(name: &str, frontmatter: &str, req: &str) -> CellTypes {
+        CellTypes::Prompt(LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some(name.to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: format!("---\n{}\n---\n{}", frontmatter, req),
+            req: req.to_string(),
+        }, TextRange::default())
+    }
+
+    /// Step the state until no operation remains to execute, returning the ids that executed in order.
+    async fn run_until_settled(mut state: ExecutionState) -> (ExecutionState, Vec<OperationId>) {
+        let mut executed = vec![];
+        while let Ok((next, outputs)) = state.step_execution().await {
+            executed.extend(outputs.into_iter().map(|(id, _)| id));
+            state = next;
+        }
+        (state, executed)
+    }
+
+    /// A chain of cells where `b` consumes the output of `a` and `c` consumes the output of `b`.
+    async fn settled_chain() -> (ExecutionState, [OperationId; 3]) {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [("a", "x = 1"), ("b", "y = x + 1"), ("c", "z = y + 1")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed, ids);
+        (state, [ids[0], ids[1], ids[2]])
+    }
+
+    #[tokio::test]
+    async fn test_run_to_completion_skips_dependents_of_failed_cells() {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [("a", "x = 1"), ("b", "y = x / 0"), ("c", "z = y + 1"), ("d", "w = x + 1")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+        let [a, b, c, d] = [ids[0], ids[1], ids[2], ids[3]];
+
+        let result = state.run_to_completion().await.unwrap();
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].operation_id, b);
+        assert_eq!(result.failed[0].name.as_deref(), Some("b"));
+        assert!(result.failed[0].stderr.iter().any(|l| l.text.contains("ZeroDivisionError")), "{:?}", result.failed[0].stderr);
+        assert_eq!(result.skipped, vec![c]);
+
+        let mut executed: Vec<OperationId> = result.outputs.keys().copied().collect();
+        executed.sort();
+        assert_eq!(executed, vec![a, b, d]);
+        assert!(result.outputs[&b].has_error);
+        assert_eq!(result.outputs[&d].output, Ok(RkyvObjectBuilder::new().insert_number("w", 2).build()));
+        assert_eq!(result.state.get_output_by_name("c").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_globals_of_the_same_name_are_consumed_by_qualified_name() {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [("a", "result = 1"), ("b", "result = 2"), ("c", "total = a.result + b[\"result\"]")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+        assert!(state.unresolved_external_inputs().is_empty());
+
+        let (state, _) = run_until_settled(state).await;
+        assert_eq!(state.get_output_by_name("a").unwrap(), Some(RkyvObjectBuilder::new().insert_number("result", 1).build()));
+        assert_eq!(state.get_output_by_name("b").unwrap(), Some(RkyvObjectBuilder::new().insert_number("result", 2).build()));
+        assert_eq!(state.get_output_by_name("c").unwrap(), Some(RkyvObjectBuilder::new().insert_number("total", 3).build()));
+
+        // Consuming the global by its bare name is ambiguous
+        let op = state.get_operation_from_cell_type(&python_cell("d", "doubled = result * 2")).unwrap();
+        let err = state.upsert_operation(op, Uuid::now_v7()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExecutionStateErrors>(),
+            Some(ExecutionStateErrors::AmbiguousGlobal(name, qualified)) if name == "result" && qualified == &vec!["a.result".to_string(), "b.result".to_string()]
+        ), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_externally_supplied_globals_are_not_replaced_by_namespaces() {
+        let mut state = ExecutionState::new_with_random_id()
+            .with_initial_globals(RkyvObjectBuilder::new().insert_number("a", 10).build())
+            .unwrap();
+        for (name, source) in [("a", "result = 1"), ("reader", "total = a + 1")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            state = state.upsert_operation(op, Uuid::now_v7()).unwrap().1;
+        }
+
+        let (state, _) = run_until_settled(state).await;
+        assert_eq!(state.get_output_by_name("reader").unwrap(), Some(RkyvObjectBuilder::new().insert_number("total", 11).build()));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_outputs_fail_the_cell() {
+        let mut state = ExecutionState::new_with_random_id().with_max_output_bytes(10_000);
+        let mut ids = vec![];
+        for (name, source) in [("large", "x = \"a\" * 100_000"), ("small", "y = \"a\" * 10"), ("dependent", "z = len(x)")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let result = state.run_to_completion().await.unwrap();
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].operation_id, ids[0]);
+        assert!(matches!(
+            result.outputs[&ids[0]].output,
+            Err(ExecutionStateErrors::OutputTooLarge { size, max_output_bytes: 10_000 }) if size > 100_000
+        ));
+        assert!(!result.outputs[&ids[1]].has_error);
+        assert_eq!(result.skipped, vec![ids[2]]);
+    }
+
+    #[test]
+    fn test_unused_outputs() {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [("a", "x = 1"), ("b", "y = x + 1"), ("dangling", "z = 5"), ("namespaced", "w = 2"), ("reader", "v = namespaced[\"w\"]")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let unused = state.unused_outputs(&["y", "reader"]);
+        assert_eq!(unused, vec![UnusedOutput {
+            operation_id: ids[2],
+            cell_name: Some("dangling".to_string()),
+            name: "z".to_string(),
+            is_function: false,
+        }]);
+
+        let unused: Vec<String> = state.unused_outputs(&[]).into_iter().map(|output| output.name).collect();
+        assert_eq!(unused, vec!["y", "z", "v"]);
+    }
+
+    #[tokio::test]
+    async fn test_absent_optional_inputs_are_bound_to_none() {
+        let mut state = ExecutionState::new_with_random_id();
+        let CellTypes::Code(mut optional, range) = python_cell("optional", "greeting = \"hello\" if name is None else name") else { unreachable!() };
+        optional.optional_inputs = Some(vec!["name".to_string()]);
+        let mut ids = vec![];
+        for cell in [CellTypes::Code(optional, range), python_cell("required", "farewell = missing + \"!\"")] {
+            let op = state.get_operation_from_cell_type(&cell).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed, vec![ids[0]]);
+        assert_eq!(
+            state.state_get_value(&ids[0]),
+            Some(&Ok(RkyvObjectBuilder::new().insert_string("greeting", "hello".to_string()).build()))
+        );
+        assert_eq!(state.state_get_value(&ids[1]), None);
+    }
+
+    #[test]
+    fn test_graph_hash_is_independent_of_cell_order() {
+        let graph = |sources: &[(&str, &str)]| {
+            let mut state = ExecutionState::new_with_random_id();
+            for (name, source) in sources {
+                let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+                state = state.upsert_operation(op, Uuid::now_v7()).unwrap().1;
+            }
+            state
+        };
+        let state = graph(&[("a", "x = 1"), ("b", "y = 2"), ("c", "z = x + y")]);
+        let reordered = graph(&[("b", "y = 2"), ("a", "x = 1"), ("c", "z = x + y")]);
+        assert_eq!(state.graph_hash(), reordered.graph_hash());
+        assert_eq!(state.graph_hash(), state.graph_hash());
+
+        let edited = graph(&[("a", "x = 1"), ("b", "y = 3"), ("c", "z = x + y")]);
+        assert_ne!(state.graph_hash(), edited.graph_hash());
+        let rewired = graph(&[("a", "x = 1"), ("b", "y = 2"), ("c", "z = x")]);
+        assert_ne!(state.graph_hash(), rewired.graph_hash());
+    }
+
+    #[tokio::test]
+    async fn test_pause_at_breakpoint_and_resume_with_modified_inputs() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("Say hello to Ada".to_string()), "Hello Ada!")
+            .build());
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let reply = prompt_cell("reply", "model: gpt-4o", "Say hello to {{name}}");
+        let mut ids = vec![];
+        for cell in [python_cell("a", "name = \"world\""), reply] {
+            let op = state.get_operation_from_cell_type(&cell).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+        let state = state.with_breakpoint_at("reply").unwrap();
+        assert!(matches!(state.clone().with_breakpoint_at("missing"), Err(ExecutionStateErrors::UnknownName(_))));
+
+        let RunProgress::Paused(paused) = state.run_to_breakpoint().await.unwrap() else {
+            panic!("Expected the run to pause ahead of the prompt cell");
+        };
+        assert_eq!(paused.operation_id, ids[1]);
+        assert!(paused.state.state_get_value(&ids[0]).is_some());
+        assert_eq!(paused.state.state_get_value(&ids[1]), None);
+        let RkyvSerializedValue::Object(mut inputs) = paused.inputs else { unreachable!() };
+        assert_eq!(inputs["globals"], RkyvObjectBuilder::new().insert_string("name", "world".to_string()).build());
+
+        inputs.insert("globals".to_string(), RkyvObjectBuilder::new().insert_string("name", "Ada".to_string()).build());
+        let RunProgress::Completed(result) = paused.state.resume(ids[1], Some(RkyvSerializedValue::Object(inputs))).await.unwrap() else {
+            panic!("Expected the resumed run to complete");
+        };
+        assert_eq!(result.outputs[&ids[1]].output, Ok(RkyvObjectBuilder::new().insert_string("reply", "Hello Ada!".to_string()).build()));
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_run_to_breakpoint_reports_failures_of_the_run() {
+        let mut state = ExecutionState::new_with_random_id();
+        let op = state.get_operation_from_cell_type(&python_cell("a", "x = 1")).unwrap();
+        state = state.upsert_operation(op, Uuid::now_v7()).unwrap().1;
+
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let cancelled = state.clone().with_cancellation(cancellation).run_to_breakpoint().await;
+        assert!(cancelled.is_err(), "a cancelled run was reported as completed");
+
+        let RunProgress::Completed(result) = state.run_to_breakpoint().await.unwrap() else {
+            panic!("Expected the run to complete");
+        };
+        assert_eq!(result.outputs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_secret_globals_are_redacted_but_usable() {
+        let mut state = ExecutionState::new_with_random_id().with_secret_globals(vec!["token".to_string()]);
+        let mut ids = vec![];
+        for (name, source) in [("a", "token = \"s3cr3t\""), ("b", "length = len(token)")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let (state, _) = run_until_settled(state).await;
+        assert_eq!(state.state_get_value(&ids[1]), Some(&Ok(RkyvObjectBuilder::new().insert_number("length", 6).build())));
+
+        let mut shared_state = crate::sdk::interactive_chidori_wrapper::SharedState::new();
+        shared_state.latest_state = Some(state.clone());
+        let observed = serde_json::to_string(&shared_state).unwrap();
+        assert!(observed.contains(REDACTED_VALUE), "{}", observed);
+        assert!(!observed.contains("s3cr3t"), "{}", observed);
+        assert!(!render_map_as_table(&state).contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_cells_run_on_a_provided_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let state = ExecutionState::new_with_random_id().with_runtime_handle(runtime.handle().clone());
+        let op = state.get_operation_from_cell_type(&python_cell("a", "x = 1")).unwrap();
+        let (id, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        // Tasks spawned from outside of any runtime run on the provided one
+        assert_eq!(runtime.block_on(state.spawn(async { 1 + 1 })).unwrap(), 2);
+
+        let (state, outcome) = runtime.handle().block_on(state.run_with_deadline(Duration::from_secs(30)));
+        assert!(!outcome.timed_out);
+        assert_eq!(state.state_get_value(&id), Some(&Ok(RkyvObjectBuilder::new().insert_number("x", 1).build())));
+        assert!(state.runtime_handle.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recursive_function_invocation_is_depth_limited() {
+        let state = ExecutionState::new_with_random_id();
+        let op = state.get_operation_from_cell_type(&python_cell("factorial", indoc! { r#"
+            async def factorial(n):
+                return 1 if n <= 1 else n * await factorial(n - 1)
+            "# })).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        let args = |n: i32| RkyvObjectBuilder::new()
+            .insert_object("args", RkyvObjectBuilder::new().insert_number("0", n))
+            .build();
+
+        let (output, resolved) = state.dispatch("factorial", args(5), None).await.unwrap();
+        assert_eq!(output, Ok(RkyvSerializedValue::Number(120)));
+        assert_eq!(resolved.dispatch_depth, 0);
+
+        // factorial(5) recurses four invocations deep, beyond a limit of three
+        let limited = state.with_max_dispatch_depth(3);
+        let message = match limited.dispatch("factorial", args(5), None).await {
+            Ok((Err(e), _)) => e.to_string(),
+            Err(e) => e.to_string(),
+            Ok((Ok(value), _)) => panic!("Expected the recursion to be rejected, got {:?}", value),
+        };
+        assert!(message.contains("exceeds the limit of 3 nested function invocations"), "{}", message);
+        let (output, _) = limited.dispatch("factorial", args(3), None).await.unwrap();
+        assert_eq!(output, Ok(RkyvSerializedValue::Number(6)));
+    }
+
+    #[tokio::test]
+    async fn test_hooks_observe_every_cell() {
+        use crate::execution::execution::hooks::{CellExecution, ExecutionHook};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct CountingHook {
+            before: AtomicUsize,
+            after: AtomicUsize,
+            names: std::sync::Mutex<Vec<String>>,
+        }
+
+        impl ExecutionHook for CountingHook {
+            fn before(&self, execution: &CellExecution, _inputs: &RkyvSerializedValue) {
+                self.before.fetch_add(1, Ordering::SeqCst);
+                assert!(matches!(execution.cell, CellTypes::Code(..)));
+            }
+
+            fn after(&self, execution: &CellExecution, output: &OperationFnOutput) {
+                self.after.fetch_add(1, Ordering::SeqCst);
+                assert!(!output.has_error);
+                self.names.lock().unwrap().push(execution.name.unwrap_or_default().to_string());
+            }
+        }
+
+        struct PanickingHook;
+
+        impl ExecutionHook for PanickingHook {
+            fn before(&self, _execution: &CellExecution, _inputs: &RkyvSerializedValue) {
+                panic!("hook failure");
+            }
+        }
+
+        let hook = Arc::new(CountingHook::default());
+        let mut state = ExecutionState::new_with_random_id()
+            .with_hook(Arc::new(PanickingHook))
+            .with_hook(hook.clone());
+        for (name, source) in [("a", "x = 1"), ("b", "y = x + 1"), ("c", "z = y + 1")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (_, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            state = next;
+        }
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed.len(), 3);
+        assert_eq!(hook.before.load(Ordering::SeqCst), 3);
+        assert_eq!(hook.after.load(Ordering::SeqCst), 3);
+        assert_eq!(*hook.names.lock().unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(state.get_output_by_name("c").unwrap(), Some(RkyvObjectBuilder::new().insert_number("z", 3).build()));
+    }
+
+    #[tokio::test]
+    async fn test_usage_summary_sums_prompt_cells() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "Hello there, how are you?")
+            .build());
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        for (name, body) in [("greeting", "Say hello"), ("farewell", "Say goodbye to everyone")] {
+            let cell = prompt_cell(name, "model: gpt-4o", body);
+            let op = state.get_operation_from_cell_type(&cell).unwrap();
+            let (_, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            state = next;
+        }
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed.len(), 2);
+
+        let usages: Vec<_> = executed.iter().map(|id| state.state_get(id).unwrap().usage().unwrap()).collect();
+        let summary = state.usage_summary();
+        assert_eq!(summary.by_model.len(), 1);
+        let usage = &summary.by_model[&("openai".to_string(), "gpt-4o".to_string())];
+        assert_eq!(usage.responses, 2);
+        assert_eq!(usage.cache_hits, 0);
+        assert_eq!(usage.prompt_tokens, usages.iter().map(|u| u.prompt_tokens as u64).sum::<u64>());
+        assert_eq!(usage.completion_tokens, usages.iter().map(|u| u.completion_tokens as u64).sum::<u64>());
+        assert!(usage.total_tokens > 0);
+        assert_eq!(usage.total_tokens, usage.prompt_tokens + usage.completion_tokens);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_history_carries_over_between_executions() {
+        let continues_first_turn = |req: &ChatCompletionReq| {
+            let contents: Vec<(MessageRole, &str)> = req.template_messages.iter()
+                .map(|m| (m.role.clone(), m.content.as_str()))
+                .collect();
+            contents == vec![
+                (MessageRole::User, "Say hello to Ada"),
+                (MessageRole::Assistant, "Hello Ada!"),
+                (MessageRole::User, "Say hello to Bob"),
+                (MessageRole::Assistant, "Hello Bob!"),
+                (MessageRole::User, "Say hello to Grace"),
+            ]
+        };
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("Ada".to_string()), "Hello Ada!")
+            .respond_when(RequestMatcher::custom("the first turn precedes the example", continues_first_turn), "Hello Grace!")
+            .build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = prompt_cell(
+            "greeting",
+            "model: gpt-4o\nconversation_id: greetings\nexamples:\n  - input: Say hello to Bob\n    output: Hello Bob!",
+            "Say hello to {{name}}",
+        );
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (_, mut state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        for name in ["Ada", "Grace"] {
+            let globals = RkyvObjectBuilder::new().insert_string("name", name.to_string()).build();
+            let (settled, executed) = run_until_settled(state.with_initial_globals(globals).unwrap()).await;
+            assert_eq!(executed.len(), 1);
+            state = settled;
+        }
+        model.assert_all_called();
+
+        // The examples are not recorded as turns of the conversation
+        assert_eq!(state.conversation_histories["greetings"].len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_usage_summary_counts_every_execution_of_a_cell() {
+        let model = Arc::new(MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "Hello there, how are you?")
+            .build());
+        let mut state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+        let cell = prompt_cell("greeting", "model: gpt-4o", "Say hello to {{name}}");
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (_, mut state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+
+        // The cell executes again for every change of the global it renders
+        for name in ["Ada", "Grace"] {
+            let globals = RkyvObjectBuilder::new().insert_string("name", name.to_string()).build();
+            let (settled, executed) = run_until_settled(state.with_initial_globals(globals).unwrap()).await;
+            assert_eq!(executed.len(), 1);
+            state = settled;
+        }
+
+        let usage = &state.usage_summary().by_model[&("openai".to_string(), "gpt-4o".to_string())];
+        assert_eq!(usage.responses, 2);
+        assert!(usage.total_tokens > 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_output_by_name() {
+        let (state, _) = settled_chain().await;
+        assert_eq!(
+            state.get_output_by_name("b").unwrap(),
+            Some(RkyvObjectBuilder::new().insert_number("y", 2).build())
+        );
+        assert_eq!(state.get_output_by_name("missing").unwrap(), None);
+
+        let op = state.get_operation_from_cell_type(&python_cell("b", "w = 3")).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        assert!(matches!(state.get_output_by_name("b"), Err(ExecutionStateErrors::AmbiguousName(name, ids)) if name == "b" && ids.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn test_editing_a_leaf_recomputes_only_that_cell() {
+        let (state, [_, _, id_c]) = settled_chain().await;
+        let op = state.get_operation_from_cell_type(&python_cell("c", "z = y + 10")).unwrap();
+        let (_, state) = state.upsert_operation(op, id_c).unwrap();
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed, vec![id_c]);
+        assert_eq!(state.state_get_value(&id_c), Some(&Ok(RkyvObjectBuilder::new().insert_number("z", 12).build())));
+    }
+
+    #[tokio::test]
+    async fn test_editing_a_root_recomputes_its_subtree() {
+        let (state, [id_a, id_b, id_c]) = settled_chain().await;
+        let op = state.get_operation_from_cell_type(&python_cell("a", "x = 2")).unwrap();
+        let (_, state) = state.upsert_operation(op, id_a).unwrap();
+        let (state, executed) = run_until_settled(state).await;
+        assert_eq!(executed, vec![id_a, id_b, id_c]);
+        assert_eq!(state.state_get_value(&id_c), Some(&Ok(RkyvObjectBuilder::new().insert_number("z", 4).build())));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_cells_are_not_recomputed() {
+        let (state, [id_a, _, _]) = settled_chain().await;
+        // Re-applying the same source, as reloading an unmodified file would, executes nothing
+        let op = state.get_operation_from_cell_type(&python_cell("a", "x = 1")).unwrap();
+        let (_, state) = state.upsert_operation(op, id_a).unwrap();
+        let (_, executed) = run_until_settled(state).await;
+        assert!(executed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_functions_declaring_emit_as_publish_events() {
+        let cell = python_cell("greeter", indoc! { r#"
+            import chidori as ch
+
+            @ch.emit_as("greeted")
+            def greet(name):
+                return "hello " + name
+
+            message = greet("world")
+            "#});
+        let state = ExecutionState::new_with_random_id();
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        let (state, _) = state.step_execution().await.unwrap();
+        assert_eq!(state.pending_events, VecDeque::from(vec![PendingEvent {
+            name: "greeted".to_string(),
+            payload: RkyvSerializedValue::String("hello world".to_string()),
+            depth: 0,
+        }]));
+    }
+
+    #[tokio::test]
+    async fn test_functions_triggered_on_an_event_receive_its_payload() {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [
+            ("greeter", indoc! { r#"
+                import chidori as ch
+
+                @ch.emit_as("greeted")
+                def greet(name):
+                    return "hello " + name
+
+                message = greet("world")
+                "#}),
+            ("recorder", indoc! { r#"
+                import chidori as ch
+
+                @ch.on_event("greeted")
+                def record(greeting):
+                    return "recorded " + greeting
+                "#}),
+            ("auditor", indoc! { r#"
+                import chidori as ch
+
+                @ch.on_event("greeted")
+                def audit(greeting):
+                    return "audited " + greeting
+                "#}),
+        ] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let mut delivered = vec![];
+        while let Ok((next, outputs)) = state.step_execution().await {
+            for (id, output) in outputs {
+                if let Ok(RkyvSerializedValue::String(text)) = output.output {
+                    delivered.push((id, text));
+                }
+            }
+            state = next;
+        }
+        // Every subscriber fires, ordered by the name of the triggered function
+        assert_eq!(delivered, vec![
+            (ids[2], "audited hello world".to_string()),
+            (ids[1], "recorded hello world".to_string()),
+        ]);
+        assert!(state.pending_events.is_empty());
+    }
+
+    #[test]
+    fn test_publishing_events_is_limited() {
+        let mut state = ExecutionState::new_with_random_id();
+        state.evaluating_event_depth = MAX_EVENT_DEPTH;
+        assert!(matches!(
+            state.publish_event("nested", RkyvSerializedValue::Null),
+            Err(ExecutionStateErrors::EventLimitExceeded(_))
+        ));
+
+        let mut state = ExecutionState::new_with_random_id();
+        for _ in 0..MAX_EVENTS_PER_RUN {
+            state.publish_event("tick", RkyvSerializedValue::Null).unwrap();
+        }
+        assert!(matches!(
+            state.publish_event("tick", RkyvSerializedValue::Null),
+            Err(ExecutionStateErrors::EventLimitExceeded(_))
+        ));
+        assert_eq!(state.pending_events.len(), MAX_EVENTS_PER_RUN);
+    }
+}
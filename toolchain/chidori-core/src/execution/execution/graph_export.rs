@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use petgraph::graphmap::DiGraphMap;
+use serde::{Deserialize, Serialize};
+
+use crate::cells::CellTypes;
+use crate::execution::execution::ExecutionState;
+use crate::execution::primitives::identifiers::{DependencyReference, OperationId};
+use crate::execution::primitives::operation::{InputItemConfiguration, InputSignature, InputType, OutputItemConfiguration, OutputSignature};
+
+/// The cells of an execution graph, their signatures and the dependencies between them, in a form
+/// that tools can consume without linking this crate. Nodes and edges are sorted so that the same
+/// graph always exports to the same JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GraphExport {
+    pub nodes: Vec<NodeExport>,
+    pub edges: Vec<EdgeExport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeExport {
+    pub id: OperationId,
+    pub name: Option<String>,
+    /// One of "code", "code_gen", "prompt", "template" or "transform".
+    pub cell_type: String,
+    pub inputs: InputsExport,
+    pub outputs: OutputsExport,
+    pub cell: CellTypes,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputsExport {
+    pub args: Vec<InputExport>,
+    pub kwargs: Vec<InputExport>,
+    pub globals: Vec<InputExport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputExport {
+    pub name: String,
+    /// The declared type of the input, e.g. "string" or "enum", absent when undeclared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    pub has_default: bool,
+    pub optional: bool,
+    pub variadic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputsExport {
+    pub globals: Vec<String>,
+    pub functions: Vec<FunctionExport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionExport {
+    pub name: String,
+    pub inputs: InputsExport,
+    /// Names the function's result is split into, empty when it returns a single value.
+    pub outputs: Vec<String>,
+}
+
+/// The dependency of `to` upon the output of `from`, through each of the references.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EdgeExport {
+    pub from: OperationId,
+    pub to: OperationId,
+    pub references: Vec<DependencyReference>,
+}
+
+impl GraphExport {
+    pub fn from_state(state: &ExecutionState) -> Self {
+        let mut nodes: Vec<NodeExport> = state.operation_by_id.iter()
+            .map(|(id, op)| NodeExport {
+                id: *id,
+                name: op.name.clone(),
+                cell_type: cell_type_name(&op.cell).to_string(),
+                inputs: InputsExport::from(&op.signature.input_signature),
+                outputs: OutputsExport::from(&op.signature.output_signature),
+                cell: op.cell.clone(),
+            })
+            .collect();
+        nodes.sort_by_key(|node| node.id);
+        let mut edges: Vec<EdgeExport> = state.get_dependency_graph_flattened()
+            .into_iter()
+            .map(|(from, to, references)| EdgeExport { from, to, references })
+            .collect();
+        edges.sort_by_key(|edge| (edge.from, edge.to));
+        GraphExport { nodes, edges }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// The dependency graph the export was taken from, in the form `ExecutionState::get_dependency_graph` returns.
+    pub fn dependency_graph(&self) -> DiGraphMap<OperationId, Vec<DependencyReference>> {
+        let mut graph = DiGraphMap::new();
+        for node in &self.nodes {
+            graph.add_node(node.id);
+        }
+        for edge in &self.edges {
+            graph.add_edge(edge.from, edge.to, edge.references.clone());
+        }
+        graph
+    }
+}
+
+fn cell_type_name(cell: &CellTypes) -> &'static str {
+    match cell {
+        CellTypes::Code(..) => "code",
+        CellTypes::CodeGen(..) => "code_gen",
+        CellTypes::Prompt(..) => "prompt",
+        CellTypes::Template(..) => "template",
+        CellTypes::Transform(..) => "transform",
+        CellTypes::Embedding(..) => "embedding",
+    }
+}
+
+fn input_type_name(ty: &InputType) -> &'static str {
+    match ty {
+        InputType::String => "string",
+        InputType::Function => "function",
+        InputType::Array => "array",
+        InputType::Object => "object",
+        InputType::Enum(_) => "enum",
+        InputType::IntRange { .. } => "int_range",
+        InputType::Pattern(_) => "pattern",
+    }
+}
+
+fn export_inputs(inputs: &HashMap<String, InputItemConfiguration>) -> Vec<InputExport> {
+    let mut exported: Vec<InputExport> = inputs.iter()
+        .map(|(name, config)| InputExport {
+            name: name.clone(),
+            ty: config.ty.as_ref().map(|ty| input_type_name(ty).to_string()),
+            has_default: config.default.is_some(),
+            optional: config.optional,
+            variadic: config.variadic,
+        })
+        .collect();
+    exported.sort_by(|a, b| a.name.cmp(&b.name));
+    exported
+}
+
+impl From<&InputSignature> for InputsExport {
+    fn from(signature: &InputSignature) -> Self {
+        InputsExport {
+            args: export_inputs(&signature.args),
+            kwargs: export_inputs(&signature.kwargs),
+            globals: export_inputs(&signature.globals),
+        }
+    }
+}
+
+impl From<&OutputSignature> for OutputsExport {
+    fn from(signature: &OutputSignature) -> Self {
+        let mut globals: Vec<String> = signature.globals.keys().cloned().collect();
+        globals.sort();
+        let mut functions: Vec<FunctionExport> = signature.functions.iter()
+            .map(|(name, config)| match config {
+                OutputItemConfiguration::Function { input_signature, outputs, .. } => FunctionExport {
+                    name: name.clone(),
+                    inputs: InputsExport::from(input_signature),
+                    outputs: outputs.clone(),
+                },
+                OutputItemConfiguration::Value => FunctionExport {
+                    name: name.clone(),
+                    inputs: InputsExport::from(&InputSignature::new()),
+                    outputs: vec![],
+                },
+            })
+            .collect();
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+        OutputsExport { globals, functions }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::execution::execution_state::tests::python_cell;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_export_two_cell_graph() {
+        let mut state = ExecutionState::new_with_random_id();
+        let mut ids = vec![];
+        for (name, source) in [("a", "x = 1"), ("b", "y = x + 1")] {
+            let op = state.get_operation_from_cell_type(&python_cell(name, source)).unwrap();
+            let (id, next) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+            ids.push(id);
+            state = next;
+        }
+
+        let export = state.export_graph();
+        assert_eq!(export.edges, vec![EdgeExport {
+            from: ids[0],
+            to: ids[1],
+            references: vec![DependencyReference::Global("x".to_string())],
+        }]);
+        let b = export.nodes.iter().find(|node| node.id == ids[1]).unwrap();
+        assert_eq!(b.cell_type, "code");
+        assert_eq!(b.inputs.globals.iter().map(|input| input.name.as_str()).collect::<Vec<_>>(), vec!["x"]);
+        assert_eq!(b.outputs.globals, vec!["y".to_string()]);
+
+        let json = export.to_json().unwrap();
+        let imported = GraphExport::from_json(&json).unwrap();
+        assert_eq!(imported, export);
+        let graph = imported.dependency_graph();
+        assert_eq!(graph.edge_weight(ids[0], ids[1]), state.get_dependency_graph().edge_weight(ids[0], ids[1]));
+        assert_eq!(graph.node_count(), 2);
+    }
+}
@@ -0,0 +1,46 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::cells::CellTypes;
+use crate::execution::execution::execution_graph::ExecutionNodeId;
+use crate::execution::primitives::identifiers::OperationId;
+use crate::execution::primitives::operation::OperationFnOutput;
+use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+
+/// The cell an execution hook is invoked around.
+#[derive(Debug, Clone, Copy)]
+pub struct CellExecution<'a> {
+    pub execution_node_id: ExecutionNodeId,
+    pub operation_id: OperationId,
+    pub name: Option<&'a str>,
+    pub cell: &'a CellTypes,
+}
+
+/// Logic run around the execution of every cell, such as emitting metrics or enforcing a quota.
+/// Hooks observe the inputs and output of a cell but cannot alter them, and a hook that panics
+/// is logged and otherwise ignored.
+pub trait ExecutionHook: Send + Sync {
+    /// Invoked before the cell executes with the inputs it will receive.
+    fn before(&self, _execution: &CellExecution, _inputs: &RkyvSerializedValue) {}
+
+    /// Invoked once the cell has executed with the output it produced, including failed outputs.
+    fn after(&self, _execution: &CellExecution, _output: &OperationFnOutput) {}
+}
+
+pub(crate) fn run_before_hooks(hooks: &[Arc<dyn ExecutionHook>], execution: &CellExecution, inputs: &RkyvSerializedValue) {
+    for hook in hooks {
+        if catch_unwind(AssertUnwindSafe(|| hook.before(execution, inputs))).is_err() {
+            warn!("Execution hook panicked before operation {}", execution.operation_id);
+        }
+    }
+}
+
+pub(crate) fn run_after_hooks(hooks: &[Arc<dyn ExecutionHook>], execution: &CellExecution, output: &OperationFnOutput) {
+    for hook in hooks {
+        if catch_unwind(AssertUnwindSafe(|| hook.after(execution, output))).is_err() {
+            warn!("Execution hook panicked after operation {}", execution.operation_id);
+        }
+    }
+}
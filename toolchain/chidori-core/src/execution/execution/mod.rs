@@ -1,5 +1,8 @@
 pub mod execution_graph;
 pub mod execution_state;
+pub mod graph_export;
+pub mod hooks;
+pub mod progress;
 
 
 use crate::execution::primitives::identifiers::{OperationId};
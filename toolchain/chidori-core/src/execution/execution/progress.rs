@@ -0,0 +1,93 @@
+use serde::Serialize;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::debug;
+
+use crate::execution::execution::execution_graph::ExecutionNodeId;
+use crate::execution::primitives::identifiers::OperationId;
+
+/// Events describing the progression of execution, intended for observers such as UIs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum ProgressEvent {
+    CellStarted {
+        execution_node_id: ExecutionNodeId,
+        operation_id: OperationId,
+        name: Option<String>,
+    },
+    CellFinished {
+        execution_node_id: ExecutionNodeId,
+        operation_id: OperationId,
+        name: Option<String>,
+        has_error: bool,
+    },
+    TokenDelta {
+        operation_id: OperationId,
+        delta: String,
+    },
+    /// A cell has run past its soft timeout and continues to run.
+    CellSlow {
+        operation_id: OperationId,
+        name: Option<String>,
+        elapsed_ms: u64,
+    },
+}
+
+impl ProgressEvent {
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            ProgressEvent::CellStarted { .. } => "CellStarted",
+            ProgressEvent::CellFinished { .. } => "CellFinished",
+            ProgressEvent::TokenDelta { .. } => "TokenDelta",
+            ProgressEvent::CellSlow { .. } => "CellSlow",
+        }
+    }
+}
+
+/// Sending half of the progress channel held by an ExecutionState.
+///
+/// Emitting never waits, when the receiver falls behind events are dropped so that
+/// observation can never stall execution.
+#[derive(Debug, Clone)]
+pub struct ProgressSender(Sender<ProgressEvent>);
+
+impl ProgressSender {
+    pub fn emit(&self, event: ProgressEvent) {
+        match self.0.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                debug!("Progress channel is full, dropping {}", event.event_name());
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+pub fn progress_channel(capacity: usize) -> (ProgressSender, Receiver<ProgressEvent>) {
+    let (tx, rx) = channel(capacity);
+    (ProgressSender(tx), rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_full_channel_drops_events() {
+        let (sender, mut receiver) = progress_channel(1);
+        let event = ProgressEvent::TokenDelta { operation_id: Uuid::nil(), delta: "a".to_string() };
+        sender.emit(event.clone());
+        sender.emit(ProgressEvent::TokenDelta { operation_id: Uuid::nil(), delta: "b".to_string() });
+        assert_eq!(receiver.recv().await, Some(event));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_serializes_with_type_tag() {
+        let event = ProgressEvent::TokenDelta { operation_id: Uuid::nil(), delta: "hi".to_string() };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "TokenDelta");
+        assert_eq!(json["delta"], "hi");
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use futures_util::{FutureExt, StreamExt};
+
+use crate::cells::MapConfiguration;
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::execution::execution::ExecutionState;
+use crate::execution::primitives::operation::{LogLine, OperationFn, OperationFnOutput};
+use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+
+const DEFAULT_MAP_CONCURRENCY: usize = 4;
+
+/// Execute `operation` once for each element of the array held by the mapped global, with at most
+/// `concurrency` elements executing at once. The outputs are collected in the order of the elements.
+/// An element that fails contributes Null to the collected output and its error is recorded in stderr,
+/// unless `fail_fast` is set in which case the first failure fails the whole map.
+pub(crate) fn map_operation(
+    operation: Box<OperationFn>,
+    configuration: MapConfiguration,
+    state: &ExecutionState,
+    payload: RkyvSerializedValue,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<OperationFnOutput>> + Send>> {
+    let payloads = match element_payloads(&payload, &configuration.over) {
+        Ok(payloads) => payloads,
+        Err(e) => return async move { Err(e) }.boxed(),
+    };
+    // The futures are lazy, so constructing all of them up front does not begin their execution
+    let executions: Vec<_> = payloads
+        .into_iter()
+        .map(|payload| operation(state, payload, None, None))
+        .collect();
+    let concurrency = configuration.concurrency.unwrap_or(DEFAULT_MAP_CONCURRENCY).max(1);
+    async move {
+        let mut outputs = futures_util::stream::iter(executions).buffered(concurrency);
+        let mut results = vec![];
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        while let Some(output) = outputs.next().await {
+            let index = results.len();
+            let output = output.unwrap_or_else(|e| OperationFnOutput {
+                has_error: true,
+                output: Err(ExecutionStateErrors::AnyhowError(e.to_string())),
+                ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+            });
+            stdout.extend(output.stdout);
+            stderr.extend(output.stderr);
+            match output.output {
+                Ok(value) if !output.has_error => results.push(value),
+                failed => {
+                    let reason = match failed {
+                        Err(e) => e.to_string(),
+                        Ok(_) => "the execution reported an error".to_string(),
+                    };
+                    if configuration.fail_fast {
+                        return Ok(OperationFnOutput {
+                            has_error: true,
+                            execution_state: None,
+                            output: Err(ExecutionStateErrors::Unknown(format!(
+                                "Element {} of {} failed: {}", index, configuration.over, reason
+                            ))),
+                            stdout,
+                            stderr,
+                            metadata: Default::default(),
+                        });
+                    }
+                    stderr.push(LogLine::stderr(format!("Element {} of {} failed: {}", index, configuration.over, reason)));
+                    results.push(RkyvSerializedValue::Null);
+                }
+            }
+        }
+        Ok(OperationFnOutput {
+            has_error: false,
+            execution_state: None,
+            output: Ok(collect_element_outputs(results)),
+            stdout,
+            stderr,
+            metadata: Default::default(),
+        })
+    }.boxed()
+}
+
+/// The payload of each execution, a copy of the payload with the mapped global replaced by one element.
+fn element_payloads(payload: &RkyvSerializedValue, over: &str) -> anyhow::Result<Vec<RkyvSerializedValue>> {
+    let globals = match payload {
+        RkyvSerializedValue::Object(payload) => match payload.get("globals") {
+            Some(RkyvSerializedValue::Object(globals)) => globals,
+            _ => return Err(anyhow::anyhow!("Mapped input {:?} was not provided", over)),
+        },
+        _ => return Err(anyhow::anyhow!("Mapped input {:?} was not provided", over)),
+    };
+    let elements = match globals.get(over) {
+        Some(RkyvSerializedValue::Array(elements)) => elements,
+        Some(other) => return Err(anyhow::anyhow!("Mapped input {:?} must be an array, got {}", over, other)),
+        None => return Err(anyhow::anyhow!("Mapped input {:?} was not provided", over)),
+    };
+    Ok(elements
+        .iter()
+        .map(|element| {
+            let mut payload = payload.clone();
+            if let RkyvSerializedValue::Object(payload) = &mut payload {
+                if let Some(RkyvSerializedValue::Object(globals)) = payload.get_mut("globals") {
+                    globals.insert(over.to_string(), element.clone());
+                }
+            }
+            payload
+        })
+        .collect())
+}
+
+/// Cells expose their values as an object keyed by name, so when every element produced an object
+/// each exposed value becomes an array of that value from every element. Otherwise the outputs
+/// are returned as they are.
+fn collect_element_outputs(results: Vec<RkyvSerializedValue>) -> RkyvSerializedValue {
+    let all_objects = results
+        .iter()
+        .all(|r| matches!(r, RkyvSerializedValue::Object(_) | RkyvSerializedValue::Null));
+    if !all_objects || results.iter().all(|r| matches!(r, RkyvSerializedValue::Null)) {
+        return RkyvSerializedValue::Array(results);
+    }
+    let mut keys: Vec<String> = results
+        .iter()
+        .filter_map(|r| match r {
+            RkyvSerializedValue::Object(m) => Some(m.keys().cloned()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    keys.sort();
+    keys.dedup();
+    let collected: HashMap<String, RkyvSerializedValue> = keys
+        .into_iter()
+        .map(|key| {
+            let values = results
+                .iter()
+                .map(|r| match r {
+                    RkyvSerializedValue::Object(m) => m.get(&key).cloned().unwrap_or(RkyvSerializedValue::Null),
+                    _ => RkyvSerializedValue::Null,
+                })
+                .collect();
+            (key, RkyvSerializedValue::Array(values))
+        })
+        .collect();
+    RkyvSerializedValue::Object(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+
+    /// Doubles the "n" global of each element, failing for negative numbers, while tracking the
+    /// greatest number of executions in flight at once.
+    fn doubling_operation(in_flight: Arc<AtomicUsize>, max_in_flight: Arc<AtomicUsize>) -> Box<OperationFn> {
+        Box::new(move |_, payload, _, _| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let RkyvSerializedValue::Object(payload) = payload else { unreachable!() };
+                let Some(RkyvSerializedValue::Object(globals)) = payload.get("globals") else { unreachable!() };
+                let Some(RkyvSerializedValue::Number(n)) = globals.get("n") else { unreachable!() };
+                if *n < 0 {
+                    return Err(anyhow::anyhow!("negative input {}", n));
+                }
+                Ok(OperationFnOutput::with_value(RkyvObjectBuilder::new().insert_number("doubled", n * 2).build()))
+            }.boxed()
+        })
+    }
+
+    fn payload(elements: Vec<i32>) -> RkyvSerializedValue {
+        RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new()
+                .insert_value("n", RkyvSerializedValue::Array(elements.into_iter().map(RkyvSerializedValue::Number).collect())))
+            .build()
+    }
+
+    fn configuration(concurrency: usize, fail_fast: bool) -> MapConfiguration {
+        MapConfiguration { over: "n".to_string(), concurrency: Some(concurrency), fail_fast }
+    }
+
+    #[tokio::test]
+    async fn test_map_collects_outputs_in_order_within_concurrency() {
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let operation = doubling_operation(Arc::new(AtomicUsize::new(0)), max_in_flight.clone());
+        let state = ExecutionState::new_with_random_id();
+        let output = map_operation(operation, configuration(2, false), &state, payload(vec![1, 2, 3, 4, 5])).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new()
+            .insert_value("doubled", RkyvSerializedValue::Array(vec![2, 4, 6, 8, 10].into_iter().map(RkyvSerializedValue::Number).collect()))
+            .build());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_map_collects_element_errors() {
+        let operation = doubling_operation(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let state = ExecutionState::new_with_random_id();
+        let output = map_operation(operation, configuration(4, false), &state, payload(vec![1, -2, 3])).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.output.unwrap(), RkyvObjectBuilder::new()
+            .insert_value("doubled", RkyvSerializedValue::Array(vec![
+                RkyvSerializedValue::Number(2),
+                RkyvSerializedValue::Null,
+                RkyvSerializedValue::Number(6),
+            ]))
+            .build());
+        assert_eq!(output.stderr.len(), 1);
+        assert!(output.stderr[0].text.contains("Element 1 of n failed: negative input -2"), "{}", output.stderr[0].text);
+    }
+
+    #[tokio::test]
+    async fn test_map_fail_fast() {
+        let operation = doubling_operation(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let state = ExecutionState::new_with_random_id();
+        let output = map_operation(operation, configuration(1, true), &state, payload(vec![1, -2, 3])).await.unwrap();
+        assert!(output.has_error);
+        assert!(output.output.unwrap_err().to_string().contains("Element 1 of n failed"));
+    }
+
+    #[tokio::test]
+    async fn test_map_requires_an_array() {
+        let operation = doubling_operation(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let state = ExecutionState::new_with_random_id();
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new().insert_number("n", 1))
+            .build();
+        assert!(map_operation(operation, configuration(1, false), &state, payload).await.is_err());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod identifiers;
+pub mod map;
 pub mod operation;
 pub mod serialized_value;
@@ -1,27 +1,96 @@
 use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+use chidori_prompt_format::templating::templates::SchemaItemType;
 
 use log::warn;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::future::Future;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::mpsc::{Receiver, Sender};
 use tokio::sync::oneshot;
 use futures_util::FutureExt;
 use tracing::{Level, span};
 use uuid::Uuid;
-use crate::cells::{CellTypes, CodeCell, SupportedLanguage, TextRange};
+use crate::cells::{CellTypes, CodeCell, InputConstraint, MapConfiguration, SupportedLanguage, TextRange};
 use crate::execution::execution::execution_graph::ExecutionNodeId;
 use crate::execution::execution::execution_state::{ExecutionStateErrors, OperationInputs};
 use crate::execution::execution::ExecutionState;
 use crate::execution::primitives::identifiers::OperationId;
+use crate::library::std::ai::llm::{TemplateMessage, ToolCallSource, Usage};
 // args, kwargs, locals and their configurations
 
 #[derive(Debug, Clone)]
 pub enum InputType {
     String,
     Function,
+    Array,
+    Object,
+    /// A string that must be one of the given values.
+    Enum(Vec<String>),
+    /// An integer within the inclusive bounds, either of which may be omitted.
+    IntRange { min: Option<i64>, max: Option<i64> },
+    /// A string that must match the regular expression.
+    Pattern(regex::Regex),
+}
+
+impl TryFrom<&InputConstraint> for InputType {
+    type Error = regex::Error;
+
+    /// Fails when a pattern constraint is not a valid regular expression, so that it is rejected
+    /// when the cell is built rather than when its inputs are validated.
+    fn try_from(constraint: &InputConstraint) -> Result<Self, Self::Error> {
+        Ok(match constraint {
+            InputConstraint::Enum(values) => InputType::Enum(values.clone()),
+            InputConstraint::IntRange { min, max } => InputType::IntRange { min: *min, max: *max },
+            InputConstraint::Pattern(pattern) => InputType::Pattern(regex::Regex::new(pattern)?),
+        })
+    }
+}
+
+impl InputType {
+    /// Check a value against the constraint of this type, describing the violation when it fails.
+    /// The structural types are hints for callers and accept any value.
+    pub fn validate(&self, value: &RkyvSerializedValue) -> Result<(), String> {
+        match (self, value) {
+            (InputType::Enum(values), RkyvSerializedValue::String(s)) if values.contains(s) => Ok(()),
+            (InputType::Enum(values), value) => Err(format!("expected one of {:?}, got {}", values, value)),
+            (InputType::IntRange { min, max }, value) if value.as_i64().is_some() => {
+                let n = value.as_i64().unwrap();
+                if min.is_some_and(|min| n < min) || max.is_some_and(|max| n > max) {
+                    Err(format!("expected an integer in {}..={}, got {}",
+                        min.map(|m| m.to_string()).unwrap_or_default(),
+                        max.map(|m| m.to_string()).unwrap_or_default(),
+                        n))
+                } else {
+                    Ok(())
+                }
+            }
+            (InputType::IntRange { .. }, value) => Err(format!("expected an integer, got {}", value)),
+            (InputType::Pattern(pattern), RkyvSerializedValue::String(s)) => {
+                if pattern.is_match(s) {
+                    Ok(())
+                } else {
+                    Err(format!("expected a string matching {:?}, got {:?}", pattern.as_str(), s))
+                }
+            }
+            (InputType::Pattern(pattern), value) => Err(format!("expected a string matching {:?}, got {}", pattern.as_str(), value)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<&SchemaItemType> for InputType {
+    fn from(ty: &SchemaItemType) -> Self {
+        match ty {
+            SchemaItemType::String => InputType::String,
+            SchemaItemType::Array => InputType::Array,
+            SchemaItemType::Object => InputType::Object,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -29,6 +98,19 @@ pub struct InputItemConfiguration {
     // TODO: should represent object and vec types
     pub ty: Option<InputType>,
     pub default: Option<RkyvSerializedValue>,
+    /// Collects any number of additional values, such as Python's `*args` and `**kwargs`,
+    /// and so is never required.
+    pub variadic: bool,
+    /// Bound to null when absent rather than preventing execution. Unlike a default, the cell can
+    /// tell that no value was provided.
+    pub optional: bool,
+}
+
+impl InputItemConfiguration {
+    /// The value bound when the input is absent, if it need not be provided.
+    fn absent_value(&self) -> Option<RkyvSerializedValue> {
+        self.default.clone().or_else(|| self.optional.then_some(RkyvSerializedValue::Null))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,6 +118,9 @@ pub struct InputSignature {
     pub args: HashMap<String, InputItemConfiguration>,
     pub kwargs: HashMap<String, InputItemConfiguration>,
     pub globals: HashMap<String, InputItemConfiguration>,
+    /// Whether every global available to the operation is provided to it, in addition to those
+    /// named in `globals`.
+    pub all_globals: bool,
 }
 
 impl InputSignature {
@@ -44,6 +129,7 @@ impl InputSignature {
             args: HashMap::new(),
             kwargs: HashMap::new(),
             globals: HashMap::new(),
+            all_globals: false,
         }
     }
 
@@ -56,6 +142,7 @@ impl InputSignature {
             args: args_map,
             kwargs: HashMap::new(),
             globals: HashMap::new(),
+            all_globals: false,
         }
     }
 
@@ -63,7 +150,7 @@ impl InputSignature {
         self.args.is_empty() && self.kwargs.is_empty() && self.globals.is_empty()
     }
 
-    #[tracing::instrument]
+    #[tracing::instrument(skip(inputs))]
     pub fn check_input_against_signature(
         &self,
         inputs: &OperationInputs,
@@ -76,21 +163,21 @@ impl InputSignature {
 
         // Validate args
         for (key, config) in &self.args {
-            if config.default.is_none() && !args.contains_key(key) {
+            if config.absent_value().is_none() && !config.variadic && !args.contains_key(key) {
                 missing_keys.insert(format!("args: {}", key));
             }
         }
 
         // Validate kwargs
         for (key, config) in &self.kwargs {
-            if config.default.is_none() && !kwargs.contains_key(key) {
+            if config.absent_value().is_none() && !config.variadic && !kwargs.contains_key(key) {
                 missing_keys.insert(format!("kwargs: {}", key));
             }
         }
 
         // Validate globals
         for (key, config) in &self.globals {
-            if config.default.is_none()
+            if config.absent_value().is_none()
                 && (!globals.contains_key(key) && !functions.contains_key(key))
             {
                 missing_keys.insert(format!("globals or functions: {}", key));
@@ -106,7 +193,31 @@ impl InputSignature {
         }
     }
 
-    #[tracing::instrument]
+    /// Validate the values present in the inputs against the types declared for them, describing
+    /// every violation. Missing values are left to `check_input_against_signature`.
+    pub fn validate_input_values(&self, inputs: &OperationInputs) -> Result<(), String> {
+        let mut violations = vec![];
+        for (kind, configurations, values) in [
+            ("args", &self.args, &inputs.args),
+            ("kwargs", &self.kwargs, &inputs.kwargs),
+            ("globals", &self.globals, &inputs.globals),
+        ] {
+            for (key, config) in configurations {
+                let (Some(ty), Some(value)) = (&config.ty, values.get(key)) else { continue; };
+                if let Err(e) = ty.validate(value) {
+                    violations.push(format!("{}: {}: {}", kind, key, e));
+                }
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            violations.sort();
+            Err(violations.join("; "))
+        }
+    }
+
+    #[tracing::instrument(skip(inputs))]
     pub fn prepopulate_defaults(
         &self,
         inputs: &mut OperationInputs,
@@ -116,26 +227,106 @@ impl InputSignature {
         let mut globals = &mut inputs.globals;
         // Prepopulate args defaults
         for (key, config) in &self.args {
-            if let Some(default) = &config.default {
-                args.entry(key.clone()).or_insert_with(|| default.clone());
+            if let Some(default) = config.absent_value() {
+                args.entry(key.clone()).or_insert(default);
             }
         }
 
         // Prepopulate kwargs defaults
         for (key, config) in &self.kwargs {
-            if let Some(default) = &config.default {
-                kwargs.entry(key.clone()).or_insert_with(|| default.clone());
+            if let Some(default) = config.absent_value() {
+                kwargs.entry(key.clone()).or_insert(default);
             }
         }
 
-        // Prepopulate globals defaults
+        // Prepopulate globals defaults, optional globals are bound to null
         for (key, config) in &self.globals {
-            if let Some(default) = &config.default {
+            if let Some(default) = config.absent_value() {
                 globals
                     .entry(key.clone())
-                    .or_insert_with(|| default.clone());
+                    .or_insert(default);
+            }
+        }
+    }
+
+    /// Fill in the defaults of arguments omitted from a function invocation payload. Defaults are
+    /// supplied as kwargs, so are only filled when the invocation passes no positional arguments,
+    /// as the positions of named arguments are not known here.
+    pub fn fill_invocation_defaults(&self, payload: RkyvSerializedValue) -> RkyvSerializedValue {
+        let RkyvSerializedValue::Object(mut payload_map) = payload else {
+            return payload;
+        };
+        let has_positional = matches!(payload_map.get("args"), Some(RkyvSerializedValue::Object(args)) if !args.is_empty());
+        if has_positional {
+            return RkyvSerializedValue::Object(payload_map);
+        }
+        let mut kwargs = match payload_map.remove("kwargs") {
+            Some(RkyvSerializedValue::Object(kwargs)) => kwargs,
+            Some(other) => {
+                payload_map.insert("kwargs".to_string(), other);
+                return RkyvSerializedValue::Object(payload_map);
+            }
+            None => HashMap::new(),
+        };
+        for (key, config) in self.args.iter().chain(self.kwargs.iter()) {
+            if let Some(default) = &config.default {
+                kwargs.entry(key.clone()).or_insert_with(|| default.clone());
+            }
+        }
+        if !kwargs.is_empty() {
+            payload_map.insert("kwargs".to_string(), RkyvSerializedValue::Object(kwargs));
+        }
+        RkyvSerializedValue::Object(payload_map)
+    }
+
+    /// The invocation payload of a function triggered by an event. A function with a single parameter
+    /// receives the event payload as that parameter, otherwise the keys of an object payload are
+    /// bound to the parameters of the same name. Each is passed by name.
+    pub fn bind_event_payload(&self, payload: RkyvSerializedValue) -> RkyvSerializedValue {
+        let parameters: Vec<&String> = self.args.iter()
+            .chain(self.kwargs.iter())
+            .filter(|(_, config)| !config.variadic)
+            .map(|(name, _)| name)
+            .collect();
+        let accepts_any_kwargs = self.kwargs.values().any(|config| config.variadic);
+        let kwargs: HashMap<String, RkyvSerializedValue> = match (parameters.as_slice(), payload) {
+            ([parameter], payload) => HashMap::from([((*parameter).clone(), payload)]),
+            (_, RkyvSerializedValue::Object(fields)) => fields
+                .into_iter()
+                .filter(|(key, _)| accepts_any_kwargs || parameters.contains(&key))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        RkyvSerializedValue::Object(HashMap::from([
+            ("args".to_string(), RkyvSerializedValue::Object(HashMap::new())),
+            ("kwargs".to_string(), RkyvSerializedValue::Object(kwargs)),
+        ]))
+    }
+
+    /// Validate the arguments of a function invocation passed by name against the types declared
+    /// for the parameters of the same name.
+    pub fn validate_invocation_values(&self, payload: &RkyvSerializedValue) -> Result<(), String> {
+        let RkyvSerializedValue::Object(payload_map) = payload else {
+            return Ok(());
+        };
+        let Some(RkyvSerializedValue::Object(kwargs)) = payload_map.get("kwargs") else {
+            return Ok(());
+        };
+        let mut violations = vec![];
+        for (key, value) in kwargs {
+            let Some(ty) = self.args.get(key).or_else(|| self.kwargs.get(key)).and_then(|config| config.ty.as_ref()) else {
+                continue;
+            };
+            if let Err(e) = ty.validate(value) {
+                violations.push(format!("{}: {}", key, e));
             }
         }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            violations.sort();
+            Err(violations.join("; "))
+        }
     }
 }
 
@@ -152,6 +343,9 @@ pub enum OutputItemConfiguration {
         input_signature: InputSignature,
         emit_event: Vec<String>,
         trigger_on: Vec<String>,
+        /// Names the function's returned tuple or dictionary is split into when it is invoked,
+        /// empty when the function returns a single value.
+        outputs: Vec<String>,
     },
     #[default]
     Value
@@ -194,11 +388,7 @@ impl Signature {
     pub(crate) fn new() -> Self {
         Self {
             trigger_on: TriggerConfiguration::OnChange,
-            input_signature: InputSignature {
-                args: HashMap::new(),
-                kwargs: HashMap::new(),
-                globals: HashMap::new(),
-            },
+            input_signature: InputSignature::new(),
             output_signature: OutputSignature {
                 globals: HashMap::new(),
                 functions: HashMap::new(),
@@ -254,15 +444,111 @@ impl fmt::Debug for AsyncRPCCommunication {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of output captured from a cell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogLine {
+    /// Microseconds since the unix epoch, strictly increasing across every line captured by this
+    /// process so that lines of stdout and stderr can be interleaved in the order they were written.
+    pub ts: u64,
+    pub stream: LogStream,
+    pub text: String,
+}
+
+static LAST_LOG_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+fn next_log_timestamp() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    let mut last = LAST_LOG_TIMESTAMP.load(Ordering::SeqCst);
+    loop {
+        let ts = now.max(last + 1);
+        match LAST_LOG_TIMESTAMP.compare_exchange(last, ts, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return ts,
+            Err(current) => last = current,
+        }
+    }
+}
+
+impl LogLine {
+    pub fn new(stream: LogStream, text: impl Into<String>) -> Self {
+        LogLine { ts: next_log_timestamp(), stream, text: text.into() }
+    }
+
+    pub fn stdout(text: impl Into<String>) -> Self {
+        LogLine::new(LogStream::Stdout, text)
+    }
+
+    pub fn stderr(text: impl Into<String>) -> Self {
+        LogLine::new(LogStream::Stderr, text)
+    }
+}
+
+/// Render log lines as flat text, one line per entry.
+pub fn render_log_text(lines: &[LogLine]) -> String {
+    lines.iter().map(|l| format!("{}\n", l.text)).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct OperationFnOutput {
     pub has_error: bool,
     pub execution_state: Option<ExecutionState>,
     pub output: Result<RkyvSerializedValue, ExecutionStateErrors>,
-    pub stdout: Vec<String>,
-    pub stderr: Vec<String>
+    pub stdout: Vec<LogLine>,
+    pub stderr: Vec<LogLine>,
+    /// Details about how the output was produced, such as the finish reason reported by a model.
+    pub metadata: HashMap<String, String>,
 }
 
+/// Metadata key holding the reason a model stopped generating, e.g. "stop" or "length".
+pub const FINISH_REASON_METADATA_KEY: &str = "finish_reason";
+
+/// Finish reason reported when the provider's content filter withheld the response.
+pub const CONTENT_FILTER_FINISH_REASON: &str = "content_filter";
+
+/// Metadata key holding the hash of the template a prompt cell rendered, identifying the version
+/// of the prompt that produced the output.
+pub const TEMPLATE_HASH_METADATA_KEY: &str = "template_hash";
+
+/// Metadata key holding the model a prompt cell's request was sent to.
+pub const MODEL_METADATA_KEY: &str = "model";
+
+/// Metadata key holding the provider a prompt cell's request was sent to.
+pub const PROVIDER_METADATA_KEY: &str = "provider";
+
+/// Metadata keys holding the tokens a prompt cell's requests consumed, summed over rounds of tool calls.
+pub const PROMPT_TOKENS_METADATA_KEY: &str = "prompt_tokens";
+pub const COMPLETION_TOKENS_METADATA_KEY: &str = "completion_tokens";
+pub const TOTAL_TOKENS_METADATA_KEY: &str = "total_tokens";
+
+/// Metadata key set to "true" when a prompt cell's response was shared with an identical request
+/// rather than requested from the model, such responses consume no tokens.
+pub const CACHE_HIT_METADATA_KEY: &str = "cache_hit";
+
+/// Metadata key holding the JSON encoded messages a prompt cell sent to the model, recorded when
+/// the cell's `include_rendered_messages` is set.
+pub const RENDERED_MESSAGES_METADATA_KEY: &str = "rendered_messages";
+
+/// Metadata key holding the JSON encoded tool calls whose results a prompt cell's model was given
+/// before it answered, in the order they were made.
+pub const SOURCES_METADATA_KEY: &str = "sources";
+
+/// Metadata key holding the class name of the exception a code cell raised, e.g. "KeyError".
+pub const EXCEPTION_TYPE_METADATA_KEY: &str = "exception_type";
+
+/// Metadata key holding the formatted traceback of the exception a code cell raised.
+pub const TRACEBACK_METADATA_KEY: &str = "traceback";
+
+/// Metadata key holding the seed the random number generators of a code cell were seeded with.
+pub const RANDOM_SEED_METADATA_KEY: &str = "random_seed";
+
 impl OperationFnOutput {
     pub fn with_value(value: RkyvSerializedValue) -> Self {
         Self {
@@ -270,9 +556,88 @@ impl OperationFnOutput {
             execution_state: None,
             output: Ok(value),
             stdout: Vec::new(),
-            stderr: Vec::new()
+            stderr: Vec::new(),
+            metadata: HashMap::new(),
         }
     }
+
+    /// The reason the model producing this output stopped generating, when reported.
+    pub fn finish_reason(&self) -> Option<&str> {
+        self.metadata.get(FINISH_REASON_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// Whether the provider's content filter withheld the response of the model producing this output.
+    pub fn blocked_by_content_filter(&self) -> bool {
+        self.finish_reason() == Some(CONTENT_FILTER_FINISH_REASON)
+    }
+
+    /// The hash of the template the prompt cell producing this output rendered, when reported.
+    pub fn template_hash(&self) -> Option<&str> {
+        self.metadata.get(TEMPLATE_HASH_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// The model the prompt cell producing this output sent its request to, when known.
+    pub fn model(&self) -> Option<&str> {
+        self.metadata.get(MODEL_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// The provider the prompt cell producing this output sent its request to, when known.
+    pub fn provider(&self) -> Option<&str> {
+        self.metadata.get(PROVIDER_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// The tokens consumed by the prompt cell producing this output, None for outputs of other cells.
+    pub fn usage(&self) -> Option<Usage> {
+        let tokens = |key: &str| self.metadata.get(key).and_then(|tokens| tokens.parse::<i32>().ok());
+        Some(Usage {
+            prompt_tokens: tokens(PROMPT_TOKENS_METADATA_KEY)?,
+            completion_tokens: tokens(COMPLETION_TOKENS_METADATA_KEY)?,
+            total_tokens: tokens(TOTAL_TOKENS_METADATA_KEY)?,
+        })
+    }
+
+    /// Whether the response of the prompt cell producing this output was shared with an identical request.
+    pub fn cache_hit(&self) -> bool {
+        self.metadata.get(CACHE_HIT_METADATA_KEY).map(String::as_str) == Some("true")
+    }
+
+    /// The messages the prompt cell producing this output sent to the model, when it recorded them.
+    pub fn rendered_messages(&self) -> Option<Vec<TemplateMessage>> {
+        self.metadata.get(RENDERED_MESSAGES_METADATA_KEY)
+            .and_then(|messages| serde_json::from_str(messages).ok())
+    }
+
+    /// The tool calls whose results the model of the prompt cell producing this output drew upon.
+    pub fn sources(&self) -> Vec<ToolCallSource> {
+        self.metadata.get(SOURCES_METADATA_KEY)
+            .and_then(|sources| serde_json::from_str(sources).ok())
+            .unwrap_or_default()
+    }
+
+    /// The class name of the exception the code cell producing this output raised, if it raised one.
+    pub fn exception_type(&self) -> Option<&str> {
+        self.metadata.get(EXCEPTION_TYPE_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// The formatted traceback of the exception the code cell producing this output raised.
+    pub fn traceback(&self) -> Option<&str> {
+        self.metadata.get(TRACEBACK_METADATA_KEY).map(|s| s.as_str())
+    }
+
+    /// Lines of both stdout and stderr in the order they were written.
+    pub fn log_lines(&self) -> Vec<LogLine> {
+        let mut lines: Vec<LogLine> = self.stdout.iter().chain(self.stderr.iter()).cloned().collect();
+        lines.sort_by_key(|l| l.ts);
+        lines
+    }
+
+    pub fn stdout_text(&self) -> String {
+        render_log_text(&self.stdout)
+    }
+
+    pub fn stderr_text(&self) -> String {
+        render_log_text(&self.stderr)
+    }
 }
 
 /// OperationFn represents functions that can be executed on the graph
@@ -309,6 +674,8 @@ pub struct OperationNode {
     pub cell: CellTypes,
     /// Signature of the inputs and outputs of this node
     pub(crate) signature: Signature,
+    /// When set the cell is executed once for each element of the mapped input
+    pub(crate) map: Option<MapConfiguration>,
 }
 
 impl core::hash::Hash for OperationNode {
@@ -341,13 +708,12 @@ impl Default for OperationNode {
             name: None,
             created_at_state_id: Uuid::nil(),
             cell: CellTypes::Code(CodeCell {
-                backing_file_reference: None,
-                name: None,
                 language: SupportedLanguage::PyO3,
                 source_code: "".to_string(),
-                function_invocation: None,
+                ..Default::default()
             }, TextRange::default()),
             signature: Signature::new(),
+            map: None,
             // operation: Box::new(|_, x, _, _| async move { Ok(OperationFnOutput::with_value(x)) }.boxed()),
         }
     }
@@ -370,7 +736,33 @@ impl OperationNode {
         node
     }
 
-    #[tracing::instrument]
+    pub fn id(&self) -> OperationId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The inputs the cell depends on, as resolved when it was constructed.
+    pub fn input_signature(&self) -> &InputSignature {
+        &self.signature.input_signature
+    }
+
+    /// The globals and functions the cell produces, as resolved when it was constructed.
+    pub fn output_signature(&self) -> &OutputSignature {
+        &self.signature.output_signature
+    }
+
+    pub(crate) fn with_map(mut self, map: Option<MapConfiguration>) -> Self {
+        self.map = map;
+        self
+    }
+
+    #[tracing::instrument(
+        skip(argument_payload, intermediate_output_channel_tx, async_communication_channel),
+        fields(payload = ?state.redact(&argument_payload)),
+    )]
     pub(crate) fn execute(
         &self,
         state: &ExecutionState,
@@ -387,6 +779,9 @@ impl OperationNode {
                     SupportedLanguage::Deno => {
                         crate::cells::code_cell::code_cell_exec_deno(code_cell.clone())
                     }
+                    SupportedLanguage::Lua => {
+                        crate::cells::code_cell::code_cell_exec_lua(code_cell.clone())
+                    }
                 }
             }
             CellTypes::CodeGen(code_gen_cell, _) => {
@@ -398,10 +793,33 @@ impl OperationNode {
             CellTypes::Template(crate::cells::TemplateCell {body, ..}, _) => {
                 crate::cells::template_cell::template_cell_exec(body.clone())
             }
+            CellTypes::Transform(transform_cell, _) => {
+                crate::cells::transform_cell::transform_cell_exec(transform_cell.clone())
+            }
+            CellTypes::Embedding(embedding_cell, _) => {
+                crate::cells::embedding_cell::embedding_cell_exec(embedding_cell.clone())
+            }
+        };
+
+        let output = if let Some(map) = &self.map {
+            crate::execution::primitives::map::map_operation(closure, map.clone(), state, argument_payload)
+        } else {
+            /// Receiver that we pass to the exec for it to capture oneshot RPC communication
+            closure(state, argument_payload, intermediate_output_channel_tx, async_communication_channel)
         };
 
-        /// Receiver that we pass to the exec for it to capture oneshot RPC communication
-        closure(state, argument_payload, intermediate_output_channel_tx, async_communication_channel)
+        // The author's tags accompany the output, without displacing metadata reported by the execution
+        let tags = self.cell.metadata().cloned().unwrap_or_default();
+        if tags.is_empty() {
+            return output;
+        }
+        async move {
+            let mut output = output.await?;
+            for (key, value) in tags {
+                output.metadata.entry(key).or_insert(value);
+            }
+            Ok(output)
+        }.boxed()
     }
 }
 
@@ -413,6 +831,91 @@ mod tests {
     // TODO: test application of Operations/composition
     // TODO: test manual evaluation of a composition of operations
 
+    #[test]
+    fn test_input_type_constraints() {
+        let ty = InputType::Enum(vec!["red".to_string(), "green".to_string()]);
+        assert!(ty.validate(&RkyvSerializedValue::String("red".to_string())).is_ok());
+        assert!(ty.validate(&RkyvSerializedValue::String("blue".to_string())).is_err());
+
+        let ty = InputType::IntRange { min: Some(1), max: Some(10) };
+        assert!(ty.validate(&RkyvSerializedValue::Number(10)).is_ok());
+        assert!(ty.validate(&RkyvSerializedValue::Number(11)).unwrap_err().contains("1..=10"));
+        assert!(ty.validate(&RkyvSerializedValue::String("5".to_string())).is_err());
+        assert!(InputType::IntRange { min: None, max: Some(0) }.validate(&RkyvSerializedValue::Number(-100)).is_ok());
+
+        let ty = InputType::Pattern(regex::Regex::new("^[A-Z]{3}$").unwrap());
+        assert!(ty.validate(&RkyvSerializedValue::String("USD".to_string())).is_ok());
+        assert!(ty.validate(&RkyvSerializedValue::String("usd".to_string())).is_err());
+
+        // Structural types do not constrain the value
+        assert!(InputType::String.validate(&RkyvSerializedValue::Number(1)).is_ok());
+    }
+
+    #[test]
+    fn test_bind_event_payload() {
+        use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+        let parameter = || InputItemConfiguration { ty: None, default: None, variadic: false, optional: false };
+        let kwargs_of = |payload: RkyvSerializedValue| match payload {
+            RkyvSerializedValue::Object(mut m) => m.remove("kwargs").unwrap(),
+            _ => unreachable!(),
+        };
+        let event = RkyvObjectBuilder::new()
+            .insert_string("path", "a.txt".to_string())
+            .insert_number("size", 3)
+            .build();
+
+        let mut single = InputSignature::new();
+        single.args.insert("ev".to_string(), parameter());
+        assert_eq!(kwargs_of(single.bind_event_payload(event.clone())), RkyvObjectBuilder::new()
+            .insert_value("ev", event.clone())
+            .build());
+
+        let mut named = InputSignature::new();
+        named.args.insert("path".to_string(), parameter());
+        named.args.insert("mode".to_string(), parameter());
+        assert_eq!(kwargs_of(named.bind_event_payload(event.clone())), RkyvObjectBuilder::new()
+            .insert_string("path", "a.txt".to_string())
+            .build());
+    }
+
+    #[test]
+    fn test_validate_input_values_reports_every_violation() {
+        let mut signature = InputSignature::new();
+        signature.globals.insert("count".to_string(), InputItemConfiguration {
+            ty: Some(InputType::IntRange { min: Some(0), max: None }),
+            ..Default::default()
+        });
+        signature.kwargs.insert("code".to_string(), InputItemConfiguration {
+            ty: Some(InputType::Pattern(regex::Regex::new("^[a-z]+$").unwrap())),
+            ..Default::default()
+        });
+        let mut inputs = OperationInputs::new();
+        inputs.globals.insert("count".to_string(), RkyvSerializedValue::Number(-1));
+        inputs.kwargs.insert("code".to_string(), RkyvSerializedValue::String("ABC".to_string()));
+        let err = signature.validate_input_values(&inputs).unwrap_err();
+        assert!(err.contains("globals: count"), "{}", err);
+        assert!(err.contains("kwargs: code"), "{}", err);
+
+        inputs.globals.insert("count".to_string(), RkyvSerializedValue::Number(3));
+        inputs.kwargs.insert("code".to_string(), RkyvSerializedValue::String("abc".to_string()));
+        assert!(signature.validate_input_values(&inputs).is_ok());
+    }
+
+    #[test]
+    fn test_log_lines_interleave_streams_in_order() {
+        let first = LogLine::stdout("first");
+        let second = LogLine::stderr("second");
+        let third = LogLine::stdout("third");
+        let output = OperationFnOutput {
+            stdout: vec![first, third],
+            stderr: vec![second],
+            ..OperationFnOutput::with_value(RkyvSerializedValue::Null)
+        };
+        let texts: Vec<_> = output.log_lines().into_iter().map(|l| l.text).collect();
+        assert_eq!(texts, vec!["first", "second", "third"]);
+        assert_eq!(output.stdout_text(), "first\nthird\n");
+    }
+
     #[tokio::test]
     async fn test_execute_with_operation() -> anyhow::Result<()> {
         let operation: Box<OperationFn> =
@@ -443,13 +946,12 @@ mod tests {
             id: id_a,
             name: None,
             cell: CellTypes::Code(CodeCell {
-                backing_file_reference: None,
-                name: None,
                 language: SupportedLanguage::PyO3,
                 source_code: "".to_string(),
-                function_invocation: None,
+                ..Default::default()
             }, TextRange::default()),
             signature: Signature::new(),
+            map: None,
             // operation: Box::new(|_, p: RkyvSerializedValue, _, async_rpccommunication: Option<AsyncRPCCommunication>| async move {
             //     let mut state = 0;
             //     let mut async_rpccommunication: AsyncRPCCommunication = async_rpccommunication.unwrap();
@@ -6,6 +6,8 @@ use rkyv::{
 };
 use serde::{Deserialize as SerdeDeserialize, Serialize as SerdeSerialize};
 use chidori_prompt_format::serde_json::Value;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use std::collections::{HashMap, HashSet};
 use std::hash::Hasher;
 
@@ -33,9 +35,13 @@ pub enum RkyvSerializedValue {
         HashSet<RkyvSerializedValue>
     ),
 
-    Float(f32),
+    Float(f64),
     Number(i32),
+    /// Integers outside of the range of `Number`, such as identifiers and timestamps
+    Integer(i64),
     String(String),
+    /// Binary data, such as images
+    Bytes(Vec<u8>),
     Boolean(bool),
     Null,
 
@@ -52,6 +58,10 @@ pub enum RkyvSerializedValue {
     ),
 }
 
+/// Keys of the object a data URL is decoded into.
+pub const DATA_URL_MIME_TYPE_KEY: &str = "mime_type";
+pub const DATA_URL_DATA_KEY: &str = "data";
+
 pub struct RkyvObjectBuilder {
     object: HashMap<String, RkyvSerializedValue>,
 }
@@ -98,6 +108,111 @@ impl RkyvObjectBuilder {
     }
 }
 
+enum PathSegment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Split a path such as `a.b[0].c` into its keys and indices, `None` when the path is malformed.
+fn parse_path(path: &str) -> Option<Vec<PathSegment>> {
+    let mut segments = vec![];
+    for part in path.split('.') {
+        let (key, mut indices) = match part.find('[') {
+            Some(start) => (&part[..start], &part[start..]),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            segments.push(PathSegment::Key(key));
+        } else if indices.is_empty() {
+            return None;
+        }
+        while !indices.is_empty() {
+            let end = indices.find(']')?;
+            segments.push(PathSegment::Index(indices[1..end].parse().ok()?));
+            indices = &indices[end + 1..];
+            if !indices.is_empty() && !indices.starts_with('[') {
+                return None;
+            }
+        }
+    }
+    Some(segments)
+}
+
+impl RkyvSerializedValue {
+    /// An integer as a `Number` when it fits, otherwise as an `Integer`.
+    pub fn from_i64(n: i64) -> RkyvSerializedValue {
+        match i32::try_from(n) {
+            Ok(n) => RkyvSerializedValue::Number(n),
+            Err(_) => RkyvSerializedValue::Integer(n),
+        }
+    }
+
+    /// Decode a `data:<mime type>;base64,<data>` URL into an object holding its mime type and
+    /// its data as bytes. Strings that are not base64 data URLs yield None.
+    pub fn from_data_url(s: &str) -> Option<RkyvSerializedValue> {
+        let (header, data) = s.strip_prefix("data:")?.split_once(',')?;
+        let mime_type = header.strip_suffix(";base64")?;
+        let bytes = STANDARD.decode(data).ok()?;
+        Some(RkyvObjectBuilder::new()
+            .insert_string(DATA_URL_MIME_TYPE_KEY, mime_type.to_string())
+            .insert_value(DATA_URL_DATA_KEY, RkyvSerializedValue::Bytes(bytes))
+            .build())
+    }
+
+    /// The data URL an object produced by `from_data_url` was decoded from.
+    pub fn as_data_url(&self) -> Option<String> {
+        let RkyvSerializedValue::Object(o) = self else { return None };
+        if o.len() != 2 {
+            return None;
+        }
+        match (o.get(DATA_URL_MIME_TYPE_KEY), o.get(DATA_URL_DATA_KEY)) {
+            (Some(RkyvSerializedValue::String(mime_type)), Some(RkyvSerializedValue::Bytes(bytes))) => {
+                Some(format!("data:{};base64,{}", mime_type, STANDARD.encode(bytes)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of a `Number` or `Integer`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            RkyvSerializedValue::Number(n) => Some(*n as i64),
+            RkyvSerializedValue::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The value nested at a dotted path with array indices, such as `a.b[0].c`. Missing keys,
+    /// out of bounds indices, and malformed paths yield `None`.
+    pub fn get_path(&self, path: &str) -> Option<&RkyvSerializedValue> {
+        parse_path(path)?.into_iter().try_fold(self, |value, segment| match (value, segment) {
+            (RkyvSerializedValue::Object(map), PathSegment::Key(key)) => map.get(key),
+            (RkyvSerializedValue::Array(items), PathSegment::Index(index)) => items.get(index),
+            _ => None,
+        })
+    }
+
+    /// Set the value at a path of the same form as `get_path`, creating objects for missing keys.
+    /// Fails when an index is out of bounds or the path traverses a value of the wrong kind.
+    pub fn set_path(&mut self, path: &str, new_value: RkyvSerializedValue) -> anyhow::Result<()> {
+        let segments = parse_path(path).ok_or_else(|| anyhow::anyhow!("Invalid path {:?}", path))?;
+        let mut value = self;
+        for segment in segments {
+            value = match (value, segment) {
+                (RkyvSerializedValue::Object(map), PathSegment::Key(key)) => map
+                    .entry(key.to_string())
+                    .or_insert_with(|| RkyvSerializedValue::Object(HashMap::new())),
+                (RkyvSerializedValue::Array(items), PathSegment::Index(index)) => items
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow::anyhow!("Index {} out of bounds in path {:?}", index, path))?,
+                _ => return Err(anyhow::anyhow!("Path {:?} does not match the shape of the value", path)),
+            };
+        }
+        *value = new_value;
+        Ok(())
+    }
+}
+
 impl std::cmp::Eq for RkyvSerializedValue {
 }
 
@@ -143,12 +258,24 @@ impl std::cmp::PartialEq for RkyvSerializedValue {
                     _ => unreachable!()
                 }
             }
+            RkyvSerializedValue::Integer(a) => {
+                match other {
+                    RkyvSerializedValue::Integer(aa) => { a == aa }
+                    _ => unreachable!()
+                }
+            }
             RkyvSerializedValue::String(a) => {
                 match other {
                     RkyvSerializedValue::String(aa) => { a == aa }
                     _ => unreachable!()
                 }
             }
+            RkyvSerializedValue::Bytes(a) => {
+                match other {
+                    RkyvSerializedValue::Bytes(aa) => { a == aa }
+                    _ => unreachable!()
+                }
+            }
             RkyvSerializedValue::Boolean(a) => {
                 match other {
                     RkyvSerializedValue::Boolean(aa) => { a == aa }
@@ -192,7 +319,7 @@ impl std::hash::Hash for RkyvSerializedValue {
                 func_name.hash(state);
             }
             RkyvSerializedValue::Cell(cell_type) => {
-                unimplemented!();
+                cell_type.source_hash().hash(state);
             }
             RkyvSerializedValue::Set(set) => {
                 for item in set {
@@ -205,9 +332,15 @@ impl std::hash::Hash for RkyvSerializedValue {
             RkyvSerializedValue::Number(n) => {
                 n.hash(state);
             }
+            RkyvSerializedValue::Integer(n) => {
+                n.hash(state);
+            }
             RkyvSerializedValue::String(s) => {
                 s.hash(state);
             }
+            RkyvSerializedValue::Bytes(b) => {
+                b.hash(state);
+            }
             RkyvSerializedValue::Boolean(b) => {
                 b.hash(state);
             }
@@ -251,7 +384,9 @@ impl std::fmt::Display for RkyvSerializedValue {
             RkyvSerializedValue::Cell(_) => write!(f, "Cell"),
             RkyvSerializedValue::Float(_) => write!(f, "Float"),
             RkyvSerializedValue::Number(_) => write!(f, "Number"),
+            RkyvSerializedValue::Integer(_) => write!(f, "Integer"),
             RkyvSerializedValue::String(_) => write!(f, "String"),
+            RkyvSerializedValue::Bytes(_) => write!(f, "Bytes"),
             RkyvSerializedValue::Boolean(_) => write!(f, "Boolean"),
             RkyvSerializedValue::Null => write!(f, "Null"),
             RkyvSerializedValue::Array(vec) => {
@@ -295,20 +430,56 @@ pub fn deserialize_from_buf(v: &[u8]) -> RkyvSerializedValue {
     arg1
 }
 
+/// How floats are written when converting values to JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FloatFormat {
+    /// Every digit of the value, as stored.
+    #[default]
+    Exact,
+    /// The shortest digits that identify the value, widened single precision values are written as
+    /// the digits of the single precision value, 0.1f32 is written as 0.1 rather than 0.10000000149011612.
+    Shortest,
+    /// Rounded to the given number of decimal places.
+    Precision(u32),
+}
+
+impl FloatFormat {
+    fn format(&self, f: f64) -> f64 {
+        match self {
+            FloatFormat::Exact => f,
+            FloatFormat::Shortest if (f as f32) as f64 == f => (f as f32).to_string().parse().unwrap_or(f),
+            FloatFormat::Shortest => f,
+            FloatFormat::Precision(places) => format!("{:.*}", *places as usize, f).parse().unwrap_or(f),
+        }
+    }
+}
+
 pub fn serialized_value_to_json_value(v: &RkyvSerializedValue) -> chidori_prompt_format::serde_json::Value {
+    serialized_value_to_json_value_with_format(v, FloatFormat::Exact)
+}
+
+/// Convert a value to JSON writing floats in the given format, for output shown to people or
+/// compared across runs where artifacts of binary floating point are unwanted.
+pub fn serialized_value_to_json_value_with_format(v: &RkyvSerializedValue, float_format: FloatFormat) -> chidori_prompt_format::serde_json::Value {
     match &v {
-        RkyvSerializedValue::Float(f) => Value::Number(f.to_string().parse().unwrap()),
-        RkyvSerializedValue::Number(n) => Value::Number(n.to_string().parse().unwrap()),
+        // JSON has no representation of NaN or infinity
+        RkyvSerializedValue::Float(f) => chidori_prompt_format::serde_json::Number::from_f64(float_format.format(*f))
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        RkyvSerializedValue::Number(n) => Value::Number((*n).into()),
+        RkyvSerializedValue::Integer(n) => Value::Number((*n).into()),
         RkyvSerializedValue::String(s) => Value::String(s.to_string()),
+        RkyvSerializedValue::Bytes(b) => Value::String(STANDARD.encode(b)),
         RkyvSerializedValue::Boolean(b) => Value::Bool(*b),
         RkyvSerializedValue::Array(a) => Value::Array(
             a.iter()
-                .map(|v| serialized_value_to_json_value(v))
+                .map(|v| serialized_value_to_json_value_with_format(v, float_format))
                 .collect(),
         ),
+        RkyvSerializedValue::Object(_) if v.as_data_url().is_some() => Value::String(v.as_data_url().unwrap()),
         RkyvSerializedValue::Object(a) => Value::Object(
             a.iter()
-                .map(|(k, v)| (k.clone(), serialized_value_to_json_value(v)))
+                .map(|(k, v)| (k.clone(), serialized_value_to_json_value_with_format(v, float_format)))
                 .collect(),
         ),
         RkyvSerializedValue::FunctionPointer(_, _) => Value::Null,
@@ -317,22 +488,36 @@ pub fn serialized_value_to_json_value(v: &RkyvSerializedValue) -> chidori_prompt
         RkyvSerializedValue::Null => Value::Null,
         RkyvSerializedValue::Set(a) => {
             a.iter()
-                .map(|v| serialized_value_to_json_value(v))
+                .map(|v| serialized_value_to_json_value_with_format(v, float_format))
                 .collect()
         }
     }
 }
 
+/// Convert a value received from JavaScript, decoding strings that are base64 data URLs, such as
+/// images from a browser, into bytes. Data URLs are encoded back into strings when the value is
+/// returned to JavaScript.
+pub fn js_value_to_serialized_value(jval: &Value) -> RkyvSerializedValue {
+    match jval {
+        Value::String(s) => RkyvSerializedValue::from_data_url(s)
+            .unwrap_or_else(|| RkyvSerializedValue::String(s.clone())),
+        Value::Array(a) => RkyvSerializedValue::Array(a.iter().map(js_value_to_serialized_value).collect()),
+        Value::Object(o) => RkyvSerializedValue::Object(
+            o.iter().map(|(k, v)| (k.clone(), js_value_to_serialized_value(v))).collect(),
+        ),
+        _ => json_value_to_serialized_value(jval),
+    }
+}
+
 /// Convert a serde_json::Value into a SerializedValue
 pub fn json_value_to_serialized_value(jval: &Value) -> RkyvSerializedValue {
     match jval {
         Value::Number(n) => {
-            if n.is_i64() {
-                RkyvSerializedValue::Number(n.as_i64().unwrap() as i32)
-            } else if n.is_f64() {
-                RkyvSerializedValue::Float(n.as_f64().unwrap() as f32)
+            if let Some(n) = n.as_i64() {
+                RkyvSerializedValue::from_i64(n)
             } else {
-                panic!("Invalid number value")
+                // Integers beyond i64, and numbers with a fractional part
+                RkyvSerializedValue::Float(n.as_f64().unwrap_or(f64::NAN))
             }
         }
         Value::String(s) => RkyvSerializedValue::String(s.clone()),
@@ -354,6 +539,45 @@ pub fn json_value_to_serialized_value(jval: &Value) -> RkyvSerializedValue {
     }
 }
 
+/// Key of the object bytes are serialized as through serde, `{"$bytes": "<base64>"}`. Only objects
+/// of this form are deserialized as bytes, strings are always deserialized as strings.
+pub const SERDE_BYTES_KEY: &str = "$bytes";
+
+/// Convert a value to JSON with bytes in their tagged form, the inverse of `tagged_json_value_to_serialized_value`.
+fn serialized_value_to_tagged_json_value(v: &RkyvSerializedValue) -> Value {
+    match v {
+        RkyvSerializedValue::Bytes(b) => Value::Object(
+            [(SERDE_BYTES_KEY.to_string(), Value::String(STANDARD.encode(b)))].into_iter().collect(),
+        ),
+        RkyvSerializedValue::Array(a) | RkyvSerializedValue::Set(a) => Value::Array(
+            a.iter().map(serialized_value_to_tagged_json_value).collect(),
+        ),
+        RkyvSerializedValue::Object(o) => Value::Object(
+            o.iter().map(|(k, v)| (k.clone(), serialized_value_to_tagged_json_value(v))).collect(),
+        ),
+        _ => serialized_value_to_json_value(v),
+    }
+}
+
+fn tagged_json_value_to_serialized_value(jval: &Value) -> RkyvSerializedValue {
+    match jval {
+        Value::Object(o) if o.len() == 1 => match o.get(SERDE_BYTES_KEY) {
+            Some(Value::String(encoded)) => match STANDARD.decode(encoded) {
+                Ok(bytes) => RkyvSerializedValue::Bytes(bytes),
+                Err(_) => json_value_to_serialized_value(jval),
+            },
+            _ => RkyvSerializedValue::Object(
+                o.iter().map(|(k, v)| (k.clone(), tagged_json_value_to_serialized_value(v))).collect(),
+            ),
+        },
+        Value::Object(o) => RkyvSerializedValue::Object(
+            o.iter().map(|(k, v)| (k.clone(), tagged_json_value_to_serialized_value(v))).collect(),
+        ),
+        Value::Array(a) => RkyvSerializedValue::Array(a.iter().map(tagged_json_value_to_serialized_value).collect()),
+        _ => json_value_to_serialized_value(jval),
+    }
+}
+
 // Implementing Serialize for RkyvSerializedValue
 impl SerdeSerialize for RkyvSerializedValue {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -361,7 +585,7 @@ impl SerdeSerialize for RkyvSerializedValue {
         S: serde::Serializer,
     {
         // Convert self to a serde_json::Value and then serialize that
-        let value = serialized_value_to_json_value(self);
+        let value = serialized_value_to_tagged_json_value(self);
         value.serialize(serializer)
     }
 }
@@ -376,10 +600,94 @@ impl<'de> SerdeDeserialize<'de> for RkyvSerializedValue {
         let value = SerdeDeserialize::deserialize(deserializer)?;
 
         // Convert the serde_json::Value to RkyvSerializedValue
-        Ok(json_value_to_serialized_value(&value))
+        Ok(tagged_json_value_to_serialized_value(&value))
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum MsgpackError {
+    #[error("{0} refers to state of the running process and can not be encoded as MessagePack")]
+    Unencodable(&'static str),
+    #[error("MessagePack maps must have string keys, found {0}")]
+    NonStringKey(String),
+    #[error("MessagePack {0} has no equivalent value")]
+    Unsupported(String),
+    #[error(transparent)]
+    Encode(#[from] rmpv::encode::Error),
+    #[error(transparent)]
+    Decode(#[from] rmpv::decode::Error),
+}
+
+/// Encode a value as MessagePack, bytes are encoded as bin and sets as arrays. Stream pointers,
+/// function pointers and cells are rejected since they only have meaning to the running process.
+pub fn to_msgpack(v: &RkyvSerializedValue) -> Result<Vec<u8>, MsgpackError> {
+    let mut buf = vec![];
+    rmpv::encode::write_value(&mut buf, &serialized_value_to_msgpack_value(v)?)?;
+    Ok(buf)
+}
+
+/// Decode a value from MessagePack, the inverse of `to_msgpack`.
+pub fn from_msgpack(mut buf: &[u8]) -> Result<RkyvSerializedValue, MsgpackError> {
+    msgpack_value_to_serialized_value(rmpv::decode::read_value(&mut buf)?)
+}
+
+fn serialized_value_to_msgpack_value(v: &RkyvSerializedValue) -> Result<rmpv::Value, MsgpackError> {
+    Ok(match v {
+        RkyvSerializedValue::Float(f) => rmpv::Value::F64(*f),
+        RkyvSerializedValue::Number(n) => rmpv::Value::from(*n),
+        RkyvSerializedValue::Integer(n) => rmpv::Value::from(*n),
+        RkyvSerializedValue::String(s) => rmpv::Value::from(s.as_str()),
+        RkyvSerializedValue::Bytes(b) => rmpv::Value::Binary(b.clone()),
+        RkyvSerializedValue::Boolean(b) => rmpv::Value::Boolean(*b),
+        RkyvSerializedValue::Null => rmpv::Value::Nil,
+        RkyvSerializedValue::Array(a) => rmpv::Value::Array(
+            a.iter().map(serialized_value_to_msgpack_value).collect::<Result<_, _>>()?,
+        ),
+        RkyvSerializedValue::Set(a) => rmpv::Value::Array(
+            a.iter().map(serialized_value_to_msgpack_value).collect::<Result<_, _>>()?,
+        ),
+        RkyvSerializedValue::Object(a) => rmpv::Value::Map(
+            a.iter()
+                .map(|(k, v)| Ok((rmpv::Value::from(k.as_str()), serialized_value_to_msgpack_value(v)?)))
+                .collect::<Result<_, MsgpackError>>()?,
+        ),
+        RkyvSerializedValue::StreamPointer(_) => return Err(MsgpackError::Unencodable("StreamPointer")),
+        RkyvSerializedValue::FunctionPointer(_, _) => return Err(MsgpackError::Unencodable("FunctionPointer")),
+        RkyvSerializedValue::Cell(_) => return Err(MsgpackError::Unencodable("Cell")),
+    })
+}
+
+fn msgpack_value_to_serialized_value(v: rmpv::Value) -> Result<RkyvSerializedValue, MsgpackError> {
+    Ok(match v {
+        rmpv::Value::Nil => RkyvSerializedValue::Null,
+        rmpv::Value::Boolean(b) => RkyvSerializedValue::Boolean(b),
+        rmpv::Value::Integer(n) => match n.as_i64() {
+            Some(n) => RkyvSerializedValue::from_i64(n),
+            // Integers beyond i64
+            None => RkyvSerializedValue::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        rmpv::Value::F32(f) => RkyvSerializedValue::Float(f as f64),
+        rmpv::Value::F64(f) => RkyvSerializedValue::Float(f),
+        rmpv::Value::String(s) => match s.into_str() {
+            Some(s) => RkyvSerializedValue::String(s),
+            None => return Err(MsgpackError::Unsupported("string that is not valid UTF-8".to_string())),
+        },
+        rmpv::Value::Binary(b) => RkyvSerializedValue::Bytes(b),
+        rmpv::Value::Array(a) => RkyvSerializedValue::Array(
+            a.into_iter().map(msgpack_value_to_serialized_value).collect::<Result<_, _>>()?,
+        ),
+        rmpv::Value::Map(m) => RkyvSerializedValue::Object(
+            m.into_iter()
+                .map(|(k, v)| match k {
+                    rmpv::Value::String(k) if k.is_str() => Ok((k.into_str().unwrap(), msgpack_value_to_serialized_value(v)?)),
+                    k => Err(MsgpackError::NonStringKey(k.to_string())),
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        rmpv::Value::Ext(ty, _) => return Err(MsgpackError::Unsupported(format!("extension type {}", ty))),
+    })
+}
+
 
 
 #[cfg(test)]
@@ -448,6 +756,74 @@ mod tests {
         round_trip(value);
     }
 
+    #[test]
+    fn test_json_numbers_keep_their_precision() {
+        let json = chidori_prompt_format::serde_json::json!({"id": 1234567890123i64, "small": 7, "ratio": 0.1});
+        let value = json_value_to_serialized_value(&json);
+        assert_eq!(value.get_path("id"), Some(&RkyvSerializedValue::Integer(1234567890123)));
+        assert_eq!(value.get_path("small"), Some(&RkyvSerializedValue::Number(7)));
+        assert_eq!(value.get_path("ratio"), Some(&RkyvSerializedValue::Float(0.1)));
+        assert_eq!(serialized_value_to_json_value(&value), json);
+    }
+
+    #[test]
+    fn test_float_formats() {
+        let value = RkyvObjectBuilder::new()
+            .insert_value("widened", RkyvSerializedValue::Float(0.1f32 as f64))
+            .insert_value("ratio", RkyvSerializedValue::Array(vec![RkyvSerializedValue::Float(2.0 / 3.0)]))
+            .build();
+        let json = |format| serialized_value_to_json_value_with_format(&value, format);
+        assert_eq!(json(FloatFormat::Exact), chidori_prompt_format::serde_json::json!({"ratio": [0.6666666666666666], "widened": 0.10000000149011612}));
+        assert_eq!(json(FloatFormat::Shortest), chidori_prompt_format::serde_json::json!({"ratio": [0.6666666666666666], "widened": 0.1}));
+        assert_eq!(json(FloatFormat::Precision(2)), chidori_prompt_format::serde_json::json!({"ratio": [0.67], "widened": 0.1}));
+        assert_eq!(json(FloatFormat::Shortest)["widened"].to_string(), "0.1");
+    }
+
+    #[test]
+    fn test_data_urls_round_trip_as_bytes() {
+        let data_url = "data:image/png;base64,iVBORw0KGgo=";
+        let value = js_value_to_serialized_value(&chidori_prompt_format::serde_json::json!({"image": data_url, "caption": "a cat"}));
+        assert_eq!(value.get_path("image.mime_type"), Some(&RkyvSerializedValue::String("image/png".to_string())));
+        assert_eq!(value.get_path("image.data"), Some(&RkyvSerializedValue::Bytes(vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])));
+        assert_eq!(value.get_path("caption"), Some(&RkyvSerializedValue::String("a cat".to_string())));
+        assert_eq!(serialized_value_to_json_value(&value), chidori_prompt_format::serde_json::json!({"image": data_url, "caption": "a cat"}));
+        round_trip(value);
+    }
+
+    #[test]
+    fn test_strings_that_are_not_data_urls_stay_strings() {
+        for s in ["data:text/plain,hello", "data:image/png;base64,not base64!", "https://example.com/cat.png"] {
+            assert_eq!(js_value_to_serialized_value(&Value::String(s.to_string())), RkyvSerializedValue::String(s.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_get_path() {
+        let value = json_value_to_serialized_value(&chidori_prompt_format::serde_json::json!({
+            "a": {"b": [{"c": 1}, {"c": 2}], "matrix": [[1, 2], [3, 4]]}
+        }));
+        assert_eq!(value.get_path("a.b[1].c"), Some(&RkyvSerializedValue::Number(2)));
+        assert_eq!(value.get_path("a.matrix[1][0]"), Some(&RkyvSerializedValue::Number(3)));
+        assert_eq!(value.get_path("a.b[2].c"), None);
+        assert_eq!(value.get_path("a.missing"), None);
+        assert_eq!(value.get_path("a.b.c"), None);
+        assert_eq!(value.get_path("a.b[x]"), None);
+    }
+
+    #[test]
+    fn test_set_path() {
+        let mut value = RkyvObjectBuilder::new()
+            .insert_value("items", RkyvSerializedValue::Array(vec![RkyvSerializedValue::Null]))
+            .build();
+        value.set_path("a.b", RkyvSerializedValue::Number(1)).unwrap();
+        value.set_path("items[0].name", RkyvSerializedValue::String("x".to_string())).unwrap_err();
+        value.set_path("items[0]", RkyvObjectBuilder::new().build()).unwrap();
+        value.set_path("items[0].name", RkyvSerializedValue::String("x".to_string())).unwrap();
+        assert_eq!(value.get_path("a.b"), Some(&RkyvSerializedValue::Number(1)));
+        assert_eq!(value.get_path("items[0].name"), Some(&RkyvSerializedValue::String("x".to_string())));
+        assert!(value.set_path("items[1]", RkyvSerializedValue::Null).is_err());
+    }
+
     #[test]
     fn test_serialize_to_vec() {
         let value = RkyvSerializedValue::String("Hello".to_string());
@@ -480,4 +856,47 @@ mod tests {
         let reserialized_vec = serialize_to_vec(&deserialized_value);
         assert_eq!(serialized_vec, reserialized_vec);
     }
+
+    #[test]
+    fn test_serde_round_trip_of_bytes() {
+        let bytes = RkyvSerializedValue::Bytes(vec![0, 159, 255]);
+        let json = chidori_prompt_format::serde_json::to_string(&bytes).unwrap();
+        assert_eq!(json, r#"{"$bytes":"AJ//"}"#);
+        assert_eq!(chidori_prompt_format::serde_json::from_str::<RkyvSerializedValue>(&json).unwrap(), bytes);
+
+        let nested = RkyvObjectBuilder::new()
+            .insert_value("image", RkyvSerializedValue::from_data_url("data:image/png;base64,AJ//").unwrap())
+            .insert_value("chunks", RkyvSerializedValue::Array(vec![bytes.clone()]))
+            .build();
+        let json = chidori_prompt_format::serde_json::to_string(&nested).unwrap();
+        assert_eq!(chidori_prompt_format::serde_json::from_str::<RkyvSerializedValue>(&json).unwrap(), nested);
+
+        // Strings are never decoded, even when they look like data URLs
+        let url = RkyvSerializedValue::String("data:image/png;base64,AJ//".to_string());
+        let json = chidori_prompt_format::serde_json::to_string(&url).unwrap();
+        assert_eq!(chidori_prompt_format::serde_json::from_str::<RkyvSerializedValue>(&json).unwrap(), url);
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let value = RkyvObjectBuilder::new()
+            .insert_string("name", "chidori".to_string())
+            .insert_number("count", 3)
+            .insert_value("id", RkyvSerializedValue::Integer(1 << 40))
+            .insert_value("ratio", RkyvSerializedValue::Float(0.25))
+            .insert_value("image", RkyvSerializedValue::Bytes(vec![0, 159, 255]))
+            .insert_object("nested", RkyvObjectBuilder::new()
+                .insert_boolean("ok", true)
+                .insert_value("missing", RkyvSerializedValue::Null)
+                .insert_value("items", RkyvSerializedValue::Array(vec![
+                    RkyvSerializedValue::Number(-1),
+                    RkyvSerializedValue::String("two".to_string()),
+                ])))
+            .build();
+        assert_eq!(from_msgpack(&to_msgpack(&value).unwrap()).unwrap(), value);
+
+        let err = to_msgpack(&RkyvSerializedValue::Array(vec![RkyvSerializedValue::StreamPointer(0)])).unwrap_err();
+        assert!(matches!(err, MsgpackError::Unencodable("StreamPointer")));
+        assert!(to_msgpack(&RkyvSerializedValue::FunctionPointer(0, "f".to_string())).is_err());
+    }
 }
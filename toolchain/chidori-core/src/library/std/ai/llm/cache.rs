@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::library::std::ai::llm::single_flight::InFlightRequests;
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionRes, ChatModelBatch, LlmError};
+
+/// Store of responses of chat models, keyed by `request_key` of the request they responded to.
+/// Registered on an `ExecutionState`, a request with a stored response is answered from the
+/// cache rather than by the model. Implement this to share responses across processes, such as
+/// through Redis.
+#[async_trait]
+pub trait ResponseCache: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<ChatCompletionRes>, String>;
+
+    async fn set(&self, key: &str, response: &ChatCompletionRes) -> Result<(), String>;
+}
+
+/// Responses held for the lifetime of the process.
+#[derive(Default)]
+pub struct InMemoryResponseCache {
+    responses: Mutex<HashMap<String, ChatCompletionRes>>,
+}
+
+#[async_trait]
+impl ResponseCache for InMemoryResponseCache {
+    async fn get(&self, key: &str) -> Result<Option<ChatCompletionRes>, String> {
+        Ok(self.responses.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, response: &ChatCompletionRes) -> Result<(), String> {
+        self.responses.lock().unwrap().insert(key.to_string(), response.clone());
+        Ok(())
+    }
+}
+
+/// Responses stored as JSON files within a directory, one per key, persisting across runs.
+pub struct DiskResponseCache {
+    dir: PathBuf,
+}
+
+impl DiskResponseCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+#[async_trait]
+impl ResponseCache for DiskResponseCache {
+    async fn get(&self, key: &str) -> Result<Option<ChatCompletionRes>, String> {
+        let path = self.path(key);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    async fn set(&self, key: &str, response: &ChatCompletionRes) -> Result<(), String> {
+        let path = self.path(key);
+        let contents = serde_json::to_vec(response).map_err(|e| e.to_string())?;
+        tokio::fs::create_dir_all(&self.dir).await
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        tokio::fs::write(&path, contents).await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+/// Hex encoded hash of a request as it would be sent to the model, along with the provider and
+/// endpoint it is sent to, the key responses are cached and shared under.
+pub fn request_key(provider: &str, endpoint: Option<&str>, chat_completion_req: &ChatCompletionReq) -> Result<String, String> {
+    let rendered = serde_json::to_vec(&(provider, endpoint, chat_completion_req)).map_err(|e| e.to_string())?;
+    Ok(Sha1::digest(&rendered).iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Answer a request from `cache` when it holds a response to it, otherwise send it through
+/// `in_flight` and store a successful response in `cache`. Reports whether the response was
+/// obtained without a call of this request's own. A cache that fails is bypassed rather than
+/// failing the request.
+///
+/// Without `lookup` the request is sent regardless of the cache, and its response replaces any
+/// cached one, as when retrying a cell whose previous response failed validation.
+pub async fn batch_through_cache(
+    cache: Option<&Arc<dyn ResponseCache>>,
+    lookup: bool,
+    in_flight: &InFlightRequests,
+    model: Arc<dyn ChatModelBatch + Send + Sync>,
+    provider: &str,
+    chat_completion_req: ChatCompletionReq,
+) -> (Result<ChatCompletionRes, LlmError>, bool) {
+    let Some(cache) = cache else {
+        return in_flight.batch_shared(model, provider, chat_completion_req).await;
+    };
+    let key = match request_key(provider, model.endpoint().as_deref(), &chat_completion_req) {
+        Ok(key) => key,
+        Err(e) => return (Err(LlmError::InvalidRequest(e)), false),
+    };
+    if lookup {
+        match cache.get(&key).await {
+            Ok(Some(response)) => return (Ok(response), true),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read cached response {}: {}", key, e),
+        }
+    }
+    let (result, shared) = in_flight.batch_shared(model, provider, chat_completion_req).await;
+    if let (Ok(response), false) = (&result, shared) {
+        if let Err(e) = cache.set(&key, response).await {
+            warn!("Failed to cache response {}: {}", key, e);
+        }
+    }
+    (result, shared)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::library::std::ai::llm::{ChatCompletionChoice, MessageRole, TemplateMessage, Usage};
+
+    /// A cache of the kind an application would register, counting its reads and writes.
+    #[derive(Default)]
+    struct CountingCache {
+        responses: Mutex<HashMap<String, ChatCompletionRes>>,
+        gets: AtomicUsize,
+        sets: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ResponseCache for CountingCache {
+        async fn get(&self, key: &str) -> Result<Option<ChatCompletionRes>, String> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            Ok(self.responses.lock().unwrap().get(key).cloned())
+        }
+
+        async fn set(&self, key: &str, response: &ChatCompletionRes) -> Result<(), String> {
+            self.sets.fetch_add(1, Ordering::SeqCst);
+            self.responses.lock().unwrap().insert(key.to_string(), response.clone());
+            Ok(())
+        }
+    }
+
+    struct CountingModel(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl ChatModelBatch for CountingModel {
+        async fn batch(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(response(&chat_completion_req.template_messages[0].content.to_uppercase()))
+        }
+    }
+
+    fn response(text: &str) -> ChatCompletionRes {
+        ChatCompletionRes {
+            id: "res".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: String::new(),
+            choices: vec![ChatCompletionChoice {
+                text: Some(text.to_string()),
+                index: 0,
+                logprobs: None,
+                finish_reason: "stop".to_string(),
+                tool_calls: None,
+            }],
+            usage: Usage::default(),
+        }
+    }
+
+    fn request(content: &str) -> ChatCompletionReq {
+        ChatCompletionReq {
+            template_messages: vec![TemplateMessage {
+                role: MessageRole::User,
+                content: content.to_string(),
+                name: None,
+                function_call: None,
+            }],
+            ..ChatCompletionReq::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_cache_round_trips_responses() {
+        let cache = CountingCache::default();
+        let key = request_key("openai", None, &request("hello")).unwrap();
+        assert!(cache.get(&key).await.unwrap().is_none());
+        cache.set(&key, &response("HELLO")).await.unwrap();
+        let cached = cache.get(&key).await.unwrap().unwrap();
+        assert_eq!(cached.choices[0].text.as_deref(), Some("HELLO"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_answered_from_the_registered_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model: Arc<dyn ChatModelBatch + Send + Sync> = Arc::new(CountingModel(calls.clone()));
+        let counting = Arc::new(CountingCache::default());
+        let cache: Arc<dyn ResponseCache> = counting.clone();
+        let in_flight = InFlightRequests::default();
+
+        let (first, cached) = batch_through_cache(Some(&cache), true, &in_flight, model.clone(), "openai", request("hello")).await;
+        assert_eq!(first.unwrap().choices[0].text.as_deref(), Some("HELLO"));
+        assert!(!cached);
+        let (second, cached) = batch_through_cache(Some(&cache), true, &in_flight, model.clone(), "openai", request("hello")).await;
+        assert_eq!(second.unwrap().choices[0].text.as_deref(), Some("HELLO"));
+        assert!(cached);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(counting.gets.load(Ordering::SeqCst), 2);
+        assert_eq!(counting.sets.load(Ordering::SeqCst), 1);
+
+        // A different request is not answered by the cached response
+        let (other, _) = batch_through_cache(Some(&cache), true, &in_flight, model.clone(), "openai", request("bye")).await;
+        assert_eq!(other.unwrap().choices[0].text.as_deref(), Some("BYE"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Nor is the same request sent to another provider
+        let (_, cached) = batch_through_cache(Some(&cache), true, &in_flight, model.clone(), "gateway", request("hello")).await;
+        assert!(!cached);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A request sent without lookup reaches the model and replaces the cached response
+        let (_, cached) = batch_through_cache(Some(&cache), false, &in_flight, model.clone(), "openai", request("hello")).await;
+        assert!(!cached);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(counting.gets.load(Ordering::SeqCst), 4);
+        assert_eq!(counting.sets.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_request_key_distinguishes_providers_and_endpoints() {
+        let key = |provider, endpoint| request_key(provider, endpoint, &request("hello")).unwrap();
+        assert_eq!(key("openai", Some("https://api.openai.com/v1")), key("openai", Some("https://api.openai.com/v1")));
+        assert_ne!(key("openai", Some("https://api.openai.com/v1")), key("azure", Some("https://api.openai.com/v1")));
+        assert_ne!(key("openai", Some("https://api.openai.com/v1")), key("openai", Some("http://localhost:4000/v1")));
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_persists_responses() {
+        let dir = std::env::temp_dir().join(format!("chidori_response_cache_{}", uuid::Uuid::now_v7()));
+        let key = request_key("openai", None, &request("hello")).unwrap();
+        DiskResponseCache::new(dir.clone()).set(&key, &response("HELLO")).await.unwrap();
+        let cached = DiskResponseCache::new(dir.clone()).get(&key).await.unwrap().unwrap();
+        assert_eq!(cached.choices[0].text.as_deref(), Some("HELLO"));
+        assert!(DiskResponseCache::new(dir.clone()).get("missing").await.unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use crate::cells::{LLMPromptCellChatConfiguration, PromptExample};
+use crate::library::std::ai::llm::{MessageRole, TemplateMessage};
+
+/// The few-shot examples of a configuration, those declared in its frontmatter followed by those of
+/// its examples file. A relative examples file is resolved against `cwd`. Loaded once when a prompt
+/// cell is built, rather than on every execution of it.
+pub fn load_examples(configuration: &LLMPromptCellChatConfiguration, cwd: Option<&str>) -> anyhow::Result<Vec<PromptExample>> {
+    let mut examples = configuration.examples.clone().unwrap_or_default();
+    if let Some(examples_file) = &configuration.examples_file {
+        let path = match cwd {
+            Some(cwd) => Path::new(cwd).join(examples_file),
+            None => Path::new(examples_file).to_path_buf(),
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read examples file {:?}: {}", path, e))?;
+        // YAML is a superset of JSON, so either may be used
+        let from_file: Vec<PromptExample> = serde_yaml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid examples file {:?}: {}", path, e))?;
+        examples.extend(from_file);
+    }
+    Ok(examples)
+}
+
+/// Insert rendered examples, pairs of an input and an output, as alternating user and assistant
+/// messages ahead of the final user message, or after every message when there is none.
+pub fn insert_examples(mut messages: Vec<TemplateMessage>, examples: Vec<(String, String)>) -> Vec<TemplateMessage> {
+    let position = messages.iter().rposition(|m| m.role == MessageRole::User).unwrap_or(messages.len());
+    let example_messages = examples.into_iter().flat_map(|(input, output)| [
+        TemplateMessage { role: MessageRole::User, content: input, name: None, function_call: None },
+        TemplateMessage { role: MessageRole::Assistant, content: output, name: None, function_call: None },
+    ]);
+    messages.splice(position..position, example_messages);
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: MessageRole, content: &str) -> TemplateMessage {
+        TemplateMessage { role, content: content.to_string(), name: None, function_call: None }
+    }
+
+    #[test]
+    fn test_examples_are_inserted_before_the_final_user_message() {
+        let messages = vec![message(MessageRole::System, "Translate."), message(MessageRole::User, "cat")];
+        let examples = vec![("dog".to_string(), "chien".to_string())];
+        assert_eq!(insert_examples(messages, examples.clone()), vec![
+            message(MessageRole::System, "Translate."),
+            message(MessageRole::User, "dog"),
+            message(MessageRole::Assistant, "chien"),
+            message(MessageRole::User, "cat"),
+        ]);
+        assert_eq!(insert_examples(vec![message(MessageRole::System, "Translate.")], examples), vec![
+            message(MessageRole::System, "Translate."),
+            message(MessageRole::User, "dog"),
+            message(MessageRole::Assistant, "chien"),
+        ]);
+    }
+
+    #[test]
+    fn test_examples_are_loaded_from_frontmatter_and_file() {
+        let dir = std::env::temp_dir().join(format!("chidori_examples_{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("examples.json"), r#"[{"input": "bird", "output": "oiseau"}]"#).unwrap();
+        let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(
+            "examples:\n  - input: dog\n    output: chien\nexamples_file: examples.json"
+        ).unwrap();
+        let examples = load_examples(&configuration, dir.to_str()).unwrap();
+        assert_eq!(examples, vec![
+            PromptExample { input: "dog".to_string(), output: "chien".to_string() },
+            PromptExample { input: "bird".to_string(), output: "oiseau".to_string() },
+        ]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
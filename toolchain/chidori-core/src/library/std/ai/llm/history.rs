@@ -0,0 +1,165 @@
+use tracing::warn;
+
+use crate::cells::ContextTrimStrategy;
+use crate::library::std::ai::llm::{estimate_message_tokens, ChatCompletionReq, ChatCompletionRes, ChatModelBatch, LlmError, MessageRole, TemplateMessage};
+
+pub fn estimate_messages_tokens(messages: &[TemplateMessage]) -> usize {
+    messages.iter().map(estimate_message_tokens).sum()
+}
+
+/// Fit a list of messages to a token budget. System messages are always retained, as is the most
+/// recent message, the remaining messages are kept newest first until the budget is exhausted.
+/// With `SummarizeOldest` the messages that did not fit are replaced by a summary from `summarizer`
+/// using `summary_model`, falling back to dropping them if the summary can not be produced or does not fit.
+pub async fn fit_messages_to_budget<M: ChatModelBatch + Sync + ?Sized>(
+    messages: Vec<TemplateMessage>,
+    token_budget: usize,
+    strategy: &ContextTrimStrategy,
+    summarizer: Option<&M>,
+    summary_model: Option<String>,
+) -> Vec<TemplateMessage> {
+    if estimate_messages_tokens(&messages) <= token_budget {
+        return messages;
+    }
+
+    let (system_messages, conversation): (Vec<_>, Vec<_>) = messages
+        .into_iter()
+        .partition(|m| m.role == MessageRole::System);
+
+    let mut remaining = token_budget.saturating_sub(estimate_messages_tokens(&system_messages));
+    let mut split = conversation.len();
+    for (i, message) in conversation.iter().enumerate().rev() {
+        let cost = estimate_message_tokens(message);
+        let is_latest = i + 1 == conversation.len();
+        if cost > remaining && !is_latest {
+            break;
+        }
+        remaining = remaining.saturating_sub(cost);
+        split = i;
+    }
+    let (dropped, retained) = conversation.split_at(split);
+
+    let mut fitted = system_messages;
+    if !dropped.is_empty() && *strategy == ContextTrimStrategy::SummarizeOldest {
+        match summarizer {
+            Some(summarizer) => match summarize_messages(summarizer, summary_model, dropped).await {
+                Ok(summary) if estimate_message_tokens(&summary) <= remaining => fitted.push(summary),
+                Ok(_) => warn!("Summary of trimmed messages exceeds the remaining budget, dropping them"),
+                Err(e) => warn!("Failed to summarize trimmed messages, dropping them: {}", e),
+            },
+            None => warn!("No model available to summarize trimmed messages, dropping them"),
+        }
+    }
+    fitted.extend(retained.iter().cloned());
+    fitted
+}
+
+async fn summarize_messages<M: ChatModelBatch + Sync + ?Sized>(summarizer: &M, model: Option<String>, messages: &[TemplateMessage]) -> Result<TemplateMessage, String> {
+    let transcript = messages
+        .iter()
+        .map(|m| format!("{:?}: {}", m.role, m.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut request = ChatCompletionReq::default();
+    if model.is_some() {
+        request.config.model = model;
+    }
+    let ChatCompletionRes { choices, .. } = summarizer.batch(ChatCompletionReq {
+        template_messages: vec![
+            TemplateMessage {
+                role: MessageRole::System,
+                content: "Summarize the following conversation concisely, preserving facts and decisions needed to continue it.".to_string(),
+                name: None,
+                function_call: None,
+            },
+            TemplateMessage {
+                role: MessageRole::User,
+                content: transcript,
+                name: None,
+                function_call: None,
+            },
+        ],
+        ..request
+    }).await.map_err(|e| e.to_string())?;
+    let summary = choices
+        .into_iter()
+        .find_map(|c| c.text)
+        .ok_or_else(|| "Summary response contained no text".to_string())?;
+    Ok(TemplateMessage {
+        role: MessageRole::System,
+        content: format!("Summary of the earlier conversation: {}", summary),
+        name: None,
+        function_call: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::library::std::ai::llm::{ChatCompletionChoice, Usage};
+
+    fn message(role: MessageRole, content: String) -> TemplateMessage {
+        TemplateMessage { role, content, name: None, function_call: None }
+    }
+
+    fn twenty_turn_history() -> Vec<TemplateMessage> {
+        let mut messages = vec![message(MessageRole::System, "You are a helpful assistant.".to_string())];
+        for turn in 0..20 {
+            messages.push(message(MessageRole::User, format!("Question number {} from the user", turn)));
+            messages.push(message(MessageRole::Assistant, format!("Answer number {} from the assistant", turn)));
+        }
+        messages
+    }
+
+    struct SummaryModel;
+
+    #[async_trait]
+    impl ChatModelBatch for SummaryModel {
+        async fn batch(&self, _chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+            Ok(ChatCompletionRes {
+                id: "".to_string(),
+                object: "".to_string(),
+                created: 0,
+                model: "".to_string(),
+                choices: vec![ChatCompletionChoice {
+                    text: Some("earlier questions".to_string()),
+                    index: 0,
+                    logprobs: None,
+                    finish_reason: "stop".to_string(),
+                    tool_calls: None,
+                }],
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_system_and_recent_turns() {
+        let history = twenty_turn_history();
+        let fitted = fit_messages_to_budget::<SummaryModel>(history.clone(), 60, &ContextTrimStrategy::DropOldest, None, None).await;
+
+        assert!(estimate_messages_tokens(&fitted) <= 60);
+        assert_eq!(fitted[0], history[0]);
+        assert!(fitted.len() > 2);
+        // The retained turns are the most recent ones, in order
+        assert_eq!(&fitted[1..], &history[history.len() - (fitted.len() - 1)..]);
+    }
+
+    #[tokio::test]
+    async fn test_within_budget_is_unchanged() {
+        let history = twenty_turn_history();
+        let fitted = fit_messages_to_budget::<SummaryModel>(history.clone(), 10_000, &ContextTrimStrategy::DropOldest, None, None).await;
+        assert_eq!(fitted, history);
+    }
+
+    #[tokio::test]
+    async fn test_summarize_oldest_inserts_summary() {
+        let history = twenty_turn_history();
+        let fitted = fit_messages_to_budget(history.clone(), 80, &ContextTrimStrategy::SummarizeOldest, Some(&SummaryModel), None).await;
+        assert_eq!(fitted[0], history[0]);
+        assert_eq!(fitted[1].role, MessageRole::System);
+        assert!(fitted[1].content.contains("earlier questions"));
+        assert_eq!(fitted.last(), history.last());
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::execution::primitives::operation::CONTENT_FILTER_FINISH_REASON;
+use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+use crate::library::std::ai::llm::history::estimate_messages_tokens;
+use crate::library::std::ai::llm::{ChatCompletionChoice, ChatCompletionReq, ChatCompletionRes, ChatCompletionToolCall, ChatCompletionToolCallFunction, ChatModelBatch, LlmError, MessageRole, Usage};
+
+/// Selects the requests an expectation of the MockChatModel responds to.
+#[derive(Clone)]
+pub enum RequestMatcher {
+    Any,
+    /// Matches when the content of the last user message contains the given text.
+    LastUserMessageContains(String),
+    Custom {
+        description: String,
+        predicate: Arc<dyn Fn(&ChatCompletionReq) -> bool + Send + Sync>,
+    },
+}
+
+impl RequestMatcher {
+    pub fn custom(description: &str, predicate: impl Fn(&ChatCompletionReq) -> bool + Send + Sync + 'static) -> Self {
+        RequestMatcher::Custom {
+            description: description.to_string(),
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    fn matches(&self, req: &ChatCompletionReq) -> bool {
+        match self {
+            RequestMatcher::Any => true,
+            RequestMatcher::LastUserMessageContains(text) => last_user_message(req)
+                .map(|content| content.contains(text.as_str()))
+                .unwrap_or(false),
+            RequestMatcher::Custom { predicate, .. } => predicate(req),
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            RequestMatcher::Any => "any request".to_string(),
+            RequestMatcher::LastUserMessageContains(text) => format!("last user message containing {:?}", text),
+            RequestMatcher::Custom { description, .. } => description.clone(),
+        }
+    }
+}
+
+fn last_user_message(req: &ChatCompletionReq) -> Option<&str> {
+    req.template_messages
+        .iter()
+        .rev()
+        .find(|m| m.role == MessageRole::User)
+        .map(|m| m.content.as_str())
+}
+
+/// Cut a canned response down to the requested max_tokens, at roughly four characters per token,
+/// reporting "length" as the finish reason when it did not fit.
+fn truncate_to_max_tokens(text: &str, max_tokens: Option<i64>) -> (String, &'static str) {
+    match max_tokens {
+        Some(max_tokens) if text.chars().count() > max_tokens.max(0) as usize * 4 => {
+            (text.chars().take(max_tokens.max(0) as usize * 4).collect(), "length")
+        }
+        _ => (text.to_string(), "stop"),
+    }
+}
+
+enum MockResponse {
+    Text(String),
+    ToolCall { name: String, arguments: RkyvSerializedValue },
+    ContentFiltered,
+    Error(LlmError),
+}
+
+struct Expectation {
+    matcher: RequestMatcher,
+    response: MockResponse,
+    calls: AtomicUsize,
+}
+
+/// A ChatModelBatch that answers with canned responses rather than contacting a provider,
+/// for exercising cells in tests without network access. Expectations are checked in the
+/// order they were registered and the first matching one responds.
+///
+/// ```ignore
+/// let model = Arc::new(MockChatModel::builder()
+///     .respond_when(RequestMatcher::LastUserMessageContains("weather".to_string()), "It is sunny")
+///     .build());
+/// let state = ExecutionState::new_with_random_id().with_chat_model(model.clone());
+/// // ... execute cells
+/// model.assert_all_called();
+/// ```
+pub struct MockChatModel {
+    expectations: Vec<Expectation>,
+}
+
+#[derive(Default)]
+pub struct MockChatModelBuilder {
+    expectations: Vec<Expectation>,
+}
+
+impl MockChatModelBuilder {
+    /// Respond with the given text to requests matching `matcher`.
+    pub fn respond_when(mut self, matcher: RequestMatcher, response: &str) -> Self {
+        self.expectations.push(Expectation {
+            matcher,
+            response: MockResponse::Text(response.to_string()),
+            calls: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Fail requests matching `matcher` with the given error, as a provider error would.
+    pub fn fail_when(mut self, matcher: RequestMatcher, error: LlmError) -> Self {
+        self.expectations.push(Expectation {
+            matcher,
+            response: MockResponse::Error(error),
+            calls: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Respond to requests matching `matcher` as if the provider's content filter had blocked the response.
+    pub fn block_when(mut self, matcher: RequestMatcher) -> Self {
+        self.expectations.push(Expectation {
+            matcher,
+            response: MockResponse::ContentFiltered,
+            calls: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Respond to requests matching `matcher` by calling the tool `name` with `arguments`.
+    pub fn call_tool_when(mut self, matcher: RequestMatcher, name: &str, arguments: RkyvSerializedValue) -> Self {
+        self.expectations.push(Expectation {
+            matcher,
+            response: MockResponse::ToolCall { name: name.to_string(), arguments },
+            calls: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    pub fn build(self) -> MockChatModel {
+        MockChatModel { expectations: self.expectations }
+    }
+}
+
+impl MockChatModel {
+    pub fn builder() -> MockChatModelBuilder {
+        MockChatModelBuilder::default()
+    }
+
+    /// Number of requests answered by the expectation registered at `index`.
+    pub fn calls(&self, index: usize) -> usize {
+        self.expectations[index].calls.load(Ordering::SeqCst)
+    }
+
+    /// Panics naming every registered expectation that never matched a request.
+    pub fn assert_all_called(&self) {
+        let uncalled = self.expectations
+            .iter()
+            .filter(|e| e.calls.load(Ordering::SeqCst) == 0)
+            .map(|e| e.matcher.description())
+            .collect::<Vec<_>>();
+        assert!(uncalled.is_empty(), "MockChatModel expectations were never called: {:?}", uncalled);
+    }
+}
+
+#[async_trait]
+impl ChatModelBatch for MockChatModel {
+    async fn batch(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+        let Some(expectation) = self.expectations.iter().find(|e| e.matcher.matches(&chat_completion_req)) else {
+            return Err(LlmError::InvalidRequest(format!(
+                "MockChatModel has no expectation matching a request with last user message {:?}, expected one of: {:?}",
+                last_user_message(&chat_completion_req),
+                self.expectations.iter().map(|e| e.matcher.description()).collect::<Vec<_>>()
+            )));
+        };
+        expectation.calls.fetch_add(1, Ordering::SeqCst);
+        let choice = match &expectation.response {
+            MockResponse::Text(text) => {
+                let (text, finish_reason) = truncate_to_max_tokens(text, chat_completion_req.config.max_tokens);
+                ChatCompletionChoice {
+                    text: Some(text),
+                    index: 0,
+                    logprobs: None,
+                    finish_reason: finish_reason.to_string(),
+                    tool_calls: None,
+                }
+            },
+            MockResponse::ToolCall { name, arguments } => ChatCompletionChoice {
+                text: None,
+                index: 0,
+                logprobs: None,
+                finish_reason: "tool_calls".to_string(),
+                tool_calls: Some(vec![ChatCompletionToolCall {
+                    id: "mock_tool_call".to_string(),
+                    ty: "function".to_string(),
+                    function: ChatCompletionToolCallFunction {
+                        name: Some(name.clone()),
+                        arguments: Some(arguments.clone()),
+                    },
+                }]),
+            },
+            MockResponse::ContentFiltered => ChatCompletionChoice {
+                text: None,
+                index: 0,
+                logprobs: None,
+                finish_reason: CONTENT_FILTER_FINISH_REASON.to_string(),
+                tool_calls: None,
+            },
+            MockResponse::Error(error) => return Err(error.clone()),
+        };
+        // Usage is estimated so that callers accounting for tokens observe plausible, non-zero counts
+        let prompt_tokens = estimate_messages_tokens(&chat_completion_req.template_messages) as i32;
+        let completion_tokens = choice.text.as_ref().map(|text| (text.chars().count() / 4) as i32).unwrap_or(0);
+        Ok(ChatCompletionRes {
+            id: "mock".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: chat_completion_req.config.model.clone().unwrap_or_default(),
+            choices: vec![choice],
+            usage: Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::std::ai::llm::TemplateMessage;
+
+    fn request(user_message: &str) -> ChatCompletionReq {
+        ChatCompletionReq {
+            template_messages: vec![TemplateMessage {
+                role: MessageRole::User,
+                content: user_message.to_string(),
+                name: None,
+                function_call: None,
+            }],
+            ..ChatCompletionReq::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_matching_requests() {
+        let model = MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("weather".to_string()), "It is sunny")
+            .respond_when(RequestMatcher::Any, "fallback")
+            .build();
+        let res = model.batch(request("What is the weather?")).await.unwrap();
+        assert_eq!(res.choices[0].text.as_deref(), Some("It is sunny"));
+        let res = model.batch(request("Hello")).await.unwrap();
+        assert_eq!(res.choices[0].text.as_deref(), Some("fallback"));
+        assert_eq!(model.calls(0), 1);
+        model.assert_all_called();
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_request_is_a_descriptive_error() {
+        let model = MockChatModel::builder()
+            .respond_when(RequestMatcher::LastUserMessageContains("weather".to_string()), "It is sunny")
+            .build();
+        let err = model.batch(request("Hello")).await.unwrap_err().to_string();
+        assert!(err.contains("\"Hello\""), "{}", err);
+        assert!(err.contains("weather"), "{}", err);
+    }
+
+    #[test]
+    #[should_panic(expected = "never called")]
+    fn test_assert_all_called_reports_unused_expectations() {
+        MockChatModel::builder()
+            .respond_when(RequestMatcher::Any, "unused")
+            .build()
+            .assert_all_called();
+    }
+}
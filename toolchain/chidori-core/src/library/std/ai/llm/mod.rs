@@ -1,30 +1,65 @@
+pub mod cache;
+pub mod examples;
+pub mod history;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+pub mod models;
 pub mod openai;
+pub mod ordering;
+pub mod rate_limit;
+pub mod router;
+pub mod single_flight;
+pub mod tokenizer;
+pub mod usage;
+pub mod validation;
 
 use async_trait::async_trait;
-use futures_util::stream::Stream;
+use futures_util::stream::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use tracing::debug;
+use std::time::Duration;
+use tracing::{debug, Instrument};
 use uuid::Uuid;
-use chidori_prompt_format::templating::templates::{ChatModelRoles, TemplateWithSource};
-use crate::cells::{LLMCodeGenCellChatConfiguration, LLMPromptCellChatConfiguration, TextRange};
+use chidori_prompt_format::templating::templates::{ChatModelRoles, PromptLibraryRecord, TemplateWithSource};
+use chidori_prompt_format::templating::truncate::Tokenizer;
+use crate::cells::{LLMCodeGenCellChatConfiguration, LLMEmbeddingCellConfiguration, LLMPromptCellChatConfiguration, TextRange};
 use crate::execution::execution::execution_state::ExecutionStateErrors;
 use crate::execution::execution::ExecutionState;
-use crate::execution::primitives::operation::InputSignature;
+use crate::execution::execution::progress::ProgressEvent;
+use crate::execution::primitives::operation::{InputSignature, CONTENT_FILTER_FINISH_REASON, FINISH_REASON_METADATA_KEY, CACHE_HIT_METADATA_KEY, COMPLETION_TOKENS_METADATA_KEY, MODEL_METADATA_KEY, PROMPT_TOKENS_METADATA_KEY, PROVIDER_METADATA_KEY, RENDERED_MESSAGES_METADATA_KEY, SOURCES_METADATA_KEY, TOTAL_TOKENS_METADATA_KEY};
 use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue, serialized_value_to_json_value};
 use crate::library::std::ai::llm::openai::OpenAIChatModel;
+use crate::library::std::ai::llm::rate_limit::RateLimitedChatModel;
+use crate::sdk::config::{ProviderConfiguration, OPENAI_PROVIDER};
 use crate::sdk::md::interpret_markdown_code_block;
+use crate::sdk::secrets::SecretError;
 
-#[derive(Debug)]
-pub enum LLMErrors {
-    ConnectionError(String),
+/// Failures talking to a model provider, categorized so that callers can tell the user what to fix.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum LlmError {
+    #[error("Provider rejected the credentials ({status}): {message}")]
+    Authentication { status: u16, message: String },
+    #[error("Could not reach the provider: {0}")]
+    Network(String),
+    #[error("Provider is rate limiting requests: {0}")]
+    RateLimited(String),
+    #[error("Provider responded with {status}: {message}")]
+    Provider { status: u16, message: String },
+    #[error("No response received from the provider within {0:?}")]
+    Timeout(Duration),
+    #[error("Could not obtain the provider credentials: {0}")]
+    Credentials(#[from] SecretError),
+    #[error("Provider sent malformed arguments for tool {name}: {message}")]
+    MalformedToolArguments { name: String, message: String },
+    #[error("Request could not be sent to the provider: {0}")]
+    InvalidRequest(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -41,14 +76,47 @@ impl Default for Usage {
     }
 }
 
+/// An item of a streamed chat completion.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LLMStreamItem {
+    /// The content of the response received since the previous content item.
+    Content(String),
+    /// A tool call, emitted once its arguments have been received in full.
+    ToolCall(ChatCompletionToolCall),
+    /// The reason the model stopped responding, following the content and tool calls of the response.
+    FinishReason(String),
+}
+
+/// A streamed chat completion.
+pub type ChatCompletionStream = Pin<Box<dyn Stream<Item = Result<LLMStreamItem, LlmError>> + Send>>;
+
+/// A tool call whose arguments are still being received.
+#[derive(Default)]
+struct PartialToolCall {
+    id: String,
+    name: Option<String>,
+    arguments: String,
+}
+
 pub struct LLMStream {
     response: Pin<Box<dyn Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Send>>,
+    /// Content of the response received so far
     buffer: String,
-    first_chunk: bool,
+    /// Whether each chunk carries the content accumulated so far rather than a delta
+    cumulative_content: bool,
+    /// Bytes received after the last complete line of the response
+    pending_line: Vec<u8>,
+    /// Tool calls being assembled, by their index in the response
+    tool_calls: BTreeMap<u64, PartialToolCall>,
+    /// Items parsed from the response that have yet to be yielded
+    ready: VecDeque<Result<LLMStreamItem, LlmError>>,
     usage: Usage,
+    /// Maximum time to wait between chunks, and the deadline for the next chunk
+    idle_timeout: Option<(Duration, Pin<Box<tokio::time::Sleep>>)>,
+    finished: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum MessageRole {
     User,
     System,
@@ -56,13 +124,18 @@ pub enum MessageRole {
     Function,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A call of a tool by the model. Carried by the assistant message that made the call, and by the
+/// function message holding its result so that the result can be linked back to the call.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FunctionCall {
+    /// Identifier the model assigned to the call, absent for the legacy function calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub name: Option<String>,
     pub arguments: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TemplateMessage {
     pub role: MessageRole,
     pub content: String,
@@ -70,6 +143,15 @@ pub struct TemplateMessage {
     pub function_call: Option<FunctionCall>,
 }
 
+/// A function the model called as a tool while producing its answer, recorded so that the
+/// answer can be attributed to the results it drew upon.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolCallSource {
+    pub name: String,
+    pub arguments: Value,
+    pub result: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Function {
     pub name: String,
@@ -123,48 +205,41 @@ pub struct ChatCompletionReq {
     pub config: LLMPromptCellChatConfiguration,
     pub template_messages: Vec<TemplateMessage>,
     pub tool_choice: Option< crate::library::std::ai::llm::ToolChoiceType >,
-    pub tools: Option<Vec< crate::library::std::ai::llm::Tool >>
+    pub tools: Option<Vec< crate::library::std::ai::llm::Tool >>,
+    /// Additional fields merged into the request body sent to OpenAI compatible providers,
+    /// these never override the explicitly modeled parameters unless they are absent.
+    pub extra: Value,
 }
 
 impl Default for ChatCompletionReq {
     fn default() -> Self {
         Self {
             config: LLMPromptCellChatConfiguration {
-                import: None,
-                function_name: None,
                 model: Some(String::from("gpt-3.5-turbo")),
-                api_url: None,
-                frequency_penalty: None,
-                max_tokens: None,
-                presence_penalty: None,
-                stop: None,
-                temperature: None,
-                logit_bias: None,
-                user: None,
-                seed: None,
-                top_p: None,
+                ..Default::default()
             },
             template_messages: Vec::new(),
             tool_choice: None,
             tools: None,
+            extra: Value::Null,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct ChatCompletionToolCallFunction {
     pub name: Option<String>,
     pub arguments: Option<RkyvSerializedValue>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct ChatCompletionToolCall {
     pub id: String,
     pub ty: String,
     pub function: ChatCompletionToolCallFunction
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionChoice {
     pub text: Option<String>,
     pub index: i32,
@@ -174,7 +249,7 @@ pub struct ChatCompletionChoice {
 }
 
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionRes {
     pub id: String,
     pub object: String,
@@ -186,8 +261,9 @@ pub struct ChatCompletionRes {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmbeddingReq {
-    content: String,
-    model: String,
+    pub content: String,
+    pub model: String,
+    pub dimensions: Option<u32>,
     frequency_penalty: Option<f32>,
     max_tokens: Option<i32>,
     presence_penalty: Option<f32>,
@@ -210,12 +286,77 @@ pub trait ChatModelBatch {
     async fn batch(
         &self,
         chat_completion_req: ChatCompletionReq,
-    ) -> Result<ChatCompletionRes, String>;
+    ) -> Result<ChatCompletionRes, LlmError>;
+
+    /// The endpoint requests are sent to, distinguishing the responses of models of the same name
+    /// served by different deployments. None for models that are not served over the network.
+    fn endpoint(&self) -> Option<String> {
+        None
+    }
+
+    /// Send the request, receiving the response as it is generated. Models that cannot stream
+    /// respond with the whole of the first choice of their batched response at once.
+    async fn stream(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionStream, LlmError> {
+        let response = self.batch(chat_completion_req).await?;
+        let mut items = vec![];
+        if let Some(choice) = response.choices.into_iter().next() {
+            items.extend(choice.text.filter(|text| !text.is_empty()).map(LLMStreamItem::Content));
+            items.extend(choice.tool_calls.into_iter().flatten().map(LLMStreamItem::ToolCall));
+            items.push(LLMStreamItem::FinishReason(choice.finish_reason));
+        }
+        Ok(futures_util::stream::iter(items.into_iter().map(Ok)).boxed())
+    }
 }
 
-#[async_trait]
-trait ChatModelStream {
-    async fn stream(&self, chat_completion_req: ChatCompletionReq) -> Result<LLMStream, String>;
+/// Report the content of a streamed chat completion to the observers of the execution state as it
+/// arrives, emitting a `TokenDelta` of the cell being evaluated for every content item.
+pub(crate) fn report_token_deltas(
+    execution_state: &ExecutionState,
+    stream: impl Stream<Item = Result<LLMStreamItem, LlmError>>,
+) -> impl Stream<Item = Result<LLMStreamItem, LlmError>> {
+    let progress_sender = execution_state.progress_sender.clone();
+    let operation_id = execution_state.evaluating_operation_id;
+    stream.inspect(move |item| {
+        if let (Some(sender), Ok(LLMStreamItem::Content(delta))) = (&progress_sender, item) {
+            sender.emit(ProgressEvent::TokenDelta { operation_id, delta: delta.clone() });
+        }
+    })
+}
+
+/// Stream a chat completion, reporting its content as it arrives, and assemble the items of the
+/// stream into a response of a single choice. Streams do not report the tokens they consume.
+async fn stream_chat_completion(
+    execution_state: &ExecutionState,
+    model: &(dyn ChatModelBatch + Send + Sync),
+    chat_completion_req: ChatCompletionReq,
+) -> Result<ChatCompletionRes, LlmError> {
+    let model_name = chat_completion_req.config.model.clone().unwrap_or_default();
+    let stream = model.stream(chat_completion_req).await?;
+    let mut stream = Box::pin(report_token_deltas(execution_state, stream));
+    let mut text = String::new();
+    let mut tool_calls = vec![];
+    let mut finish_reason = String::new();
+    while let Some(item) = stream.next().await {
+        match item? {
+            LLMStreamItem::Content(delta) => text.push_str(&delta),
+            LLMStreamItem::ToolCall(tool_call) => tool_calls.push(tool_call),
+            LLMStreamItem::FinishReason(reason) => finish_reason = reason,
+        }
+    }
+    Ok(ChatCompletionRes {
+        id: String::new(),
+        object: "chat.completion".to_string(),
+        created: 0,
+        model: model_name,
+        choices: vec![ChatCompletionChoice {
+            text: Some(text),
+            index: 0,
+            logprobs: None,
+            finish_reason,
+            tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
+        }],
+        usage: Usage::default(),
+    })
 }
 
 #[async_trait]
@@ -228,7 +369,7 @@ trait CompletionModel {
 }
 
 #[async_trait]
-trait EmbeddingModel {
+pub trait EmbeddingModel {
     async fn embed(&self, embedding_req: EmbeddingReq) -> Result<Vec<f32>, String>;
 }
 
@@ -244,38 +385,90 @@ pub async fn ai_llm_run_completion_model(
 }
 
 
+/// Embedding model used by embedding cells that do not name one.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Whether an embedding model can be asked for vectors of the given length. Models known not to
+/// support shortening their embeddings, or asked for more dimensions than they produce, are
+/// rejected before any request is made.
+fn validate_embedding_dimensions(model: &str, dimensions: u32) -> Result<(), String> {
+    let max_dimensions = match model {
+        "text-embedding-3-small" => 1536,
+        "text-embedding-3-large" => 3072,
+        "text-embedding-ada-002" => {
+            return Err(format!("Embedding model {} does not support requesting {} dimensions", model, dimensions));
+        }
+        _ => return Ok(()),
+    };
+    if dimensions == 0 || dimensions > max_dimensions {
+        return Err(format!("Embedding model {} supports between 1 and {} dimensions, {} were requested", model, max_dimensions, dimensions));
+    }
+    Ok(())
+}
+
+/// Embed `content` with the model and dimensions of an embedding cell's configuration, failing
+/// when the returned vector is not of the requested length.
+async fn embed_content(
+    model: &(dyn EmbeddingModel + Sync),
+    content: String,
+    configuration: &LLMEmbeddingCellConfiguration,
+) -> Result<Vec<f32>, String> {
+    let model_name = configuration.model.clone().unwrap_or(DEFAULT_EMBEDDING_MODEL.to_string());
+    if let Some(dimensions) = configuration.dimensions {
+        validate_embedding_dimensions(&model_name, dimensions)?;
+    }
+    let embedding = model.embed(EmbeddingReq {
+        content,
+        model: model_name.clone(),
+        dimensions: configuration.dimensions,
+        frequency_penalty: None,
+        max_tokens: None,
+        presence_penalty: None,
+        stop: None,
+    }).await?;
+    match configuration.dimensions {
+        Some(dimensions) if embedding.len() != dimensions as usize => Err(format!(
+            "Embedding model {} returned a vector of {} dimensions, {} were requested", model_name, embedding.len(), dimensions
+        )),
+        _ => Ok(embedding),
+    }
+}
+
+/// Embed the rendered `template` of an embedding cell, with the embedding model of the execution
+/// state when one is set, otherwise with the configured OpenAI provider.
 pub async fn ai_llm_run_embedding_model(
     execution_state: &ExecutionState,
     payload: RkyvSerializedValue,
-    template: TemplateWithSource,
+    template: &str,
     name: Option<String>,
     is_function_invocation: bool,
-) -> RkyvSerializedValue {
-    let api_key = env::var("OPENAI_API_KEY").unwrap().to_string();
-    let api_url_v1: &str = "https://api.openai.com/v1";
-    let model = OpenAIChatModel::new(api_url_v1.to_string(), api_key);
+    configuration: &LLMEmbeddingCellConfiguration,
+) -> Result<RkyvSerializedValue, ExecutionStateErrors> {
+    let model: Arc<dyn EmbeddingModel + Send + Sync> = match &execution_state.embedding_model {
+        Some(embedding_model) => embedding_model.clone(),
+        None => {
+            let provider = execution_state.provider_configuration(OPENAI_PROVIDER).await
+                .map_err(|e| ExecutionStateErrors::Unknown(e.to_string()))?;
+            Arc::new(OpenAIChatModel::from_provider_configuration(&provider, None))
+        }
+    };
     let data = template_data_payload_from_rkyv(&payload);
-    let result = model.embed(EmbeddingReq {
-        content: chidori_prompt_format::templating::templates::render_template_prompt(&template.source, &data, &HashMap::new()).unwrap(),
-        model: "text-embedding-3-small".to_string(),
-        frequency_penalty: None,
-        max_tokens: None,
-        presence_penalty: None,
-        stop: None,
-    }).await;
-    if let Ok(result) = result {
-        // if invoked as a function don't nest the result in a named key, return the response as a direct string
-        let mut result_map = HashMap::new();
-        if !is_function_invocation {
-            if let Some(name) = &name {
-                result_map.insert(name.clone(), RkyvSerializedValue::Array(result.iter().map(|v| RkyvSerializedValue::Float(*v)).collect()));
-                return RkyvSerializedValue::Object(result_map);
-            }
+    let partials = execution_state.partials()
+        .map_err(|e| ExecutionStateErrors::Unknown(e.to_string()))?;
+    let template_tokenizer = tokenizer::template_tokenizer(configuration.model.as_deref())
+        .map_err(|e| ExecutionStateErrors::Unknown(e.to_string()))?;
+    let content = execution_state.compiled_templates.render(template, &data, &partials, Some(&template_tokenizer))?;
+    let embedding = embed_content(model.as_ref(), content, configuration).await
+        .map_err(ExecutionStateErrors::Unknown)?;
+    let embedding = RkyvSerializedValue::Array(embedding.iter().map(|v| RkyvSerializedValue::Float(*v as f64)).collect());
+
+    // if invoked as a function don't nest the result in a named key, return the response as a direct string
+    if !is_function_invocation {
+        if let Some(name) = &name {
+            return Ok(RkyvObjectBuilder::new().insert_value(name, embedding).build());
         }
-        RkyvSerializedValue::Array(result.iter().map(|v| RkyvSerializedValue::Float(*v)).collect())
-    } else {
-        RkyvSerializedValue::Null
     }
+    Ok(embedding)
 }
 
 fn input_signature_to_json_properties(input_signature: InputSignature) -> HashMap<String, Box<JSONSchemaDefine>> {
@@ -313,17 +506,125 @@ fn input_signature_to_json_properties(input_signature: InputSignature) -> HashMa
     properties
 }
 
+/// Rough token estimate for a message, roughly four characters per token plus per-message overhead.
+pub(crate) fn estimate_message_tokens(message: &TemplateMessage) -> usize {
+    message.content.len() / 4 + 4
+}
+
+/// Retain the most recent messages of a conversation history that fit within the token budget,
+/// dropping the oldest messages first.
+pub fn trim_conversation_history(history: &[TemplateMessage], token_budget: usize) -> Vec<TemplateMessage> {
+    let mut total = 0;
+    let mut retained = vec![];
+    for message in history.iter().rev() {
+        total += estimate_message_tokens(message);
+        if total > token_budget {
+            break;
+        }
+        retained.push(message.clone());
+    }
+    retained.reverse();
+    retained
+}
+
+/// Assemble the messages sent for a turn of a conversation, the system messages of the template
+/// come first, followed by the prior exchanges and then the newly rendered turn.
+fn prepare_conversation_messages(
+    execution_state: &ExecutionState,
+    conversation_id: &str,
+    template_messages: Vec<TemplateMessage>,
+) -> Vec<TemplateMessage> {
+    let (system_messages, turn_messages): (Vec<_>, Vec<_>) = template_messages
+        .into_iter()
+        .partition(|m| m.role == MessageRole::System);
+    let history = execution_state.conversation_histories
+        .get(conversation_id)
+        .cloned()
+        .unwrap_or_default();
+    system_messages.into_iter()
+        .chain(history)
+        .chain(turn_messages)
+        .collect()
+}
+
+/// Append the exchange of this turn to the conversation history on the execution state. Only the
+/// turn rendered from the template is recorded, system messages are re-rendered from the template
+/// on every execution and examples are inserted into every request.
+fn record_conversation_turn(
+    execution_state: &mut ExecutionState,
+    conversation_id: &str,
+    turn_messages: &[TemplateMessage],
+    response: &str,
+    token_budget: Option<usize>,
+) {
+    let mut history = execution_state.conversation_histories
+        .get(conversation_id)
+        .cloned()
+        .unwrap_or_default();
+    history.extend(turn_messages.iter().filter(|m| m.role != MessageRole::System).cloned());
+    history.push(TemplateMessage {
+        role: MessageRole::Assistant,
+        content: response.to_string(),
+        name: None,
+        function_call: None,
+    });
+    if let Some(budget) = token_budget {
+        history = trim_conversation_history(&history, budget);
+    }
+    execution_state.conversation_histories.insert(conversation_id.to_string(), history);
+}
+
+/// The chat model for a cell to use, the execution state's substitute when one is set otherwise the provider,
+/// held to the provider's `requests_per_minute` when it configures one.
+fn chat_model(execution_state: &ExecutionState, provider_name: &str, provider: &ProviderConfiguration, api_url: Option<String>) -> Arc<dyn ChatModelBatch + Send + Sync> {
+    let model: Arc<dyn ChatModelBatch + Send + Sync> = match &execution_state.chat_model {
+        Some(chat_model) => chat_model.clone(),
+        None => Arc::new(OpenAIChatModel::from_provider_configuration(provider, api_url)),
+    };
+    match provider.requests_per_minute {
+        Some(requests_per_minute) => Arc::new(RateLimitedChatModel::new(
+            model,
+            execution_state.rate_limiters.limiter(provider_name, requests_per_minute),
+        )),
+        None => model,
+    }
+}
+
+/// The end-user identifier to attach to a request, a cell's own configuration takes precedence over the run's identity.
+fn request_user(execution_state: &ExecutionState, cell_user: &Option<String>) -> Option<String> {
+    cell_user.clone().or_else(|| execution_state.user.clone())
+}
+
+/// Render the template of a role block, normalizing its whitespace when the cell asks for it.
+fn render_role_block(
+    execution_state: &ExecutionState,
+    source: &str,
+    data: &chidori_prompt_format::serde_json::Value,
+    partials: &HashMap<String, PromptLibraryRecord>,
+    template_tokenizer: &Arc<dyn Tokenizer>,
+    normalize_whitespace: Option<bool>,
+) -> anyhow::Result<String> {
+    let content = execution_state.compiled_templates.render(source, data, partials, Some(template_tokenizer))?;
+    if normalize_whitespace.unwrap_or(false) {
+        return Ok(chidori_prompt_format::templating::templates::normalize_whitespace(&content));
+    }
+    Ok(content)
+}
+
 pub async fn ai_llm_run_chat_model(
     execution_state: &ExecutionState,
     payload: RkyvSerializedValue,
     role_blocks: Vec<(ChatModelRoles, Option<TemplateWithSource>)>,
     name: Option<String>,
     is_function_invocation: bool,
-    configuration: LLMPromptCellChatConfiguration
-) -> anyhow::Result<(Result<RkyvSerializedValue, ExecutionStateErrors>, Option<ExecutionState>)> {
+    configuration: LLMPromptCellChatConfiguration,
+    reuse_cached_responses: bool,
+) -> anyhow::Result<(Result<RkyvSerializedValue, ExecutionStateErrors>, Option<ExecutionState>, HashMap<String, String>)> {
     debug!("Executing ai_llm_run_chat_model");
     let mut template_messages: Vec<TemplateMessage> = Vec::new();
     let data = template_data_payload_from_rkyv(&payload);
+    let partials = execution_state.partials()?;
+    let template_tokenizer = tokenizer::template_tokenizer(configuration.model.as_deref())?;
 
     for (a, b) in &role_blocks.clone() {
         template_messages.push(TemplateMessage {
@@ -332,83 +633,225 @@ pub async fn ai_llm_run_chat_model(
                 ChatModelRoles::System => MessageRole::System,
                 ChatModelRoles::Assistant => MessageRole::Assistant,
             },
-            content: chidori_prompt_format::templating::templates::render_template_prompt(&b.as_ref().unwrap().source, &data, &HashMap::new()).unwrap(),
-            name: None,
+            content: render_role_block(execution_state, &b.as_ref().unwrap().source, &data, &partials, &template_tokenizer, configuration.normalize_whitespace)?,
+            name: b.as_ref().unwrap().name.clone(),
             function_call: None,
         });
     }
 
-    let tools = infer_tool_usage_from_imports(execution_state, &configuration.import);
+    // The turn recorded in the conversation history, before examples and prior turns are added
+    let turn_messages = template_messages.clone();
 
-    let api_url_v1 = configuration.api_url.clone();
-    let c = crate::library::std::ai::llm::openai::OpenAIChatModel::new(api_url_v1.unwrap_or("http://localhost:4000/v1".to_string()), "".to_string());
+    // The examples of the examples file were merged into the configuration when the cell was built
+    let examples = configuration.examples.iter()
+        .flatten()
+        .map(|example| Ok((
+            render_role_block(execution_state, &example.input, &data, &partials, &template_tokenizer, configuration.normalize_whitespace)?,
+            render_role_block(execution_state, &example.output, &data, &partials, &template_tokenizer, configuration.normalize_whitespace)?,
+        )))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    template_messages = examples::insert_examples(template_messages, examples);
 
-    let result = c.batch(ChatCompletionReq {
-        config: configuration.clone(),
-        template_messages,
-        tool_choice: None,
-        tools: if tools.is_empty() {
-            None
-        } else {
-            Some(tools)
-        },
-    }).await;
+    if let Some(conversation_id) = &configuration.conversation_id {
+        template_messages = prepare_conversation_messages(execution_state, conversation_id, template_messages);
+    }
+
+    let route = configuration.routes.as_deref()
+        .and_then(|routes| router::select_route(routes, &template_messages, &data));
+    let mut provider_name = route.and_then(|route| route.provider.as_deref()).unwrap_or(OPENAI_PROVIDER).to_string();
+    let mut provider = execution_state.provider_configuration(&provider_name).await?;
+    let mut c = chat_model(execution_state, &provider_name, &provider, configuration.api_url.clone());
+    let mut request_configuration = configuration.clone();
+    if let Some(route) = route {
+        request_configuration.model = Some(route.model.clone());
+    }
+    if request_configuration.model.is_none() {
+        request_configuration.model = provider.default_model.clone();
+    }
+    request_configuration.logit_bias = tokenizer::resolve_logit_bias(&configuration, request_configuration.model.as_deref())?;
+    let mut metadata = HashMap::from([(PROVIDER_METADATA_KEY.to_string(), provider_name.clone())]);
+    if let Some(model) = &request_configuration.model {
+        metadata.insert(MODEL_METADATA_KEY.to_string(), model.clone());
+    }
+    request_configuration.user = request_user(execution_state, &configuration.user);
 
-    if let Err(e) = result {
-        return Ok((Result::Err(ExecutionStateErrors::AnyhowError(e)), None))
+    // Leave room within the context window for the completion itself
+    if let Some(context_window) = configuration.context_window {
+        let budget = context_window.saturating_sub(configuration.max_tokens.unwrap_or(0).max(0) as usize);
+        template_messages = history::fit_messages_to_budget(
+            template_messages,
+            budget,
+            &configuration.context_trim_strategy.clone().unwrap_or_default(),
+            Some(c.as_ref()),
+            request_configuration.model.clone(),
+        ).await;
+    }
+    if configuration.include_rendered_messages.unwrap_or(false) {
+        metadata.insert(RENDERED_MESSAGES_METADATA_KEY.to_string(), serde_json::to_string(&template_messages)?);
     }
-    let Ok(ChatCompletionRes { choices, .. }) = result else { unreachable!() };
 
+    let tools = infer_tool_usage_from_imports(execution_state, &configuration.import);
+    let max_tool_rounds = configuration.max_tool_rounds.unwrap_or(DEFAULT_MAX_TOOL_ROUNDS);
 
+    // Tools the model calls are invoked and their results returned to it as function messages,
+    // until it responds without calling any further tools
     let execution_state_handle = Arc::new(Mutex::new(execution_state.clone()));
-    let mut results = vec![];
-    for choice in choices {
-        let mut result_map = HashMap::new();
-        match choice.tool_calls {
-            Some(tool_calls) => {
-                for tool_call in tool_calls {
-                    if let Some(function_name) = tool_call.function.name {
-                        let args = tool_call.function.arguments.unwrap_or(RkyvSerializedValue::Null);
-                        let args = RkyvObjectBuilder::new().insert_value("kwargs", args).build();
-
-
-                        let mut new_exec_state = {
-                            let mut exec_state = execution_state_handle.lock().unwrap();
-                            let exec_state_clone = exec_state.clone();
-                            exec_state_clone
-                        };
-                        let (dispatch_result, mut result_execution_state) = new_exec_state.dispatch(&function_name, args, None).await?;
-
-                        if !dispatch_result.is_ok() {
-                            return Ok((dispatch_result, Some(result_execution_state)));
-                        }
-
-                        let mut exec_state = execution_state_handle.lock().unwrap();
-                        std::mem::swap(&mut *exec_state, &mut result_execution_state);
-
-                        result_map.insert(function_name, dispatch_result.unwrap());
-                    }
+    let mut tool_rounds = 0;
+    let mut usage = Usage::default();
+    let mut cache_hit = false;
+    let mut fallbacks = configuration.fallbacks.iter().flatten();
+    let mut sources = vec![];
+    let choices = loop {
+        let request_span = tracing::info_span!(
+            "llm_request",
+            provider = provider_name.as_str(),
+            model = request_configuration.model.as_deref().unwrap_or_default(),
+            round = tool_rounds,
+            prompt_tokens = tracing::field::Empty,
+            completion_tokens = tracing::field::Empty,
+            total_tokens = tracing::field::Empty,
+            cache_hit = tracing::field::Empty,
+        );
+        let chat_completion_req = ChatCompletionReq {
+            config: request_configuration.clone(),
+            template_messages: ordering::order_messages(template_messages.clone(), &provider.message_ordering.unwrap_or_default()),
+            tool_choice: None,
+            tools: if tools.is_empty() {
+                None
+            } else {
+                Some(tools.clone())
+            },
+            extra: configuration.extra_value(),
+        };
+        // Streamed responses are reported as they arrive, and so are neither cached nor shared
+        let (result, shared) = if configuration.stream.unwrap_or(false) {
+            (stream_chat_completion(execution_state, c.as_ref(), chat_completion_req).instrument(request_span.clone()).await, false)
+        } else {
+            cache::batch_through_cache(execution_state.response_cache.as_ref(), reuse_cached_responses, &execution_state.in_flight_requests, c.clone(), &provider_name, chat_completion_req)
+                .instrument(request_span.clone())
+                .await
+        };
+
+        // Failures another model may not share move the cell on to its next fallback, which
+        // then serves the remaining rounds of the cell
+        if let Err(e) = &result {
+            if should_fall_back(e) {
+                if let Some(fallback) = fallbacks.next() {
+                    debug!("Falling back to {} after the request failed: {}", fallback.model, e);
+                    provider_name = fallback.provider.clone().unwrap_or_else(|| OPENAI_PROVIDER.to_string());
+                    provider = execution_state.provider_configuration(&provider_name).await?;
+                    c = chat_model(execution_state, &provider_name, &provider, None);
+                    request_configuration.model = Some(fallback.model.clone());
+                    // Literal text is biased by its tokens in the fallback model's tokenizer
+                    request_configuration.logit_bias = tokenizer::resolve_logit_bias(&configuration, request_configuration.model.as_deref())?;
+                    metadata.insert(PROVIDER_METADATA_KEY.to_string(), provider_name.clone());
+                    metadata.insert(MODEL_METADATA_KEY.to_string(), fallback.model.clone());
+                    continue;
                 }
-                let result = if is_function_invocation {
-                    RkyvSerializedValue::Object(result_map)
-                } else {
-                    RkyvObjectBuilder::new().insert_value(name.as_deref().unwrap(), RkyvSerializedValue::Object(result_map)).build()
-                };
-                results.push(result);
             }
-            None => {
-                let result = if is_function_invocation {
-                    RkyvSerializedValue::String(choice.text.as_ref().unwrap().clone())
+        }
+        if let Ok(response) = &result {
+            request_span.record("prompt_tokens", response.usage.prompt_tokens);
+            request_span.record("completion_tokens", response.usage.completion_tokens);
+            request_span.record("total_tokens", response.usage.total_tokens);
+            request_span.record("cache_hit", shared);
+        }
+
+        let mut choices = match result {
+            Ok(ChatCompletionRes { choices, usage: response_usage, .. }) => {
+                // The tokens of a shared response were consumed by the request it was shared from
+                if shared {
+                    cache_hit = true;
                 } else {
-                    let default_name = String::from("output");
-                    let name = name.as_ref().unwrap_or(&default_name);
-                    let text = choice.text.as_ref().unwrap().clone();
-                    result_map.insert(name.clone(), RkyvSerializedValue::String(text));
-                    RkyvSerializedValue::Object(result_map)
-                };
-                results.push(result)
+                    usage.prompt_tokens += response_usage.prompt_tokens;
+                    usage.completion_tokens += response_usage.completion_tokens;
+                    usage.total_tokens += response_usage.total_tokens;
+                }
+                choices
             }
+            Err(e) => return Ok((Result::Err(ExecutionStateErrors::AnyhowError(e.to_string())), None, metadata)),
+        };
+        let Some(tool_calls) = choices.first_mut()
+            .and_then(|choice| choice.tool_calls.take())
+            .filter(|tool_calls| !tool_calls.is_empty()) else {
+            break choices;
+        };
+        if tool_rounds >= max_tool_rounds {
+            return Ok((Result::Err(ExecutionStateErrors::Unknown(format!(
+                "The model was still calling tools after {} rounds", max_tool_rounds
+            ))), None, metadata));
         }
+        tool_rounds += 1;
+
+        for tool_call in tool_calls {
+            let Some(function_name) = tool_call.function.name else { continue; };
+            let arguments = tool_call.function.arguments.unwrap_or(RkyvSerializedValue::Null);
+            let span = tracing::info_span!("tool_call", round = tool_rounds, function = %function_name);
+            let content = invoke_tool(&execution_state_handle, &function_name, arguments.clone())
+                .instrument(span)
+                .await;
+            sources.push(ToolCallSource {
+                name: function_name.clone(),
+                arguments: serialized_value_to_json_value(&arguments),
+                result: content.clone(),
+            });
+            template_messages.push(TemplateMessage {
+                role: MessageRole::Assistant,
+                content: String::new(),
+                name: None,
+                function_call: Some(FunctionCall {
+                    id: Some(tool_call.id.clone()),
+                    name: Some(function_name.clone()),
+                    arguments: Some(serialized_value_to_json_value(&arguments).to_string()),
+                }),
+            });
+            template_messages.push(TemplateMessage {
+                role: MessageRole::Function,
+                content,
+                name: Some(function_name.clone()),
+                function_call: Some(FunctionCall {
+                    id: Some(tool_call.id),
+                    name: Some(function_name),
+                    arguments: None,
+                }),
+            });
+        }
+    };
+
+    metadata.insert(PROMPT_TOKENS_METADATA_KEY.to_string(), usage.prompt_tokens.to_string());
+    metadata.insert(COMPLETION_TOKENS_METADATA_KEY.to_string(), usage.completion_tokens.to_string());
+    metadata.insert(TOTAL_TOKENS_METADATA_KEY.to_string(), usage.total_tokens.to_string());
+    if cache_hit {
+        metadata.insert(CACHE_HIT_METADATA_KEY.to_string(), "true".to_string());
+    }
+    if !sources.is_empty() {
+        metadata.insert(SOURCES_METADATA_KEY.to_string(), serde_json::to_string(&sources)?);
+    }
+    if let Some(finish_reason) = choices.first()
+        .map(|choice| choice.finish_reason.clone())
+        .filter(|finish_reason| !finish_reason.is_empty()) {
+        metadata.insert(FINISH_REASON_METADATA_KEY.to_string(), finish_reason);
+    }
+    // A withheld response is distinguished from a model that responded with nothing
+    if metadata.get(FINISH_REASON_METADATA_KEY).map(String::as_str) == Some(CONTENT_FILTER_FINISH_REASON) {
+        return Ok((Result::Err(ExecutionStateErrors::BlockedByContentFilter), None, metadata));
+    }
+    // The conversation continues from the first choice
+    if let (Some(conversation_id), Some(choice)) = (&configuration.conversation_id, choices.first()) {
+        let mut exec_state = execution_state_handle.lock().unwrap();
+        let text = choice.text.clone().unwrap_or_default();
+        record_conversation_turn(&mut exec_state, conversation_id, &turn_messages, &text, configuration.conversation_token_budget);
+    }
+    let mut results = vec![];
+    for choice in choices {
+        let text = choice.text.unwrap_or_default();
+        let result = if is_function_invocation {
+            RkyvSerializedValue::String(text)
+        } else {
+            let name = name.clone().unwrap_or(String::from("output"));
+            RkyvObjectBuilder::new().insert_string(&name, text).build()
+        };
+        results.push(result)
     }
 
     let out = if results.len() == 1 {
@@ -417,7 +860,37 @@ pub async fn ai_llm_run_chat_model(
         RkyvSerializedValue::Array(results)
     };
     let mut exec_state = execution_state_handle.lock().unwrap().clone();
-    Ok((Ok(out), Some(exec_state)))
+    Ok((Ok(out), Some(exec_state), metadata))
+}
+
+/// Whether a failed request may succeed when sent to another model. Requests the provider rejected,
+/// other than for rate limiting, would be rejected by any model and are not sent to a fallback.
+fn should_fall_back(error: &LlmError) -> bool {
+    match *error {
+        LlmError::RateLimited(_) | LlmError::Network(_) | LlmError::Timeout(_) => true,
+        LlmError::Provider { status, .. } => status >= 500 || status == 408,
+        _ => false,
+    }
+}
+
+/// Upper bound on the rounds of tool calls a chat cell will service before giving up on a final answer.
+const DEFAULT_MAX_TOOL_ROUNDS: usize = 8;
+
+/// Invoke a function the model called as a tool, rendering its result or error as the content
+/// of the function message returned to the model.
+async fn invoke_tool(execution_state_handle: &Arc<Mutex<ExecutionState>>, function_name: &str, arguments: RkyvSerializedValue) -> String {
+    let execution_state = execution_state_handle.lock().unwrap().clone();
+    let args = RkyvObjectBuilder::new().insert_value("kwargs", arguments).build();
+    let content = match execution_state.dispatch(function_name, args, None).await {
+        Ok((Ok(value), new_execution_state)) => {
+            *execution_state_handle.lock().unwrap() = new_execution_state;
+            serialized_value_to_json_value(&value).to_string()
+        }
+        Ok((Err(e), _)) => format!("Error: {}", e),
+        Err(e) => format!("Error: {}", e),
+    };
+    tracing::info!(result = %content, "tool call completed");
+    content
 }
 
 pub async fn ai_llm_code_generation_chat_model(
@@ -428,71 +901,173 @@ pub async fn ai_llm_code_generation_chat_model(
     is_function_invocation: bool,
     configuration: LLMCodeGenCellChatConfiguration
 ) -> anyhow::Result<(RkyvSerializedValue, Option<ExecutionState>)> {
+    match ai_llm_generate_code(execution_state, &payload, &role_blocks, vec![], &configuration).await? {
+        Some(text) => {
+            let generated = GeneratedCode::from_response(&text, &configuration);
+            let new_execution_state = apply_generated_code(execution_state, &generated.source).await?;
+            Ok((generated.output(&configuration), Some(new_execution_state)))
+        }
+        None => Ok((RkyvSerializedValue::Null, None)),
+    }
+}
+
+/// Code produced by a code generation cell, along with the model's explanation of it when one
+/// was requested.
+pub struct GeneratedCode {
+    /// Markdown containing the generated code blocks, from which cells are added to the graph.
+    pub source: String,
+    pub code: String,
+    pub explanation: String,
+}
+
+impl GeneratedCode {
+    /// Interpret the text of a response. A structured response is read for its `code` and
+    /// `explanation`, otherwise the code is taken from the fenced blocks of the text, or the text
+    /// itself when it contains none, and the explanation is left empty.
+    pub fn from_response(text: &str, configuration: &LLMCodeGenCellChatConfiguration) -> Self {
+        if !configuration.explanation {
+            return GeneratedCode { source: text.to_string(), code: text.to_string(), explanation: String::new() };
+        }
+        let structured = serde_json::from_str::<Value>(text.trim()).ok().and_then(|value| {
+            let code = value.get("code")?.as_str()?.to_string();
+            let explanation = value.get("explanation").and_then(|e| e.as_str()).unwrap_or_default().to_string();
+            Some((code, explanation))
+        });
+        let (code, explanation) = structured.unwrap_or_else(|| {
+            let blocks = crate::sdk::md::extract_code_blocks(text);
+            let code = if blocks.is_empty() {
+                text.trim().to_string()
+            } else {
+                blocks.iter().map(|block| block.body.trim_end().to_string()).collect::<Vec<_>>().join("\n\n")
+            };
+            (code, String::new())
+        });
+        let language = configuration.language.clone().unwrap_or("python".to_string());
+        GeneratedCode {
+            source: format!("```{}\n{}\n```", language, code.trim_end()),
+            code,
+            explanation,
+        }
+    }
+
+    /// The output of the cell, the generated text or an object of the code and its explanation,
+    /// with the code also exposed under the cell's function name when it declares one.
+    pub fn output(&self, configuration: &LLMCodeGenCellChatConfiguration) -> RkyvSerializedValue {
+        if !configuration.explanation {
+            return RkyvSerializedValue::String(self.source.clone());
+        }
+        let mut output = RkyvObjectBuilder::new()
+            .insert_string("code", self.code.clone())
+            .insert_string("explanation", self.explanation.clone());
+        if let Some(function_name) = &configuration.function_name {
+            output = output.insert_string(function_name, self.code.clone());
+        }
+        output.build()
+    }
+}
+
+/// Response format requesting the code and its explanation as separate fields.
+fn code_with_explanation_response_format() -> Value {
+    serde_json::json!({
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": {
+                "name": "generated_code",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "code": {"type": "string", "description": "The source code, without markdown fences"},
+                        "explanation": {"type": "string", "description": "An explanation of the code"}
+                    },
+                    "required": ["code", "explanation"],
+                    "additionalProperties": false
+                }
+            }
+        }
+    })
+}
+
+/// Request code from the model for the given role blocks, followed by `feedback` messages such
+/// as the errors of previously generated code. None when the model produced no code.
+pub async fn ai_llm_generate_code(
+    execution_state: &ExecutionState,
+    payload: &RkyvSerializedValue,
+    role_blocks: &Vec<(ChatModelRoles, Option<TemplateWithSource>)>,
+    feedback: Vec<TemplateMessage>,
+    configuration: &LLMCodeGenCellChatConfiguration
+) -> anyhow::Result<Option<String>> {
     let mut template_messages: Vec<TemplateMessage> = Vec::new();
-    let data = template_data_payload_from_rkyv(&payload);
+    let data = template_data_payload_from_rkyv(payload);
+    let partials = execution_state.partials()?;
+    let template_tokenizer = tokenizer::template_tokenizer(configuration.model.as_deref())?;
 
-    for (a, b) in &role_blocks.clone() {
+    for (a, b) in role_blocks {
         template_messages.push(TemplateMessage {
             role: match a {
                 ChatModelRoles::User => MessageRole::User,
                 ChatModelRoles::System => MessageRole::System,
                 ChatModelRoles::Assistant => MessageRole::Assistant,
             },
-            content: chidori_prompt_format::templating::templates::render_template_prompt(&b.as_ref().unwrap().source, &data, &HashMap::new()).unwrap(),
-            name: None,
+            content: render_role_block(execution_state, &b.as_ref().unwrap().source, &data, &partials, &template_tokenizer, configuration.normalize_whitespace)?,
+            name: b.as_ref().unwrap().name.clone(),
             function_call: None,
         });
     }
+    template_messages.extend(feedback);
 
-    let api_url_v1 = configuration.api_url.unwrap_or("http://localhost:4000/v1".to_string());
-    let c = crate::library::std::ai::llm::openai::OpenAIChatModel::new(api_url_v1, "".to_string());
+    let provider = execution_state.provider_configuration(OPENAI_PROVIDER).await?;
+    let c = chat_model(execution_state, OPENAI_PROVIDER, &provider, configuration.api_url.clone());
 
-    let result = c.batch(ChatCompletionReq {
+    let (result, _) = cache::batch_through_cache(execution_state.response_cache.as_ref(), true, &execution_state.in_flight_requests, c.clone(), OPENAI_PROVIDER, ChatCompletionReq {
         config: LLMPromptCellChatConfiguration {
-            import: None,
-            function_name: None,
-            model: configuration.model.clone(),
-            api_url: None,
+            model: configuration.model.clone().or(provider.default_model.clone()),
             frequency_penalty: configuration.frequency_penalty.clone(),
             max_tokens: configuration.max_tokens.clone(),
             presence_penalty: configuration.presence_penalty.clone(),
             stop: configuration.stop.clone(),
             temperature: configuration.temperature.clone(),
             logit_bias: configuration.logit_bias.clone(),
-            user: configuration.user.clone(),
+            user: request_user(execution_state, &configuration.user),
             seed: configuration.seed.clone(),
             top_p: configuration.top_p.clone(),
+            ..Default::default()
         },
         template_messages,
         tool_choice: None,
         tools: None,
+        extra: if configuration.explanation {
+            code_with_explanation_response_format()
+        } else {
+            Value::Null
+        },
     }).await;
 
-
     if let Ok(ChatCompletionRes { choices, .. }) = result {
-        for choice in choices {
-            let text = choice.text.as_ref().unwrap().clone();
+        if let Some(text) = choices.into_iter().find_map(|choice| choice.text) {
             println!("Code generation cell run, returning this payload: {}", &text);
-            let mut new_execution_state = execution_state.clone();
-
-            let mut cells = vec![];
-            crate::sdk::md::extract_code_blocks(&text)
-                .iter()
-                .filter_map(|block| interpret_markdown_code_block(block, None).unwrap())
-                .for_each(|block| { cells.push(block); });
-            cells.sort();
-
-            for cell in cells {
-                let (s, _) = new_execution_state.update_operation(cell, Uuid::now_v7()).await?;
-                new_execution_state = s;
-            }
-
-            return Ok((RkyvSerializedValue::String(text.clone()), Some(new_execution_state)));
+            return Ok(Some(text));
         }
-        Ok((RkyvSerializedValue::Null, None))
-    } else {
-        Ok((RkyvSerializedValue::Null, None))
     }
+    Ok(None)
+}
+
+/// Add the cells contained in generated code to a new revision of the execution state.
+pub async fn apply_generated_code(execution_state: &ExecutionState, text: &str) -> anyhow::Result<ExecutionState> {
+    let mut new_execution_state = execution_state.clone();
+
+    let mut cells = vec![];
+    crate::sdk::md::extract_code_blocks(text)
+        .iter()
+        .filter_map(|block| interpret_markdown_code_block(block, None).unwrap())
+        .for_each(|block| { cells.push(block); });
+    cells.sort();
+
+    for cell in cells {
+        let (s, _) = new_execution_state.update_operation(cell, Uuid::now_v7()).await?;
+        new_execution_state = s;
+    }
+    Ok(new_execution_state)
 }
 
 
@@ -541,7 +1116,110 @@ mod test {
     use uuid::Uuid;
     use crate::cells::{CellTypes, CodeCell, LLMPromptCellChatConfiguration, SupportedLanguage, TextRange};
     use crate::execution::execution::ExecutionState;
-    use crate::library::std::ai::llm::infer_tool_usage_from_imports;
+    use crate::library::std::ai::llm::{embed_content, infer_tool_usage_from_imports, prepare_conversation_messages, record_conversation_turn, should_fall_back, trim_conversation_history, EmbeddingModel, EmbeddingReq, LlmError, MessageRole, TemplateMessage};
+    use std::time::Duration;
+    use crate::cells::LLMEmbeddingCellConfiguration;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_should_fall_back() {
+        assert!(should_fall_back(&LlmError::RateLimited("slow down".to_string())));
+        assert!(should_fall_back(&LlmError::Network("connection reset".to_string())));
+        assert!(should_fall_back(&LlmError::Timeout(Duration::from_secs(30))));
+        assert!(should_fall_back(&LlmError::Provider { status: 503, message: "overloaded".to_string() }));
+        assert!(should_fall_back(&LlmError::Provider { status: 408, message: "request timeout".to_string() }));
+        assert!(!should_fall_back(&LlmError::Provider { status: 400, message: "invalid messages".to_string() }));
+        assert!(!should_fall_back(&LlmError::Authentication { status: 401, message: "invalid key".to_string() }));
+        assert!(!should_fall_back(&LlmError::InvalidRequest("unsupported model".to_string())));
+    }
+
+    fn message(role: MessageRole, content: &str) -> TemplateMessage {
+        TemplateMessage {
+            role,
+            content: content.to_string(),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    #[test]
+    fn test_conversation_history_included_in_second_request() {
+        let mut state = ExecutionState::new_with_random_id();
+        let rendered_turn = |content: &str| vec![
+            message(MessageRole::System, "You are a helpful assistant."),
+            message(MessageRole::User, content),
+        ];
+
+        // First execution of the cell, there is no prior history
+        let first_request = prepare_conversation_messages(&state, "chat", rendered_turn("Hello"));
+        assert_eq!(first_request, rendered_turn("Hello"));
+        record_conversation_turn(&mut state, "chat", &rendered_turn("Hello"), "Hi there", None);
+
+        // Second execution includes the first exchange between the system message and the new turn
+        let second_request = prepare_conversation_messages(&state, "chat", rendered_turn("How are you?"));
+        assert_eq!(second_request, vec![
+            message(MessageRole::System, "You are a helpful assistant."),
+            message(MessageRole::User, "Hello"),
+            message(MessageRole::Assistant, "Hi there"),
+            message(MessageRole::User, "How are you?"),
+        ]);
+        record_conversation_turn(&mut state, "chat", &rendered_turn("How are you?"), "Good", None);
+        assert_eq!(state.conversation_histories.get("chat").unwrap().len(), 4);
+
+        // Other conversations are unaffected
+        assert_eq!(prepare_conversation_messages(&state, "other", rendered_turn("Hello")), rendered_turn("Hello"));
+    }
+
+    /// Responds with vectors of a fixed length, recording the dimensions it was asked for.
+    struct FixedLengthEmbeddingModel {
+        length: usize,
+        requested_dimensions: Mutex<Vec<Option<u32>>>,
+    }
+
+    #[async_trait]
+    impl EmbeddingModel for FixedLengthEmbeddingModel {
+        async fn embed(&self, embedding_req: EmbeddingReq) -> Result<Vec<f32>, String> {
+            self.requested_dimensions.lock().unwrap().push(embedding_req.dimensions);
+            Ok(vec![0.0; self.length])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embedding_dimensions() {
+        let model = FixedLengthEmbeddingModel { length: 256, requested_dimensions: Mutex::new(vec![]) };
+        let configuration = |model: &str, dimensions: Option<u32>| LLMEmbeddingCellConfiguration {
+            model: Some(model.to_string()),
+            dimensions,
+        };
+
+        let embedding = embed_content(&model, "hello".to_string(), &configuration("text-embedding-3-large", Some(256))).await.unwrap();
+        assert_eq!(embedding.len(), 256);
+        assert!(embed_content(&model, "hello".to_string(), &LLMEmbeddingCellConfiguration::default()).await.is_ok());
+        assert_eq!(*model.requested_dimensions.lock().unwrap(), vec![Some(256), None]);
+
+        let err = embed_content(&model, "hello".to_string(), &configuration("text-embedding-3-small", Some(512))).await.unwrap_err();
+        assert!(err.contains("256 dimensions, 512 were requested"), "{}", err);
+
+        // Unsupported requests are rejected without calling the model
+        let err = embed_content(&model, "hello".to_string(), &configuration("text-embedding-ada-002", Some(256))).await.unwrap_err();
+        assert!(err.contains("does not support"), "{}", err);
+        let err = embed_content(&model, "hello".to_string(), &configuration("text-embedding-3-small", Some(4096))).await.unwrap_err();
+        assert!(err.contains("between 1 and 1536"), "{}", err);
+        assert_eq!(model.requested_dimensions.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_trim_conversation_history_drops_oldest() {
+        let history = vec![
+            message(MessageRole::User, &"a".repeat(400)),
+            message(MessageRole::Assistant, &"b".repeat(40)),
+            message(MessageRole::User, &"c".repeat(40)),
+        ];
+        let trimmed = trim_conversation_history(&history, 40);
+        assert_eq!(trimmed, history[1..].to_vec());
+        assert!(trim_conversation_history(&history, 0).is_empty());
+    }
 
     #[tokio::test]
     async fn test_tool_usage_inference() -> anyhow::Result<()> {
@@ -549,8 +1227,6 @@ mod test {
         let id_a = Uuid::now_v7();
         let id_b = Uuid::now_v7();
         let (mut state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         import asyncio
@@ -558,17 +1234,15 @@ mod test {
                             await asyncio.sleep(1)
                             return 100 + await demo_second_function_call()
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_a)?;
         let (mut state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         def complex_args(a, b, c=2, d=3):
                             return a + b + c + d
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_b)?;
 
         insta::with_settings!({
@@ -0,0 +1,141 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::library::std::ai::llm::LlmError;
+
+/// A model offered by a provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    /// Context window of the model in tokens, when the provider reports it.
+    pub context_window: Option<usize>,
+}
+
+/// Providers able to list the models they offer, such as for a model picker.
+#[async_trait]
+pub trait ModelCatalog {
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LlmError>;
+}
+
+/// Categorize an unsuccessful response from a provider.
+pub(crate) fn error_for_status(status: u16, message: String) -> LlmError {
+    match status {
+        401 | 403 => LlmError::Authentication { status, message },
+        429 => LlmError::RateLimited(message),
+        status => LlmError::Provider { status, message },
+    }
+}
+
+/// Fetch a JSON document from a provider, categorizing the failures.
+pub(crate) async fn get_json(request: reqwest::RequestBuilder) -> Result<Value, LlmError> {
+    let response = request.send().await.map_err(|e| LlmError::Network(e.to_string()))?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(error_for_status(status.as_u16(), message));
+    }
+    response.json::<Value>().await.map_err(|e| LlmError::Network(e.to_string()))
+}
+
+/// Lists the models pulled into an Ollama server, from its `/api/tags` endpoint.
+pub struct OllamaModelCatalog {
+    /// Root of the Ollama server, e.g. http://localhost:11434
+    api_url: String,
+}
+
+impl OllamaModelCatalog {
+    pub fn new(api_url: impl Into<String>) -> Self {
+        Self { api_url: api_url.into().trim_end_matches('/').to_string() }
+    }
+}
+
+#[async_trait]
+impl ModelCatalog for OllamaModelCatalog {
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LlmError> {
+        let body = get_json(reqwest::Client::new().get(format!("{}/api/tags", self.api_url))).await?;
+        Ok(body.get("models")
+            .and_then(|models| models.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|model| model.get("name").and_then(|name| name.as_str()))
+            .map(|name| ModelInfo { id: name.to_string(), context_window: None })
+            .collect())
+    }
+}
+
+/// Wraps a catalog so that its models are only requested again once `ttl` has elapsed since they
+/// were last listed. Failures are not cached.
+pub struct CachedModelCatalog<C> {
+    inner: C,
+    ttl: Duration,
+    cached: Mutex<Option<(Instant, Vec<ModelInfo>)>>,
+}
+
+impl<C: ModelCatalog + Send + Sync> CachedModelCatalog<C> {
+    pub fn new(inner: C, ttl: Duration) -> Self {
+        Self { inner, ttl, cached: Mutex::new(None) }
+    }
+}
+
+#[async_trait]
+impl<C: ModelCatalog + Send + Sync> ModelCatalog for CachedModelCatalog<C> {
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LlmError> {
+        let mut cached = self.cached.lock().await;
+        if let Some((listed_at, models)) = cached.as_ref() {
+            if listed_at.elapsed() < self.ttl {
+                return Ok(models.clone());
+            }
+        }
+        let models = self.inner.list_models().await?;
+        *cached = Some((Instant::now(), models.clone()));
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::routing::get;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ollama_models() {
+        let app = axum::Router::new().route("/api/tags", get(|| async {
+            axum::Json(serde_json::json!({"models": [{"name": "llama3:latest"}, {"name": "mistral:7b"}]}))
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let models = OllamaModelCatalog::new(format!("http://{}/", addr)).list_models().await.unwrap();
+        assert_eq!(models.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["llama3:latest", "mistral:7b"]);
+    }
+
+    struct CountingCatalog(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl ModelCatalog for CountingCatalog {
+        async fn list_models(&self) -> Result<Vec<ModelInfo>, LlmError> {
+            let calls = self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![ModelInfo { id: format!("model-{}", calls), context_window: None }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_catalog_lists_again_after_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let catalog = CachedModelCatalog::new(CountingCatalog(calls.clone()), Duration::from_millis(50));
+        assert_eq!(catalog.list_models().await.unwrap()[0].id, "model-0");
+        assert_eq!(catalog.list_models().await.unwrap()[0].id, "model-0");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(catalog.list_models().await.unwrap()[0].id, "model-1");
+    }
+}
@@ -3,95 +3,147 @@ use async_trait::async_trait;
 use futures_util::TryStreamExt;
 
 use crate::library::std::ai::llm;
+use crate::library::std::ai::llm::models::error_for_status;
 use crate::library::std::ai::llm::openai::OpenAIChatModel;
-use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionRes, ChatModelBatch, JSONSchemaDefine, JSONSchemaType, Tool, ToolChoiceType};
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionRes, ChatCompletionStream, ChatModelBatch, JSONSchemaDefine, JSONSchemaType, LlmError, Tool, ToolChoiceType};
 
 use openai_api_rs::v1::chat_completion::{
-    ChatCompletionMessage, ChatCompletionRequest, MessageRole,
+    ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, FinishReason, MessageRole,
 };
-use crate::cells::LLMPromptCellChatConfiguration;
-use crate::execution::primitives::serialized_value::json_value_to_serialized_value;
+use serde_json::Value;
+use crate::cells::{LLMPromptCellChatConfiguration, RequestHeaders};
+use crate::execution::primitives::serialized_value::{json_value_to_serialized_value, RkyvSerializedValue};
+
+impl OpenAIChatModel {
+    /// The JSON body sent to the chat completions endpoint, including any additional fields
+    /// from the request's `extra` that do not collide with explicitly modeled parameters.
+    pub fn chat_completion_req_to_openai_body(chat_completion_req: &ChatCompletionReq) -> Result<Value, String> {
+        let req = Self::chat_completion_req_to_openai_req(chat_completion_req);
+        let mut body = serde_json::to_value(&req).map_err(|e| e.to_string())?;
+        merge_extra_fields(&mut body, &chat_completion_req.extra);
+        if chat_completion_req.config.reasoning_model.unwrap_or_else(|| is_reasoning_model(&req.model)) {
+            adapt_body_for_reasoning_model(&mut body);
+        }
+        Ok(body)
+    }
+
+    async fn post_chat_completion(&self, body: &Value, cell_headers: Option<&RequestHeaders>) -> Result<ChatCompletionResponse, LlmError> {
+        let response = self.authorize(reqwest::Client::new().post(format!("{}/chat/completions", self.api_url)), cell_headers)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(error_for_status(status.as_u16(), text));
+        }
+        response.json::<ChatCompletionResponse>().await.map_err(|e| LlmError::Network(e.to_string()))
+    }
+}
+
+/// The finish reason of a choice as reported by the API, empty when none was given.
+fn finish_reason_name(finish_reason: &Option<FinishReason>) -> String {
+    match finish_reason {
+        Some(FinishReason::stop) => "stop",
+        Some(FinishReason::length) => "length",
+        Some(FinishReason::content_filter) => "content_filter",
+        Some(FinishReason::tool_calls) => "tool_calls",
+        Some(FinishReason::null) | None => "",
+    }.to_string()
+}
+
+/// Whether a model is one of OpenAI's o-series reasoning models, e.g. o1, o3-mini or openai/o4-mini.
+pub fn is_reasoning_model(model: &str) -> bool {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// Reasoning models reject the sampling parameters and take their completion budget as
+/// `max_completion_tokens` rather than `max_tokens`.
+fn adapt_body_for_reasoning_model(body: &mut Value) {
+    let Value::Object(body) = body else { return };
+    body.remove("temperature");
+    body.remove("top_p");
+    if let Some(max_tokens) = body.remove("max_tokens").filter(|max_tokens| !max_tokens.is_null()) {
+        body.entry("max_completion_tokens").or_insert(max_tokens);
+    }
+}
+
+fn merge_extra_fields(body: &mut Value, extra: &Value) {
+    if let (Value::Object(body), Value::Object(extra)) = (body, extra) {
+        for (key, value) in extra {
+            if matches!(body.get(key), None | Some(Value::Null)) {
+                body.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
 
 #[async_trait]
 impl ChatModelBatch for OpenAIChatModel {
+    fn endpoint(&self) -> Option<String> {
+        Some(self.api_url.clone())
+    }
+
+    async fn stream(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionStream, LlmError> {
+        Ok(Box::pin(self.stream_chat_completion(chat_completion_req).await?))
+    }
+
     async fn batch(
         &self,
         chat_completion_req: ChatCompletionReq,
-    ) -> Result<ChatCompletionRes, String> {
-        let model = &chat_completion_req.config.model;
-        if self.api_url == "https://api.openai.com/v1" {
-            if !vec![
-                "gpt-4-1106-preview",
-                "gpt-4-vision-preview",
-                "gpt-4",
-                "gpt-4-0314",
-                "gpt-4-0613",
-                "gpt-4-32k",
-                "gpt-4-32k-0314",
-                "gpt-4-32k-0613",
-                "gpt-3.5-turbo",
-                "gpt-3.5-turbo-16k",
-                "gpt-3.5-turbo-0301",
-                "gpt-3.5-turbo-0613",
-                "gpt-3.5-turbo-1106",
-                "gpt-3.5-turbo-16k-0613",
-            ]
-                .contains(&model.as_ref().unwrap_or(&String::from("gpt-3.5-turbo")).as_str())
-            {
-                return Err(format!("Model {:?} is not supported", model));
-            }
+    ) -> Result<ChatCompletionRes, LlmError> {
+        let body = Self::chat_completion_req_to_openai_body(&chat_completion_req).map_err(LlmError::InvalidRequest)?;
+        let res = self.post_chat_completion(&body, chat_completion_req.config.headers.as_ref()).await?;
+        let mut choices = vec![];
+        for c in &res.choices {
+            let tool_calls = match &c.message.tool_calls {
+                Some(tool_calls) => Some(tool_calls.iter().map(|tool_call| Ok(llm::ChatCompletionToolCall {
+                    id: tool_call.id.clone(),
+                    ty: "function".to_string(),
+                    function: llm::ChatCompletionToolCallFunction {
+                        name: tool_call.function.name.clone(),
+                        arguments: tool_call_arguments(tool_call.function.name.as_deref(), tool_call.function.arguments.as_deref())?,
+                    },
+                })).collect::<Result<Vec<_>, LlmError>>()?),
+                None => None,
+            };
+            choices.push(llm::ChatCompletionChoice {
+                text: c.message.content.clone(),
+                index: 0,
+                logprobs: None,
+                finish_reason: finish_reason_name(&c.finish_reason),
+                tool_calls,
+            });
         }
-
-        let req = Self::chat_completion_req_to_openai_req(&chat_completion_req);
-        self.client
-            .chat_completion(req)
-            .await
-            .map(|res| {
-                ChatCompletionRes {
-                id: res.id,
-                object: res.object,
-                created: res.created,
-                model: res.model,
-                choices: res
-                    .choices
-                    .iter()
-                    .map(|c| llm::ChatCompletionChoice {
-                        text: c.message.content.clone(),
-                        index: 0,
-                        logprobs: None,
-                        finish_reason: "".to_string(),
-                        tool_calls: c.message.tool_calls.clone().map(|tool_calls| {
-                            tool_calls
-                                .iter()
-                                .map(|tool_call| {
-                                    llm::ChatCompletionToolCall {
-                                        id: tool_call.id.clone(),
-                                        ty: "function".to_string(),
-                                        function: llm::ChatCompletionToolCallFunction {
-                                            name: tool_call.function.name.clone(),
-                                            arguments: tool_call.function.arguments
-                                                .as_ref()
-                                                .map(|x| {dbg!(&x); x })
-                                                .map(|x| x.as_str())
-                                                .map(|x| serde_json::from_str(x).unwrap())
-                                                .map(|x| json_value_to_serialized_value(&x)),
-                                        }
-                                    }
-                                })
-                                .collect()
-                        }),
-                    })
-                    .collect(),
-                usage: llm::Usage {
-                    prompt_tokens: res.usage.prompt_tokens,
-                    completion_tokens: res.usage.completion_tokens,
-                    total_tokens: res.usage.total_tokens,
-                },
-            }})
-            .map_err(|e| e.to_string())
+        Ok(ChatCompletionRes {
+            id: res.id,
+            object: res.object,
+            created: res.created,
+            model: res.model,
+            choices,
+            usage: llm::Usage {
+                prompt_tokens: res.usage.prompt_tokens,
+                completion_tokens: res.usage.completion_tokens,
+                total_tokens: res.usage.total_tokens,
+            },
+        })
     }
 }
 
+/// The arguments of a tool call, which the API sends as a string of JSON. Arguments that are not
+/// valid JSON fail the request rather than being passed on to the tool.
+fn tool_call_arguments(name: Option<&str>, arguments: Option<&str>) -> Result<Option<RkyvSerializedValue>, LlmError> {
+    let Some(arguments) = arguments else { return Ok(None) };
+    let arguments: Value = serde_json::from_str(arguments).map_err(|e| LlmError::MalformedToolArguments {
+        name: name.unwrap_or_default().to_string(),
+        message: e.to_string(),
+    })?;
+    Ok(Some(json_value_to_serialized_value(&arguments)))
+}
+
 
 
 #[cfg(test)]
@@ -115,4 +167,191 @@ mod tests {
         assert!(result.is_ok());
         let response = result.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_configured_headers_are_sent() {
+        let received = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let app = {
+            let received = received.clone();
+            axum::Router::new().route("/v1/chat/completions", axum::routing::post(move |headers: axum::http::HeaderMap| async move {
+                *received.lock().unwrap() = Some(headers);
+                (axum::http::StatusCode::BAD_REQUEST, "{}")
+            }))
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let model = OpenAIChatModel::new(format!("http://{}/v1", addr), "key".to_string())
+            .with_headers(RequestHeaders(HashMap::from([
+                ("x-api-version".to_string(), "2024-06-01".to_string()),
+                ("x-route".to_string(), "provider".to_string()),
+            ])));
+        let mut chat_completion_req = ChatCompletionReq::default();
+        chat_completion_req.config.headers = Some(RequestHeaders(HashMap::from([
+            ("X-Route".to_string(), "cell".to_string()),
+        ])));
+        let _ = model.batch(chat_completion_req).await;
+
+        let headers = received.lock().unwrap().take().expect("request should reach the provider");
+        assert_eq!(headers.get("x-api-version").and_then(|v| v.to_str().ok()), Some("2024-06-01"));
+        // Headers declared by the cell take precedence over those of the provider
+        assert_eq!(headers.get_all("x-route").iter().collect::<Vec<_>>(), vec!["cell"]);
+    }
+
+    #[test]
+    fn test_headers_from_frontmatter() {
+        let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(indoc::indoc! {r#"
+            model: gpt-4o
+            headers:
+              x-route: eu
+              x-gateway-key: gw-secret
+            "#}).unwrap();
+        let headers = configuration.headers.as_ref().unwrap();
+        assert_eq!(headers.0.get("x-route").map(String::as_str), Some("eu"));
+        let debugged = format!("{:?}", configuration);
+        assert!(!debugged.contains("gw-secret"), "{}", debugged);
+    }
+
+    #[test]
+    fn test_extra_fields_merged_into_request_body() {
+        let mut chat_completion_req = ChatCompletionReq {
+            extra: serde_json::json!({
+                "reasoning_effort": "high",
+                "temperature": 1.5,
+                "seed": 7
+            }),
+            ..ChatCompletionReq::default()
+        };
+        chat_completion_req.config.temperature = Some(0.2);
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+
+        // Unknown fields pass through
+        assert_eq!(body["reasoning_effort"], "high");
+        // Explicitly modeled fields are not overridden
+        assert_eq!(body["temperature"], 0.2);
+        // Modeled fields that are absent are filled from extra
+        assert_eq!(body["seed"], 7);
+        assert_eq!(body["model"], "gpt-3.5-turbo");
+    }
+
+    #[test]
+    fn test_extra_fields_from_frontmatter() {
+        let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(indoc::indoc! {r#"
+            model: gpt-4o
+            extra:
+              logprobs: true
+              thinking:
+                budget_tokens: 1024
+            "#}).unwrap();
+        let chat_completion_req = ChatCompletionReq {
+            extra: configuration.extra_value(),
+            config: configuration,
+            ..ChatCompletionReq::default()
+        };
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+        assert_eq!(body["logprobs"], true);
+        assert_eq!(body["thinking"]["budget_tokens"], 1024);
+    }
+
+    #[test]
+    fn test_reasoning_model_request_body() {
+        let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(indoc::indoc! {r#"
+            model: o3-mini
+            temperature: 0.2
+            top_p: 0.9
+            max_tokens: 2048
+            reasoning_effort: high
+            "#}).unwrap();
+        let chat_completion_req = ChatCompletionReq {
+            extra: configuration.extra_value(),
+            config: configuration,
+            ..ChatCompletionReq::default()
+        };
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("max_tokens").is_none());
+        assert_eq!(body["max_completion_tokens"], 2048);
+        assert_eq!(body["reasoning_effort"], "high");
+    }
+
+    #[test]
+    fn test_reasoning_model_can_be_declared_in_frontmatter() {
+        let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(indoc::indoc! {r#"
+            model: deepseek-reasoner
+            reasoning_model: true
+            temperature: 0.2
+            max_tokens: 2048
+            "#}).unwrap();
+        let chat_completion_req = ChatCompletionReq { config: configuration, ..ChatCompletionReq::default() };
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+        assert!(body.get("temperature").is_none());
+        assert_eq!(body["max_completion_tokens"], 2048);
+
+        let mut chat_completion_req = ChatCompletionReq::default();
+        chat_completion_req.config.model = Some("o1-compatible-gateway".to_string());
+        chat_completion_req.config.reasoning_model = Some(false);
+        chat_completion_req.config.temperature = Some(0.2);
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+        assert_eq!(body["temperature"], 0.2);
+    }
+
+    #[test]
+    fn test_non_reasoning_model_request_body_is_unchanged() {
+        let mut chat_completion_req = ChatCompletionReq::default();
+        chat_completion_req.config.model = Some("gpt-4o".to_string());
+        chat_completion_req.config.temperature = Some(0.2);
+        chat_completion_req.config.max_tokens = Some(2048);
+        let body = OpenAIChatModel::chat_completion_req_to_openai_body(&chat_completion_req).unwrap();
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["max_tokens"], 2048);
+        assert!(body.get("max_completion_tokens").is_none());
+
+        assert!(is_reasoning_model("o1"));
+        assert!(is_reasoning_model("openai/o4-mini"));
+        assert!(!is_reasoning_model("gpt-4o"));
+        assert!(!is_reasoning_model("omni-moderation-latest"));
+    }
+
+    #[test]
+    fn test_malformed_tool_arguments_fail_the_request() {
+        assert_eq!(
+            tool_call_arguments(Some("add"), Some(r#"{"a": 1}"#)).unwrap(),
+            Some(json_value_to_serialized_value(&serde_json::json!({"a": 1}))),
+        );
+        assert_eq!(tool_call_arguments(Some("add"), None).unwrap(), None);
+        let err = tool_call_arguments(Some("add"), Some(r#"{"a": 1"#)).unwrap_err();
+        assert!(matches!(err, LlmError::MalformedToolArguments { ref name, .. } if name == "add"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_finish_reason_name_stop() {
+        assert_eq!(finish_reason_name(&Some(FinishReason::stop)), "stop");
+    }
+
+    #[test]
+    fn test_finish_reason_name_length() {
+        assert_eq!(finish_reason_name(&Some(FinishReason::length)), "length");
+    }
+
+    #[test]
+    fn test_finish_reason_name_content_filter() {
+        assert_eq!(finish_reason_name(&Some(FinishReason::content_filter)), "content_filter");
+    }
+
+    #[test]
+    fn test_finish_reason_name_tool_calls() {
+        assert_eq!(finish_reason_name(&Some(FinishReason::tool_calls)), "tool_calls");
+    }
+
+    #[test]
+    fn test_finish_reason_name_null() {
+        assert_eq!(finish_reason_name(&Some(FinishReason::null)), "");
+    }
+
+    #[test]
+    fn test_finish_reason_name_absent() {
+        assert_eq!(finish_reason_name(&None), "");
+    }
 }
@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use openai_api_rs::v1::embedding::{EmbeddingRequest, EmbeddingResponse};
-use crate::library::std::ai::llm::{ChatCompletionReq, ChatModelBatch, ChatModelStream, EmbeddingModel, EmbeddingReq, Usage};
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatModelBatch, EmbeddingModel, EmbeddingReq, Usage};
 use crate::library::std::ai::llm::openai::OpenAIChatModel;
 
 #[async_trait]
@@ -24,7 +24,7 @@ impl EmbeddingModel for OpenAIChatModel {
         let req = EmbeddingRequest {
             model,
             input: embedding_request.content,
-            dimensions: None,
+            dimensions: embedding_request.dimensions.map(|dimensions| dimensions as i32),
             user: None,
         };
         self.client
@@ -48,6 +48,7 @@ mod tests {
         let result = model.embed(EmbeddingReq {
             content: "".to_string(),
             model: "text-embedding-3-small".to_string(),
+            dimensions: None,
             frequency_penalty: None,
             max_tokens: None,
             presence_penalty: None,
@@ -5,14 +5,28 @@ mod embedding;
 use std::collections::HashMap;
 use openai_api_rs::v1::api::OpenAIClient;
 use std::env;
-use openai_api_rs::v1::chat_completion::{ChatCompletionMessage, ChatCompletionRequest, MessageRole};
-use crate::cells::LLMPromptCellChatConfiguration;
+use openai_api_rs::v1::chat_completion::{ChatCompletionMessage, ChatCompletionRequest, MessageRole, ToolCall, ToolCallFunction};
+use crate::cells::{LLMPromptCellChatConfiguration, RequestHeaders};
 use crate::library::std::ai::llm;
-use crate::library::std::ai::llm::{ChatCompletionReq, JSONSchemaDefine, JSONSchemaType, Tool, ToolChoiceType};
+use crate::library::std::ai::llm::{ChatCompletionReq, JSONSchemaDefine, JSONSchemaType, LlmError, Tool, ToolChoiceType};
+use crate::sdk::config::{ChidoriConfig, ProviderConfiguration};
+use crate::sdk::secrets::SecretResolver;
+use crate::library::std::ai::llm::models::{error_for_status, get_json, ModelCatalog, ModelInfo};
+use async_trait::async_trait;
+
+/// Endpoint used when neither the cell, the provider configuration nor the environment specify one, expects a local proxy.
+pub const DEFAULT_API_URL: &str = "http://localhost:4000/v1";
+
+/// Environment variables that redirect OpenAI traffic, such as through a corporate proxy, in order of precedence.
+pub const API_URL_ENV_VARS: [&str; 2] = ["OPENAI_BASE_URL", "OPENAI_API_BASE"];
 
 pub struct OpenAIChatModel {
     api_url: String,
     api_key: String,
+    organization: Option<String>,
+    headers: RequestHeaders,
+    /// Whether each streamed chunk carries the content accumulated so far
+    cumulative_stream_content: bool,
     client: OpenAIClient,
 }
 
@@ -20,28 +34,81 @@ impl OpenAIChatModel {
     // TODO: remove api_key parameter, expect usage of a proxy
     pub fn new(api_url: String, api_key: String) -> Self {
         let client = OpenAIClient::new_with_endpoint(api_url.clone(), api_key.clone());
-        Self { api_url, client, api_key }
+        Self { api_url, client, api_key, organization: None, headers: Default::default(), cumulative_stream_content: false }
+    }
+
+    /// The endpoint requests are sent to.
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    /// Bill requests to the given organization rather than the default organization of the api key.
+    pub fn with_organization(mut self, organization: Option<String>) -> Self {
+        self.client.organization = organization.clone();
+        self.organization = organization;
+        self
+    }
+
+    /// Attach these headers to every request, such as those required by a gateway in front of the provider.
+    pub fn with_headers(mut self, headers: RequestHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Treat every streamed chunk as the content accumulated so far, for gateways that stream
+    /// responses that way rather than as deltas.
+    pub fn with_cumulative_stream_content(mut self, cumulative_stream_content: bool) -> Self {
+        self.cumulative_stream_content = cumulative_stream_content;
+        self
+    }
+
+    /// Construct a client for the configured provider, an api_url declared by a cell takes precedence
+    /// over that of the provider, which takes precedence over the environment.
+    pub fn from_provider_configuration(provider: &ProviderConfiguration, api_url: Option<String>) -> Self {
+        let api_url = resolve_api_url(api_url, provider, |key| env::var(key).ok());
+        Self::new(api_url, provider.api_key.clone().unwrap_or_default())
+            .with_organization(provider.organization.clone())
+            .with_headers(provider.headers.clone())
+            .with_cumulative_stream_content(provider.cumulative_stream_content.unwrap_or(false))
+    }
+
+    /// Attach the credentials and headers of this client to a request, along with the headers
+    /// declared by the cell making it.
+    fn authorize(&self, request: reqwest::RequestBuilder, cell_headers: Option<&RequestHeaders>) -> reqwest::RequestBuilder {
+        let mut request = request.bearer_auth(&self.api_key);
+        if let Some(organization) = &self.organization {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        for (name, value) in self.headers.merged_with(cell_headers).0 {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Verify that the provider is reachable and accepts our credentials by listing its models,
+    /// which consumes no tokens.
+    pub async fn health_check(&self) -> Result<(), LlmError> {
+        let response = self.authorize(reqwest::Client::new().get(format!("{}/models", self.api_url)), None)
+            .send()
+            .await
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        Err(error_for_status(status.as_u16(), message))
     }
 
     pub fn chat_completion_req_to_openai_req(chat_completion_req: &ChatCompletionReq) -> ChatCompletionRequest {
         let config = &chat_completion_req.config;
         ChatCompletionRequest {
             model: config.model.as_ref().unwrap_or(&String::from("gpt-3.5-turbo")).clone(),
+            // OpenAI takes system messages wherever they appear, so the order of the template is kept
             messages: chat_completion_req
                 .template_messages
                 .iter()
-                .map(|m| ChatCompletionMessage {
-                    role: match m.role {
-                        llm::MessageRole::User => MessageRole::user,
-                        llm::MessageRole::System => MessageRole::system,
-                        llm::MessageRole::Assistant => MessageRole::assistant,
-                        llm::MessageRole::Function => MessageRole::function,
-                    },
-                    content: openai_api_rs::v1::chat_completion::Content::Text(m.content.clone()),
-                    name: m.name.clone(),
-                    tool_calls: None,
-                    tool_call_id: None,
-                })
+                .map(our_message_to_openai)
                 .collect(),
             tool_choice: chat_completion_req.tool_choice.clone().map(our_tool_choice_to_openai),
             tools: chat_completion_req.tools.clone().map(|t| t.into_iter().map(our_tool_to_openai_tool).collect()),
@@ -65,6 +132,78 @@ impl OpenAIChatModel {
 
 
 
+/// Models are listed from the models endpoint. OpenAI does not report context windows, though
+/// gateways in front of other providers commonly include them as `context_window` or `context_length`.
+#[async_trait]
+impl ModelCatalog for OpenAIChatModel {
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, LlmError> {
+        let body = get_json(self.authorize(reqwest::Client::new().get(format!("{}/models", self.api_url)), None)).await?;
+        Ok(body.get("data")
+            .and_then(|models| models.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|model| {
+                let id = model.get("id")?.as_str()?.to_string();
+                let context_window = model.get("context_window")
+                    .or_else(|| model.get("context_length"))
+                    .and_then(|context_window| context_window.as_u64())
+                    .map(|context_window| context_window as usize);
+                Some(ModelInfo { id, context_window })
+            })
+            .collect())
+    }
+}
+
+/// Check every configured provider, keyed by provider name.
+pub async fn health_check_providers(config: &ChidoriConfig, secrets: &dyn SecretResolver) -> HashMap<String, Result<(), LlmError>> {
+    let mut results = HashMap::new();
+    for name in config.providers.keys() {
+        let provider = match config.provider(name, secrets).await {
+            Ok(provider) => provider,
+            Err(e) => {
+                results.insert(name.clone(), Err(e.into()));
+                continue;
+            }
+        };
+        let result = OpenAIChatModel::from_provider_configuration(&provider, None).health_check().await;
+        results.insert(name.clone(), result);
+    }
+    results
+}
+
+/// Calls of tools are sent as the `tool_calls` of the assistant message making them, and their
+/// results as `tool` messages referencing the call by its id. Function messages without the id of
+/// their call are sent as legacy `function` messages.
+fn our_message_to_openai(message: &llm::TemplateMessage) -> ChatCompletionMessage {
+    let call_id = message.function_call.as_ref().and_then(|call| call.id.clone());
+    let (role, name, tool_calls, tool_call_id) = match message.role {
+        llm::MessageRole::User => (MessageRole::user, message.name.clone(), None, None),
+        llm::MessageRole::System => (MessageRole::system, message.name.clone(), None, None),
+        llm::MessageRole::Assistant => (
+            MessageRole::assistant,
+            message.name.clone(),
+            message.function_call.as_ref().map(|call| vec![ToolCall {
+                id: call.id.clone().unwrap_or_default(),
+                r#type: "function".to_string(),
+                function: ToolCallFunction {
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                },
+            }]),
+            None,
+        ),
+        llm::MessageRole::Function if call_id.is_some() => (MessageRole::tool, None, None, call_id),
+        llm::MessageRole::Function => (MessageRole::function, message.name.clone(), None, None),
+    };
+    ChatCompletionMessage {
+        role,
+        content: openai_api_rs::v1::chat_completion::Content::Text(message.content.clone()),
+        name,
+        tool_calls,
+        tool_call_id,
+    }
+}
+
 fn our_json_schema_type_to_openai(schema_type: JSONSchemaType) -> openai_api_rs::v1::chat_completion::JSONSchemaType {
     match schema_type {
         JSONSchemaType::Object => openai_api_rs::v1::chat_completion::JSONSchemaType::Object,
@@ -113,3 +252,151 @@ fn our_tool_choice_to_openai(tool_choice: ToolChoiceType) -> openai_api_rs::v1::
         }
     }
 }
+
+fn resolve_api_url(api_url: Option<String>, provider: &ProviderConfiguration, env: impl Fn(&str) -> Option<String>) -> String {
+    api_url
+        .or_else(|| provider.api_url.clone())
+        .or_else(|| API_URL_ENV_VARS.iter().find_map(|key| env(key).filter(|url| !url.is_empty())))
+        .unwrap_or(DEFAULT_API_URL.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+
+    #[test]
+    fn test_system_messages_keep_their_place_in_the_template() {
+        let message = |role, content: &str| llm::TemplateMessage { role, content: content.to_string(), name: None, function_call: None };
+        let req = ChatCompletionReq {
+            template_messages: vec![message(llm::MessageRole::User, "Hello"), message(llm::MessageRole::System, "Be brief.")],
+            ..ChatCompletionReq::default()
+        };
+        let openai_req = OpenAIChatModel::chat_completion_req_to_openai_req(&req);
+        assert!(matches!(openai_req.messages[0].role, MessageRole::user));
+        assert!(matches!(openai_req.messages[1].role, MessageRole::system));
+    }
+
+    #[test]
+    fn test_tool_calls_and_results_are_linked() {
+        let message = |role, content: &str, function_call| llm::TemplateMessage { role, content: content.to_string(), name: None, function_call };
+        let call = |arguments: Option<&str>| Some(llm::FunctionCall {
+            id: Some("call_1".to_string()),
+            name: Some("add".to_string()),
+            arguments: arguments.map(str::to_string),
+        });
+        let req = ChatCompletionReq {
+            template_messages: vec![
+                message(llm::MessageRole::User, "What is 1 + 2?", None),
+                message(llm::MessageRole::Assistant, "", call(Some("{\"a\":1,\"b\":2}"))),
+                llm::TemplateMessage { name: Some("add".to_string()), ..message(llm::MessageRole::Function, "3", call(None)) },
+            ],
+            ..ChatCompletionReq::default()
+        };
+        let body = serde_json::to_value(OpenAIChatModel::chat_completion_req_to_openai_req(&req)).unwrap();
+        assert_eq!(body["messages"][1], serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "add", "arguments": "{\"a\":1,\"b\":2}"}}],
+        }));
+        assert_eq!(body["messages"][2], serde_json::json!({
+            "role": "tool",
+            "content": "3",
+            "tool_call_id": "call_1",
+        }));
+    }
+
+    async fn serve_models(status: StatusCode) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/v1/models", get(move || async move { (status, "{}") }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/v1", addr)
+    }
+
+    #[tokio::test]
+    async fn test_health_check_ok() {
+        let model = OpenAIChatModel::new(serve_models(StatusCode::OK).await, "key".to_string());
+        assert_eq!(model.health_check().await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_authentication_failure() {
+        let model = OpenAIChatModel::new(serve_models(StatusCode::UNAUTHORIZED).await, "bad".to_string());
+        assert!(matches!(model.health_check().await, Err(LlmError::Authentication { status: 401, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_organization_header_is_sent() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/v1/models", get(|headers: axum::http::HeaderMap| async move {
+            match headers.get("openai-organization").and_then(|v| v.to_str().ok()) {
+                Some("org-123") => (StatusCode::OK, "{}"),
+                _ => (StatusCode::BAD_REQUEST, "missing organization"),
+            }
+        }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let api_url = format!("http://{}/v1", addr);
+
+        let provider = ProviderConfiguration {
+            api_url: Some(api_url.clone()),
+            organization: Some("org-123".to_string()),
+            ..Default::default()
+        };
+        let model = OpenAIChatModel::from_provider_configuration(&provider, None);
+        assert_eq!(model.client.organization.as_deref(), Some("org-123"));
+        assert_eq!(model.health_check().await, Ok(()));
+
+        let model = OpenAIChatModel::new(api_url, "key".to_string());
+        assert!(matches!(model.health_check().await, Err(LlmError::Provider { status: 400, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let app = axum::Router::new().route("/v1/models", get(|| async {
+            axum::Json(serde_json::json!({"object": "list", "data": [
+                {"id": "gpt-4o", "object": "model", "context_window": 128000},
+                {"id": "gpt-4o-mini", "object": "model"}
+            ]}))
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let model = OpenAIChatModel::new(format!("http://{}/v1", addr), "key".to_string());
+        assert_eq!(model.list_models().await.unwrap(), vec![
+            ModelInfo { id: "gpt-4o".to_string(), context_window: Some(128000) },
+            ModelInfo { id: "gpt-4o-mini".to_string(), context_window: None },
+        ]);
+
+        let model = OpenAIChatModel::new(serve_models(StatusCode::UNAUTHORIZED).await, "bad".to_string());
+        assert!(matches!(model.list_models().await, Err(LlmError::Authentication { status: 401, .. })));
+    }
+
+    #[test]
+    fn test_api_url_from_environment() {
+        let env = HashMap::from([
+            ("OPENAI_BASE_URL", "https://proxy.internal/v1"),
+            ("OPENAI_API_BASE", "https://legacy.internal/v1"),
+        ]);
+        let lookup = |key: &str| env.get(key).map(|url| url.to_string());
+        let provider = ProviderConfiguration::default();
+        assert_eq!(resolve_api_url(None, &provider, lookup), "https://proxy.internal/v1");
+        assert_eq!(resolve_api_url(None, &provider, |key| lookup(key).filter(|_| key == "OPENAI_API_BASE")), "https://legacy.internal/v1");
+        assert_eq!(resolve_api_url(None, &provider, |_| None), DEFAULT_API_URL);
+
+        let provider = ProviderConfiguration { api_url: Some("https://provider/v1".to_string()), ..Default::default() };
+        assert_eq!(resolve_api_url(None, &provider, lookup), "https://provider/v1");
+        assert_eq!(resolve_api_url(Some("https://cell/v1".to_string()), &provider, lookup), "https://cell/v1");
+    }
+
+    #[tokio::test]
+    async fn test_health_check_network_failure() {
+        // Bind and immediately release a port so that nothing is listening on it
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let model = OpenAIChatModel::new(format!("http://{}/v1", addr), "key".to_string());
+        assert!(matches!(model.health_check().await, Err(LlmError::Network(_))));
+    }
+}
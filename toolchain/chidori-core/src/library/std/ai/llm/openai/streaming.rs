@@ -1,102 +1,181 @@
 use crate::library::std::ai::llm;
 use crate::library::std::ai::llm::openai::OpenAIChatModel;
-use crate::library::std::ai::llm::{ChatCompletionReq, ChatModelStream, LLMStream, Usage};
-use async_trait::async_trait;
+use crate::execution::primitives::serialized_value::json_value_to_serialized_value;
+use crate::library::std::ai::llm::models::error_for_status;
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionToolCall, ChatCompletionToolCallFunction, LLMStream, LLMStreamItem, LlmError, Usage};
 use futures_util::stream::Stream;
-use openai_api_rs::v1::chat_completion::ChatCompletionMessage;
-use openai_api_rs::v1::chat_completion::ChatCompletionRequest;
-use openai_api_rs::v1::chat_completion::MessageRole;
-use reqwest::{Client, Response};
+use reqwest::Client;
 use serde_json::Value;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use std::future::Future;
 
-#[async_trait]
-impl ChatModelStream for OpenAIChatModel {
-    async fn stream(&self, chat_completion_req: ChatCompletionReq) -> Result<LLMStream, String> {
-        let api_url = &self.api_url;
-        let client = Client::new();
-        let mut req = Self::chat_completion_req_to_openai_req(&chat_completion_req);
-        req.stream = Some(true);
-        let response: Response = match client
-            .post(api_url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&req)
+impl OpenAIChatModel {
+    /// Send the request to the chat completions endpoint as a streamed request, the stream fails
+    /// with a timeout when it goes longer than the request's `stream_idle_timeout` between chunks.
+    pub async fn stream_chat_completion(&self, chat_completion_req: ChatCompletionReq) -> Result<LLMStream, LlmError> {
+        let mut body = Self::chat_completion_req_to_openai_body(&chat_completion_req).map_err(LlmError::InvalidRequest)?;
+        body["stream"] = Value::Bool(true);
+        let response = self.authorize(Client::new().post(format!("{}/chat/completions", self.api_url)), chat_completion_req.config.headers.as_ref())
+            .json(&body)
             .send()
             .await
-        {
-            Ok(response) => response,
-            Err(error) => return Err(format!("API request error: {}", error)),
-        };
-
-        if response.status().is_success() {
-            Ok(LLMStream {
-                response: Box::pin(response.bytes_stream()),
-                buffer: String::new(),
-                first_chunk: true,
-                usage: Usage::default(),
-            })
-        } else {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| String::from("Unknown error"));
-            Err(format!("API request error: {}", error_text))
+            .map_err(|e| LlmError::Network(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(error_for_status(status.as_u16(), text));
+        }
+        let idle_timeout = chat_completion_req.config.stream_idle_timeout
+            .map(Duration::from_secs)
+            .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+        Ok(LLMStream {
+            response: Box::pin(response.bytes_stream()),
+            buffer: String::new(),
+            cumulative_content: self.cumulative_stream_content,
+            pending_line: vec![],
+            tool_calls: Default::default(),
+            ready: Default::default(),
+            usage: Usage::default(),
+            idle_timeout,
+            finished: false,
+        })
+    }
+}
+
+impl LLMStream {
+    /// Parse a line of the server-sent events of the response, queueing the items it completes.
+    fn handle_line(&mut self, line: &str) {
+        let Some(data) = line.strip_prefix("data:") else { return };
+        let data = data.trim();
+        if data == "[DONE]" {
+            self.flush_tool_calls();
+            return;
+        }
+        let Ok(json) = serde_json::from_str::<Value>(data) else { return };
+        let Some(choice) = json.get("choices").and_then(|choices| choices.get(0)) else { return };
+        if let Some(delta) = choice.get("delta") {
+            if let Some(content) = delta.get("content").and_then(|content| content.as_str()) {
+                let delta = self.content_delta(content);
+                if !delta.is_empty() {
+                    self.ready.push_back(Ok(LLMStreamItem::Content(delta)));
+                }
+            }
+            if let Some(tool_calls) = delta.get("tool_calls").and_then(|tool_calls| tool_calls.as_array()) {
+                for (position, tool_call) in tool_calls.iter().enumerate() {
+                    let index = tool_call.get("index").and_then(|index| index.as_u64()).unwrap_or(position as u64);
+                    self.accumulate_tool_call(index, tool_call.get("id"), tool_call.get("function"));
+                }
+            }
+            // Function calls, the predecessor of tool calls, are limited to one per response
+            if let Some(function_call) = delta.get("function_call") {
+                self.accumulate_tool_call(0, None, Some(function_call));
+            }
+        }
+        if let Some(finish_reason) = choice.get("finish_reason").and_then(|reason| reason.as_str()) {
+            self.flush_tool_calls();
+            self.ready.push_back(Ok(LLMStreamItem::FinishReason(finish_reason.to_string())));
+        }
+    }
+
+    /// The text a chunk of content adds to the response. For providers streaming the content
+    /// accumulated so far, that is the part of the chunk beyond the content already received.
+    /// A cumulative chunk that does not extend the content received is taken as a delta.
+    fn content_delta(&mut self, content: &str) -> String {
+        let delta = match content.strip_prefix(self.buffer.as_str()) {
+            Some(extension) if self.cumulative_content => extension,
+            _ => content,
+        }.to_string();
+        self.buffer.push_str(&delta);
+        delta
+    }
+
+    /// Arguments of tool calls are streamed in fragments, which are appended to those received so far.
+    fn accumulate_tool_call(&mut self, index: u64, id: Option<&Value>, function: Option<&Value>) {
+        let tool_call = self.tool_calls.entry(index).or_default();
+        if let Some(id) = id.and_then(|id| id.as_str()) {
+            tool_call.id = id.to_string();
+        }
+        if let Some(function) = function {
+            if let Some(name) = function.get("name").and_then(|name| name.as_str()) {
+                tool_call.name = Some(name.to_string());
+            }
+            if let Some(arguments) = function.get("arguments").and_then(|arguments| arguments.as_str()) {
+                tool_call.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    /// Queue the tool calls assembled so far, in the order of their index. A call whose arguments
+    /// are not valid JSON is queued as an error.
+    fn flush_tool_calls(&mut self) {
+        for (_, tool_call) in std::mem::take(&mut self.tool_calls) {
+            let arguments = if tool_call.arguments.trim().is_empty() {
+                Ok(Value::Object(Default::default()))
+            } else {
+                serde_json::from_str::<Value>(&tool_call.arguments)
+            };
+            self.ready.push_back(match arguments {
+                Ok(arguments) => Ok(LLMStreamItem::ToolCall(ChatCompletionToolCall {
+                    id: tool_call.id,
+                    ty: "function".to_string(),
+                    function: ChatCompletionToolCallFunction {
+                        name: tool_call.name,
+                        arguments: Some(json_value_to_serialized_value(&arguments)),
+                    },
+                })),
+                Err(e) => Err(LlmError::MalformedToolArguments {
+                    name: tool_call.name.unwrap_or_default(),
+                    message: e.to_string(),
+                }),
+            });
         }
     }
 }
 
 impl Stream for LLMStream {
-    type Item = String;
+    type Item = Result<LLMStreamItem, LlmError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if self.finished {
+                return Poll::Ready(None);
+            }
             match self.response.as_mut().poll_next(cx) {
                 Poll::Ready(Some(Ok(chunk))) => {
-                    let mut utf8_str = String::from_utf8_lossy(&chunk).to_string();
-
-                    if self.first_chunk {
-                        let lines: Vec<&str> = utf8_str.lines().collect();
-                        utf8_str = if lines.len() >= 2 {
-                            lines[lines.len() - 2].to_string()
-                        } else {
-                            utf8_str.clone()
-                        };
-                        self.first_chunk = false;
+                    if let Some((timeout, deadline)) = self.idle_timeout.as_mut() {
+                        deadline.as_mut().reset(tokio::time::Instant::now() + *timeout);
                     }
-
-                    let trimmed_str = utf8_str.trim_start_matches("data: ");
-
-                    let json_result: Result<Value, _> = serde_json::from_str(trimmed_str);
-
-                    match json_result {
-                        Ok(json) => {
-                            if let Some(choices) = json.get("choices") {
-                                if let Some(choice) = choices.get(0) {
-                                    if let Some(content) =
-                                        choice.get("delta").and_then(|delta| delta.get("content"))
-                                    {
-                                        if let Some(content_str) = content.as_str() {
-                                            self.buffer.push_str(content_str);
-                                            let output = self.buffer.replace("\\n", "\n");
-                                            return Poll::Ready(Some(output));
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {}
+                    // Events may be split across chunks, only complete lines are parsed
+                    self.pending_line.extend_from_slice(&chunk);
+                    while let Some(newline) = self.pending_line.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = self.pending_line.drain(..=newline).collect();
+                        self.handle_line(String::from_utf8_lossy(&line).trim());
                     }
                 }
                 Poll::Ready(Some(Err(error))) => {
-                    eprintln!("Error in stream: {:?}", error);
-                    return Poll::Ready(None);
+                    self.finished = true;
+                    return Poll::Ready(Some(Err(LlmError::Network(error.to_string()))));
                 }
                 Poll::Ready(None) => {
-                    return Poll::Ready(None);
+                    let line = std::mem::take(&mut self.pending_line);
+                    self.handle_line(String::from_utf8_lossy(&line).trim());
+                    self.flush_tool_calls();
+                    self.finished = true;
                 }
                 Poll::Pending => {
+                    // A stream that stops delivering chunks without closing is failed once idle for too long
+                    if let Some((timeout, deadline)) = self.idle_timeout.as_mut() {
+                        if deadline.as_mut().poll(cx).is_ready() {
+                            let timeout = *timeout;
+                            self.finished = true;
+                            return Poll::Ready(Some(Err(LlmError::Timeout(timeout))));
+                        }
+                    }
                     return Poll::Pending;
                 }
             }
@@ -111,16 +190,171 @@ mod tests {
     use futures_util::stream::StreamExt;
     use openai_api_rs::v1::chat_completion::{ChatCompletionMessage, MessageRole};
     use std::env;
+    use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue};
+    use crate::sdk::config::ProviderConfiguration;
+    use crate::execution::execution::ExecutionState;
+    use crate::execution::execution::progress::{progress_channel, ProgressEvent};
+    use uuid::Uuid;
 
     #[ignore]
     #[tokio::test]
     async fn test_gpt_stream_raw_line() {
         dotenv::dotenv().ok();
-        let model = crate::library::std::ai::llm::openai::OpenAIChatModel::new("http://localhost:4000/v1/chat/completions".to_string(), "".to_string());
-        let stream = model.stream(Default::default()).await.unwrap();
+        let model = crate::library::std::ai::llm::openai::OpenAIChatModel::new("http://localhost:4000/v1".to_string(), "".to_string());
+        let stream = model.stream_chat_completion(Default::default()).await.unwrap();
         let mut stream = Box::pin(stream);
         while let Some(value) = stream.next().await {
-            println!("{}", value);
+            println!("{:?}", value.unwrap());
         }
     }
+
+    #[tokio::test]
+    async fn test_stalled_stream_times_out() {
+        // Responds with a single chunk and then holds the connection open without sending more
+        let first_chunk = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n";
+        let app = axum::Router::new().route("/v1/chat/completions", axum::routing::post(move || async move {
+            let chunks = futures_util::stream::once(async move { Ok::<_, std::io::Error>(first_chunk) })
+                .chain(futures_util::stream::pending());
+            axum::body::Body::from_stream(chunks)
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let model = OpenAIChatModel::new(format!("http://{}/v1", addr), "".to_string());
+        let mut req = ChatCompletionReq::default();
+        req.config.stream_idle_timeout = Some(1);
+        let mut stream = Box::pin(model.stream_chat_completion(req).await.unwrap());
+        let items = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut items = vec![];
+            while let Some(item) = stream.next().await {
+                items.push(item);
+            }
+            items
+        }).await.expect("stalled stream should terminate");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0], Ok(LLMStreamItem::Content("Hello".to_string())));
+        assert_eq!(items[1], Err(LlmError::Timeout(Duration::from_secs(1))));
+    }
+
+    /// Serve the given chunks as the body of a streamed response.
+    async fn serve_chunks(chunks: Vec<&'static str>) -> OpenAIChatModel {
+        serve_chunks_from_provider(chunks, &ProviderConfiguration::default()).await
+    }
+
+    async fn serve_chunks_from_provider(chunks: Vec<&'static str>, provider: &ProviderConfiguration) -> OpenAIChatModel {
+        let app = axum::Router::new().route("/v1/chat/completions", axum::routing::post(move || async move {
+            let chunks = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+            axum::body::Body::from_stream(chunks)
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        OpenAIChatModel::from_provider_configuration(&ProviderConfiguration {
+            api_url: Some(format!("http://{}/v1", addr)),
+            ..provider.clone()
+        }, None)
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_tool_call_arguments_are_assembled() {
+        // Events split across chunks, with content interleaved between the fragments of two tool calls
+        let model = serve_chunks(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Let me check\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"weather\",\"arguments\":\"{\\\"ci\"}}]}}]}\n\ndata: {\"choices\":[{\"delta\":{\"tool_ca",
+            "lls\":[{\"index\":1,\"id\":\"call_2\",\"function\":{\"name\":\"time\",\"arguments\":\"{}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\".\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"ty\\\": \\\"Paris\\\"}\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\ndata: [DONE]\n\n",
+        ]).await;
+        let items: Vec<_> = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap().collect().await;
+        let tool_call = |id: &str, name: &str, arguments: RkyvSerializedValue| LLMStreamItem::ToolCall(ChatCompletionToolCall {
+            id: id.to_string(),
+            ty: "function".to_string(),
+            function: ChatCompletionToolCallFunction { name: Some(name.to_string()), arguments: Some(arguments) },
+        });
+        assert_eq!(items, vec![
+            Ok(LLMStreamItem::Content("Let me check".to_string())),
+            Ok(LLMStreamItem::Content(".".to_string())),
+            Ok(tool_call("call_1", "weather", RkyvObjectBuilder::new().insert_string("city", "Paris".to_string()).build())),
+            Ok(tool_call("call_2", "time", RkyvObjectBuilder::new().build())),
+            Ok(LLMStreamItem::FinishReason("tool_calls".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_cumulative_content_is_emitted_as_deltas() {
+        let provider = ProviderConfiguration { cumulative_stream_content: Some(true), ..Default::default() };
+        let model = serve_chunks_from_provider(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello, world\"}}]}\n\ndata: [DONE]\n\n",
+        ], &provider).await;
+        let items: Vec<_> = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap().collect().await;
+        assert_eq!(items, vec![
+            Ok(LLMStreamItem::Content("Hel".to_string())),
+            Ok(LLMStreamItem::Content("lo".to_string())),
+            Ok(LLMStreamItem::Content(", world".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_incremental_content_is_emitted_unchanged() {
+        // Deltas that repeat or extend the content before them are not mistaken for cumulative chunks
+        let model = serve_chunks(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ha\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ha\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"hahan\"}}]}\n\ndata: [DONE]\n\n",
+        ]).await;
+        let items: Vec<_> = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap().collect().await;
+        assert_eq!(items, vec![
+            Ok(LLMStreamItem::Content("ha".to_string())),
+            Ok(LLMStreamItem::Content("ha".to_string())),
+            Ok(LLMStreamItem::Content("hahan".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_content_is_reported_as_token_deltas() {
+        let model = serve_chunks(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\n\ndata: [DONE]\n\n",
+        ]).await;
+        let (sender, mut receiver) = progress_channel(8);
+        let mut state = ExecutionState::new_with_random_id();
+        state.progress_sender = Some(sender);
+        state.evaluating_operation_id = Uuid::now_v7();
+        let stream = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap();
+        let items: Vec<_> = llm::report_token_deltas(&state, stream).collect().await;
+        assert_eq!(items.len(), 2);
+        for delta in ["Hel", "lo"] {
+            assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::TokenDelta {
+                operation_id: state.evaluating_operation_id,
+                delta: delta.to_string(),
+            });
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_escaped_content_is_decoded_once() {
+        // A literal backslash followed by n, as when the model writes a newline escape in code
+        let model = serve_chunks(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"print(\\\"a\\\\nb\\\")\\n\"}}]}\n\ndata: [DONE]\n\n",
+        ]).await;
+        let items: Vec<_> = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap().collect().await;
+        assert_eq!(items, vec![Ok(LLMStreamItem::Content("print(\"a\\nb\")\n".to_string()))]);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_tool_call_arguments_are_an_error() {
+        let model = serve_chunks(vec![
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"weather\",\"arguments\":\"{\\\"city\"}}]}}]}\n\n",
+            "data: [DONE]\n\n",
+        ]).await;
+        let items: Vec<_> = model.stream_chat_completion(ChatCompletionReq::default()).await.unwrap().collect().await;
+        assert_eq!(items.len(), 1);
+        assert!(matches!(&items[0], Err(LlmError::MalformedToolArguments { name, .. }) if name == "weather"), "{:?}", items);
+    }
 }
@@ -0,0 +1,110 @@
+use crate::library::std::ai::llm::{MessageRole, TemplateMessage};
+use crate::sdk::config::MessageOrdering;
+
+/// Adjust the messages of a request to the ordering a provider requires. Merged messages have their
+/// contents joined by a blank line, messages carrying a function call or a name are never merged.
+pub fn order_messages(mut messages: Vec<TemplateMessage>, ordering: &MessageOrdering) -> Vec<TemplateMessage> {
+    // Function messages keep their name, which identifies the function whose result they carry
+    if ordering.drop_message_names {
+        for message in messages.iter_mut().filter(|m| m.role != MessageRole::Function) {
+            message.name = None;
+        }
+    }
+    let messages = if ordering.single_system_message {
+        merge_system_messages(messages)
+    } else {
+        messages
+    };
+    if !ordering.alternate_roles {
+        return messages;
+    }
+    let mut ordered: Vec<TemplateMessage> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match ordered.last_mut() {
+            Some(previous) if is_mergeable(previous) && is_mergeable(&message) && previous.role == message.role => {
+                previous.content.push_str("\n\n");
+                previous.content.push_str(&message.content);
+            }
+            _ => ordered.push(message),
+        }
+    }
+    ordered
+}
+
+fn is_mergeable(message: &TemplateMessage) -> bool {
+    matches!(message.role, MessageRole::User | MessageRole::Assistant)
+        && message.function_call.is_none()
+        && message.name.is_none()
+}
+
+/// Join every system message into one, placed ahead of the other messages.
+fn merge_system_messages(messages: Vec<TemplateMessage>) -> Vec<TemplateMessage> {
+    let (system_messages, conversation): (Vec<_>, Vec<_>) = messages
+        .into_iter()
+        .partition(|m| m.role == MessageRole::System);
+    if system_messages.len() < 2 {
+        return system_messages.into_iter().chain(conversation).collect();
+    }
+    let content = system_messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>().join("\n\n");
+    std::iter::once(TemplateMessage {
+        role: MessageRole::System,
+        content,
+        name: None,
+        function_call: None,
+    }).chain(conversation).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::std::ai::llm::FunctionCall;
+
+    fn message(role: MessageRole, content: &str) -> TemplateMessage {
+        TemplateMessage { role, content: content.to_string(), name: None, function_call: None }
+    }
+
+    #[test]
+    fn test_order_messages() {
+        let messages = vec![
+            message(MessageRole::System, "Be brief."),
+            message(MessageRole::User, "Here is a document."),
+            message(MessageRole::User, "Summarize it."),
+            message(MessageRole::System, "Answer in French."),
+            message(MessageRole::Assistant, ""),
+        ];
+        assert_eq!(order_messages(messages.clone(), &MessageOrdering::default()), messages);
+
+        let strict = MessageOrdering { alternate_roles: true, single_system_message: true, drop_message_names: false };
+        assert_eq!(order_messages(messages, &strict), vec![
+            message(MessageRole::System, "Be brief.\n\nAnswer in French."),
+            message(MessageRole::User, "Here is a document.\n\nSummarize it."),
+            message(MessageRole::Assistant, ""),
+        ]);
+
+        // The calls of a tool and their results keep their own messages
+        let call = TemplateMessage {
+            function_call: Some(FunctionCall { id: None, name: Some("lookup".to_string()), arguments: None }),
+            ..message(MessageRole::Assistant, "")
+        };
+        let messages = vec![message(MessageRole::Assistant, "Let me check."), call];
+        assert_eq!(order_messages(messages.clone(), &strict), messages);
+    }
+
+    #[test]
+    fn test_drop_message_names() {
+        let named = |role, content: &str, name: &str| TemplateMessage { name: Some(name.to_string()), ..message(role, content) };
+        let messages = vec![
+            named(MessageRole::User, "Hi Bob.", "alice"),
+            named(MessageRole::User, "Hi Alice.", "bob"),
+            named(MessageRole::Function, "42", "lookup"),
+        ];
+        assert_eq!(order_messages(messages.clone(), &MessageOrdering::default()), messages);
+
+        let unnamed = MessageOrdering { drop_message_names: true, ..MessageOrdering::default() };
+        assert_eq!(order_messages(messages, &unnamed), vec![
+            message(MessageRole::User, "Hi Bob."),
+            message(MessageRole::User, "Hi Alice."),
+            named(MessageRole::Function, "42", "lookup"),
+        ]);
+    }
+}
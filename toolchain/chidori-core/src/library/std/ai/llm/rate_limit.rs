@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionRes, ChatCompletionStream, ChatModelBatch, LlmError};
+
+/// Spaces requests evenly so that no more than `requests_per_minute` are sent in any minute.
+pub struct RateLimiter {
+    interval: Duration,
+    next_request: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests_per_minute.max(1),
+            next_request: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Wait until the next request may be sent, reserving its slot.
+    pub async fn acquire(&self) {
+        let mut next_request = self.next_request.lock().await;
+        let now = Instant::now();
+        let slot = next_request.map_or(now, |next| next.max(now));
+        *next_request = Some(slot + self.interval);
+        drop(next_request);
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Rate limiters of the providers requests are sent to, keyed by provider name and shared by
+/// every state of the run so that concurrent cells calling the same provider share its limit.
+#[derive(Default)]
+pub struct RateLimiters {
+    limiters: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl RateLimiters {
+    /// The limiter of `provider`, replaced if the provider's limit has been reconfigured.
+    pub fn limiter(&self, provider: &str, requests_per_minute: u32) -> Arc<RateLimiter> {
+        let interval = Duration::from_secs(60) / requests_per_minute.max(1);
+        let mut limiters = self.limiters.lock().unwrap();
+        match limiters.get(provider) {
+            Some(limiter) if limiter.interval == interval => limiter.clone(),
+            _ => {
+                let limiter = Arc::new(RateLimiter::new(requests_per_minute));
+                limiters.insert(provider.to_string(), limiter.clone());
+                limiter
+            }
+        }
+    }
+}
+
+/// A chat model whose requests wait on the rate limiter of its provider before they are sent.
+pub struct RateLimitedChatModel {
+    inner: Arc<dyn ChatModelBatch + Send + Sync>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedChatModel {
+    pub fn new(inner: Arc<dyn ChatModelBatch + Send + Sync>, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+#[async_trait]
+impl ChatModelBatch for RateLimitedChatModel {
+    async fn batch(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+        self.limiter.acquire().await;
+        self.inner.batch(chat_completion_req).await
+    }
+
+    async fn stream(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionStream, LlmError> {
+        self.limiter.acquire().await;
+        self.inner.stream(chat_completion_req).await
+    }
+
+    fn endpoint(&self) -> Option<String> {
+        self.inner.endpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::std::ai::llm::Usage;
+
+    struct TimestampingModel(Arc<Mutex<Vec<Instant>>>);
+
+    #[async_trait]
+    impl ChatModelBatch for TimestampingModel {
+        async fn batch(&self, _chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+            self.0.lock().unwrap().push(Instant::now());
+            Ok(ChatCompletionRes {
+                id: "res".to_string(),
+                object: "chat.completion".to_string(),
+                created: 0,
+                model: String::new(),
+                choices: vec![],
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_spaced_by_the_provider_limit() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let limiters = RateLimiters::default();
+        let model: Arc<dyn ChatModelBatch + Send + Sync> = Arc::new(RateLimitedChatModel::new(
+            Arc::new(TimestampingModel(sent.clone())),
+            limiters.limiter("openai", 600),
+        ));
+
+        let requests = (0..3).map(|_| model.batch(ChatCompletionReq::default()));
+        for response in futures::future::join_all(requests).await {
+            response.unwrap();
+        }
+
+        // 600 requests per minute leaves 100ms between requests, even when sent concurrently
+        let mut sent = sent.lock().unwrap().clone();
+        sent.sort();
+        assert_eq!(sent.len(), 3);
+        for pair in sent.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(95), "{:?}", pair[1] - pair[0]);
+        }
+    }
+
+    #[test]
+    fn test_limiters_are_shared_per_provider() {
+        let limiters = RateLimiters::default();
+        assert!(Arc::ptr_eq(&limiters.limiter("openai", 60), &limiters.limiter("openai", 60)));
+        assert!(!Arc::ptr_eq(&limiters.limiter("openai", 60), &limiters.limiter("gateway", 60)));
+        assert!(!Arc::ptr_eq(&limiters.limiter("openai", 60), &limiters.limiter("openai", 120)));
+    }
+}
@@ -0,0 +1,78 @@
+use chidori_prompt_format::serde_json::Value;
+
+use crate::cells::ModelRoute;
+use crate::library::std::ai::llm::history::estimate_messages_tokens;
+use crate::library::std::ai::llm::TemplateMessage;
+
+/// The first route matching a prompt, given its rendered messages and the data it was rendered from.
+pub fn select_route<'a>(routes: &'a [ModelRoute], messages: &[TemplateMessage], data: &Value) -> Option<&'a ModelRoute> {
+    let prompt_tokens = estimate_messages_tokens(messages);
+    routes.iter().find(|route| route_matches(route, prompt_tokens, data))
+}
+
+fn route_matches(route: &ModelRoute, prompt_tokens: usize, data: &Value) -> bool {
+    if route.min_prompt_tokens.is_some_and(|min| prompt_tokens < min) {
+        return false;
+    }
+    if route.max_prompt_tokens.is_some_and(|max| prompt_tokens > max) {
+        return false;
+    }
+    if let Some(input) = &route.when_input {
+        return !matches!(data.get(input), None | Some(Value::Null) | Some(Value::Bool(false)));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use chidori_prompt_format::serde_json::json;
+
+    use super::*;
+    use crate::library::std::ai::llm::MessageRole;
+
+    fn user_message(content: &str) -> TemplateMessage {
+        TemplateMessage {
+            role: MessageRole::User,
+            content: content.to_string(),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    fn routes() -> Vec<ModelRoute> {
+        vec![
+            ModelRoute {
+                model: "o1".to_string(),
+                when_input: Some("complex".to_string()),
+                ..Default::default()
+            },
+            ModelRoute {
+                model: "gpt-4o-mini".to_string(),
+                max_prompt_tokens: Some(50),
+                ..Default::default()
+            },
+            ModelRoute {
+                model: "gpt-4o".to_string(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_routes_by_prompt_tokens() {
+        let routes = routes();
+        let short = vec![user_message("What is the capital of France?")];
+        assert_eq!(select_route(&routes, &short, &json!({})).unwrap().model, "gpt-4o-mini");
+        let long = vec![user_message(&"Summarize this report. ".repeat(100))];
+        assert_eq!(select_route(&routes, &long, &json!({})).unwrap().model, "gpt-4o");
+    }
+
+    #[test]
+    fn test_routes_by_input() {
+        let routes = routes();
+        let short = vec![user_message("What is the capital of France?")];
+        assert_eq!(select_route(&routes, &short, &json!({"complex": true})).unwrap().model, "o1");
+        assert_eq!(select_route(&routes, &short, &json!({"complex": false})).unwrap().model, "gpt-4o-mini");
+        assert!(select_route(&routes[..1], &short, &json!({})).is_none());
+    }
+}
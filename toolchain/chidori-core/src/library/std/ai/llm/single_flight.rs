@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+use crate::library::std::ai::llm::cache::request_key;
+use crate::library::std::ai::llm::{ChatCompletionReq, ChatCompletionRes, ChatModelBatch, LlmError};
+
+type SharedResponse = Shared<BoxFuture<'static, Result<ChatCompletionRes, LlmError>>>;
+
+/// Requests to chat models that are awaiting a response, keyed by `request_key` of the request.
+/// A request identical to one already in flight awaits that request's response instead of making
+/// another call, so that a fan-out of cells rendering the same prompt results in a single call.
+#[derive(Default)]
+pub struct InFlightRequests {
+    requests: Mutex<HashMap<String, SharedResponse>>,
+}
+
+impl InFlightRequests {
+    /// Send a request to `model`, or await the response to an identical request already in flight.
+    pub async fn batch(&self, model: Arc<dyn ChatModelBatch + Send + Sync>, provider: &str, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+        self.batch_shared(model, provider, chat_completion_req).await.0
+    }
+
+    /// As `batch`, additionally reporting whether the response was shared with an identical request
+    /// already in flight rather than obtained by a call of this request's own.
+    pub async fn batch_shared(&self, model: Arc<dyn ChatModelBatch + Send + Sync>, provider: &str, chat_completion_req: ChatCompletionReq) -> (Result<ChatCompletionRes, LlmError>, bool) {
+        let key = match request_key(provider, model.endpoint().as_deref(), &chat_completion_req) {
+            Ok(key) => key,
+            Err(e) => return (Err(LlmError::InvalidRequest(e)), false),
+        };
+        let (response, shared) = {
+            let mut requests = self.requests.lock().unwrap();
+            let shared = requests.contains_key(&key);
+            let response = requests
+                .entry(key.clone())
+                .or_insert_with(|| async move { model.batch(chat_completion_req).await }.boxed().shared())
+                .clone();
+            (response, shared)
+        };
+        let result = response.clone().await;
+
+        // Later identical requests are made anew, the entry is only removed if it has not already
+        // been replaced by such a request
+        let mut requests = self.requests.lock().unwrap();
+        if requests.get(&key).is_some_and(|in_flight| in_flight.ptr_eq(&response)) {
+            requests.remove(&key);
+        }
+        (result, shared)
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::library::std::ai::llm::{ChatCompletionChoice, MessageRole, TemplateMessage, Usage};
+
+    struct SlowCountingModel(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl ChatModelBatch for SlowCountingModel {
+        async fn batch(&self, chat_completion_req: ChatCompletionReq) -> Result<ChatCompletionRes, LlmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(ChatCompletionRes {
+                id: "res".to_string(),
+                object: "chat.completion".to_string(),
+                created: 0,
+                model: chat_completion_req.config.model.unwrap_or_default(),
+                choices: vec![ChatCompletionChoice {
+                    text: Some(chat_completion_req.template_messages[0].content.to_uppercase()),
+                    index: 0,
+                    logprobs: None,
+                    finish_reason: "stop".to_string(),
+                    tool_calls: None,
+                }],
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    fn request(content: &str) -> ChatCompletionReq {
+        ChatCompletionReq {
+            template_messages: vec![TemplateMessage {
+                role: MessageRole::User,
+                content: content.to_string(),
+                name: None,
+                function_call: None,
+            }],
+            ..ChatCompletionReq::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identical_concurrent_requests_share_one_call() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model: Arc<dyn ChatModelBatch + Send + Sync> = Arc::new(SlowCountingModel(calls.clone()));
+        let in_flight = InFlightRequests::default();
+
+        let responses = futures_util::future::join_all((0..10).map(|_| in_flight.batch_shared(model.clone(), "openai", request("hello")))).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(responses.iter().filter(|(_, shared)| *shared).count(), 9);
+        for (response, _) in responses {
+            assert_eq!(response.unwrap().choices[0].text.as_deref(), Some("HELLO"));
+        }
+        assert!(in_flight.is_empty());
+
+        let (a, b) = tokio::join!(in_flight.batch(model.clone(), "openai", request("a")), in_flight.batch(model.clone(), "openai", request("b")));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(a.unwrap().choices[0].text.as_deref(), Some("A"));
+        assert_eq!(b.unwrap().choices[0].text.as_deref(), Some("B"));
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chidori_prompt_format::templating::truncate::Tokenizer;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Encoding};
+use tiktoken_rs::CoreBPE;
+
+use crate::cells::LLMPromptCellChatConfiguration;
+
+/// Largest magnitude of a bias accepted by OpenAI's `logit_bias`.
+const MAX_LOGIT_BIAS: i32 = 100;
+
+/// Tokenizers built so far, one per encoding, as building one parses the encoding's whole vocabulary.
+static TOKENIZERS: Lazy<DashMap<Encoding, Arc<CoreBPE>>> = Lazy::new(DashMap::new);
+
+/// The tokenizer of a model, models it does not recognize are assumed to use cl100k_base.
+fn tokenizer(model: Option<&str>) -> anyhow::Result<Arc<CoreBPE>> {
+    let encoding = model.and_then(get_tokenizer).unwrap_or(Encoding::Cl100kBase);
+    let bpe = TOKENIZERS
+        .entry(encoding)
+        .or_try_insert_with(|| tiktoken_rs::get_bpe_from_tokenizer(encoding).map(Arc::new))?;
+    Ok(bpe.clone())
+}
+
+/// A model's tokenizer, as the `{{truncate}}` template helper counts its budget in.
+struct TemplateTokenizer(Arc<CoreBPE>);
+
+impl Tokenizer for TemplateTokenizer {
+    fn encode(&self, text: &str) -> Vec<usize> {
+        self.0.encode_ordinary(text)
+    }
+
+    fn decode(&self, tokens: &[usize]) -> Option<String> {
+        self.0.decode(tokens.to_vec()).ok()
+    }
+}
+
+/// The tokenizer templates rendered for `model` truncate values with.
+pub fn template_tokenizer(model: Option<&str>) -> anyhow::Result<Arc<dyn Tokenizer>> {
+    Ok(Arc::new(TemplateTokenizer(tokenizer(model)?)))
+}
+
+/// Check the logit biases of a configuration, the keys of `logit_bias` must be token ids and
+/// every bias must be within -100..=100.
+pub fn validate_logit_bias(configuration: &LLMPromptCellChatConfiguration) -> Result<(), String> {
+    for (token_id, bias) in configuration.logit_bias.iter().flatten() {
+        if token_id.parse::<u32>().is_err() {
+            return Err(format!("logit_bias key {:?} is not a token id, use logit_bias_text to bias literal text", token_id));
+        }
+        check_bias(token_id, *bias)?;
+    }
+    for (text, bias) in configuration.logit_bias_text.iter().flatten() {
+        check_bias(text, *bias)?;
+    }
+    Ok(())
+}
+
+fn check_bias(key: &str, bias: i32) -> Result<(), String> {
+    if !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(&bias) {
+        return Err(format!("logit bias {} for {:?} is outside of -{max}..={max}", bias, key, max = MAX_LOGIT_BIAS));
+    }
+    Ok(())
+}
+
+/// The logit biases to send for a configuration, keyed by token id. Literal text of
+/// `logit_bias_text` is encoded with the tokenizer of `model` and every token it encodes to
+/// receives its bias, biases given for a token id in `logit_bias` take precedence.
+pub fn resolve_logit_bias(configuration: &LLMPromptCellChatConfiguration, model: Option<&str>) -> anyhow::Result<Option<HashMap<String, i32>>> {
+    let Some(logit_bias_text) = configuration.logit_bias_text.as_ref().filter(|text| !text.is_empty()) else {
+        return Ok(configuration.logit_bias.clone());
+    };
+    let bpe = tokenizer(model)?;
+    let mut logit_bias = HashMap::new();
+    for (text, bias) in logit_bias_text {
+        for token in bpe.encode_ordinary(text) {
+            logit_bias.insert(token.to_string(), *bias);
+        }
+    }
+    logit_bias.extend(configuration.logit_bias.clone().unwrap_or_default());
+    Ok(Some(logit_bias))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(frontmatter: &str) -> LLMPromptCellChatConfiguration {
+        serde_yaml::from_str(frontmatter).unwrap()
+    }
+
+    #[test]
+    fn test_logit_bias_text_resolves_to_token_ids() {
+        let configuration = configuration(indoc::indoc! {r#"
+            model: gpt-4o
+            logit_bias:
+              "1734": 5
+            logit_bias_text:
+              " delve": -100
+            "#});
+        validate_logit_bias(&configuration).unwrap();
+        let logit_bias = resolve_logit_bias(&configuration, Some("gpt-4o")).unwrap().unwrap();
+        let delve = tiktoken_rs::get_bpe_from_model("gpt-4o").unwrap().encode_ordinary(" delve");
+        assert!(!delve.is_empty());
+        for token in delve {
+            assert_eq!(logit_bias.get(&token.to_string()), Some(&-100));
+        }
+        assert_eq!(logit_bias.get("1734"), Some(&5));
+    }
+
+    #[test]
+    fn test_tokenizer_is_built_once_per_encoding() {
+        let gpt_4 = tokenizer(Some("gpt-4")).unwrap();
+        assert!(Arc::ptr_eq(&gpt_4, &tokenizer(Some("gpt-3.5-turbo")).unwrap()));
+        // Unrecognized models share the cl100k_base tokenizer
+        assert!(Arc::ptr_eq(&gpt_4, &tokenizer(None).unwrap()));
+        assert!(Arc::ptr_eq(&gpt_4, &tokenizer(Some("a-model-of-our-own")).unwrap()));
+        assert!(!Arc::ptr_eq(&gpt_4, &tokenizer(Some("gpt-4o")).unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_logit_bias() {
+        assert!(validate_logit_bias(&configuration("logit_bias:\n  \"1734\": 101")).is_err());
+        assert!(validate_logit_bias(&configuration("logit_bias_text:\n  hello: -101")).is_err());
+        assert!(validate_logit_bias(&configuration("logit_bias:\n  hello: 1")).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::execution::primitives::operation::OperationFnOutput;
+
+/// Tokens consumed by the responses of one model, or of every model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelUsage {
+    /// Responses received, including those shared with an identical request.
+    pub responses: usize,
+    /// Responses shared with an identical request, which consumed no tokens of their own.
+    pub cache_hits: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl ModelUsage {
+    fn add(&mut self, other: &ModelUsage) {
+        self.responses += other.responses;
+        self.cache_hits += other.cache_hits;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// Token usage of a run broken down by provider and model, displayed as a report of one line per model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageSummary {
+    /// Usage keyed by provider and model.
+    pub by_model: BTreeMap<(String, String), ModelUsage>,
+}
+
+impl UsageSummary {
+    /// Summarize the usage recorded in the metadata of prompt cell outputs, outputs of other cells are ignored.
+    pub fn from_outputs<'a>(outputs: impl IntoIterator<Item = &'a OperationFnOutput>) -> Self {
+        let mut summary = UsageSummary::default();
+        for output in outputs {
+            summary.record(output);
+        }
+        summary
+    }
+
+    pub fn record(&mut self, output: &OperationFnOutput) {
+        let Some(usage) = output.usage() else { return };
+        let key = (
+            output.provider().unwrap_or_default().to_string(),
+            output.model().unwrap_or_default().to_string(),
+        );
+        // The tokens of shared responses are recorded as zero, they were consumed by the request shared from
+        self.by_model.entry(key).or_default().add(&ModelUsage {
+            responses: 1,
+            cache_hits: output.cache_hit() as usize,
+            prompt_tokens: usage.prompt_tokens.max(0) as u64,
+            completion_tokens: usage.completion_tokens.max(0) as u64,
+            total_tokens: usage.total_tokens.max(0) as u64,
+        });
+    }
+
+    /// Usage summed over every provider and model.
+    pub fn total(&self) -> ModelUsage {
+        let mut total = ModelUsage::default();
+        for usage in self.by_model.values() {
+            total.add(usage);
+        }
+        total
+    }
+}
+
+impl fmt::Display for UsageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line = |f: &mut fmt::Formatter<'_>, label: &str, usage: &ModelUsage| writeln!(
+            f,
+            "{}: {} prompt + {} completion = {} tokens over {} responses ({} cached)",
+            label, usage.prompt_tokens, usage.completion_tokens, usage.total_tokens, usage.responses, usage.cache_hits
+        );
+        for ((provider, model), usage) in &self.by_model {
+            line(f, &format!("{}/{}", provider, model), usage)?;
+        }
+        line(f, "total", &self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::primitives::operation::{CACHE_HIT_METADATA_KEY, COMPLETION_TOKENS_METADATA_KEY, MODEL_METADATA_KEY, PROMPT_TOKENS_METADATA_KEY, PROVIDER_METADATA_KEY, TOTAL_TOKENS_METADATA_KEY};
+    use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+
+    fn output(model: &str, prompt_tokens: i32, completion_tokens: i32, cache_hit: bool) -> OperationFnOutput {
+        let mut output = OperationFnOutput::with_value(RkyvSerializedValue::Null);
+        output.metadata.insert(PROVIDER_METADATA_KEY.to_string(), "openai".to_string());
+        output.metadata.insert(MODEL_METADATA_KEY.to_string(), model.to_string());
+        output.metadata.insert(PROMPT_TOKENS_METADATA_KEY.to_string(), prompt_tokens.to_string());
+        output.metadata.insert(COMPLETION_TOKENS_METADATA_KEY.to_string(), completion_tokens.to_string());
+        output.metadata.insert(TOTAL_TOKENS_METADATA_KEY.to_string(), (prompt_tokens + completion_tokens).to_string());
+        if cache_hit {
+            output.metadata.insert(CACHE_HIT_METADATA_KEY.to_string(), "true".to_string());
+        }
+        output
+    }
+
+    #[test]
+    fn test_usage_summary_by_model() {
+        let outputs = vec![
+            output("gpt-4o", 10, 5, false),
+            output("gpt-4o", 20, 7, false),
+            output("gpt-4o", 0, 0, true),
+            output("gpt-4o-mini", 3, 1, false),
+            OperationFnOutput::with_value(RkyvSerializedValue::Null),
+        ];
+        let summary = UsageSummary::from_outputs(&outputs);
+        assert_eq!(summary.by_model.len(), 2);
+        assert_eq!(summary.by_model[&("openai".to_string(), "gpt-4o".to_string())], ModelUsage {
+            responses: 3,
+            cache_hits: 1,
+            prompt_tokens: 30,
+            completion_tokens: 12,
+            total_tokens: 42,
+        });
+        assert_eq!(summary.total().total_tokens, 46);
+        assert_eq!(summary.to_string().lines().last(), Some("total: 33 prompt + 13 completion = 46 tokens over 4 responses (1 cached)"));
+    }
+}
@@ -0,0 +1,276 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::cells::LLMOutputValidationConfiguration;
+use crate::execution::primitives::operation::{LogLine, OperationFnOutput};
+use crate::execution::primitives::serialized_value::RkyvSerializedValue;
+
+/// Validates the text produced by a model, returning the reason for the failure when invalid.
+pub type OutputValidator = Arc<dyn Fn(&str) -> Result<Validated, String> + Send + Sync>;
+
+/// Text that satisfied a validator, either as it was produced or once repaired.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Validated {
+    AsProduced,
+    /// The repaired text along with a description of each repair that was made.
+    Repaired { text: String, repairs: Vec<&'static str> },
+}
+
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// Build the validator described by a cell's frontmatter, None when no validation is requested.
+pub fn validator_from_configuration(configuration: &LLMOutputValidationConfiguration) -> Result<Option<OutputValidator>, String> {
+    let schema: Option<Value> = match &configuration.schema {
+        Some(schema) => Some(serde_json::from_str(schema).map_err(|e| format!("Invalid validation schema: {}", e))?),
+        None => None,
+    };
+    if schema.is_none() && !configuration.json {
+        return Ok(None);
+    }
+    let repair = configuration.repair;
+    Ok(Some(Arc::new(move |text: &str| {
+        let (value, validated) = match serde_json::from_str::<Value>(text.trim()) {
+            Ok(value) => (value, Validated::AsProduced),
+            Err(e) => match repair.then(|| repair_json(text)).flatten() {
+                Some((value, repairs)) => {
+                    let text = serde_json::to_string(&value).map_err(|e| e.to_string())?;
+                    (value, Validated::Repaired { text, repairs })
+                }
+                None => return Err(format!("Output is not valid JSON: {}", e)),
+            },
+        };
+        if let Some(schema) = &schema {
+            validate_against_schema(&value, schema, "$")?;
+        }
+        Ok(validated)
+    })))
+}
+
+/// Parse JSON with the malformations models commonly produce, returning the value along with a
+/// description of each repair that was needed. None when the text can not be repaired.
+pub fn repair_json(text: &str) -> Option<(Value, Vec<&'static str>)> {
+    let mut repairs = vec![];
+    let mut text = text.trim();
+    if let Some(fenced) = text.strip_prefix("```").and_then(|t| t.strip_suffix("```")) {
+        // The language of the fence, such as ```json, runs until the end of its line
+        text = fenced.split_once('\n').map_or(fenced, |(_, body)| body).trim();
+        repairs.push("removed the surrounding code fence");
+    }
+    if let Ok(value) = serde_json::from_str(text) {
+        return Some((value, repairs));
+    }
+    let value = json5::from_str::<Value>(text).ok()?;
+    repairs.push("parsed leniently, accepting trailing commas, comments, single quoted strings and unquoted keys");
+    Some((value, repairs))
+}
+
+/// Validates a value against the subset of JSON schema used for model output:
+/// `type`, `enum`, `properties`, `required` and `items`.
+pub fn validate_against_schema(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(ty) = schema.get("type").and_then(|t| t.as_str()) {
+        let matches = match ty {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{} expected type {} but found {}", path, ty, value));
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(|e| e.as_array()) {
+        if !allowed.contains(value) {
+            return Err(format!("{} is not one of the allowed values {:?}", path, allowed));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        if let Some(required) = schema.get("required").and_then(|r| r.as_array()) {
+            for key in required.iter().filter_map(|k| k.as_str()) {
+                if !object.contains_key(key) {
+                    return Err(format!("{} is missing required property {}", path, key));
+                }
+            }
+        }
+        if let Some(Value::Object(properties)) = schema.get("properties") {
+            for (key, property_schema) in properties {
+                if let Some(property) = object.get(key) {
+                    validate_against_schema(property, property_schema, &format!("{}.{}", path, key))?;
+                }
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate_against_schema(item, item_schema, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
+
+/// The text of a chat cell's output, either returned directly or nested under the cell's name.
+fn output_text(value: &RkyvSerializedValue) -> Option<&str> {
+    match value {
+        RkyvSerializedValue::String(s) => Some(s.as_str()),
+        RkyvSerializedValue::Object(m) if m.len() == 1 => match m.values().next() {
+            Some(RkyvSerializedValue::String(s)) => Some(s.as_str()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replace the text of a chat cell's output, wherever `output_text` found it.
+fn replace_output_text(value: &mut RkyvSerializedValue, text: String) {
+    match value {
+        RkyvSerializedValue::String(s) => *s = text,
+        RkyvSerializedValue::Object(m) => {
+            if let Some(RkyvSerializedValue::String(s)) = m.values_mut().next() {
+                *s = text;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// An attempt made by `retry_until_valid`, numbered from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempt {
+    pub number: usize,
+    /// How many of the preceding attempts were cut off by the model's token limit, so that
+    /// the next attempt can allow for a longer completion.
+    pub truncated_attempts: usize,
+}
+
+/// Repeatedly invoke `attempt` until its output satisfies the validator or the attempts are exhausted.
+/// Each failed attempt is logged with its reason, and once exhausted the last output is returned with
+/// `has_error` set and the failure reasons in stderr. Errors produced by the attempt itself are not retried.
+/// Output that is valid once repaired is replaced by its repaired text, with the repairs noted in stderr.
+pub async fn retry_until_valid<F, Fut>(
+    max_attempts: Option<usize>,
+    validator: OutputValidator,
+    mut attempt: F,
+) -> anyhow::Result<OperationFnOutput>
+where
+    F: FnMut(Attempt) -> Fut,
+    Fut: Future<Output = anyhow::Result<OperationFnOutput>>,
+{
+    let max_attempts = max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+    let mut failures = vec![];
+    let mut attempt_number = 1;
+    let mut truncated_attempts = 0;
+    loop {
+        let mut output = attempt(Attempt { number: attempt_number, truncated_attempts }).await?;
+        let value = match &output.output {
+            Ok(value) => value,
+            Err(_) => return Ok(output),
+        };
+        let validated = match output_text(value) {
+            Some(text) => validator(text),
+            None => Err("Output did not contain text to validate".to_string()),
+        };
+        let mut reason = match validated {
+            Ok(Validated::AsProduced) => return Ok(output),
+            Ok(Validated::Repaired { text, repairs }) => {
+                info!(attempt = attempt_number, repairs = ?repairs, "Repaired the JSON of the cell's output");
+                if let Ok(value) = &mut output.output {
+                    replace_output_text(value, text);
+                }
+                output.stderr.extend(repairs.into_iter().map(|repair| LogLine::stderr(format!("Repaired output JSON: {}", repair))));
+                return Ok(output);
+            }
+            Err(reason) => reason,
+        };
+        if output.finish_reason() == Some("length") {
+            truncated_attempts += 1;
+            reason.push_str(" (the output was truncated by max_tokens)");
+        }
+        warn!(attempt = attempt_number, max_attempts, reason = %reason, "Cell output failed validation");
+        failures.push(format!("Attempt {} failed validation: {}", attempt_number, reason));
+        if attempt_number >= max_attempts {
+            output.has_error = true;
+            output.stderr.extend(failures.into_iter().map(LogLine::stderr));
+            return Ok(output);
+        }
+        attempt_number += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+
+    fn schema_validator() -> OutputValidator {
+        validator_from_configuration(&LLMOutputValidationConfiguration {
+            max_attempts: Some(3),
+            json: false,
+            schema: Some(r#"{"type": "object", "required": ["count"], "properties": {"count": {"type": "integer"}}}"#.to_string()),
+            repair: false,
+        }).unwrap().unwrap()
+    }
+
+    fn text_output(text: &str) -> OperationFnOutput {
+        OperationFnOutput::with_value(RkyvObjectBuilder::new().insert_string("out", text.to_string()).build())
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_output_is_valid() {
+        let responses = vec!["not json", r#"{"count": "three"}"#, r#"{"count": 3}"#];
+        let mut calls = 0;
+        let output = retry_until_valid(Some(3), schema_validator(), |attempt| {
+            calls += 1;
+            let response = responses[attempt.number - 1];
+            async move { Ok(text_output(response)) }
+        }).await.unwrap();
+        assert_eq!(calls, 3);
+        assert!(!output.has_error);
+        assert_eq!(output.output, text_output(r#"{"count": 3}"#).output);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_attempts_return_last_output_with_error() {
+        let output = retry_until_valid(Some(2), schema_validator(), |_| async move {
+            Ok(text_output(r#"{"total": 3}"#))
+        }).await.unwrap();
+        assert!(output.has_error);
+        assert_eq!(output.stderr.len(), 2);
+        assert!(output.stderr[1].text.contains("missing required property count"));
+    }
+
+    #[tokio::test]
+    async fn test_repair_of_nearly_valid_json() {
+        let configuration = LLMOutputValidationConfiguration { json: true, ..Default::default() };
+        let strict = validator_from_configuration(&configuration).unwrap().unwrap();
+        assert!(strict(r#"{"count": 3,}"#).unwrap_err().contains("Output is not valid JSON"));
+
+        let lenient = validator_from_configuration(&LLMOutputValidationConfiguration { repair: true, ..configuration }).unwrap().unwrap();
+        assert_eq!(lenient(r#"{"count": 3}"#), Ok(Validated::AsProduced));
+        assert!(lenient("not json").unwrap_err().contains("Output is not valid JSON"));
+
+        let mut calls = 0;
+        let output = retry_until_valid(Some(3), lenient, |_| {
+            calls += 1;
+            async move { Ok(text_output("```json\n{\"items\": [1, 2,],}\n```")) }
+        }).await.unwrap();
+        assert_eq!(calls, 1);
+        assert!(!output.has_error);
+        assert_eq!(output.output, text_output(r#"{"items":[1,2]}"#).output);
+        assert_eq!(output.stderr.len(), 2);
+        assert!(output.stderr[0].text.contains("code fence"));
+    }
+
+    #[test]
+    fn test_no_validator_without_schema_or_json() {
+        assert!(validator_from_configuration(&LLMOutputValidationConfiguration::default()).unwrap().is_none());
+    }
+}
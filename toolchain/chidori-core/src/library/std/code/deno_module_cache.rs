@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use deno_ast::ModuleSpecifier;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tracing::debug;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ModuleCacheError {
+    #[error("Failed to fetch module {url}: {message}")]
+    Fetch { url: String, message: String },
+    #[error("Failed to access module cache at {path}: {message}")]
+    Io { path: String, message: String },
+    #[error("Module {url} does not match its pinned integrity {expected}, its contents have integrity {actual}")]
+    Integrity { url: String, expected: String, actual: String },
+}
+
+/// On-disk cache of remote modules imported by Deno cells, shared by every runtime in the process
+/// so that a module is downloaded once regardless of how many cells import it.
+///
+/// The integrity of every module is pinned in a lockfile when it is first fetched, unless the
+/// lockfile already pins it. Cached entries that do not match their pinned integrity are fetched
+/// again, and a fetched module that does not match it is an error.
+pub struct ModuleCache {
+    dir: PathBuf,
+    lockfile: PathBuf,
+    /// Integrities pinned by the lockfile, read on first use
+    pins: Mutex<Option<BTreeMap<String, String>>>,
+    /// Locks per module URL, so that concurrent imports of a module wait on a single download
+    in_flight: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+static SHARED_MODULE_CACHE: Lazy<ModuleCache> = Lazy::new(|| {
+    let dir = std::env::var_os("CHIDORI_DENO_CACHE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| dirs::cache_dir().map(|d| d.join("chidori").join("deno_modules")))
+        .unwrap_or_else(|| std::env::temp_dir().join("chidori_deno_modules"));
+    let cache = ModuleCache::new(dir);
+    match std::env::var_os("CHIDORI_DENO_LOCKFILE") {
+        Some(lockfile) => cache.with_lockfile(PathBuf::from(lockfile)),
+        None => cache,
+    }
+});
+
+impl ModuleCache {
+    /// A cache in `dir`, pinning integrities in the `lock.json` it contains.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            lockfile: dir.join("lock.json"),
+            dir,
+            pins: Mutex::new(None),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pin integrities in `lockfile` instead, such as one checked in alongside a project.
+    pub fn with_lockfile(mut self, lockfile: PathBuf) -> Self {
+        self.lockfile = lockfile;
+        self
+    }
+
+    /// The cache used by `source_code_run_deno`, located at `CHIDORI_DENO_CACHE_DIR` and pinning
+    /// integrities in `CHIDORI_DENO_LOCKFILE` when they are set.
+    pub fn shared() -> &'static ModuleCache {
+        &SHARED_MODULE_CACHE
+    }
+
+    /// Return the modules at `roots` along with every remote module they import, directly or
+    /// through other modules, as pairs of url and contents. Modules without a valid cached copy
+    /// are obtained from `fetch`.
+    pub async fn get_or_fetch_graph<F, Fut>(&self, roots: &[String], fetch: F) -> Result<Vec<(String, Vec<u8>)>, ModuleCacheError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, ModuleCacheError>>,
+    {
+        let mut modules = vec![];
+        let mut visited: HashSet<String> = roots.iter().cloned().collect();
+        let mut pending: VecDeque<String> = roots.iter().cloned().collect();
+        while let Some(url) = pending.pop_front() {
+            let contents = self.get_or_fetch(&url, || fetch(url.clone())).await?;
+            for import in module_imports(&String::from_utf8_lossy(&contents), &url) {
+                if visited.insert(import.clone()) {
+                    pending.push_back(import);
+                }
+            }
+            modules.push((url, contents));
+        }
+        Ok(modules)
+    }
+
+    /// Return the contents of the module at `url`, invoking `fetch` only when there is no valid
+    /// cached copy. Failed fetches, and fetched contents that do not match the pinned integrity,
+    /// are not cached.
+    pub async fn get_or_fetch<F, Fut>(&self, url: &str, fetch: F) -> Result<Vec<u8>, ModuleCacheError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, ModuleCacheError>>,
+    {
+        if let Some(contents) = self.read(url)? {
+            return Ok(contents);
+        }
+
+        let lock = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.entry(url.to_string()).or_default().clone()
+        };
+        let _guard = lock.lock().await;
+
+        // Another import may have completed the download while we were waiting
+        if let Some(contents) = self.read(url)? {
+            return Ok(contents);
+        }
+        debug!("Fetching module {}", url);
+        let contents = fetch().await?;
+        let integrity = integrity_of(&contents);
+        match self.pinned_integrity(url)? {
+            Some(expected) if expected != integrity => {
+                return Err(ModuleCacheError::Integrity { url: url.to_string(), expected, actual: integrity });
+            }
+            Some(_) => {}
+            None => self.pin(url, integrity)?,
+        }
+        self.write(url, &contents)?;
+        Ok(contents)
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.dir.join(hex_digest(url.as_bytes()))
+    }
+
+    fn read(&self, url: &str) -> Result<Option<Vec<u8>>, ModuleCacheError> {
+        let Ok(contents) = std::fs::read(self.entry_path(url)) else {
+            return Ok(None);
+        };
+        if self.pinned_integrity(url)? != Some(integrity_of(&contents)) {
+            debug!("Cached module {} failed its integrity check", url);
+            return Ok(None);
+        }
+        Ok(Some(contents))
+    }
+
+    fn write(&self, url: &str, contents: &[u8]) -> Result<(), ModuleCacheError> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| io_error(&self.dir, e))?;
+        let contents_path = self.entry_path(url);
+        std::fs::write(&contents_path, contents).map_err(|e| io_error(&contents_path, e))?;
+        Ok(())
+    }
+
+    fn pinned_integrity(&self, url: &str) -> Result<Option<String>, ModuleCacheError> {
+        let mut pins = self.pins.lock().unwrap();
+        Ok(self.loaded_pins(&mut pins)?.get(url).cloned())
+    }
+
+    fn pin(&self, url: &str, integrity: String) -> Result<(), ModuleCacheError> {
+        let mut pins = self.pins.lock().unwrap();
+        let pins = self.loaded_pins(&mut pins)?;
+        pins.insert(url.to_string(), integrity);
+        if let Some(parent) = self.lockfile.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+        }
+        let lockfile = serde_json::to_vec_pretty(pins).expect("pins serialize to JSON");
+        std::fs::write(&self.lockfile, lockfile).map_err(|e| io_error(&self.lockfile, e))?;
+        Ok(())
+    }
+
+    fn loaded_pins<'a>(&self, pins: &'a mut Option<BTreeMap<String, String>>) -> Result<&'a mut BTreeMap<String, String>, ModuleCacheError> {
+        if pins.is_none() {
+            let loaded = match std::fs::read(&self.lockfile) {
+                Ok(lockfile) => serde_json::from_slice(&lockfile).map_err(|e| ModuleCacheError::Io {
+                    path: self.lockfile.to_string_lossy().to_string(),
+                    message: e.to_string(),
+                })?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(io_error(&self.lockfile, e)),
+            };
+            *pins = Some(loaded);
+        }
+        Ok(pins.as_mut().unwrap())
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> ModuleCacheError {
+    ModuleCacheError::Io {
+        path: path.to_string_lossy().to_string(),
+        message: e.to_string(),
+    }
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    Sha1::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Subresource integrity string of the contents of a module.
+fn integrity_of(contents: &[u8]) -> String {
+    format!("sha1-{}", STANDARD.encode(Sha1::digest(contents)))
+}
+
+/// Download a module, treating any unsuccessful response as an error.
+pub async fn fetch_remote_module(url: &str) -> Result<Vec<u8>, ModuleCacheError> {
+    let fetch_error = |message: String| ModuleCacheError::Fetch { url: url.to_string(), message };
+    let response = reqwest::get(url).await.map_err(|e| fetch_error(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(fetch_error(format!("server responded with {}", response.status())));
+    }
+    let bytes = response.bytes().await.map_err(|e| fetch_error(e.to_string()))?;
+    Ok(bytes.to_vec())
+}
+
+/// The specifiers of the modules statically imported or re-exported by a block of source code.
+fn import_specifiers(source_code: &str) -> impl Iterator<Item = &str> {
+    static IMPORT: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#"(?m)^\s*(?:import|export)\b[^'"]*?['"]([^'"]+)['"]"#).unwrap()
+    });
+    IMPORT.captures_iter(source_code).map(|capture| capture.get(1).unwrap().as_str())
+}
+
+/// The remote modules statically imported or re-exported by a block of source code.
+pub fn remote_imports(source_code: &str) -> Vec<String> {
+    let mut imports = vec![];
+    for url in import_specifiers(source_code).filter(|s| s.starts_with("http://") || s.starts_with("https://")) {
+        if !imports.iter().any(|import| import == url) {
+            imports.push(url.to_string());
+        }
+    }
+    imports
+}
+
+/// The remote modules imported by the module at `url`, with relative specifiers resolved against it.
+fn module_imports(source_code: &str, url: &str) -> Vec<String> {
+    let Ok(base) = ModuleSpecifier::parse(url) else {
+        return vec![];
+    };
+    let mut imports = vec![];
+    for specifier in import_specifiers(source_code) {
+        let Ok(resolved) = base.join(specifier) else { continue };
+        if matches!(resolved.scheme(), "http" | "https") && !imports.contains(&resolved.to_string()) {
+            imports.push(resolved.to_string());
+        }
+    }
+    imports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use uuid::Uuid;
+
+    fn temp_cache() -> ModuleCache {
+        ModuleCache::new(std::env::temp_dir().join(format!("chidori_module_cache_{}", Uuid::now_v7())))
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_imports_fetch_once() {
+        let cache = Arc::new(temp_cache());
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let mut handles = vec![];
+        for _ in 0..8 {
+            let cache = cache.clone();
+            let fetches = fetches.clone();
+            handles.push(tokio::spawn(async move {
+                cache.get_or_fetch("https://example.com/mod.ts", || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                    Ok(b"export const x = 1;".to_vec())
+                }).await
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap(), b"export const x = 1;".to_vec());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_is_not_cached() {
+        let cache = temp_cache();
+        let url = "https://example.com/missing.ts";
+        let err = cache.get_or_fetch(url, || async {
+            Err(ModuleCacheError::Fetch { url: url.to_string(), message: "server responded with 404 Not Found".to_string() })
+        }).await.unwrap_err();
+        assert!(err.to_string().contains("404"));
+        let contents = cache.get_or_fetch(url, || async { Ok(b"ok".to_vec()) }).await.unwrap();
+        assert_eq!(contents, b"ok".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_entry_is_fetched_again() {
+        let cache = temp_cache();
+        let url = "https://example.com/mod.ts";
+        cache.get_or_fetch(url, || async { Ok(b"original".to_vec()) }).await.unwrap();
+        std::fs::write(cache.entry_path(url), b"tampered").unwrap();
+        let contents = cache.get_or_fetch(url, || async { Ok(b"original".to_vec()) }).await.unwrap();
+        assert_eq!(contents, b"original".to_vec());
+
+        // The integrity pinned at the first fetch is not replaced by that of a changed module
+        std::fs::write(cache.entry_path(url), b"tampered").unwrap();
+        let err = cache.get_or_fetch(url, || async { Ok(b"changed".to_vec()) }).await.unwrap_err();
+        assert!(matches!(err, ModuleCacheError::Integrity { .. }), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_integrity_is_pinned_by_the_lockfile() {
+        let dir = std::env::temp_dir().join(format!("chidori_module_lock_{}", Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lockfile = dir.join("deno_modules.lock");
+        let url = "https://example.com/mod.ts";
+        std::fs::write(&lockfile, serde_json::to_vec(&BTreeMap::from([(url, integrity_of(b"expected"))])).unwrap()).unwrap();
+
+        let cache = temp_cache().with_lockfile(lockfile.clone());
+        let err = cache.get_or_fetch(url, || async { Ok(b"poisoned".to_vec()) }).await.unwrap_err();
+        assert!(matches!(err, ModuleCacheError::Integrity { .. }), "{:?}", err);
+        assert_eq!(cache.get_or_fetch(url, || async { Ok(b"expected".to_vec()) }).await.unwrap(), b"expected".to_vec());
+
+        // Modules the lockfile does not pin are pinned in it when first fetched
+        let other = "https://example.com/other.ts";
+        cache.get_or_fetch(other, || async { Ok(b"other".to_vec()) }).await.unwrap();
+        let pins: BTreeMap<String, String> = serde_json::from_slice(&std::fs::read(&lockfile).unwrap()).unwrap();
+        assert_eq!(pins.get(other), Some(&integrity_of(b"other")));
+    }
+
+    #[tokio::test]
+    async fn test_module_graph_is_cached() {
+        let cache = temp_cache();
+        let sources = HashMap::from([
+            ("https://example.com/lib/mod.ts", "import { dep } from \"./dep.ts\";\nexport * from \"https://cdn.example.com/other.ts\";"),
+            ("https://example.com/lib/dep.ts", "import { util } from \"../util.ts\";\nexport const dep = util;"),
+            ("https://example.com/util.ts", "import \"./lib/mod.ts\";\nexport const util = 1;"),
+            ("https://cdn.example.com/other.ts", "export const other = 2;"),
+        ]);
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetch = |url: String| {
+            let fetches = fetches.clone();
+            let contents = sources.get(url.as_str()).map(|source| source.as_bytes().to_vec());
+            async move {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                contents.ok_or(ModuleCacheError::Fetch { url, message: "not found".to_string() })
+            }
+        };
+        let roots = vec!["https://example.com/lib/mod.ts".to_string()];
+
+        let modules = cache.get_or_fetch_graph(&roots, fetch).await.unwrap();
+        let mut urls: Vec<&str> = modules.iter().map(|(url, _)| url.as_str()).collect();
+        urls.sort();
+        let mut expected: Vec<&str> = sources.keys().copied().collect();
+        expected.sort();
+        assert_eq!(urls, expected);
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+
+        assert_eq!(cache.get_or_fetch_graph(&roots, fetch).await.unwrap().len(), 4);
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_remote_imports() {
+        let source = r#"
+import { serve } from "https://deno.land/std/http/server.ts";
+import "https://example.com/side_effect.js";
+export * from 'https://example.com/reexport.ts';
+import { local } from "./local.ts";
+"#;
+        assert_eq!(remote_imports(source), vec![
+            "https://deno.land/std/http/server.ts".to_string(),
+            "https://example.com/side_effect.js".to_string(),
+            "https://example.com/reexport.ts".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,53 @@
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use tokio_util::sync::CancellationToken;
+
+/// Interrupts the code of a cell when its run is cancelled, for the lifetime of the guard.
+///
+/// Python and JavaScript run synchronously on the thread executing the cell and never yield to the
+/// cancellation of the run, so the cancellation is watched from a thread of its own, which invokes
+/// `interrupt` to stop the code from outside. Dropping the guard ends the watch without interrupting.
+pub struct InterruptOnCancellation {
+    _finished: oneshot::Sender<()>,
+}
+
+impl InterruptOnCancellation {
+    pub fn watch(cancellation: &CancellationToken, interrupt: impl FnOnce() + Send + 'static) -> Self {
+        let cancellation = cancellation.clone();
+        let (finished, finished_rx) = oneshot::channel::<()>();
+        std::thread::spawn(move || {
+            let cancelled = std::pin::pin!(cancellation.cancelled());
+            if let Either::Left(_) = futures::executor::block_on(select(cancelled, finished_rx)) {
+                interrupt();
+            }
+        });
+        Self { _finished: finished }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_interrupts_once_cancelled() {
+        let cancellation = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let _guard = InterruptOnCancellation::watch(&cancellation, move || tx.send(()).unwrap());
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        cancellation.cancel();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_dropped_guard_does_not_interrupt() {
+        let cancellation = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        drop(InterruptOnCancellation::watch(&cancellation, move || tx.send(()).unwrap()));
+        cancellation.cancel();
+        // The watching thread drops the interrupt, and with it the sender, without invoking it
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Err(mpsc::RecvTimeoutError::Disconnected));
+    }
+}
@@ -1,4 +1,8 @@
 /// We can add support for any language that supports code execution, whose types can be serialized to
 /// RkyvSerializedValue, and whose AST can be parsed into a Report.
+pub mod deno_module_cache;
+pub mod interrupt;
 pub mod runtime_deno;
+pub mod runtime_lua;
 pub mod runtime_pyo3;
+pub mod transpile;
@@ -4,9 +4,11 @@ use deno_core::error::AnyError;
 use deno_core::{Extension, ExtensionFileSource, ExtensionFileSourceCode, FastString, JsRuntime, ModuleSpecifier, Op, op2, OpState, PollEventLoopOptions, RuntimeOptions, serde_json, serde_v8, v8};
 use deno;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use once_cell::sync::Lazy;
 
 use crate::execution::primitives::serialized_value::{
-    json_value_to_serialized_value, RkyvObjectBuilder, RkyvSerializedValue,
+    js_value_to_serialized_value, json_value_to_serialized_value, serialized_value_to_json_value, RkyvObjectBuilder, RkyvSerializedValue,
 };
 use chidori_static_analysis::language::javascript::parse::{build_report, extract_dependencies_js};
 use deno_core::_ops::{RustToV8, RustToV8NoScope};
@@ -26,16 +28,45 @@ use pyo3::{Py, PyAny};
 use pyo3::types::{IntoPyDict, PyTuple};
 use tokio::runtime::{Builder, Runtime};
 use tracing::{debug, Id, Span};
-use crate::cells::{CellTypes, CodeCell, LLMPromptCell};
+use crate::cells::{CellTypes, CodeCell, CodeCellPermissions, LLMPromptCell};
+use crate::execution::primitives::operation::LogLine;
+use crate::library::std::code::transpile::transpile_typescript;
+use crate::library::std::code::deno_module_cache::{fetch_remote_module, remote_imports, ModuleCache};
+use crate::library::std::code::interrupt::InterruptOnCancellation;
 use crate::execution::execution::execution_state::{EnclosedState, ExecutionStateErrors};
 use crate::execution::execution::ExecutionState;
 
 
+/// A value exchanged with JavaScript. Bytes are given to JavaScript as base64 strings and decoded
+/// data URLs as data URL strings, strings received from JavaScript that are base64 data URLs, such
+/// as images from a browser, are decoded into bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct JsValue(RkyvSerializedValue);
+
+impl serde::Serialize for JsValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&serialized_value_to_json_value(&self.0), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for JsValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
+        Ok(JsValue(js_value_to_serialized_value(&value)))
+    }
+}
+
 fn serde_v8_to_rkyv(
     mut scope: &mut HandleScope,
     arg0: v8::Local<v8::Value>,
 ) -> Result<RkyvSerializedValue, String> {
-    let arg0 = match deno_core::_ops::serde_v8_to_rust(&mut scope, arg0) {
+    let arg0: JsValue = match deno_core::_ops::serde_v8_to_rust(&mut scope, arg0) {
         Ok(t) => t,
         Err(arg0_err) => {
             let msg = deno_core::v8::String::new(&mut scope, &{
@@ -51,7 +82,7 @@ fn serde_v8_to_rkyv(
             return Err(arg0_err.to_string());
         }
     };
-    Ok(arg0)
+    Ok(arg0.0)
 }
 
 struct MyOpState {
@@ -60,8 +91,8 @@ struct MyOpState {
     payload: RkyvSerializedValue,
     cell_depended_values: HashMap<String, String>,
     execution_state_handle: Arc<Mutex<ExecutionState>>,
-    stdout: Vec<String>,
-    stderr: Vec<String>,
+    stdout: Vec<LogLine>,
+    stderr: Vec<LogLine>,
     functions: HashMap<
         String,
         FunctionConstructorState,
@@ -91,7 +122,7 @@ fn op_console_log(
     let op_state = state.borrow();
     let my_op_state: &Arc<Mutex<MyOpState>> = (*op_state).borrow();
     let mut my_op_state = my_op_state.lock().unwrap();
-    my_op_state.stdout.push(message.clone());
+    my_op_state.stdout.push(LogLine::stdout(message.trim_end_matches('\n')));
     println!("[Custom console.log] {:?}", message);
     Ok(())
 }
@@ -105,7 +136,7 @@ fn op_console_err(
     let op_state = state.borrow();
     let my_op_state: &Arc<Mutex<MyOpState>> = (*op_state).borrow();
     let mut my_op_state = my_op_state.lock().unwrap();
-    my_op_state.stderr.push(message.clone());
+    my_op_state.stderr.push(LogLine::stderr(message.trim_end_matches('\n')));
     println!("[Custom console.err] {:?}", message);
     Ok(())
 }
@@ -121,9 +152,11 @@ struct FunctionConstructorState {
 async fn op_call_rust(
     state: Rc<RefCell<OpState>>,
     #[string] name: String,
-    #[serde] args: Vec<RkyvSerializedValue>,
-    #[serde] kwargs: HashMap<String, RkyvSerializedValue>,
-) -> Result<RkyvSerializedValue, AnyError> {
+    #[serde] args: Vec<JsValue>,
+    #[serde] kwargs: HashMap<String, JsValue>,
+) -> Result<JsValue, AnyError> {
+    let args: Vec<RkyvSerializedValue> = args.into_iter().map(|arg| arg.0).collect();
+    let kwargs: HashMap<String, RkyvSerializedValue> = kwargs.into_iter().map(|(k, v)| (k, v.0)).collect();
     let (func_constructor, execution_state_handle) = {
         let op_state = state.borrow();
         let my_op_state: &Arc<Mutex<MyOpState>> = (*op_state).borrow();
@@ -167,7 +200,7 @@ async fn op_call_rust(
 
     // Call the function without holding any borrows
     let result = func(args, kwargs).await?;
-    Ok(result)
+    Ok(JsValue(result))
 }
 
 
@@ -175,15 +208,29 @@ async fn op_call_rust(
 #[serde]
 fn op_save_result<'scope>(
     state: Rc<RefCell<OpState>>,
-    #[serde] val: RkyvSerializedValue,
+    #[serde] val: JsValue,
 ) -> Result<(), AnyError> {
     let op_state = state.borrow();
     let my_op_state: &Arc<Mutex<MyOpState>> = op_state.borrow();
     let mut my_op_state = my_op_state.lock().unwrap();
-    my_op_state.output = Some(val);
+    my_op_state.output = Some(val.0);
     Ok(())
 }
 
+/// The contents of an attachment of the run, a missing attachment throws within the cell.
+#[op2]
+#[buffer]
+fn op_attachment(
+    state: Rc<RefCell<OpState>>,
+    #[string] name: String,
+) -> Result<Vec<u8>, AnyError> {
+    let op_state = state.borrow();
+    let my_op_state: &Arc<Mutex<MyOpState>> = op_state.borrow();
+    let execution_state_handle = my_op_state.lock().unwrap().execution_state_handle.clone();
+    let contents = execution_state_handle.lock().unwrap().attachment(&name)?;
+    Ok(contents)
+}
+
 // TODO: template to implement array storage
 // #[op2]
 // fn op_transfer_arraybuffer<'a>(
@@ -202,14 +249,14 @@ fn op_save_result<'scope>(
 #[serde]
 fn op_save_result_object<'scope>(
     state: Rc<RefCell<OpState>>,
-    #[serde] kwargs: HashMap<String, RkyvSerializedValue>,
+    #[serde] kwargs: HashMap<String, JsValue>,
 ) -> Result<(), AnyError> {
     let op_state = state.borrow();
     let my_op_state: &Arc<Mutex<MyOpState>> = op_state.borrow();
     let mut my_op_state = my_op_state.lock().unwrap();
     let mut output = RkyvObjectBuilder::new();
     for (key, value) in kwargs {
-        output = output.insert_value(&key, value);
+        output = output.insert_value(&key, value.0);
     }
     // TODO: union with the existing value if there is one
     my_op_state.output = Some(output.build());
@@ -223,7 +270,7 @@ fn op_invoke_function<'scope>(
     scope: &mut v8::HandleScope<'scope>,
     state: Rc<RefCell<OpState>>,
     input: v8::Local<v8::Function>,
-) -> Result<RkyvSerializedValue, AnyError> {
+) -> Result<JsValue, AnyError> {
     let global = scope.get_current_context().global(scope);
 
     // TODO: handle async functions
@@ -251,7 +298,7 @@ fn op_invoke_function<'scope>(
                     .map(|(_, v)|
                         deno_core::_ops::RustToV8Fallible::to_v8_fallible(
                             deno_core::_ops::RustToV8Marker::<deno_core::_ops::SerdeMarker, _>::from(
-                                v,
+                                JsValue(v.clone()),
                             ),
                             scope,
                         ).unwrap()
@@ -266,7 +313,7 @@ fn op_invoke_function<'scope>(
                 kwargs.push((k,
                              deno_core::_ops::RustToV8Fallible::to_v8_fallible(
                                  deno_core::_ops::RustToV8Marker::<deno_core::_ops::SerdeMarker, _>::from(
-                                     v,
+                                     JsValue(v.clone()),
                                  ),
                                  scope,
                              ).unwrap()
@@ -280,7 +327,7 @@ fn op_invoke_function<'scope>(
 
     if let Some(result) = result {
         let result = serde_v8_to_rkyv(scope, result).unwrap();
-        Ok(result)
+        Ok(JsValue(result))
     } else {
         Err(anyhow::Error::msg("Failure".to_string()))
     }
@@ -308,7 +355,7 @@ fn op_set_globals<'scope>(
                 let key = deno_core::v8::String::new(scope, key).unwrap();
                 if let Ok(value) = match deno_core::_ops::RustToV8Fallible::to_v8_fallible(
                     deno_core::_ops::RustToV8Marker::<deno_core::_ops::SerdeMarker, _>::from(
-                        value,
+                        JsValue(value.clone()),
                     ),
                     scope,
                 ) {
@@ -419,37 +466,100 @@ fn js_args_to_rkyv(args: Vec<RkyvSerializedValue>, kwargs: Option<HashMap<String
 
 
 
-#[tracing::instrument]
+type DenoJob = Box<dyn FnOnce() + Send>;
+
+/// Threads Deno cells execute on. A Deno worker is bound to the thread that created it, so each cell
+/// executes entirely on one of these threads, and threads are kept once idle so that the runtime
+/// each builds is reused by the cells that execute on it after.
+struct DenoThreads {
+    sender: Mutex<std::sync::mpsc::Sender<DenoJob>>,
+    receiver: Arc<Mutex<std::sync::mpsc::Receiver<DenoJob>>>,
+    idle: Arc<AtomicUsize>,
+}
+
+static DENO_THREADS: Lazy<DenoThreads> = Lazy::new(|| {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    DenoThreads { sender: Mutex::new(sender), receiver: Arc::new(Mutex::new(receiver)), idle: Default::default() }
+});
+
+impl DenoThreads {
+    /// Execute `job` on an idle thread, or on a new thread when every thread is busy.
+    fn execute(&self, job: impl FnOnce() + Send + 'static) -> anyhow::Result<()> {
+        if self.idle.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| idle.checked_sub(1)).is_err() {
+            let receiver = self.receiver.clone();
+            let idle = self.idle.clone();
+            std::thread::Builder::new().name("chidori-deno".to_string()).spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                job();
+                idle.fetch_add(1, Ordering::SeqCst);
+            })?;
+        }
+        self.sender.lock().unwrap().send(Box::new(job)).map_err(|_| anyhow::anyhow!("Deno threads have shut down"))
+    }
+}
+
+thread_local! {
+    static DENO_RUNTIME: RefCell<Option<Rc<Runtime>>> = const { RefCell::new(None) };
+}
+
+/// The single-threaded runtime of the current thread, built by the first cell to execute on it.
+fn deno_runtime() -> std::io::Result<Rc<Runtime>> {
+    DENO_RUNTIME.with(|runtime| {
+        if let Some(runtime) = runtime.borrow().as_ref() {
+            return Ok(runtime.clone());
+        }
+        let built = Rc::new(Builder::new_current_thread().enable_all().build()?);
+        *runtime.borrow_mut() = Some(built.clone());
+        Ok(built)
+    })
+}
+
+#[tracing::instrument(skip(payload), fields(payload = ?execution_state.redact(payload)))]
 pub async fn source_code_run_deno(
     execution_state: &ExecutionState,
     source_code: &String,
     payload: &RkyvSerializedValue,
     function_invocation: &Option<String>,
+    permissions: &Option<CodeCellPermissions>,
+    cwd: Option<&str>,
 ) -> anyhow::Result<(
     Result<RkyvSerializedValue, ExecutionStateErrors>,
-    Vec<String>,
-    Vec<String>,
+    Vec<LogLine>,
+    Vec<LogLine>,
     ExecutionState
 )> {
     let execution_state = execution_state.clone();
     let source_code = source_code.clone();
     let function_invocation = function_invocation.clone();
+    let permissions = permissions.clone().unwrap_or_default();
+    let cwd = cwd.map(PathBuf::from);
     let payload = payload.clone();
     let (tx, rx) = std::sync::mpsc::channel();
     // let (tx, rx) = tokio::sync::oneshot::channel();
 
-    std::thread::spawn(move || {
+    DENO_THREADS.execute(move || {
         let thread_result = (|| -> anyhow::Result<(
             Result<RkyvSerializedValue, ExecutionStateErrors>,
-            Vec<String>,
-            Vec<String>,
+            Vec<LogLine>,
+            Vec<LogLine>,
             ExecutionState
         )> {
-            let source_code = source_code.clone();
+            // Cells are analyzed and executed as the JavaScript transpiled from them, a syntax error fails the cell
+            let transpiled = match transpile_typescript(&source_code) {
+                Ok(transpiled) => transpiled,
+                Err(e) => {
+                    let message = e.to_string();
+                    return Ok((Err(ExecutionStateErrors::Unknown(message.clone())), vec![], vec![LogLine::stderr(&message)], execution_state.clone()));
+                }
+            };
             // Capture the current span's ID
             let current_span_id = Span::current().id();
 
-            let dependencies = extract_dependencies_js(&source_code)?;
+            let source_code = &transpiled.code;
+            let dependencies = extract_dependencies_js(source_code)?;
             let report = build_report(&dependencies);
 
             // A list of function names this block of code is depending on existing
@@ -483,6 +593,7 @@ pub async fn source_code_run_deno(
                         op_invoke_function(),
                         op_console_log(),
                         op_console_err(),
+                        op_attachment(),
                     ])
                 )),
                 op_state_fn: Some(Box::new(move |state| {
@@ -503,6 +614,7 @@ pub async fn source_code_run_deno(
           const op_invoke_function = Deno.core.ops.op_invoke_function;
           const op_console_log = Deno.core.ops.op_console_log;
           const op_console_err = Deno.core.ops.op_console_err;
+          const op_attachment = Deno.core.ops.op_attachment;
 
           globalThis.op_invoke_function = op_invoke_function;
           globalThis.op_call_rust = op_call_rust;
@@ -531,6 +643,9 @@ pub async fn source_code_run_deno(
               },
               saveOutput: (object) => {
                   op_save_result_object(object);
+              },
+              attachment: (name) => {
+                  return op_attachment(name);
               }
           };
 
@@ -563,24 +678,17 @@ pub async fn source_code_run_deno(
             // );
 
 
-            // Set global variables, provide specialized ops
-            let source = if let Some(func_name) = function_invocation {
-                let mut source = String::new();
-                source.push_str("\n");
-                source.push_str(&source_code);
-                source.push_str("\n");
-                source.push_str(&format!(
+            // Set global variables, provide specialized ops. The code of the cell begins the module so
+            // that its source map locates the lines of the cell, what is appended follows it.
+            let appended = if let Some(func_name) = function_invocation {
+                format!(
                     r#"Chidori.saveValue(op_invoke_function({name}));"#,
                     name = func_name
-                ));
-                source.push_str("\n");
-                source
+                )
             } else {
                 let mut source = String::new();
-                source.push_str("\n");
                 source.push_str("export const chidoriResult = {};");
                 source.push_str("\n");
-                source.push_str(&source_code);
                 for (name, report_item) in &report.triggerable_functions {
                     source.push_str("\n");
                     source.push_str(&format!(
@@ -599,19 +707,25 @@ pub async fn source_code_run_deno(
                 }
                 source.push_str("\n");
                 source.push_str("Chidori.saveOutput(chidoriResult);");
-                source.push_str("\n");
                 source
             };
+            let source = transpiled.with_inline_source_map(&appended);
 
 
+            // Nothing is granted beyond the cell's permissions, and operations that were not are
+            // denied rather than prompted for
             let mut flags = deno::args::Flags::default();
-            // TODO: give user control over this in configuration
-            // TODO: allow_net is causing this to block our execution entirely
-            flags.permissions.allow_net = Some(vec![]);
-            flags.permissions.allow_env = Some(vec![]);
-            flags.permissions.allow_read = Some(vec![]);
-            flags.permissions.allow_write = Some(vec![]);
-            flags.permissions.allow_run = Some(vec![]);
+            flags.permissions.allow_net = if execution_state.deny_network {
+                None
+            } else {
+                permission_allowlist(&permissions.net)
+            };
+            flags.permissions.allow_env = permission_allowlist(&permissions.env);
+            flags.permissions.allow_read = permission_allowlist(&permissions.read);
+            flags.permissions.allow_write = permission_allowlist(&permissions.write);
+            flags.permissions.no_prompt = true;
+            // Relative paths of the cell resolve against its working directory
+            flags.initial_cwd = cwd;
             let factory = deno::factory::CliFactory::from_flags(Arc::new(flags));
             let cli_options = factory.cli_options()?;
             let file_fetcher = factory.file_fetcher()?;
@@ -629,34 +743,82 @@ pub async fn source_code_run_deno(
                 &cli_options.permissions_options()?,
             )?);
 
-            // Create a single-threaded runtime
-            let runtime = Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("Failed to create Tokio runtime");
+            let runtime = deno_runtime()?;
+
+            // Resolve remote imports, and the modules they import in turn, through the shared module
+            // cache so that they are downloaded once across all cells, rather than by each worker independently
+            let modules = runtime.block_on(
+                ModuleCache::shared().get_or_fetch_graph(&remote_imports(source_code), |url| async move {
+                    fetch_remote_module(&url).await
+                })
+            );
+            let modules = match modules {
+                Ok(modules) => modules,
+                Err(e) => {
+                    return Ok((Err(ExecutionStateErrors::Unknown(e.to_string())), vec![], vec![], execution_state.clone()));
+                }
+            };
+            for (url, contents) in modules {
+                let specifier = match ModuleSpecifier::parse(&url) {
+                    Ok(specifier) => specifier,
+                    Err(e) => {
+                        return Ok((Err(ExecutionStateErrors::Unknown(format!("Invalid module specifier {}: {}", url, e))), vec![], vec![], execution_state.clone()));
+                    }
+                };
+                file_fetcher.insert_memory_files(File {
+                    specifier,
+                    maybe_headers: None,
+                    source: contents.into(),
+                });
+            }
 
-            // Use the newly created single-threaded runtime to run our async code
-            runtime.block_on(async {
+            // Run the cell on the runtime of this thread
+            let run_result = runtime.block_on(async {
                 let worker_factory = factory.create_cli_main_worker_factory().await?;
                 let mut worker = worker_factory
                     .create_custom_worker(
                         deno::deno_runtime::WorkerExecutionMode::Run,
-                        main_module,
+                        main_module.clone(),
                         permissions,
                         vec![ext],
                         Default::default(),
                     )
-                    .await?;
-
-                let exit_code = worker.run().await?;
+                    .await?
+                    .into_main_worker();
+
+                // Cancelling the run terminates the script, which otherwise runs to completion
+                let isolate = worker.js_runtime.v8_isolate().thread_safe_handle();
+                let _interrupt = InterruptOnCancellation::watch(&execution_state.cancellation, move || {
+                    isolate.terminate_execution();
+                });
+                worker.execute_main_module(&main_module).await?;
+                worker.run_event_loop(false).await?;
                 Ok::<(), anyhow::Error>(())
-            }).map_err(|e| {
-                // TODO: map error
-                dbg!(&e);
-                e
-            })?;
+            });
             let mut my_op_state = my_op_state.lock().unwrap();
-            let output = Ok(my_op_state.output.clone().unwrap_or(RkyvSerializedValue::Null));
+            let output = match run_result {
+                Ok(()) => Ok(my_op_state.output.clone().unwrap_or(RkyvSerializedValue::Null)),
+                // An operation the cell was not granted fails the cell, with the denial in its stderr
+                Err(e) => match permission_denied_message(&e) {
+                    Some(message) => {
+                        my_op_state.stderr.push(LogLine::stderr(&message));
+                        Err(ExecutionStateErrors::Unknown(message))
+                    }
+                    // Exceptions thrown by the cell fail the cell, with their stack in its stderr
+                    None => match e.downcast_ref::<deno_core::error::JsError>() {
+                        Some(js_error) => {
+                            let stack = js_error.stack.clone().unwrap_or_else(|| js_error.to_string());
+                            my_op_state.stderr.push(LogLine::stderr(&stack));
+                            Err(ExecutionStateErrors::JavaScriptException {
+                                name: js_error.name.clone().unwrap_or_else(|| "Error".to_string()),
+                                message: js_error.message.clone().unwrap_or_else(|| js_error.exception_message.clone()),
+                                stack,
+                            })
+                        }
+                        None => return Err(e),
+                    },
+                },
+            };
             let execution_state = my_op_state.execution_state_handle.lock().unwrap().clone();
             let stdout = my_op_state.stdout.clone();
             let stderr = my_op_state.stderr.clone();
@@ -666,8 +828,7 @@ pub async fn source_code_run_deno(
         if tx.send(thread_result).is_err() {
             debug!("Receiver dropped");
         }
-        anyhow::Ok(())
-    });
+    })?;
 
     // Receive the result asynchronously without blocking the executor
     let result_of_thread = tokio::task::spawn_blocking(move || {
@@ -679,6 +840,22 @@ pub async fn source_code_run_deno(
     Ok(result_of_thread)
 }
 
+/// The allowlist of a permission as understood by Deno, where an empty list grants the permission
+/// without restriction and None grants nothing.
+fn permission_allowlist(allowlist: &Option<Vec<String>>) -> Option<Vec<String>> {
+    match allowlist {
+        Some(entries) if entries.iter().any(|entry| entry == "*") => Some(vec![]),
+        Some(entries) if !entries.is_empty() => Some(entries.clone()),
+        _ => None,
+    }
+}
+
+/// The message of an error raised by an operation the cell was not permitted to perform.
+fn permission_denied_message(error: &anyhow::Error) -> Option<String> {
+    let js_error = error.downcast_ref::<deno_core::error::JsError>()?;
+    (js_error.name.as_deref() == Some("PermissionDenied")).then(|| js_error.exception_message.clone())
+}
+
 fn replace_identifier(code: &str, old_identifier: &str, new_identifier: &str) -> String {
     let pattern = if old_identifier.starts_with('$') {
         format!(r"(^|[^a-zA-Z0-9_$])({})(?![a-zA-Z0-9_$])", regex::escape(old_identifier))
@@ -701,6 +878,7 @@ mod tests {
     use super::*;
     use crate::cells::{SupportedLanguage, TextRange};
     use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+    use crate::execution::execution::execution_state::Attachment;
     use indoc::indoc;
     use uuid::Uuid;
 
@@ -712,15 +890,13 @@ mod tests {
         let id_a = Uuid::now_v7();
         let (state, _) = state.update_operation(CellTypes::Code(
             crate::cells::CodeCell {
-                backing_file_reference: None,
-                name: None,
                 language: SupportedLanguage::PyO3,
                 source_code: String::from(indoc! { r#"
                                     def test_function(a, b):
                                         return a + b
                                 "#
                                 }),
-                function_invocation: None,
+                ..Default::default()
             }, TextRange::default()), id_a)?;
         let result = source_code_run_deno(
             &state,
@@ -728,6 +904,8 @@ mod tests {
             &RkyvObjectBuilder::new()
                 .build(),
             &None,
+            &None,
+            None,
         ).await;
         assert_eq!(
             result.unwrap(),
@@ -755,6 +933,8 @@ mod tests {
                 )
                 .build(),
             &None,
+            &None,
+            None,
         ).await;
         assert_eq!(
             result.unwrap(),
@@ -769,7 +949,7 @@ mod tests {
     #[tokio::test]
     async fn test_source_code_run_deno_success() {
         let source_code = String::from("const x = 42;");
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -783,14 +963,145 @@ mod tests {
     #[tokio::test]
     async fn test_source_code_run_deno_failure() {
         let source_code = String::from("throw new Error('Test Error');");
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_source_code_run_deno_missing_import_is_an_error() {
+        // A server without routes responds to every request with a 404
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, axum::Router::new()).await });
+
+        let source_code = format!("import {{ x }} from \"http://{}/missing.ts\";\nconst y = x;", addr);
+        let (output, _, _, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        let Err(ExecutionStateErrors::Unknown(message)) = output else {
+            panic!("Expected the import to fail, got {:?}", output);
+        };
+        assert!(message.contains("missing.ts") && message.contains("404"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_deno_permissions() {
+        // Cargo sets the manifest directory in the environment of the tests it runs, reading it
+        // avoids changing the environment of the process other tests share
+        let source_code = String::from(r#"const value = Deno.env.get("CARGO_MANIFEST_DIR");"#);
+
+        // Nothing is granted by default
+        let (output, _, stderr, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        let Err(ExecutionStateErrors::Unknown(message)) = output else {
+            panic!("Expected env access to be denied, got {:?}", output);
+        };
+        assert!(message.contains("env access"), "{}", message);
+        assert_eq!(stderr.iter().map(|l| l.text.as_str()).collect::<Vec<_>>(), vec![message.as_str()]);
+
+        let permissions = CodeCellPermissions {
+            env: Some(vec!["CARGO_MANIFEST_DIR".to_string()]),
+            ..Default::default()
+        };
+        let (output, _, stderr, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &Some(permissions), None).await.unwrap();
+        assert_eq!(output, Ok(RkyvObjectBuilder::new().insert_string("value", env!("CARGO_MANIFEST_DIR").to_string()).build()));
+        assert!(stderr.is_empty());
+
+        // Grants do not extend beyond the listed entries
+        let permissions = CodeCellPermissions {
+            env: Some(vec!["*".to_string()]),
+            ..Default::default()
+        };
+        let source_code = String::from(r#"const contents = Deno.readTextFileSync("Cargo.toml");"#);
+        let (output, _, _, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &Some(permissions), None).await.unwrap();
+        let Err(ExecutionStateErrors::Unknown(message)) = output else {
+            panic!("Expected read access to be denied, got {:?}", output);
+        };
+        assert!(message.contains("read access"), "{}", message);
+
+        // A run that denies network access overrides the cell's grants
+        let permissions = CodeCellPermissions {
+            net: Some(vec!["*".to_string()]),
+            ..Default::default()
+        };
+        let source_code = String::from(r#"const status = (await fetch("http://localhost:9")).status;"#);
+        let state = ExecutionState::new_with_random_id().with_deny_network(true);
+        let (output, _, _, _) = source_code_run_deno(&state, &source_code, &RkyvSerializedValue::Null, &None, &Some(permissions), None).await.unwrap();
+        let Err(ExecutionStateErrors::Unknown(message)) = output else {
+            panic!("Expected net access to be denied, got {:?}", output);
+        };
+        assert!(message.contains("net access"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_deno_attachments() {
+        let state = ExecutionState::new_with_random_id()
+            .with_attachment("fixture", Attachment::Bytes(b"{\"count\": 3}".to_vec()));
+        let source_code = String::from(r#"const fixture = JSON.parse(new TextDecoder().decode(Chidori.attachment("fixture")));"#);
+        let (output, _, _, _) = source_code_run_deno(&state, &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        assert_eq!(output, Ok(RkyvObjectBuilder::new()
+            .insert_object("fixture", RkyvObjectBuilder::new().insert_number("count", 3))
+            .build()));
+
+        let source_code = String::from(r#"const fixture = Chidori.attachment("missing");"#);
+        let (output, _, _, _) = source_code_run_deno(&state, &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        let Err(error) = output else {
+            panic!("Expected the missing attachment to fail the cell, got {:?}", output);
+        };
+        assert!(error.to_string().contains("no attachment named \"missing\""), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_deno_typescript() {
+        let source_code = String::from(indoc! { r#"
+            interface Point {
+                x: number;
+                y: number;
+            }
+            function add(a: Point, b: Point): Point {
+                return { x: a.x + b.x, y: a.y + b.y };
+            }
+            const sum: Point = add({ x: 1, y: 2 }, { x: 3, y: 4 });
+            const total = sum.x + sum.y;
+        "# });
+        let (output, _, _, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        assert_eq!(output, Ok(RkyvObjectBuilder::new()
+            .insert_string("add", "function".to_string())
+            .insert_object("sum", RkyvObjectBuilder::new().insert_number("x", 4).insert_number("y", 6))
+            .insert_number("total", 10)
+            .build()));
+
+        let source_code = String::from("const x: number = ;");
+        let (output, _, stderr, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        let Err(ExecutionStateErrors::Unknown(message)) = output else {
+            panic!("Expected a syntax error, got {:?}", output);
+        };
+        assert!(message.starts_with("Syntax error"), "{}", message);
+        assert_eq!(stderr.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_deno_thrown_exception_is_structured() {
+        let source_code = String::from(indoc! { r#"
+            const x = 1;
+            function check(value: number) {
+                throw new TypeError(`bad value ${value}`);
+            }
+            check(x);
+        "# });
+        let (output, _, stderr, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await.unwrap();
+        let Err(ExecutionStateErrors::JavaScriptException { name, message, stack }) = output else {
+            panic!("Expected a JavaScript exception, got {:?}", output);
+        };
+        assert_eq!(name, "TypeError");
+        assert_eq!(message, "bad value 1");
+        assert!(stack.contains("at check"), "{}", stack);
+        // Locations are those of the cell's source, the throw is on its third line
+        assert!(stack.contains(":3:"), "{}", stack);
+        assert!(stderr.iter().any(|line| line.text == stack));
+    }
+
     #[tokio::test]
     async fn test_source_code_run_deno_json_serialization() {
         let source_code = String::from("const obj  = {foo: 'bar'};");
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -808,7 +1119,7 @@ mod tests {
     #[tokio::test]
     async fn test_source_code_run_deno_expose_global_variables() {
         let source_code = String::from("const x = 30;");
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -825,7 +1136,7 @@ mod tests {
         let args = RkyvObjectBuilder::new()
             .insert_object("args", RkyvObjectBuilder::new().insert_number("0", 10).insert_number("1", 20))
             .build();
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &args, &Some("demonstrationAdd".to_string())).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &args, &Some("demonstrationAdd".to_string()), &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -845,15 +1156,11 @@ mod tests {
         "#);
         let args = RkyvObjectBuilder::new()
             .build();
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &args, &None).await;
-        assert_eq!(
-            result.unwrap(),
-            (
-                Ok(RkyvObjectBuilder::new().build()),
-                vec![String::from("[out]: \"testing, output\"\n")],
-                vec![String::from("[out]: \"testing, stderr\"\n")],
-            )
-        );
+        let (output, stdout, stderr, _) = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &args, &None, &None, None).await.unwrap();
+        assert_eq!(output, Ok(RkyvObjectBuilder::new().build()));
+        assert_eq!(stdout.iter().map(|l| l.text.as_str()).collect::<Vec<_>>(), vec!["[out]: \"testing, output\""]);
+        assert_eq!(stderr.iter().map(|l| l.text.as_str()).collect::<Vec<_>>(), vec!["[out]: \"testing, stderr\""]);
+        assert!(stdout[0].ts < stderr[0].ts);
     }
 
     #[test]
@@ -885,7 +1192,7 @@ mod tests {
     #[tokio::test]
     async fn test_typescript_basic() {
         let source_code = String::from("const x: number = 42;");
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -905,7 +1212,7 @@ mod tests {
         }
         const person: Person = { name: "Alice", age: 30 };
     "#);
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -931,7 +1238,7 @@ mod tests {
         }
         const result = identity<string>("TypeScript");
     "#);
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -953,7 +1260,7 @@ mod tests {
         }
         const data = await fetchData();
     "#);
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -977,7 +1284,7 @@ mod tests {
         }
         const selectedColor: Color = Color.Green;
     "#);
-        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await;
+        let result = source_code_run_deno(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
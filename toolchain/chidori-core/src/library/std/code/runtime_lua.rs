@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chidori_static_analysis::language::lua::parse::{build_report, extract_dependencies_lua};
+use mlua::{Lua, MultiValue, Table, Value, Variadic};
+use tracing::Span;
+
+use crate::execution::execution::execution_state::ExecutionStateErrors;
+use crate::execution::execution::ExecutionState;
+use crate::execution::primitives::operation::LogLine;
+use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue};
+use crate::library::std::code::runtime_pyo3::denied_import;
+
+fn lua_value_to_rkyv(value: &Value) -> mlua::Result<RkyvSerializedValue> {
+    Ok(match value {
+        Value::Nil => RkyvSerializedValue::Null,
+        Value::Boolean(b) => RkyvSerializedValue::Boolean(*b),
+        Value::Integer(i) => RkyvSerializedValue::from_i64(*i),
+        Value::Number(n) => RkyvSerializedValue::Float(*n),
+        Value::String(s) => RkyvSerializedValue::String(s.to_str()?.to_string()),
+        Value::Table(table) => lua_table_to_rkyv(table)?,
+        Value::Function(_) => RkyvSerializedValue::String("function".to_string()),
+        _ => RkyvSerializedValue::Null,
+    })
+}
+
+/// Tables that are sequences (keys exactly 1..n) become arrays, every other table becomes an object
+/// keyed by the string form of its keys.
+fn lua_table_to_rkyv(table: &Table) -> mlua::Result<RkyvSerializedValue> {
+    let mut entries = vec![];
+    for pair in table.clone().pairs::<Value, Value>() {
+        entries.push(pair?);
+    }
+
+    let length = table.raw_len();
+    let is_sequence = length > 0
+        && entries.len() == length
+        && entries.iter().all(|(k, _)| matches!(k, Value::Integer(i) if *i >= 1 && (*i as usize) <= length));
+    if is_sequence {
+        let mut values = vec![RkyvSerializedValue::Null; length];
+        for (k, v) in &entries {
+            if let Value::Integer(i) = k {
+                values[(*i as usize) - 1] = lua_value_to_rkyv(v)?;
+            }
+        }
+        return Ok(RkyvSerializedValue::Array(values));
+    }
+
+    let mut object = HashMap::new();
+    for (k, v) in &entries {
+        let key = match k {
+            Value::String(s) => s.to_str()?.to_string(),
+            Value::Integer(i) => i.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Boolean(b) => b.to_string(),
+            _ => continue,
+        };
+        object.insert(key, lua_value_to_rkyv(v)?);
+    }
+    Ok(RkyvSerializedValue::Object(object))
+}
+
+fn rkyv_to_lua_value<'lua>(lua: &'lua Lua, value: &RkyvSerializedValue) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        RkyvSerializedValue::Null => Value::Nil,
+        RkyvSerializedValue::Boolean(b) => Value::Boolean(*b),
+        RkyvSerializedValue::Number(n) => Value::Integer(*n as i64),
+        RkyvSerializedValue::Integer(n) => Value::Integer(*n),
+        RkyvSerializedValue::Float(f) => Value::Number(*f),
+        RkyvSerializedValue::String(s) => Value::String(lua.create_string(s)?),
+        RkyvSerializedValue::Bytes(b) => Value::String(lua.create_string(b)?),
+        RkyvSerializedValue::Array(values) => {
+            let table = lua.create_table()?;
+            for (i, v) in values.iter().enumerate() {
+                table.raw_set(i + 1, rkyv_to_lua_value(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        RkyvSerializedValue::Set(values) => {
+            let table = lua.create_table()?;
+            for (i, v) in values.iter().enumerate() {
+                table.raw_set(i + 1, rkyv_to_lua_value(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        RkyvSerializedValue::Object(map) => {
+            let table = lua.create_table()?;
+            for (k, v) in map {
+                table.raw_set(k.as_str(), rkyv_to_lua_value(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        RkyvSerializedValue::StreamPointer(_)
+        | RkyvSerializedValue::FunctionPointer(_, _)
+        | RkyvSerializedValue::Cell(_) => Value::Nil,
+    })
+}
+
+/// Deny the cell `require` of the modules of `denied_imports`, along with loading native libraries
+/// directly. When the network is denied, the functions spawning processes through which the cell
+/// could otherwise reach it are denied too.
+fn install_import_denylist(lua: &Lua, denied_imports: &[String], deny_network: bool) -> mlua::Result<()> {
+    let globals = lua.globals();
+    if !denied_imports.is_empty() {
+        let require: mlua::Function = globals.get("require")?;
+        lua.set_named_registry_value("chidori_require", require)?;
+        let denied_imports = denied_imports.to_vec();
+        let guarded_require = lua.create_function(move |lua, name: String| {
+            if denied_imports.iter().any(|module| name == *module || name.starts_with(&format!("{}.", module))) {
+                return Err(mlua::Error::external(denied_import(&name, deny_network)));
+            }
+            let require: mlua::Function = lua.named_registry_value("chidori_require")?;
+            require.call::<_, MultiValue>(name)
+        })?;
+        globals.set("require", guarded_require)?;
+        let package: Table = globals.get("package")?;
+        package.set("loadlib", Value::Nil)?;
+    }
+    if deny_network {
+        for (library, function) in [("io", "popen"), ("os", "execute")] {
+            let name = format!("{}.{}", library, function);
+            let denied = lua.create_function(move |_, _: MultiValue| -> mlua::Result<()> {
+                Err(mlua::Error::external(ExecutionStateErrors::NetworkDenied(name.clone())))
+            })?;
+            globals.get::<_, Table>(library)?.set(function, denied)?;
+        }
+    }
+    Ok(())
+}
+
+/// The denial raised by a guarded function, however deeply the callbacks of the cell wrapped it.
+fn denied_error(error: &mlua::Error) -> Option<ExecutionStateErrors> {
+    match error {
+        mlua::Error::CallbackError { cause, .. } => denied_error(cause),
+        mlua::Error::ExternalError(error) => error.downcast_ref::<ExecutionStateErrors>().cloned(),
+        _ => None,
+    }
+}
+
+fn run_lua(
+    source_code: &str,
+    payload: &RkyvSerializedValue,
+    function_invocation: &Option<String>,
+    stdout: Arc<Mutex<Vec<LogLine>>>,
+    denied_imports: &[String],
+    deny_network: bool,
+) -> mlua::Result<RkyvSerializedValue> {
+    let dependencies = extract_dependencies_lua(source_code)
+        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+    let report = build_report(&dependencies);
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    // Redirect print into the captured stdout of this cell
+    let print_stdout = stdout.clone();
+    let print = lua.create_function(move |lua, args: Variadic<Value>| {
+        let tostring: mlua::Function = lua.globals().get("tostring")?;
+        let mut parts = vec![];
+        for arg in args.iter() {
+            parts.push(tostring.call::<_, String>(arg.clone())?);
+        }
+        print_stdout.lock().unwrap().push(LogLine::stdout(parts.join("\t")));
+        Ok(())
+    })?;
+    globals.set("print", print)?;
+    install_import_denylist(&lua, denied_imports, deny_network)?;
+
+    if let RkyvSerializedValue::Object(payload_map) = payload {
+        if let Some(RkyvSerializedValue::Object(globals_map)) = payload_map.get("globals") {
+            for (k, v) in globals_map {
+                globals.set(k.as_str(), rkyv_to_lua_value(&lua, v)?)?;
+            }
+        }
+    }
+
+    // TODO: lua cells do not yet dispatch calls to functions exposed by other cells
+    let chunk_result: MultiValue = lua.load(source_code).set_name("cell").eval()?;
+
+    if let Some(function_name) = function_invocation {
+        let function: mlua::Function = globals.get(function_name.as_str())?;
+        let mut args = vec![];
+        if let RkyvSerializedValue::Object(payload_map) = payload {
+            if let Some(RkyvSerializedValue::Object(args_map)) = payload_map.get("args") {
+                let mut args_vec: Vec<_> = args_map
+                    .iter()
+                    .map(|(k, v)| (k.parse::<i32>().unwrap_or(0), v))
+                    .collect();
+                args_vec.sort_by_key(|k| k.0);
+                for (_, v) in args_vec {
+                    args.push(rkyv_to_lua_value(&lua, v)?);
+                }
+            }
+            if let Some(RkyvSerializedValue::Object(kwargs_map)) = payload_map.get("kwargs") {
+                if !kwargs_map.is_empty() {
+                    args.push(rkyv_to_lua_value(&lua, &RkyvSerializedValue::Object(kwargs_map.clone()))?);
+                }
+            }
+        }
+        let result: Value = function.call(MultiValue::from_vec(args))?;
+        return lua_value_to_rkyv(&result);
+    }
+
+    // A chunk that returns a value produces that value, otherwise we expose its global assignments
+    if let Some(value) = chunk_result.into_iter().next() {
+        if !matches!(value, Value::Nil) {
+            return lua_value_to_rkyv(&value);
+        }
+    }
+
+    let mut output = RkyvObjectBuilder::new();
+    for (name, _) in &report.triggerable_functions {
+        output = output.insert_string(name, "function".to_string());
+    }
+    for (name, _) in &report.cell_exposed_values {
+        let value: Value = globals.get(name.as_str())?;
+        output = output.insert_value(name, lua_value_to_rkyv(&value)?);
+    }
+    Ok(output.build())
+}
+
+pub async fn source_code_run_lua(
+    execution_state: &ExecutionState,
+    source_code: &String,
+    payload: &RkyvSerializedValue,
+    function_invocation: &Option<String>,
+) -> anyhow::Result<(
+    Result<RkyvSerializedValue, ExecutionStateErrors>,
+    Vec<LogLine>,
+    Vec<LogLine>,
+    ExecutionState
+)> {
+    let execution_state = execution_state.clone();
+    let source_code = source_code.clone();
+    let payload = payload.clone();
+    let function_invocation = function_invocation.clone();
+    let parent_span = Span::current();
+
+    // The Lua state is not Send, so the entire evaluation happens on a blocking thread
+    let result = tokio::task::spawn_blocking(move || {
+        let _enter = parent_span.enter();
+        let stdout = Arc::new(Mutex::new(vec![]));
+        let output = run_lua(&source_code, &payload, &function_invocation, stdout.clone(), &execution_state.denied_imports, execution_state.deny_network)
+            .map_err(|e| denied_error(&e).unwrap_or_else(|| ExecutionStateErrors::Unknown(e.to_string())));
+        let stdout = stdout.lock().unwrap().clone();
+        (output, stdout, vec![], execution_state)
+    }).await?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_success() {
+        let source_code = String::from("x = 42");
+        let result = source_code_run_lua(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_number("x", 42).build()));
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_globals_set_by_payload() {
+        let source_code = String::from("z = a + b");
+        let result = source_code_run_lua(
+            &ExecutionState::new_with_random_id(),
+            &source_code,
+            &RkyvObjectBuilder::new()
+                .insert_object(
+                    "globals",
+                    RkyvObjectBuilder::new()
+                        .insert_number("a", 20)
+                        .insert_number("b", 5),
+                )
+                .build(),
+            &None,
+        ).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_number("z", 25).build()));
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_captures_print() {
+        let source_code = String::from(indoc! { r#"
+            local greeting = "hello"
+            print(greeting, 1)
+            "#});
+        let result = source_code_run_lua(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(result.1.iter().map(|l| l.text.as_str()).collect::<Vec<_>>(), vec!["hello\t1"]);
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_tables() {
+        let source_code = String::from(indoc! { r#"
+            list = {1, 2, 3}
+            obj = {foo = "bar"}
+            "#});
+        let result = source_code_run_lua(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(
+            result.0,
+            Ok(RkyvObjectBuilder::new()
+                .insert_value("list", RkyvSerializedValue::Array(vec![
+                    RkyvSerializedValue::Number(1),
+                    RkyvSerializedValue::Number(2),
+                    RkyvSerializedValue::Number(3),
+                ]))
+                .insert_object("obj", RkyvObjectBuilder::new().insert_string("foo", "bar".to_string()))
+                .build())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_function_invocation() {
+        let source_code = String::from(indoc! { r#"
+            function add(a, b)
+                return a + b
+            end
+            "#});
+        let result = source_code_run_lua(
+            &ExecutionState::new_with_random_id(),
+            &source_code,
+            &RkyvObjectBuilder::new()
+                .insert_object("args", RkyvObjectBuilder::new().insert_number("0", 5).insert_number("1", 3))
+                .build(),
+            &Some("add".to_string()),
+        ).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvSerializedValue::Number(8)));
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_denied_imports() {
+        let state = ExecutionState::new_with_random_id().with_denied_imports(vec!["socket".to_string()], false);
+        for source_code in ["local socket = require('socket')", "local http = require('socket.http')"] {
+            let result = source_code_run_lua(&state, &source_code.to_string(), &RkyvSerializedValue::Null, &None).await.unwrap();
+            let module = source_code.split('\'').nth(1).unwrap();
+            assert_eq!(result.0, Err(ExecutionStateErrors::Unknown(format!("Import of module '{}' is denied", module))));
+        }
+        let result = source_code_run_lua(&state, &"x = package.loadlib == nil".to_string(), &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_value("x", RkyvSerializedValue::Boolean(true)).build()));
+
+        let result = source_code_run_lua(&state, &"local string = require('string')\nx = string.upper('a')".to_string(), &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_string("x", "A".to_string()).build()));
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_network_denied() {
+        let state = ExecutionState::new_with_random_id().with_deny_network(true);
+        let result = source_code_run_lua(&state, &"local f = io.popen('curl http://localhost:9')".to_string(), &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert_eq!(result.0, Err(ExecutionStateErrors::NetworkDenied("io.popen".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_source_code_run_lua_failure() {
+        let source_code = String::from("error('Test Error')");
+        let result = source_code_run_lua(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None).await.unwrap();
+        assert!(result.0.is_err());
+    }
+}
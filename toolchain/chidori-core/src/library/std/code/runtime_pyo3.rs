@@ -7,10 +7,11 @@ use chidori_static_analysis::language::python::parse::{
 
 use futures_util::FutureExt;
 use pyo3::prelude::*;
-use pyo3::types::{IntoPyDict, PyCFunction, PyDict, PyList, PySet, PyTuple};
+use pyo3::types::{IntoPyDict, PyBytes, PyCFunction, PyDict, PyList, PySet, PyTuple};
 use std::sync::mpsc::{self, Sender};
 
 use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue};
+use crate::execution::primitives::operation::{LogLine, LogStream};
 use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::{env, mem};
@@ -36,9 +37,11 @@ use pyo3::PythonVersionInfo;
 use regex::Regex;
 
 use sha1::{Sha1, Digest};
-use tracing::{debug, Id, Span};
+use tracing::{debug, warn, Id, Span};
 use uuid::Uuid;
 use crate::execution::execution::execution_state::{EnclosedState, ExecutionStateErrors};
+use crate::library::std::code::interrupt::InterruptOnCancellation;
+use crate::sdk::config::ChidoriConfig;
 
 static SOURCE_CODE_RUN_COUNTER: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(0));
 static CURRENT_PYTHON_EXECUTION_ID: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(0));
@@ -98,12 +101,13 @@ fn get_or_create_default_venv(v: &PythonVersionInfo) -> anyhow::Result<PathBuf>
 fn pyany_to_rkyv_serialized_value(p: &PyAny) -> RkyvSerializedValue {
     match p.get_type().name() {
         Ok(s) => match s {
-            "int" => {
-                let val = p.extract::<i32>().unwrap();
-                RkyvSerializedValue::Number(val)
-            }
+            "int" => match p.extract::<i64>() {
+                Ok(val) => RkyvSerializedValue::from_i64(val),
+                // Python integers are unbounded, those beyond i64 keep their magnitude as floats
+                Err(_) => RkyvSerializedValue::Float(p.extract::<f64>().unwrap_or(f64::NAN)),
+            },
             "float" => {
-                let val = p.extract::<f32>().unwrap();
+                let val = p.extract::<f64>().unwrap();
                 RkyvSerializedValue::Float(val)
             }
             "str" => {
@@ -114,6 +118,10 @@ fn pyany_to_rkyv_serialized_value(p: &PyAny) -> RkyvSerializedValue {
                 let val = p.extract::<bool>().unwrap();
                 RkyvSerializedValue::Boolean(val)
             }
+            "bytes" => {
+                let val = p.downcast::<PyBytes>().unwrap();
+                RkyvSerializedValue::Bytes(val.as_bytes().to_vec())
+            }
             "list" => {
                 let list = p.downcast::<PyList>().unwrap();
                 let arr = list
@@ -153,20 +161,46 @@ fn pyany_to_rkyv_serialized_value(p: &PyAny) -> RkyvSerializedValue {
             "Future" => {
                 RkyvSerializedValue::Null
             },
+            x @ _ if is_numpy_value(p) => {
+                // Arrays become nested lists and numpy scalars their python equivalents
+                match p.call_method0("tolist") {
+                    Ok(converted) => pyany_to_rkyv_serialized_value(converted),
+                    Err(e) => {
+                        warn!("Failed to convert numpy value of type {}: {}", x, e);
+                        repr_of(p)
+                    }
+                }
+            },
             x @ _  => {
-                panic!("Py03 marshalling unsupported type: {}", x);
-                RkyvSerializedValue::Null
+                warn!("Py03 marshalling unsupported type {}, falling back to its repr", x);
+                repr_of(p)
             },
         },
         Err(_) => RkyvSerializedValue::Null,
     }
 }
 
+fn is_numpy_value(p: &PyAny) -> bool {
+    p.get_type()
+        .getattr("__module__")
+        .and_then(|m| m.extract::<String>())
+        .is_ok_and(|m| m == "numpy" || m.starts_with("numpy."))
+        && p.hasattr("tolist").unwrap_or(false)
+}
+
+fn repr_of(p: &PyAny) -> RkyvSerializedValue {
+    p.repr()
+        .map(|r| RkyvSerializedValue::String(r.to_string()))
+        .unwrap_or(RkyvSerializedValue::Null)
+}
+
 fn rkyv_serialized_value_to_pyany(py: Python, value: &RkyvSerializedValue) -> PyObject {
     match value {
         RkyvSerializedValue::Number(n) => n.into_py(py),
+        RkyvSerializedValue::Integer(n) => n.into_py(py),
         RkyvSerializedValue::Float(f) => f.into_py(py),
         RkyvSerializedValue::String(s) => s.into_py(py),
+        RkyvSerializedValue::Bytes(b) => PyBytes::new(py, b).into_py(py),
         RkyvSerializedValue::Boolean(b) => b.into_py(py),
         RkyvSerializedValue::Array(a) => {
             let py_list = PyList::empty(py);
@@ -214,6 +248,50 @@ fn on_event(py: Python, arg: PyObject) -> PyResult<PyObject> {
     Ok(obj)
 }
 
+/// Decorator declaring the event a function publishes its return value as. The declaration is read
+/// by static analysis, at runtime the function is returned unchanged.
+#[pyfunction]
+fn emit_as(py: Python, arg: PyObject) -> PyResult<PyObject> {
+    let identity_func = wrap_pyfunction!(identity_function, py)?;
+    let obj: PyObject = identity_func.into_py(py);
+    Ok(obj)
+}
+
+/// Name of the cell global holding the function `chidori.attachment` reads attachments through.
+const ATTACHMENT_ACCESSOR: &str = "__chidori_attachment__";
+
+/// The contents of an attachment of the run, as bytes. The module is shared by the cells of every
+/// run, so the attachment is read through the accessor installed in the globals of the calling cell.
+#[pyfunction]
+fn attachment(py: Python, name: &str) -> PyResult<PyObject> {
+    let caller_globals = py.import("sys")?.call_method1("_getframe", (0,))?.getattr("f_globals")?;
+    let accessor = caller_globals.get_item(ATTACHMENT_ACCESSOR)
+        .map_err(|_| pyo3::exceptions::PyRuntimeError::new_err("Attachments may only be read from within a cell"))?;
+    Ok(accessor.call1((name,))?.into_py(py))
+}
+
+/// Install the accessor of the run's attachments in the globals of a cell. A missing attachment
+/// raises a `KeyError` within the cell.
+fn install_attachment_accessor(py: Python, globals: &PyDict, execution_state: &Arc<Mutex<ExecutionState>>) -> Result<(), Error> {
+    let execution_state = execution_state.clone();
+    let accessor = PyCFunction::new_closure(
+        py,
+        None,
+        None,
+        move |args: &PyTuple, _kwargs: Option<&PyDict>| -> PyResult<PyObject> {
+            let name: String = args.get_item(0)?.extract()?;
+            let contents = execution_state.lock().unwrap().attachment(&name);
+            match contents {
+                Ok(bytes) => Ok(PyBytes::new(args.py(), &bytes).into_py(args.py())),
+                Err(e @ ExecutionStateErrors::MissingAttachment(_)) => Err(pyo3::exceptions::PyKeyError::new_err(e.to_string())),
+                Err(e) => Err(pyo3::exceptions::PyOSError::new_err(e.to_string())),
+            }
+        },
+    )?;
+    globals.set_item(ATTACHMENT_ACCESSOR, accessor)?;
+    Ok(())
+}
+
 /// When called this suspends execution with a long running rust function
 /// we hand back the GIL for other python execution. Invoke is used to execute another
 /// cell's provided function, or a cell as a function.
@@ -240,44 +318,101 @@ fn increment_source_code_run_counter() -> usize {
 }
 
 static PYTHON_OUTPUT_MAP: Lazy<Arc<DashMap<usize, DashMap<String, RkyvSerializedValue>>>> = Lazy::new(|| Arc::new(DashMap::new()));
-static PYTHON_LOGGING_BUFFER_STDOUT: Lazy<Arc<DashMap<usize, Vec<String>>>> = Lazy::new(|| Arc::new(DashMap::new()));
-static PYTHON_LOGGING_BUFFER_STDERR: Lazy<Arc<DashMap<usize, Vec<String>>>> = Lazy::new(|| Arc::new(DashMap::new()));
+static PYTHON_LOGGING_BUFFER_STDOUT: Lazy<Arc<DashMap<usize, Vec<LogLine>>>> = Lazy::new(|| Arc::new(DashMap::new()));
+static PYTHON_LOGGING_BUFFER_STDERR: Lazy<Arc<DashMap<usize, Vec<LogLine>>>> = Lazy::new(|| Arc::new(DashMap::new()));
+
+/// Modules imported by warm start, held so that they remain loaded for the lifetime of the process.
+static PRELOADED_MODULES: Lazy<DashMap<String, Py<PyModule>>> = Lazy::new(DashMap::new);
+
+/// Modules imported by the wrapper every cell is evaluated within.
+const WRAPPER_MODULES: [&str; 2] = ["sys", "asyncio"];
 
+/// Initialize the interpreter and import the given modules ahead of the first Python cell, so that
+/// cell does not pay for either. Modules already preloaded are not imported again.
+pub fn warm_start_python(modules: &[String]) -> anyhow::Result<()> {
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+        for module in WRAPPER_MODULES.into_iter().chain(modules.iter().map(String::as_str)) {
+            if PRELOADED_MODULES.contains_key(module) {
+                continue;
+            }
+            let imported = py.import(module).map_err(|e| anyhow!("Failed to preload module {:?}: {}", module, e))?;
+            PRELOADED_MODULES.insert(module.to_string(), imported.into());
+        }
+        Ok(())
+    })
+}
+
+/// Warm start the interpreter with the modules of a project's configuration, when it asks for it.
+pub fn warm_start_configured_python(configuration: &ChidoriConfig) -> anyhow::Result<()> {
+    match configuration.python.as_ref().filter(|python| python.warm_start) {
+        Some(python) => warm_start_python(&python.preload_modules),
+        None => Ok(()),
+    }
+}
+
+/// Captures writes to a python stream, assembling them into lines. A line is timestamped when its
+/// first fragment is written.
 #[pyclass]
 struct LoggingToChannel {
     exec_id: usize,
-    sender: Sender<(usize, String)>,
-    output_buffer_set: Arc<DashMap<usize, Vec<String>>>,
-    buffered_write: Vec<(usize, String)>,
+    stream: LogStream,
+    sender: Sender<(usize, LogLine)>,
+    output_buffer_set: Arc<DashMap<usize, Vec<LogLine>>>,
+    partial_line: Option<LogLine>,
+    buffered_write: Vec<(usize, LogLine)>,
 }
 
 impl LoggingToChannel {
-    fn new(sender: Sender<(usize, String)>, buffer_set: Arc<DashMap<usize, Vec<String>>>, exec_id: usize) -> Self {
+    fn new(sender: Sender<(usize, LogLine)>, buffer_set: Arc<DashMap<usize, Vec<LogLine>>>, exec_id: usize, stream: LogStream) -> Self {
         LoggingToChannel {
             exec_id,
+            stream,
             sender,
             output_buffer_set: buffer_set,
+            partial_line: None,
             buffered_write: vec![]
         }
     }
+
+    fn complete_line(&mut self) {
+        if let Some(line) = self.partial_line.take() {
+            let _ = self.sender.send((self.exec_id, line.clone()));
+            self.buffered_write.push((self.exec_id, line));
+        }
+    }
 }
 
 #[pymethods]
 impl LoggingToChannel {
     fn set_exec_id(&mut self, exec_id: usize) {
+        self.complete_line();
         self.exec_id = exec_id;
     }
 
     fn write(&mut self, data: &str) {
-        let exec_id = self.exec_id;;
-        self.buffered_write.push((exec_id, data.to_string()));
-        let _ = self.sender.send((exec_id, data.to_string()));
+        let mut fragments = data.split('\n').peekable();
+        while let Some(fragment) = fragments.next() {
+            let is_last = fragments.peek().is_none();
+            if is_last && fragment.is_empty() {
+                break;
+            }
+            let stream = self.stream;
+            self.partial_line
+                .get_or_insert_with(|| LogLine::new(stream, ""))
+                .text
+                .push_str(fragment);
+            if !is_last {
+                self.complete_line();
+            }
+        }
     }
 
     fn flush(&mut self) {
-        for (exec_id, data) in self.buffered_write.drain(..) {
+        self.complete_line();
+        for (exec_id, line) in self.buffered_write.drain(..) {
             let mut output = self.output_buffer_set.entry(exec_id).or_insert(vec![]);
-            output.push(data.clone());
+            output.push(line);
         }
     }
 }
@@ -297,7 +432,38 @@ impl std::convert::From<AnyhowErrWrapper> for PyErr {
 
 
 
-#[tracing::instrument]
+/// Enters the working directory of a cell through `os.chdir` with the GIL held, so that no other
+/// Python code observes it, returning to the prior directory when dropped.
+struct PythonWorkingDirectory {
+    previous: Option<PyObject>,
+}
+
+impl PythonWorkingDirectory {
+    fn enter(py: Python, cwd: Option<&str>) -> anyhow::Result<Self> {
+        let Some(cwd) = cwd else {
+            return Ok(Self { previous: None });
+        };
+        let os = py.import("os")?;
+        let previous = os.call_method0("getcwd")?.into_py(py);
+        os.call_method1("chdir", (cwd,))
+            .map_err(|e| anyhow!("Failed to enter working directory {}: {}", cwd, e))?;
+        Ok(Self { previous: Some(previous) })
+    }
+}
+
+impl Drop for PythonWorkingDirectory {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            Python::with_gil(|py| {
+                if let Err(e) = py.import("os").and_then(|os| os.call_method1("chdir", (previous,))) {
+                    warn!("Failed to restore working directory: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[tracing::instrument(skip(payload), fields(payload = ?execution_state.redact(payload)))]
 pub async fn source_code_run_python(
     execution_state: &ExecutionState,
     source_code: &String,
@@ -305,7 +471,8 @@ pub async fn source_code_run_python(
     function_invocation: &Option<String>,
     virtualenv_path: &Option<String>,
     requirements_dir: &Option<String>,
-) -> anyhow::Result<(Result<RkyvSerializedValue, ExecutionStateErrors>, Vec<String>, Vec<String>, ExecutionState)> {
+    cwd: Option<&str>,
+) -> anyhow::Result<(Result<RkyvSerializedValue, ExecutionStateErrors>, Vec<LogLine>, Vec<LogLine>, ExecutionState)> {
 
     // Capture the current span's ID
     let current_span_id = Span::current().id();
@@ -321,6 +488,10 @@ pub async fn source_code_run_python(
     let dependencies = extract_dependencies_python(&source_code)?;
     let report = build_report(&dependencies);
 
+    let denied_imports = execution_state.denied_imports.clone();
+    let deny_network = execution_state.deny_network;
+    let random_seed = execution_state.cell_random_seed(execution_state.evaluating_operation_id);
+    let cancellation = execution_state.cancellation.clone();
     let execution_state = Arc::new(Mutex::new(execution_state.clone()));
     let result =  Python::with_gil(|py| {
         let v = py.version_info();
@@ -355,6 +526,7 @@ pub async fn source_code_run_python(
         let globals = PyDict::new(py);
         create_external_function_shims(&execution_state, &report, py, globals, current_span_id.clone())?;
         create_internal_proxy_shims(&execution_state, &report, py, globals, current_span_id)?;
+        install_attachment_accessor(py, globals, &execution_state)?;
 
 
         let sys = py.import("sys")?;
@@ -385,7 +557,9 @@ pub async fn source_code_run_python(
             // so this is treated as an initialization handler.
             let chidori_module = PyModule::new(py, "chidori")?;
             chidori_module.add_function(wrap_pyfunction!(on_event, chidori_module)?)?;
+            chidori_module.add_function(wrap_pyfunction!(emit_as, chidori_module)?)?;
             chidori_module.add_function(wrap_pyfunction!(identity_function, chidori_module)?)?;
+            chidori_module.add_function(wrap_pyfunction!(attachment, chidori_module)?)?;
             let chidori_set_value = PyCFunction::new_closure(
                 py,
                 None,
@@ -406,20 +580,31 @@ pub async fn source_code_run_python(
         }
 
         // Set up capture of stdout from python process and storing it into a Vec
-        let stdout_capture = LoggingToChannel::new(sender_stdout, PYTHON_LOGGING_BUFFER_STDOUT.clone(), exec_id);
+        let stdout_capture = LoggingToChannel::new(sender_stdout, PYTHON_LOGGING_BUFFER_STDOUT.clone(), exec_id, LogStream::Stdout);
         let stdout_capture_py = stdout_capture.into_py(py);
-        let stderr_capture = LoggingToChannel::new(sender_stderr, PYTHON_LOGGING_BUFFER_STDERR.clone(), exec_id);
+        let stderr_capture = LoggingToChannel::new(sender_stderr, PYTHON_LOGGING_BUFFER_STDERR.clone(), exec_id, LogStream::Stderr);
         let stderr_capture_py = stderr_capture.into_py(py);
 
         sys.setattr("stdout", stdout_capture_py)?;
         sys.setattr("stderr", stderr_capture_py)?;
 
         if let RkyvSerializedValue::Object(ref payload_map) = payload {
+            let namespaces: HashSet<&str> = match payload_map.get("namespaces") {
+                Some(RkyvSerializedValue::Array(names)) => names.iter().filter_map(|name| match name {
+                    RkyvSerializedValue::String(name) => Some(name.as_str()),
+                    _ => None,
+                }).collect(),
+                _ => HashSet::new(),
+            };
             if let Some(RkyvSerializedValue::Object(globals_map)) = payload_map.get("globals") {
                 for (key, value) in globals_map {
                     println!("Setting globals {}: {:?}", key, value);
                     let py_value = rkyv_serialized_value_to_pyany(py, value); // Implement this function to convert RkyvSerializedValue to PyObject
-                    globals.set_item(key, py_value)?;
+                    if namespaces.contains(key.as_str()) {
+                        globals.set_item(key, namespace_of(py, py_value)?)?;
+                    } else {
+                        globals.set_item(key, py_value)?;
+                    }
                 }
             }
         }
@@ -430,6 +615,18 @@ import sys
 sys.stdout.set_exec_id({exec_id})
 sys.stderr.set_exec_id({exec_id})
         "#, exec_id=exec_id);
+        if let Some(seed) = random_seed {
+            // numpy only accepts 32 bit seeds
+            initial_source_code.push_str(&format!(r#"
+import random as __chidori_random
+__chidori_random.seed({seed})
+try:
+    import numpy as __chidori_numpy
+    __chidori_numpy.random.seed({seed} % 2**32)
+except Exception:
+    pass
+"#, seed=seed));
+        }
         initial_source_code.push_str("\n");
         initial_source_code.push_str(&source_code.clone());
 
@@ -508,8 +705,26 @@ asyncio.run(__wrapper())
         "#, indent_all_source_code)
         };
 
+        // Relative paths of the cell resolve against its working directory while it runs
+        let _cwd = PythonWorkingDirectory::enter(py, cwd)?;
+        let _denylist = PythonImportDenylist::enter(py, &denied_imports)?;
+
+        // Cancelling the run raises KeyboardInterrupt within the cell. The exception is raised on the
+        // thread running the cell, PyErr_SetInterrupt would only interrupt the main thread.
+        let thread_id: u64 = py.import("threading")?.call_method0("get_ident")?.extract()?;
+        let _interrupt = InterruptOnCancellation::watch(&cancellation, move || {
+            Python::with_gil(|_| unsafe {
+                pyo3::ffi::PyThreadState_SetAsyncExc(thread_id as _, pyo3::ffi::PyExc_KeyboardInterrupt);
+            });
+        });
+
         // Important: this is the point of initial execution of the source code
-        py.run(&complete_code, Some(globals), None)?;
+        if let Err(err) = py.run(&complete_code, Some(globals), None) {
+            if let Some(error) = denied_import_error(py, &err, deny_network) {
+                return Ok(Box::pin(async move { Err(error) }) as Pin<Box<dyn Future<Output = Result<RkyvSerializedValue, ExecutionStateErrors>> + Send>>);
+            }
+            return Err(python_exception(py, &err).into());
+        }
 
         // With the source environment established, we can now invoke specific methods provided by this node
         return match function_invocation {
@@ -525,10 +740,10 @@ asyncio.run(__wrapper())
                     }) as Pin<Box<dyn Future<Output = Result<RkyvSerializedValue, ExecutionStateErrors>> + Send>>)
                 })
             }
-            Some(name) => {
+            Some(function_name) => {
                 // Function invocations refer to the function hashed _once_ this is the reference
                 // to the original definition of the function.
-                let name = hash_to_python_method_name(&name);
+                let name = hash_to_python_method_name(&function_name);
 
                 // This is calling to the not proxied version, so it is the Hash A instance of the function
                 // otherwise we're in a loop of external dispatches
@@ -565,10 +780,15 @@ asyncio.run(__wrapper())
                     let args = PyTuple::new(py, &args);
                     let kwargs = kwargs.into_iter().into_py_dict(py);
 
-                    let result = py_func.call(args, Some(kwargs)).map_err(|e| {
-                        dbg!(&e);
-                        e
-                    })?;
+                    let result = match py_func.call(args, Some(kwargs)) {
+                        Ok(result) => result,
+                        Err(err) => {
+                            if let Some(error) = denied_import_error(py, &err, deny_network) {
+                                return Ok(Box::pin(async move { Err(error) }) as Pin<Box<dyn Future<Output = Result<RkyvSerializedValue, ExecutionStateErrors>> + Send>>);
+                            }
+                            return Err(python_exception(py, &err).into());
+                        }
+                    };
                     if result.get_type().name().unwrap() == "coroutine" {
                         // If the function is a coroutine, we need to await it
                         let is_running = event_loop.call_method0("is_running")?.extract::<bool>()?;
@@ -588,7 +808,7 @@ asyncio.run(__wrapper())
                         Ok(Box::pin(async move {
                             println!("waiting the python coroutine");
                             let final_result = if let Some(fut) = fut {
-                                fut.await.map_err(|e| ExecutionStateErrors::Unknown(e.to_string()))?
+                                fut.await.map_err(|e| Python::with_gil(|py| python_exception(py, &e)))?
                             } else {
                                 result.unwrap()
                             };
@@ -608,7 +828,7 @@ asyncio.run(__wrapper())
                         }) as Pin<Box<dyn Future<Output=Result<RkyvSerializedValue, ExecutionStateErrors>> + Send>>)
                     }
                 } else {
-                    Err(anyhow::anyhow!("Function not found"))
+                    Err(anyhow::anyhow!("Function {:?} is not defined by this cell", function_name))
                 }
             }
         }
@@ -621,12 +841,167 @@ asyncio.run(__wrapper())
             let (_, output_stderr) = PYTHON_LOGGING_BUFFER_STDERR.remove(&exec_id).unwrap_or((0, vec![]));
             Ok((awaited_result, output_stdout, output_stderr, execution_state))
         }
-        Err(e) => {
-            return Err(anyhow::anyhow!(e.to_string()));
+        Err(e) => Err(e),
+    }
+}
+
+/// Convert an exception raised by the cell into a typed error, keeping the class of the exception so
+/// that callers can tell a `KeyError` from a `ValueError` without parsing the message.
+pub(crate) fn python_exception(py: Python, err: &PyErr) -> ExecutionStateErrors {
+    let exception_type = err.get_type(py).name().map(|name| name.to_string()).unwrap_or_else(|_| "Exception".to_string());
+    let message = err.value(py).str().map(|message| message.to_string()).unwrap_or_default();
+    let traceback = err.traceback(py).and_then(|traceback| traceback.format().ok()).unwrap_or_default();
+    ExecutionStateErrors::PythonException {
+        traceback: format!("{}{}: {}", traceback, exception_type, message),
+        exception_type,
+        message,
+    }
+}
+
+
+const NAMESPACE_SOURCE: &str = r#"
+class Namespace(dict):
+    def __getattr__(self, name):
+        try:
+            return self[name]
+        except KeyError:
+            raise AttributeError(name) from None
+"#;
+
+/// The namespace of a cell, the dict of the globals it provides with those globals also accessible
+/// as attributes, as `cellname.result`.
+fn namespace_of(py: Python, globals: PyObject) -> Result<PyObject, Error> {
+    let module = PyModule::from_code(py, NAMESPACE_SOURCE, "chidori_namespace.py", "chidori_namespace")?;
+    Ok(module.getattr("Namespace")?.call1((globals,))?.into_py(py))
+}
+
+const IMPORT_DENYLIST_SOURCE: &str = r#"
+import builtins
+import contextvars
+import sys
+import threading
+
+denied_modules = contextvars.ContextVar("chidori_denied_modules", default=())
+
+# Modules already loaded by an allowed module remain reachable through it, so the operations
+# of denied modules are also refused as the interpreter audits them
+MODULE_EVENTS = {
+    "socket": ("socket.connect", "socket.bind", "socket.getaddrinfo", "socket.gethostbyname",
+               "socket.gethostbyaddr", "socket.sendto", "socket.sendmsg"),
+    "subprocess": ("subprocess.Popen",),
+}
+
+def denied_module(name):
+    for module in denied_modules.get():
+        if name == module or name.startswith(module + "."):
+            return name
+    return None
+
+def denied(name):
+    error = ImportError(f"Import of module '{name}' is denied")
+    error.chidori_denied_module = name
+    return error
+
+original_import = builtins.__import__
+
+def guarded_import(name, globals=None, locals=None, fromlist=(), level=0):
+    if level == 0 and denied_module(name):
+        raise denied(name)
+    return original_import(name, globals, locals, fromlist, level)
+
+def audit(event, args):
+    if not denied_modules.get():
+        return
+    if event == "import" and denied_module(args[0]):
+        raise denied(args[0])
+    for module, events in MODULE_EVENTS.items():
+        if event in events and denied_module(module):
+            raise denied(module)
+
+# Threads do not inherit the context of the thread starting them, the denylist is carried over
+original_start = threading.Thread.start
+
+def guarded_start(self):
+    denied = denied_modules.get()
+    run = self.run
+    def guarded_run():
+        denied_modules.set(denied)
+        run()
+    self.run = guarded_run
+    return original_start(self)
+
+builtins.__import__ = guarded_import
+sys.addaudithook(audit)
+threading.Thread.start = guarded_start
+"#;
+
+/// Denies the modules of `denied_imports` to the cell while it is held. The guard is installed once
+/// for the whole interpreter, so that the imports of modules the cell was allowed to import are
+/// checked too, and is scoped to the cell by a context variable that its asyncio tasks inherit.
+struct PythonImportDenylist {
+    token: Option<PyObject>,
+}
+
+impl PythonImportDenylist {
+    fn enter(py: Python, denied_imports: &[String]) -> Result<Self, Error> {
+        if denied_imports.is_empty() {
+            return Ok(Self { token: None });
+        }
+        let modules = py.import("sys")?.getattr("modules")?;
+        let guard = match modules.get_item("chidori_import_denylist") {
+            Ok(guard) => guard,
+            Err(_) => {
+                let guard: &PyAny = PyModule::from_code(py, IMPORT_DENYLIST_SOURCE, "chidori_import_denylist.py", "chidori_import_denylist")?;
+                modules.set_item("chidori_import_denylist", guard)?;
+                guard
+            }
+        };
+        let token = guard.getattr("denied_modules")?.call_method1("set", (PyTuple::new(py, denied_imports),))?;
+        Ok(Self { token: Some(token.into_py(py)) })
+    }
+}
+
+impl Drop for PythonImportDenylist {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            Python::with_gil(|py| {
+                let reset = py.import("chidori_import_denylist")
+                    .and_then(|guard| guard.getattr("denied_modules"))
+                    .and_then(|denied_modules| denied_modules.call_method1("reset", (token,)));
+                if let Err(e) = reset {
+                    warn!("Failed to lift the import denylist: {}", e);
+                }
+            });
         }
     }
 }
 
+/// Modules through which Python cells could reach the network, denied to every cell of a run that
+/// denies network access.
+pub(crate) const NETWORK_MODULES: &[&str] = &[
+    "socket", "ssl", "http", "urllib", "urllib3", "requests", "httpx", "aiohttp",
+    "ftplib", "smtplib", "poplib", "imaplib", "telnetlib", "xmlrpc", "websocket", "websockets",
+];
+
+fn is_network_module(module: &str) -> bool {
+    NETWORK_MODULES.iter().any(|network| module == *network || module.starts_with(&format!("{}.", network)))
+}
+
+/// The error of a cell that imported a denied module, imports of networking modules in a run that
+/// denies network access are reported as such.
+pub(crate) fn denied_import(module: &str, deny_network: bool) -> ExecutionStateErrors {
+    if deny_network && is_network_module(module) {
+        ExecutionStateErrors::NetworkDenied(module.to_string())
+    } else {
+        ExecutionStateErrors::Unknown(format!("Import of module '{}' is denied", module))
+    }
+}
+
+/// The error of a cell that attempted to import a denied module.
+fn denied_import_error(py: Python, err: &PyErr, deny_network: bool) -> Option<ExecutionStateErrors> {
+    let module = err.value(py).getattr("chidori_denied_module").ok()?.extract::<String>().ok()?;
+    Some(denied_import(&module, deny_network))
+}
 
 fn create_internal_proxy_shims(execution_state_handle: &Arc<Mutex<ExecutionState>>, report: &Report, py: Python, globals: &PyDict, parent_span_id: Option<tracing::Id>) -> Result<(), Error> {
     // Create shims for the functions declared within this file,
@@ -672,9 +1047,12 @@ fn create_python_dispatch_closure(py: Python, clone_function_name: String, execu
                     .await.map_err(|e| AnyhowErrWrapper(e))?;
 
                 // swap this execution state with the root state of this cell execution
-                // so that we continue from the state where this function has resolved
-                let mut exec_state = execution_state_handle.lock().unwrap();
-                std::mem::swap(&mut *exec_state, &mut result_execution_state);
+                // so that we continue from the state where this function has resolved. The lock is
+                // released before the GIL is acquired, as recursive invocations contend for both
+                {
+                    let mut exec_state = execution_state_handle.lock().unwrap();
+                    std::mem::swap(&mut *exec_state, &mut result_execution_state);
+                }
 
                 match result {
                     Ok(result) => {
@@ -818,6 +1196,7 @@ mod tests {
     use chidori_static_analysis::language::{InternalCallGraph, ReportTriggerableFunctions};
     use crate::execution::execution::execution_graph::ExecutionGraphSendPayload;
     use crate::execution::primitives::operation::OperationFnOutput;
+    use crate::execution::execution::execution_state::Attachment;
 
     #[derive(Clone)]
     pub enum ExecutionStateEvaluation {
@@ -842,13 +1221,95 @@ mod tests {
     //     return 42 + suspend()
     //         "#,
     //         );
-    //         let result = source_code_run_python(source_code);
+    //         let result = source_code_run_python(source_code, None);
     //         // TODO: this should deserialize to a function pointer
     //         // assert_eq!(
     //         //     result.unwrap(),
     //         //     HashMap::from_iter(vec![("fun".to_string(), RkyvSerializedValue::Number(42),),])
     //     }
 
+    #[tokio::test]
+    async fn test_warm_start_preloads_modules() {
+        let configuration = ChidoriConfig {
+            python: Some(crate::sdk::config::PythonConfiguration {
+                warm_start: true,
+                preload_modules: vec!["decimal".to_string()],
+            }),
+            ..ChidoriConfig::default()
+        };
+        let state = ExecutionState::new_with_random_id().with_configuration(Arc::new(configuration)).unwrap();
+        assert!(PRELOADED_MODULES.contains_key("asyncio"));
+        assert!(PRELOADED_MODULES.contains_key("decimal"));
+
+        let source_code = String::from("import sys\npreloaded = \"decimal\" in sys.modules");
+        let result = source_code_run_python(&state, &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_boolean("preloaded", true).build()));
+
+        let err = warm_start_python(&["chidori_module_that_does_not_exist".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("chidori_module_that_does_not_exist"), "{}", err);
+    }
+
+    /// Writer collecting everything logged while it is the default subscriber's output.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_secret_globals_are_not_traced() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = ExecutionState::new_with_random_id().with_secret_globals(vec!["api_token".to_string()]);
+        let payload = RkyvObjectBuilder::new()
+            .insert_object("globals", RkyvObjectBuilder::new()
+                .insert_string("api_token", "sk-very-secret".to_string())
+                .insert_string("city", "Paris".to_string()))
+            .build();
+        let source_code = String::from("y = 1");
+        source_code_run_python(&state, &source_code, &payload, &None, &None, &None, None).await.unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("source_code_run_python"), "{}", logs);
+        assert!(logs.contains("Paris"), "{}", logs);
+        assert!(!logs.contains("sk-very-secret"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_cells_read_attachments() {
+        let state = ExecutionState::new_with_random_id()
+            .with_attachment("fixture", Attachment::Bytes(b"a,b\n1,2".to_vec()));
+        let source_code = String::from("import chidori\nrows = chidori.attachment(\"fixture\").decode().splitlines()");
+        let result = source_code_run_python(&state, &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap();
+        assert_eq!(result.0, Ok(RkyvObjectBuilder::new().insert_value("rows", RkyvSerializedValue::Array(vec![
+            RkyvSerializedValue::String("a,b".to_string()),
+            RkyvSerializedValue::String("1,2".to_string()),
+        ])).build()));
+
+        let source_code = String::from("import chidori\ndata = chidori.attachment(\"missing\")");
+        let err = source_code_run_python(&state, &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap_err();
+        let Ok(ExecutionStateErrors::PythonException { exception_type, message, .. }) = err.downcast::<ExecutionStateErrors>() else {
+            panic!("Expected a python exception");
+        };
+        assert_eq!(exception_type, "KeyError");
+        assert!(message.contains("no attachment named \"missing\""), "{}", message);
+    }
+
     #[tokio::test]
     async fn test_py_source_without_entrypoint() {
         println!("running A");
@@ -859,7 +1320,7 @@ x = 12 + y
 li = [x, y]
         "#,
         );
-        let result = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None).await;
+        let result = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await;
         assert_eq!(
             result.unwrap(),
             (
@@ -880,6 +1341,34 @@ li = [x, y]
         );
     }
 
+    #[tokio::test]
+    async fn test_py_source_structured_outputs() {
+        let source_code = String::from(
+            r#"
+class Point:
+    def __repr__(self):
+        return "Point(1, 2)"
+
+nested = {"a": [1, 2, 3]}
+point = Point()
+        "#,
+        );
+        let (output, _, _, _) = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap();
+        let RkyvSerializedValue::Object(output) = output.unwrap() else { panic!("Expected an object") };
+        assert_eq!(
+            output.get("nested"),
+            Some(&RkyvObjectBuilder::new()
+                .insert_value("a", RkyvSerializedValue::Array(vec![
+                    RkyvSerializedValue::Number(1),
+                    RkyvSerializedValue::Number(2),
+                    RkyvSerializedValue::Number(3),
+                ]))
+                .build())
+        );
+        // Values without a structured equivalent are represented by their repr
+        assert_eq!(output.get("point"), Some(&RkyvSerializedValue::String("Point(1, 2)".to_string())));
+    }
+
     #[tokio::test]
     async fn test_py_source_without_entrypoint_with_stdout() {
         println!("running B");
@@ -888,14 +1377,34 @@ li = [x, y]
 print("testing")
         "#,
         );
-        let result = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None).await;
+        let (output, stdout, stderr, _) = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap();
+        assert_eq!(output, Ok(RkyvSerializedValue::Object(HashMap::from_iter(vec![]))));
+        assert_eq!(stdout.iter().map(|l| (l.stream, l.text.as_str())).collect::<Vec<_>>(), vec![(LogStream::Stdout, "testing")]);
+        assert!(stderr.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_py_source_stdout_and_stderr_are_ordered_lines() {
+        let source_code = String::from(
+            r#"
+import sys
+print("one", end="")
+print(" two")
+print("three", file=sys.stderr)
+print("four\nfive")
+        "#,
+        );
+        let (_, stdout, stderr, _) = source_code_run_python(&ExecutionState::new_with_random_id(), &source_code, &RkyvSerializedValue::Null, &None, &None, &None, None).await.unwrap();
+        let mut lines: Vec<LogLine> = stdout.into_iter().chain(stderr).collect();
+        lines.sort_by_key(|l| l.ts);
         assert_eq!(
-            result.unwrap(),
-            (
-                Ok(RkyvSerializedValue::Object(HashMap::from_iter(vec![]))),
-                vec![String::from("testing"), String::from("\n")],
-                vec![]
-            )
+            lines.iter().map(|l| (l.stream, l.text.as_str())).collect::<Vec<_>>(),
+            vec![
+                (LogStream::Stdout, "one two"),
+                (LogStream::Stderr, "three"),
+                (LogStream::Stdout, "four"),
+                (LogStream::Stdout, "five"),
+            ]
         );
     }
 
@@ -917,10 +1426,32 @@ def example():
                                             &Some("example".to_string()),
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(result.unwrap(), (Ok(RkyvSerializedValue::Number(20)), vec![], vec![]));
     }
 
+    #[tokio::test]
+    async fn test_large_integers_keep_their_precision() {
+        let source_code = String::from(
+            r#"
+def example(x):
+    return x + 1
+        "#,
+        );
+        let result = source_code_run_python(&ExecutionState::new_with_random_id(),
+                                            &source_code,
+                                            &RkyvObjectBuilder::new()
+                .insert_object("args", RkyvObjectBuilder::new().insert_value("0", RkyvSerializedValue::Integer(1234567890123)))
+                .build(),
+                                            &Some("example".to_string()),
+                                            &None,
+                                            &None,
+                                            None,
+        ).await;
+        assert_eq!(result.unwrap().0, Ok(RkyvSerializedValue::Integer(1234567890124)));
+    }
+
     #[tokio::test]
     async fn test_execution_of_internal_function_with_arguments() {
         let source_code = String::from(
@@ -940,6 +1471,7 @@ def example(x):
                                             &Some("example".to_string()),
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(result.unwrap(), (Ok(RkyvSerializedValue::Number(25)), vec![], vec![]));
     }
@@ -954,14 +1486,12 @@ a = 20 + await demo()
         let mut state = ExecutionState::new_with_random_id();
         let id_a = Uuid::now_v7();
         let (state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         def demo():
                             return 100
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_a)?;
         let result = source_code_run_python(&state,
                                             &source_code,
@@ -971,6 +1501,7 @@ a = 20 + await demo()
                                             &None,
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(
             result.unwrap(),
@@ -988,8 +1519,6 @@ a = 20 + await demo()
         let mut state = ExecutionState::new_with_random_id();
         let id_a = Uuid::now_v7();
         let (state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         import asyncio
@@ -997,7 +1526,7 @@ a = 20 + await demo()
                             await asyncio.sleep(1)
                             return 100
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_a)?;
         let result = source_code_run_python(&state,
                                             &String::from( r#"data = await demo()"#, ),
@@ -1008,6 +1537,7 @@ a = 20 + await demo()
                                             &None,
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(
             result.unwrap(),
@@ -1031,8 +1561,6 @@ data = await demo()
         let mut state = ExecutionState::new_with_random_id();
         let id_a = Uuid::now_v7();
         let (mut state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         import asyncio
@@ -1040,12 +1568,10 @@ data = await demo()
                             await asyncio.sleep(1)
                             return 100 + await demo_second_function_call()
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_a)?;
         let id_b = Uuid::now_v7();
         let (state, _) = state.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         import asyncio
@@ -1053,7 +1579,7 @@ data = await demo()
                             await asyncio.sleep(1)
                             return 100
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_b)?;
         let result = source_code_run_python(&state,
                                             &source_code,
@@ -1062,6 +1588,7 @@ data = await demo()
                                             &None,
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(
             result.unwrap(),
@@ -1080,8 +1607,6 @@ data = await demo()
         let mut state_a = ExecutionState::new_with_graph_sender(Uuid::nil(), Arc::new(sender.clone()));
         let id_a = Uuid::now_v7();
         let (state, _) = state_a.update_operation(CellTypes::Code(CodeCell {
-            backing_file_reference: None,
-            name: None,
             language: SupportedLanguage::PyO3,
             source_code: String::from(indoc! {r#"
                         import asyncio
@@ -1097,7 +1622,7 @@ data = await demo()
                             await asyncio.sleep(1)
                             return 100 + await function_b()
                         "#}),
-            function_invocation: None,
+            ..Default::default()
         }, TextRange::default()), id_a)?;
         let source_code = String::from(
             r#"data = await function_c()"#,
@@ -1146,6 +1671,7 @@ data = await demo()
             &None,
             &None,
             &None,
+            None,
         ).await;
         cancellation_notify.notify_one();
         assert_eq!(
@@ -1168,7 +1694,7 @@ data = await demo()
         // Helper function to check OperationFnOutput
         fn check_operation_output(output: &Arc<OperationFnOutput>, expected_value: i64) -> bool {
             match output.as_ref() {
-                OperationFnOutput { has_error: false, execution_state: None, output: output_value, stdout, stderr } => {
+                OperationFnOutput { has_error: false, execution_state: None, output: output_value, stdout, stderr, .. } => {
                     matches!(output_value, Ok(RkyvSerializedValue::Number(n)) if *n == expected_value as i32)
                         && stdout.is_empty()
                         && stderr.is_empty()
@@ -1228,11 +1754,12 @@ unittest.TextTestRunner().run(unittest.TestLoader().loadTestsFromTestCase(TestMa
                                             &None,
                                             &None,
                                             &None,
+                                            None,
         ).await;
         let (result, _, stderr) = result.unwrap();
         dbg!(&stderr);
-        assert_eq!(stderr.iter().filter(|x| x.contains("Ran 1 test")).count(), 1);
-        assert_eq!(stderr.iter().filter(|x| x.contains("OK")).count(), 1);
+        assert_eq!(stderr.iter().filter(|x| x.text.contains("Ran 1 test")).count(), 1);
+        assert_eq!(stderr.iter().filter(|x| x.text.contains("OK")).count(), 1);
     }
 
     #[ignore]
@@ -1262,11 +1789,12 @@ unittest.TextTestRunner().run(unittest.TestLoader().loadTestsFromTestCase(TestMa
                                             &None,
                                             &None,
                                             &None,
+                                            None,
         ).await;
         let (result, _, stderr) = result.unwrap();
         dbg!(&stderr);
-        assert_eq!(stderr.iter().filter(|x| x.contains("Ran 1 test")).count(), 1);
-        assert_eq!(stderr.iter().filter(|x| x.contains("OK")).count(), 1);
+        assert_eq!(stderr.iter().filter(|x| x.text.contains("Ran 1 test")).count(), 1);
+        assert_eq!(stderr.iter().filter(|x| x.text.contains("OK")).count(), 1);
     }
 
     // TODO: the expected behavior is that as we execute the function again and again from another location, the state mutates
@@ -1290,6 +1818,7 @@ def example(x):
                                             &Some("example".to_string()),
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(result.unwrap(), (Ok(RkyvSerializedValue::Number(1)), vec![], vec![]));
         let result = source_code_run_python(&ExecutionState::new_with_random_id(),
@@ -1300,6 +1829,7 @@ def example(x):
                                             &Some("example".to_string()),
                                             &None,
                                             &None,
+                                            None,
         ).await;
         assert_eq!(result.unwrap(), (Ok(RkyvSerializedValue::Number(2)), vec![], vec![]));
     }
@@ -1322,6 +1852,7 @@ fn example():
             &Some("example".to_string()),
             &None,
             &None,
+            None,
         ).await;
         match result {
             Ok(_) => {panic!("Must return error.")}
@@ -1348,6 +1879,7 @@ raise ValueError("Raising a python error")
             &Some("example".to_string()),
             &None,
             &None,
+            None,
         ).await;
         match result {
             Ok(_) => {panic!("Must return error.")}
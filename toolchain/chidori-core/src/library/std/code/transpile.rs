@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use dashmap::DashMap;
+use deno_ast::{EmitOptions, MediaType, ModuleSpecifier, ParseParams, SourceMapOption, TranspileOptions};
+use once_cell::sync::Lazy;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TranspileError {
+    #[error("Syntax error: {0}")]
+    Syntax(String),
+    #[error("Failed to transpile: {0}")]
+    Emit(String),
+}
+
+/// The JavaScript transpiled from the source of a Deno cell, with the source map relating its
+/// locations to those of the cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranspiledCell {
+    pub code: String,
+    pub source_map: String,
+}
+
+impl TranspiledCell {
+    /// The code followed by `appended`, with the source map inlined as the final line so that the
+    /// runtime reports the locations in stack traces as lines of the cell.
+    pub fn with_inline_source_map(&self, appended: &str) -> String {
+        format!(
+            "{}\n{}\n//# sourceMappingURL=data:application/json;base64,{}\n",
+            self.code.trim_end(),
+            appended,
+            STANDARD.encode(&self.source_map)
+        )
+    }
+}
+
+/// Cells transpiled so far, keyed by the hash of their source, so that a cell is transpiled once
+/// however often it is analyzed and executed.
+static TRANSPILED: Lazy<DashMap<[u8; 20], Arc<TranspiledCell>>> = Lazy::new(DashMap::new);
+
+/// Transpile the source of a Deno cell from TypeScript to JavaScript. Type annotations, interfaces and
+/// other type-only constructs are stripped without being checked, and JavaScript passes through unchanged
+/// in meaning, so every Deno cell is transpiled before it is analyzed or executed.
+pub fn transpile_typescript(source_code: &str) -> Result<Arc<TranspiledCell>, TranspileError> {
+    let transpiled = TRANSPILED
+        .entry(Sha1::digest(source_code.as_bytes()).into())
+        .or_try_insert_with(|| transpile(source_code).map(Arc::new))?;
+    Ok(transpiled.clone())
+}
+
+fn transpile(source_code: &str) -> Result<TranspiledCell, TranspileError> {
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier: ModuleSpecifier::parse("file:///cell.ts").unwrap(),
+        text: source_code.into(),
+        media_type: MediaType::TypeScript,
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+    }).map_err(|e| TranspileError::Syntax(e.to_string()))?;
+    let emitted = parsed
+        .transpile(&TranspileOptions::default(), &EmitOptions {
+            source_map: SourceMapOption::Separate,
+            ..Default::default()
+        })
+        .map_err(|e| TranspileError::Emit(e.to_string()))?
+        .into_source()
+        .into_string()
+        .map_err(|e| TranspileError::Emit(e.to_string()))?;
+    Ok(TranspiledCell {
+        code: emitted.text,
+        source_map: emitted.source_map.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chidori_static_analysis::language::javascript::parse::{build_report, extract_dependencies_js};
+    use indoc::indoc;
+
+    #[test]
+    fn test_transpile_strips_types() {
+        let source = indoc! { r#"
+            interface Greeting {
+                name: string;
+            }
+            type Loud = string;
+            function greet(greeting: Greeting, excited?: boolean): Loud {
+                return `Hello ${greeting.name}` + (excited ? "!" : "");
+            }
+            const result: string = greet({ name: prefix } as Greeting, true);
+        "# };
+        let transpiled = transpile_typescript(source).unwrap();
+        assert!(!transpiled.code.contains("interface"), "{}", transpiled.code);
+        assert!(!transpiled.code.contains(": string"), "{}", transpiled.code);
+        assert!(!transpiled.source_map.is_empty());
+
+        let report = build_report(&extract_dependencies_js(&transpiled.code).unwrap());
+        assert!(report.cell_exposed_values.contains_key("result"));
+        assert!(report.cell_depended_values.contains_key("prefix"));
+        assert!(report.triggerable_functions.contains_key("greet"));
+        assert!(!report.cell_exposed_values.contains_key("Greeting"));
+    }
+
+    #[test]
+    fn test_cells_are_transpiled_once() {
+        let source = "const total: number = 1 + 2;";
+        assert!(Arc::ptr_eq(&transpile_typescript(source).unwrap(), &transpile_typescript(source).unwrap()));
+
+        let inlined = transpile_typescript(source).unwrap().with_inline_source_map("Chidori.saveOutput({});");
+        assert!(inlined.starts_with("const total = 1 + 2;"), "{}", inlined);
+        assert!(inlined.trim_end().lines().last().unwrap().starts_with("//# sourceMappingURL=data:application/json;base64,"));
+    }
+
+    #[test]
+    fn test_transpile_reports_syntax_errors() {
+        let err = transpile_typescript("const x: number = ;").unwrap_err();
+        assert!(matches!(err, TranspileError::Syntax(_)), "{:?}", err);
+    }
+}
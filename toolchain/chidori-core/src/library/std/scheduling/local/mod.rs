@@ -61,7 +61,7 @@ pub async fn run_cron(
                                     crate::cells::code_cell::code_cell(Uuid::nil(), &c, r)
                                 }
                                 CellTypes::Prompt(c, r) => {
-                                    crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, r)
+                                    crate::cells::llm_prompt_cell::llm_prompt_cell(Uuid::nil(), &c, r, None)
                                 }
                                 _ => {
                                     unreachable!("Unsupported cell type");
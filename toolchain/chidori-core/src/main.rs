@@ -18,6 +18,7 @@ use tracing::info;
 pub use uuid;
 use chidori_core::sdk::interactive_chidori_wrapper::InteractiveChidoriWrapper;
 use chidori_core::sdk::chidori_runtime_instance::PlaybackState;
+use chidori_core::sdk::config::ChidoriConfig;
 pub use chidori_static_analysis;
 pub use chidori_prompt_format;
 
@@ -35,6 +36,9 @@ enum Commands {
         /// Path to the configuration file
         #[arg(short, long)]
         load: PathBuf,
+        /// Profile of chidori.toml to apply, defaults to CHIDORI_PROFILE
+        #[arg(short, long)]
+        profile: Option<String>,
     },
     // /// Run tests
     // Test {
@@ -56,9 +60,12 @@ enum Commands {
     // },
 }
 
-async fn run_command(run_directory: &PathBuf) -> anyhow::Result<()> {
+async fn run_command(run_directory: &PathBuf, profile: Option<String>) -> anyhow::Result<()> {
     let runtime = tokio::runtime::Handle::current();
 
+    // Surface a misconfigured project, such as an unknown profile, before the instance starts
+    ChidoriConfig::load_from_directory_with_profile(run_directory, profile.as_deref())?;
+
     let (trace_event_sender, trace_event_receiver) = mpsc::channel();
     let (runtime_event_sender, runtime_event_receiver) = mpsc::channel();
 
@@ -66,6 +73,7 @@ async fn run_command(run_directory: &PathBuf) -> anyhow::Result<()> {
         trace_event_sender,
         runtime_event_sender,
     );
+    chidori.set_profile(profile);
 
     let run_directory_clone = run_directory.clone();
     runtime.spawn(async move {
@@ -100,9 +108,9 @@ async fn main() -> anyhow::Result<()>{
     let cli = Cli::parse();
 
     match &cli.command {
-        Some(Commands::Run { load }) => {
+        Some(Commands::Run { load, profile }) => {
             info!("Running Chidori with target src directory: {:?}", load);
-            run_command(load).await
+            run_command(load, profile.clone()).await
         }
         // Some(Commands::Test { test_dir, verbose }) => {
         //     println!("Running tests in directory: {:?}", test_dir);
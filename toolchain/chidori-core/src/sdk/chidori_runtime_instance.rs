@@ -8,10 +8,11 @@ use uuid::Uuid;
 use std::time::Duration;
 use anyhow::anyhow;
 use dashmap::mapref::one::Ref;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 use crate::cells::CellTypes;
 use crate::execution::execution::execution_graph::{ExecutionGraph, ExecutionNodeId};
-use crate::execution::execution::execution_state::{EnclosedState};
+use crate::execution::execution::execution_state::{EnclosedState, ExecutionStateErrors, RunOutcome, RunResult};
 use crate::execution::execution::ExecutionState;
 use crate::execution::primitives::identifiers::OperationId;
 use crate::execution::primitives::operation::OperationFnOutput;
@@ -115,6 +116,39 @@ impl ChidoriRuntimeInstance {
     /// Entrypoint for execution of an instanced environment, handles messages from the host
     // #[tracing::instrument]
     pub async fn run(&mut self, initial_playback_state: PlaybackState) -> anyhow::Result<()> {
+        self.run_with_cancellation(initial_playback_state, CancellationToken::new()).await.map(|_| ())
+    }
+
+    /// Entrypoint for execution within a wall-clock budget for the whole run, which returns once
+    /// no cell remains to execute or `deadline` elapses, whichever is first. Cells in progress when
+    /// the deadline elapses are cancelled. The outputs of the cells that completed remain at the
+    /// execution head, and the outcome is flagged as timed out when the deadline elapsed.
+    pub async fn run_with_deadline(&mut self, initial_playback_state: PlaybackState, deadline: Duration) -> anyhow::Result<RunOutcome> {
+        let deadline_cancellation = CancellationToken::new();
+        let timer = {
+            let deadline_cancellation = deadline_cancellation.clone();
+            self.get_state_at_current_execution_head_result()?.spawn(async move {
+                tokio::time::sleep(deadline).await;
+                deadline_cancellation.cancel();
+            })
+        };
+        let outcome = self.run_until(initial_playback_state, deadline_cancellation.clone(), true).await;
+        timer.abort();
+        let mut outcome = outcome?;
+        outcome.timed_out = deadline_cancellation.is_cancelled();
+        Ok(outcome)
+    }
+
+    /// Entrypoint for execution that ends once `cancellation` is cancelled. No further cells are
+    /// scheduled after cancellation and any cell in progress is abandoned, the returned outcome
+    /// describes which cells completed, were cancelled, or never started.
+    pub async fn run_with_cancellation(&mut self, initial_playback_state: PlaybackState, cancellation: CancellationToken) -> anyhow::Result<RunOutcome> {
+        self.run_until(initial_playback_state, cancellation, false).await
+    }
+
+    /// Execute until `cancellation` is cancelled or, when `until_settled`, until no cell remains
+    /// to execute.
+    async fn run_until(&mut self, initial_playback_state: PlaybackState, cancellation: CancellationToken, until_settled: bool) -> anyhow::Result<RunOutcome> {
         println!("Starting instanced environment");
         self.set_playback_state(initial_playback_state);
 
@@ -124,8 +158,22 @@ impl ChidoriRuntimeInstance {
         let executing_states = Arc::new(Mutex::new(HashSet::new()));
         // Create a channel for error notifications
         let (error_tx, mut error_rx) = tokio::sync::mpsc::channel(32);
+        let mut cancelled_operations = vec![];
 
         loop {
+            if cancellation.is_cancelled() {
+                // Wait for the steps in progress to observe the cancellation before reporting
+                while !executing_states.lock().unwrap().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                while let Ok(error) = error_rx.try_recv() {
+                    if let Some(ExecutionStateErrors::Cancelled(Some(operation_id))) = error.downcast_ref::<ExecutionStateErrors>() {
+                        cancelled_operations.push(*operation_id);
+                    }
+                }
+                return self.finish_run(&cancelled_operations);
+            }
+
             // Handle user interactions first for responsiveness
             if let Ok(message) = self.env_rx.try_recv() {
                 println!("Received message from user: {:?}", message);
@@ -135,6 +183,15 @@ impl ChidoriRuntimeInstance {
             // Check for execution errors
             if let Ok(error) = error_rx.try_recv() {
                 // println!("Received execution error: {:?}", error);
+                match error.downcast_ref::<ExecutionStateErrors>() {
+                    Some(ExecutionStateErrors::Cancelled(Some(operation_id))) => cancelled_operations.push(*operation_id),
+                    Some(ExecutionStateErrors::Cancelled(None)) => {}
+                    // The graph has settled, with every state it produced already received
+                    Some(ExecutionStateErrors::NoFurtherExecutionDetected) if until_settled => {
+                        return self.finish_run(&cancelled_operations);
+                    }
+                    _ => {}
+                }
                 self.set_playback_state(PlaybackState::Paused);
                 // TODO: notify the client about the error
                 // self.push_update_to_client(&ExecutionState::Error(error));
@@ -166,17 +223,14 @@ impl ChidoriRuntimeInstance {
                     // Spawn the progression of the given step in a separate task
                     let executing_states = Arc::clone(&executing_states);
                     let error_tx = error_tx.clone();
-                    let state = self.get_state_at_current_execution_head_result()?.clone();
+                    let state = self.get_state_at_current_execution_head_result()?
+                        .clone()
+                        .with_cancellation(cancellation.clone());
+                    // Steps run on the runtime provided to the engine, otherwise on the caller's
+                    let runtime_handle = state.runtime_handle.clone().unwrap_or_else(tokio::runtime::Handle::current);
 
                     std::thread::spawn(move || {
-                        // Create a new tokio runtime for this thread
-                        let runtime = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
-
-                        // Enter the runtime context
-                        let _guard = runtime.enter();
-
-                        // Execute the async block on this runtime
-                        runtime.block_on(async {
+                        let step = async {
                             let result = state.step_execution().await;
                             // Clear execution
                             let mut executing_states_lock = executing_states.lock().unwrap();
@@ -192,13 +246,27 @@ impl ChidoriRuntimeInstance {
                                 }
                                 Ok(_) => {},
                             }
-                        });
+                        };
+
+                        runtime_handle.block_on(step)
                     });
                 }
             }
         }
     }
 
+    /// Apply the states produced by the run that are yet to be received and pause, describing the
+    /// run from the resulting execution head.
+    fn finish_run(&mut self, cancelled_operations: &[OperationId]) -> anyhow::Result<RunOutcome> {
+        while let Ok(state) = self.rx_execution_states.try_recv() {
+            self.push_update_to_client(&state);
+            self.set_execution_head(&state);
+        }
+        self.set_playback_state(PlaybackState::Paused);
+        let state = self.get_state_at_current_execution_head_result()?;
+        Ok(state.run_outcome(cancelled_operations))
+    }
+
     fn set_playback_state(&mut self, playback_state: PlaybackState) {
         self.playback_state = playback_state.clone();
         if let Some(sender ) = self.runtime_event_sender.as_mut() {
@@ -264,7 +332,7 @@ impl ChidoriRuntimeInstance {
                 // self.db.execute_operation_in_isolation(&cell.cell, args).await?;
             }
             UserInteractionMessage::Reset => {
-                self.db = ExecutionGraph::new();
+                self.db = ExecutionGraph::new_with_runtime_handle(self.db.runtime_handle());
                 self.set_playback_state(PlaybackState::Paused);
                 let id = Uuid::nil();
                 self.execution_head_state_id = id;
@@ -285,6 +353,12 @@ impl ChidoriRuntimeInstance {
         Ok(state)
     }
 
+    /// The outputs and failures of the cells as of the execution head, including those of cells
+    /// that failed and the cells that were skipped because an input of theirs failed.
+    pub fn run_result(&self) -> anyhow::Result<RunResult> {
+        Ok(self.get_state_at_current_execution_head_result()?.run_result())
+    }
+
     #[cfg(test)]
     pub fn get_state_at_current_execution_head(&self) -> ExecutionState {
         self.db.get_state_at_id(self.execution_head_state_id).unwrap()
@@ -325,6 +399,19 @@ impl ChidoriRuntimeInstance {
         }
     }
 
+    /// Entrypoint for execution seeded with values for the external inputs of the graph, see
+    /// `ExecutionState::unresolved_external_inputs` for the inputs a graph expects.
+    pub async fn run_with_initial_globals(&mut self, initial_playback_state: PlaybackState, initial_globals: RkyvSerializedValue) -> anyhow::Result<()> {
+        self.reload_cells().await?;
+        let seeded_state = {
+            let state = self.get_state_at_current_execution_head_result()?.clone();
+            state.seed_initial_globals(initial_globals).await?
+        };
+        self.push_update_to_client(&seeded_state);
+        self.set_execution_head(&seeded_state);
+        self.run(initial_playback_state).await
+    }
+
     /// Increment the execution graph by one step
     #[tracing::instrument]
     pub async fn step(&mut self) -> anyhow::Result<Vec<(OperationId, OperationFnOutput)>> {
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cells::{RequestHeaders, SupportedModelProviders};
+use crate::sdk::secrets::{SecretError, SecretResolver};
+
+/// Name of the project configuration file, looked up in the root of a loaded directory.
+pub const CONFIG_FILE_NAME: &str = "chidori.toml";
+
+pub const OPENAI_PROVIDER: &str = "openai";
+
+/// Environment variable naming the profile applied when a project's configuration is loaded.
+pub const PROFILE_ENV_VAR: &str = "CHIDORI_PROFILE";
+
+/// Project level configuration, deserialized from `chidori.toml`.
+///
+/// ```toml
+/// default_provider = "openai"
+/// templates_dir = "templates"
+/// secret_globals = ["api_token"]
+/// llm_timeout_ms = 120000
+///
+/// [providers.openai]
+/// api_key = "sk-..."
+/// api_url = "https://api.openai.com/v1"
+/// default_model = "gpt-4o"
+/// requests_per_minute = 500
+/// organization = "org-..."
+///
+/// [providers.openai.headers]
+/// x-api-version = "2024-06-01"
+///
+/// [profiles.staging.providers.openai]
+/// api_url = "https://staging.example.com/v1"
+/// default_model = "gpt-4o-mini"
+///
+/// [python]
+/// warm_start = true
+/// preload_modules = ["numpy", "pandas"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChidoriConfig {
+    /// Provider used by prompt and code generation cells that do not declare one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_provider: Option<SupportedModelProviders>,
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderConfiguration>,
+    /// Directory of `.hbs` and `.md` files registered as partials for every prompt, relative to
+    /// the project directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub templates_dir: Option<PathBuf>,
+    /// Named sets of provider settings, one of which may be selected when the configuration is
+    /// loaded to redirect the requests of every cell that does not set them itself.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, ProfileConfiguration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub python: Option<PythonConfiguration>,
+    /// Globals whose values are redacted from logs, traces and the state reported to observers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret_globals: Vec<String>,
+    /// Milliseconds after which a cell calling a model fails, for cells that do not set a hard
+    /// timeout of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub llm_timeout_ms: Option<u64>,
+}
+
+/// Settings of the Python interpreter shared by the code cells of every instance.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PythonConfiguration {
+    /// Initialize the interpreter when the configuration is applied to a run or execution state
+    /// rather than when the first Python cell runs.
+    #[serde(default)]
+    pub warm_start: bool,
+    /// Modules imported during warm start, typically large libraries the project's cells import.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preload_modules: Vec<String>,
+}
+
+/// Provider settings taking precedence over those at the top level of the configuration while the
+/// profile is selected, settings the profile leaves unset are retained.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfiguration {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_provider: Option<SupportedModelProviders>,
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderConfiguration>,
+}
+
+/// Connection details for a model provider. Values set in a cell's frontmatter take precedence
+/// over these, and these take precedence over the secret resolver of the run.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfiguration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_model: Option<String>,
+    /// Requests sent to the provider are spaced so that no more than this many are sent a minute.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    /// Organization billed for requests, sent as the `OpenAI-Organization` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    /// Headers attached to every request, headers declared by a cell take precedence.
+    #[serde(default, skip_serializing_if = "RequestHeaders::is_empty")]
+    pub headers: RequestHeaders,
+    /// Adjustments made to the messages of each request, for providers that restrict their order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_ordering: Option<MessageOrdering>,
+    /// Whether the provider streams the content accumulated so far with every chunk rather than
+    /// the content added since the previous one. Streams are reduced to deltas either way.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cumulative_stream_content: Option<bool>,
+}
+
+/// Adjustments made to the messages of a request to satisfy the ordering a provider requires.
+///
+/// ```toml
+/// [providers.openai.message_ordering]
+/// alternate_roles = true
+/// single_system_message = true
+/// drop_message_names = true
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageOrdering {
+    /// Merge consecutive user messages, and consecutive assistant messages, for providers that
+    /// require the two roles to alternate.
+    #[serde(default)]
+    pub alternate_roles: bool,
+    /// Merge every system message into one sent ahead of the others, for providers that accept a
+    /// single system message at the start of the conversation.
+    #[serde(default)]
+    pub single_system_message: bool,
+    /// Drop the participant names of user, system and assistant messages, for providers that do
+    /// not accept a name per message.
+    #[serde(default)]
+    pub drop_message_names: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid configuration in {path}: {message}")]
+    Parse {
+        path: String,
+        message: String,
+    },
+    #[error("Unknown profile {name:?}, the configured profiles are {available:?}")]
+    UnknownProfile {
+        name: String,
+        available: Vec<String>,
+    },
+}
+
+impl ChidoriConfig {
+    pub fn from_toml_str(s: &str, path: &str) -> Result<Self, ConfigError> {
+        toml::from_str(s).map_err(|e| ConfigError::Parse {
+            path: path.to_string(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Load the configuration at the given path, a missing file yields the default configuration.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let path_string = path.to_string_lossy().to_string();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::from_toml_str(&contents, &path_string),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(ConfigError::Io { path: path_string, source: e }),
+        }
+    }
+
+    /// Load the `chidori.toml` in the root of a project directory, applying the profile named by
+    /// `CHIDORI_PROFILE` when it is set.
+    pub fn load_from_directory(directory: &Path) -> Result<Self, ConfigError> {
+        Self::load_from_directory_with_profile(directory, None)
+    }
+
+    /// Load the `chidori.toml` in the root of a project directory, applying the given profile or
+    /// otherwise the profile named by `CHIDORI_PROFILE`.
+    pub fn load_from_directory_with_profile(directory: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let mut config = Self::load(&directory.join(CONFIG_FILE_NAME))?;
+        if let Some(templates_dir) = config.templates_dir.as_mut().filter(|dir| dir.is_relative()) {
+            *templates_dir = directory.join(&templates_dir);
+        }
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| std::env::var(PROFILE_ENV_VAR).ok().filter(|profile| !profile.is_empty()));
+        match profile {
+            Some(profile) => config.with_profile(&profile),
+            None => Ok(config),
+        }
+    }
+
+    /// This configuration with the settings of the named profile taking precedence.
+    pub fn with_profile(mut self, name: &str) -> Result<Self, ConfigError> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            let mut available: Vec<String> = self.profiles.keys().cloned().collect();
+            available.sort();
+            return Err(ConfigError::UnknownProfile { name: name.to_string(), available });
+        };
+        if profile.default_provider.is_some() {
+            self.default_provider = profile.default_provider;
+        }
+        for (provider_name, overrides) in profile.providers {
+            let provider = self.providers.entry(provider_name).or_default();
+            *provider = provider.overridden_by(&overrides);
+        }
+        Ok(self)
+    }
+
+    /// The configuration of a provider with an api key missing from the file obtained from the
+    /// secret resolver. A secret the resolver does not hold leaves the key unset.
+    pub async fn provider(&self, name: &str, secrets: &dyn SecretResolver) -> Result<ProviderConfiguration, SecretError> {
+        let mut provider = self.providers.get(name).cloned().unwrap_or_default();
+        if provider.api_key.is_none() {
+            if let Some(key) = ProviderConfiguration::api_key_secret(name) {
+                provider.api_key = match secrets.resolve(key).await {
+                    Ok(api_key) => Some(api_key),
+                    Err(SecretError::NotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+        Ok(provider)
+    }
+}
+
+impl ProviderConfiguration {
+    /// This configuration with the settings `overrides` sets taking precedence.
+    fn overridden_by(&self, overrides: &ProviderConfiguration) -> ProviderConfiguration {
+        ProviderConfiguration {
+            api_key: overrides.api_key.clone().or_else(|| self.api_key.clone()),
+            api_url: overrides.api_url.clone().or_else(|| self.api_url.clone()),
+            default_model: overrides.default_model.clone().or_else(|| self.default_model.clone()),
+            requests_per_minute: overrides.requests_per_minute.or(self.requests_per_minute),
+            organization: overrides.organization.clone().or_else(|| self.organization.clone()),
+            headers: self.headers.merged_with(Some(&overrides.headers)),
+            message_ordering: overrides.message_ordering.or(self.message_ordering),
+            cumulative_stream_content: overrides.cumulative_stream_content.or(self.cumulative_stream_content),
+        }
+    }
+
+    /// Name of the secret holding the api key of a provider.
+    fn api_key_secret(name: &str) -> Option<&'static str> {
+        match name {
+            OPENAI_PROVIDER => Some("OPENAI_API_KEY"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for ProviderConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderConfiguration")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("api_url", &self.api_url)
+            .field("default_model", &self.default_model)
+            .field("requests_per_minute", &self.requests_per_minute)
+            .field("organization", &self.organization)
+            .field("headers", &self.headers)
+            .field("message_ordering", &self.message_ordering)
+            .field("cumulative_stream_content", &self.cumulative_stream_content)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdk::secrets::{EnvSecretResolver, InMemorySecretResolver};
+    use indoc::indoc;
+
+    #[tokio::test]
+    async fn test_parse_providers() {
+        let config = ChidoriConfig::from_toml_str(indoc! {r#"
+            [providers.openai]
+            api_key = "sk-test"
+            api_url = "https://api.openai.com/v1"
+            default_model = "gpt-4o"
+            requests_per_minute = 500
+            organization = "org-123"
+            "#}, "chidori.toml").unwrap();
+        let provider = config.provider(OPENAI_PROVIDER, &EnvSecretResolver).await.unwrap();
+        assert_eq!(provider.api_key.as_deref(), Some("sk-test"));
+        assert_eq!(provider.api_url.as_deref(), Some("https://api.openai.com/v1"));
+        assert_eq!(provider.default_model.as_deref(), Some("gpt-4o"));
+        assert_eq!(provider.requests_per_minute, Some(500));
+        assert_eq!(provider.organization.as_deref(), Some("org-123"));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_is_obtained_from_the_secret_resolver() {
+        let secrets = InMemorySecretResolver::new(HashMap::from([("OPENAI_API_KEY".to_string(), "sk-vault".to_string())]));
+        let provider = ChidoriConfig::default().provider(OPENAI_PROVIDER, &secrets).await.unwrap();
+        assert_eq!(provider.api_key.as_deref(), Some("sk-vault"));
+
+        let provider = ChidoriConfig::default().provider(OPENAI_PROVIDER, &InMemorySecretResolver::default()).await.unwrap();
+        assert_eq!(provider.api_key, None);
+    }
+
+    #[test]
+    fn test_api_key_is_redacted_from_debug_output() {
+        let provider = ProviderConfiguration { api_key: Some("sk-secret".to_string()), ..Default::default() };
+        let debugged = format!("{:?}", provider);
+        assert!(!debugged.contains("sk-secret"), "{}", debugged);
+        assert!(debugged.contains("<redacted>"), "{}", debugged);
+    }
+
+    #[test]
+    fn test_parse_provider_headers() {
+        let config = ChidoriConfig::from_toml_str(indoc! {r#"
+            [providers.openai.headers]
+            x-api-version = "2024-06-01"
+            x-gateway-token = "gw-secret"
+            "#}, "chidori.toml").unwrap();
+        let headers = &config.providers[OPENAI_PROVIDER].headers;
+        assert_eq!(headers.0.get("x-api-version").map(String::as_str), Some("2024-06-01"));
+        let debugged = format!("{:?}", config);
+        assert!(debugged.contains("2024-06-01"), "{}", debugged);
+        assert!(!debugged.contains("gw-secret"), "{}", debugged);
+    }
+
+    #[test]
+    fn test_parse_default_provider() {
+        let config = ChidoriConfig::from_toml_str("default_provider = \"openai\"", "chidori.toml").unwrap();
+        assert_eq!(config.default_provider, Some(SupportedModelProviders::OpenAI));
+    }
+
+    #[test]
+    fn test_parse_python_warm_start() {
+        let config = ChidoriConfig::from_toml_str(indoc! {r#"
+            [python]
+            warm_start = true
+            preload_modules = ["json", "decimal"]
+        "#}, "chidori.toml").unwrap();
+        assert_eq!(config.python, Some(PythonConfiguration {
+            warm_start: true,
+            preload_modules: vec!["json".to_string(), "decimal".to_string()],
+        }));
+        assert_eq!(ChidoriConfig::default().python, None);
+    }
+
+    #[test]
+    fn test_secret_globals_are_redacted_from_the_state() {
+        let config = ChidoriConfig::from_toml_str(r#"secret_globals = ["api_token"]"#, "chidori.toml").unwrap();
+        let mut state = crate::execution::execution::ExecutionState::new_with_random_id();
+        state.configuration = std::sync::Arc::new(config);
+        assert!(state.is_secret_global("api_token"));
+        assert!(!state.is_secret_global("api_url"));
+    }
+
+    #[test]
+    fn test_profile_overrides_providers() {
+        let config = ChidoriConfig::from_toml_str(indoc! {r#"
+            [providers.openai]
+            api_key = "sk-test"
+            api_url = "https://api.openai.com/v1"
+            default_model = "gpt-4o"
+
+            [profiles.staging.providers.openai]
+            api_url = "https://staging.example.com/v1"
+            default_model = "gpt-4o-mini"
+
+            [profiles.prod]
+            "#}, "chidori.toml").unwrap();
+
+        let staging = config.clone().with_profile("staging").unwrap();
+        let provider = &staging.providers[OPENAI_PROVIDER];
+        assert_eq!(provider.api_url.as_deref(), Some("https://staging.example.com/v1"));
+        assert_eq!(provider.default_model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(provider.api_key.as_deref(), Some("sk-test"));
+
+        let prod = config.clone().with_profile("prod").unwrap();
+        assert_eq!(prod.providers, config.providers);
+
+        let err = config.with_profile("qa").unwrap_err();
+        assert!(matches!(&err, ConfigError::UnknownProfile { name, available } if name == "qa" && available == &["prod", "staging"]), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_file_is_default() {
+        let config = ChidoriConfig::load(Path::new("/nonexistent/chidori.toml")).unwrap();
+        assert_eq!(config, ChidoriConfig::default());
+    }
+
+    #[test]
+    fn test_malformed_file_names_offending_key() {
+        let err = ChidoriConfig::from_toml_str(indoc! {r#"
+            [providers.openai]
+            api_kee = "sk-test"
+            "#}, "chidori.toml").unwrap_err();
+        assert!(err.to_string().contains("api_kee"), "{}", err);
+
+        let err = ChidoriConfig::from_toml_str(indoc! {r#"
+            [providers.openai]
+            requests_per_minute = "fast"
+            "#}, "chidori.toml").unwrap_err();
+        assert!(err.to_string().contains("requests_per_minute"), "{}", err);
+    }
+}
@@ -18,6 +18,7 @@ use crate::execution::execution::execution_graph::{ExecutionGraph, ExecutionNode
 use crate::execution::execution::ExecutionState;
 use crate::execution::primitives::identifiers::{DependencyReference, OperationId};
 use crate::sdk::chidori_runtime_instance::{ChidoriRuntimeInstance, PlaybackState, UserInteractionMessage};
+use crate::sdk::config::ChidoriConfig;
 use crate::sdk::md::{interpret_markdown_code_block, load_folder};
 use crate::utils::telemetry::{init_internal_telemetry, TraceEvents};
 
@@ -39,6 +40,15 @@ pub struct InteractiveChidoriWrapper {
     pub shared_state: Arc<Mutex<SharedState>>,
     pub loaded_path: Option<String>,
 
+    /// Project configuration loaded from the chidori.toml of the loaded directory
+    pub configuration: Arc<ChidoriConfig>,
+
+    /// Profile of the configuration applied when a directory is loaded, falls back to `CHIDORI_PROFILE`
+    pub profile: Option<String>,
+
+    /// Runtime of the embedding application, instances run their work on it rather than creating their own
+    pub runtime_handle: Option<tokio::runtime::Handle>,
+
     pub tracing_guard: Option<DefaultGuard>
 }
 
@@ -67,6 +77,9 @@ impl InteractiveChidoriWrapper {
             trace_event_sender: None,
             loaded_path: None,
             shared_state: initialize_shared_state_object(),
+            configuration: Default::default(),
+            profile: None,
+            runtime_handle: None,
             tracing_guard: None,
         }
     }
@@ -81,6 +94,9 @@ impl InteractiveChidoriWrapper {
             trace_event_sender: Some(sender),
             loaded_path: None,
             shared_state: initialize_shared_state_object(),
+            configuration: Default::default(),
+            profile: None,
+            runtime_handle: None,
             tracing_guard: Some(guard)
         }
     }
@@ -93,21 +109,16 @@ impl InteractiveChidoriWrapper {
         Ok(())
     }
 
-    fn load_cells(&mut self, cells: Vec<CellTypes>) -> anyhow::Result<()>  {
+    fn load_cells(&mut self, mut cells: Vec<CellTypes>) -> anyhow::Result<()>  {
         // TODO: this overrides the entire shared state object
-        let cell_name_map = {
-            let previous_cells = &self.shared_state.lock().unwrap().editor_cells;
-            previous_cells.values().map(|cell| {
-                let name = cell.cell.name();
-                (name.clone(), cell.clone())
-            }).collect::<HashMap<_, _>>()
-        };
+        let previous_cells = self.shared_state.lock().unwrap().editor_cells.clone();
 
+        cells.sort();
+        let ids = CellTypes::stable_ids(&cells);
         let mut new_cells_state = HashMap::new();
-        for cell in cells {
-            let name = cell.name();
-            // If the named cell exists in our map already
-            if let Some(existing_cell_instance) = cell_name_map.get(&name) {
+        for (cell, id) in cells.into_iter().zip(ids) {
+            // If the cell exists in our map already
+            if let Some(existing_cell_instance) = previous_cells.get(&id) {
                 // If it's not the same cell, replace it
                 if existing_cell_instance.cell != cell {
                     new_cells_state.insert(existing_cell_instance.op_id, CellHolder {
@@ -122,7 +133,6 @@ impl InteractiveChidoriWrapper {
                 }
             } else {
                 // This is a new cell, so we push it with a null applied at
-                let id = Uuid::now_v7();
                 new_cells_state.insert(id, CellHolder {
                     cell,
                     applied_at: None,
@@ -148,7 +158,18 @@ impl InteractiveChidoriWrapper {
         self.load_cells(cells)
     }
 
+    /// Select the profile of the configuration applied by subsequent loads of a directory.
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile;
+    }
+
+    /// Run the work of subsequently created instances on the given runtime.
+    pub fn set_runtime_handle(&mut self, runtime_handle: tokio::runtime::Handle) {
+        self.runtime_handle = Some(runtime_handle);
+    }
+
     pub fn load_md_directory(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.configuration = Arc::new(ChidoriConfig::load_from_directory_with_profile(path, self.profile.as_deref())?);
         let files = load_folder(path)?;
         let mut cells = vec![];
         for file in files {
@@ -167,7 +188,8 @@ impl InteractiveChidoriWrapper {
     pub fn get_instance(&mut self) -> anyhow::Result<ChidoriRuntimeInstance> {
         let (instanced_env_tx, env_rx) = mpsc::channel();
         self.instanced_env_tx = Some(instanced_env_tx);
-        let mut db = ExecutionGraph::new();
+        let mut db = ExecutionGraph::new_with_runtime_handle(self.runtime_handle.clone());
+        db.set_configuration(self.configuration.clone())?;
         let execution_event_rx = db.take_execution_event_receiver();
         let state_id = Uuid::nil();
         let playback_state = PlaybackState::Paused;
@@ -218,7 +240,8 @@ impl Serialize for SharedState {
         let mut state = serializer.serialize_map(None)?;
         if let Some(map) = &self.latest_state {
             for (k, v) in &map.state {
-                state.serialize_entry(&k, &v.deref().output)?; // Dereference `Arc` to serialize the value inside
+                let output = v.deref().output.as_ref().map(|value| map.redact(value)); // Dereference `Arc` to serialize the value inside
+                state.serialize_entry(&k, &output)?;
             }
         }
         state.end()
@@ -251,4 +274,54 @@ pub struct CellHolder {
     pub op_id: OperationId,
     pub applied_at: Option<ExecutionNodeId>,
     pub needs_update: bool
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_loading_the_same_cells_assigns_identical_ids() {
+        let source = indoc! {r#"
+            ```python (add)
+            def add(x, y):
+                return x + y
+            ```
+
+            ```python
+            y = 1
+            ```
+
+            ```python
+            y = 1
+            ```
+            "#};
+        let cell_ids = || {
+            let mut wrapper = InteractiveChidoriWrapper::new();
+            wrapper.load_md_string(source).unwrap();
+            let mut ids = wrapper.shared_state.lock().unwrap().editor_cells.keys().cloned().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let ids = cell_ids();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids, cell_ids());
+    }
+
+    #[test]
+    fn test_reloading_an_edited_unnamed_cell_keeps_its_id() {
+        let mut wrapper = InteractiveChidoriWrapper::new();
+        wrapper.load_md_string("```python\nx = 1\n```\n\n```python\ny = 1\n```\n").unwrap();
+        let ids = |wrapper: &InteractiveChidoriWrapper| {
+            let mut ids = wrapper.shared_state.lock().unwrap().editor_cells.keys().cloned().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let before = ids(&wrapper);
+
+        wrapper.load_md_string("```python\nx = 2\n```\n\n```python\ny = 1\n```\n").unwrap();
+        assert_eq!(ids(&wrapper), before);
+        let editor_cells = wrapper.shared_state.lock().unwrap().editor_cells.clone();
+        assert!(editor_cells.values().any(|cell| cell.cell.source() == "x = 2"));
+    }
+}
@@ -5,7 +5,7 @@ use std::collections::HashMap;
 use std::path::Path;
 use serde_derive::Serialize;
 use thiserror::Error;
-use crate::cells::{BackingFileReference, CellTypes, CodeCell, LLMCodeGenCell, LLMEmbeddingCell, LLMPromptCell, MemoryCell, SupportedLanguage, SupportedMemoryProviders, SupportedModelProviders, TemplateCell, TextRange, WebserviceCell};
+use crate::cells::{BackingFileReference, CellTypes, CodeCell, CodeCellConfiguration, LLMCodeGenCell, LLMCodeGenCellChatConfiguration, LLMEmbeddingCell, LLMEmbeddingCellConfiguration, LLMPromptCell, LLMPromptCellChatConfiguration, MemoryCell, SupportedLanguage, SupportedMemoryProviders, TemplateCell, TextRange, TransformCell, TransformCellConfiguration, WebserviceCell};
 
 #[derive(PartialEq, Serialize, Debug)]
 pub struct MarkdownCodeBlock {
@@ -93,8 +93,10 @@ fn parse_markdown_file(filename: &Path) -> ParsedFile {
 
 pub fn load_folder(path: &Path) -> anyhow::Result<Vec<ParsedFile>> {
     let mut res = vec![];
-    for entry in path.read_dir()? {
-        let entry = entry?;
+    // Directory iteration order is platform dependent, sort for a deterministic cell order
+    let mut entries = path.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
         let metadata = entry.metadata()?;
 
         let path = entry.path();
@@ -105,7 +107,7 @@ pub fn load_folder(path: &Path) -> anyhow::Result<Vec<ParsedFile>> {
         if metadata.is_file() {
             if let Some(extension)  = path.extension().and_then(|s| s.to_str()) {
                 match extension {
-                    "md" | "py" | "js" | "ts" => {
+                    "md" | "py" | "js" | "ts" | "lua" => {
                         let parsed_file = parse_markdown_file(&path);
                         res.push(parsed_file);
                     },
@@ -137,43 +139,95 @@ pub fn interpret_markdown_code_block(block: &MarkdownCodeBlock, file_path: Optio
         text_range: Some(block.range.clone())
     });
     Ok(match block.tag.as_str() {
-        "python" | "javascript" | "py" | "js" | "ts" | "typescript" => {
+        "python" | "javascript" | "py" | "js" | "ts" | "typescript" | "lua" => {
             let language = match block.tag.as_str() {
                 "python" | "py" => SupportedLanguage::PyO3,
                 "javascript" | "js" | "typescript" | "ts" => SupportedLanguage::Deno,
+                "lua" => SupportedLanguage::Lua,
                 _ => unreachable!(), // Given the outer match, this branch should never be reached
             };
+            // Frontmatter is only recognized at the very start of a code block, so that source
+            // code containing a `---` line is left untouched
+            let (source_code, configuration) = if block.body.trim_start().starts_with("---") {
+                let configuration: CodeCellConfiguration = serde_yaml::from_str(&frontmatter)?;
+                (body, configuration)
+            } else {
+                (block.body.clone(), CodeCellConfiguration::default())
+            };
             Some(CellTypes::Code(CodeCell {
                 backing_file_reference,
                 name: block.name.clone(),
                 language,
-                source_code: block.body.clone(),
+                source_code,
                 function_invocation: None,
+                cwd: configuration.cwd,
+                denied_imports: configuration.denied_imports,
+                permissions: configuration.permissions,
+                output_schema: configuration.output_schema,
+                optional_inputs: configuration.optional_inputs,
+                map: configuration.map,
+                metadata: configuration.metadata,
+            }, block.range.clone()))
+        },
+        "prompt" => {
+            let configuration: LLMPromptCellChatConfiguration = serde_yaml::from_str(&frontmatter)?;
+            Some(CellTypes::Prompt(LLMPromptCell::Chat {
+                backing_file_reference,
+                is_function_invocation: false,
+                provider: configuration.provider.clone(),
+                configuration,
+                name: block.name.clone(),
+                complete_body: whole_body,
+                req: body,
+            }, block.range.clone()))
+        },
+        "codegen" => {
+            let configuration: LLMCodeGenCellChatConfiguration = serde_yaml::from_str(&frontmatter)?;
+            Some(CellTypes::CodeGen(LLMCodeGenCell {
+                backing_file_reference,
+                function_invocation: false,
+                provider: configuration.provider.clone(),
+                configuration,
+                name: block.name.clone(),
+                complete_body: whole_body,
+                req: body,
             }, block.range.clone()))
         },
-        "prompt" => Some(CellTypes::Prompt(LLMPromptCell::Chat {
-            backing_file_reference,
-            is_function_invocation: false,
-            configuration: serde_yaml::from_str(&frontmatter)?,
-            name: block.name.clone(),
-            provider: SupportedModelProviders::OpenAI,
-            complete_body: whole_body,
-            req: body,
-        }, block.range.clone())),
-        "codegen" => Some(CellTypes::CodeGen(LLMCodeGenCell {
-            backing_file_reference,
-            function_invocation: false,
-            configuration: serde_yaml::from_str(&frontmatter)?,
-            name: block.name.clone(),
-            complete_body: whole_body,
-            provider: SupportedModelProviders::OpenAI,
-            req: body,
-        }, block.range.clone())),
         "html" | "template" => Some(CellTypes::Template(TemplateCell {
             backing_file_reference,
             name: block.name.clone(),
             body: block.body.clone(),
         }, block.range.clone())),
+        "embedding" => {
+            let (req, configuration) = if block.body.trim_start().starts_with("---") {
+                let configuration: LLMEmbeddingCellConfiguration = serde_yaml::from_str(&frontmatter)?;
+                (body, configuration)
+            } else {
+                (block.body.clone(), LLMEmbeddingCellConfiguration::default())
+            };
+            Some(CellTypes::Embedding(LLMEmbeddingCell {
+                backing_file_reference,
+                function_invocation: false,
+                configuration,
+                name: block.name.clone(),
+                req,
+            }, block.range.clone()))
+        },
+        "jq" | "transform" => {
+            let (body, configuration) = if block.body.trim_start().starts_with("---") {
+                let configuration: TransformCellConfiguration = serde_yaml::from_str(&frontmatter)?;
+                (body, configuration)
+            } else {
+                (block.body.clone(), TransformCellConfiguration::default())
+            };
+            Some(CellTypes::Transform(TransformCell {
+                backing_file_reference,
+                name: block.name.clone(),
+                inputs: configuration.inputs,
+                body,
+                metadata: configuration.metadata,
+            }, block.range.clone()))
+        },
         _ => None,
     })
 }
@@ -182,7 +236,7 @@ pub fn interpret_markdown_code_block(block: &MarkdownCodeBlock, file_path: Optio
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::execution::primitives::serialized_value::RkyvObjectBuilder;
+    use crate::execution::primitives::serialized_value::{RkyvObjectBuilder, RkyvSerializedValue};
     use indoc::indoc;
     use std::collections::HashMap;
     use std::fs;
@@ -285,6 +339,69 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_code_cell_frontmatter_sets_cwd() {
+        let contents = indoc! { r#"
+            ```python
+            ---
+            cwd: ./data
+            ---
+            x = 1
+            ```
+            "#};
+        let blocks = extract_code_blocks(contents);
+        let Some(CellTypes::Code(cell, _)) = interpret_markdown_code_block(&blocks[0], None).unwrap() else {
+            panic!("Expected a code cell");
+        };
+        assert_eq!(cell.cwd.as_deref(), Some("./data"));
+        assert_eq!(cell.source_code.trim(), "x = 1");
+    }
+
+    #[test]
+    fn test_transform_cell_declares_its_inputs() {
+        let contents = indoc! { r#"
+            ```jq (totals)
+            ---
+            inputs:
+              - orders
+            ---
+            [.orders[] | .total]
+            ```
+            "#};
+        let blocks = extract_code_blocks(contents);
+        let Some(CellTypes::Transform(cell, _)) = interpret_markdown_code_block(&blocks[0], None).unwrap() else {
+            panic!("Expected a transform cell");
+        };
+        assert_eq!(cell.name.as_deref(), Some("totals"));
+        assert_eq!(cell.inputs, vec!["orders".to_string()]);
+        assert_eq!(cell.body.trim(), "[.orders[] | .total]");
+    }
+
+    #[tokio::test]
+    async fn test_cell_metadata_is_copied_to_outputs() {
+        let contents = indoc! { r#"
+            ```python
+            ---
+            metadata:
+              owner: billing
+              category: invoices
+            ---
+            x = 1
+            ```
+            "#};
+        let blocks = extract_code_blocks(contents);
+        let Some(CellTypes::Code(cell, range)) = interpret_markdown_code_block(&blocks[0], None).unwrap() else {
+            panic!("Expected a code cell");
+        };
+        assert_eq!(cell.metadata.get("owner").map(|s| s.as_str()), Some("billing"));
+
+        let op = crate::cells::code_cell::code_cell(uuid::Uuid::nil(), &cell, &range).unwrap();
+        let output = op.execute(&crate::execution::execution::ExecutionState::new_with_random_id(), RkyvSerializedValue::Null, None, None).await.unwrap();
+        assert!(!output.has_error);
+        assert_eq!(output.metadata.get("owner").map(|s| s.as_str()), Some("billing"));
+        assert_eq!(output.metadata.get("category").map(|s| s.as_str()), Some("invoices"));
+    }
+
     #[test]
     fn test_extract_markdown() {
         let extracted = extract_code_blocks(indoc! {  r#"
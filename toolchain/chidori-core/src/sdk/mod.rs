@@ -1,3 +1,8 @@
+pub mod config;
 pub mod md;
+pub mod partials;
+pub mod secrets;
 pub mod interactive_chidori_wrapper;
 pub mod chidori_runtime_instance;
+#[cfg(feature = "sse-server")]
+pub mod sse_server;
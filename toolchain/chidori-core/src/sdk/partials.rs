@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chidori_prompt_format::templating::templates::PromptLibraryRecord;
+use thiserror::Error;
+
+/// Extensions of the files registered as partials.
+const PARTIAL_EXTENSIONS: [&str; 2] = ["hbs", "md"];
+
+#[derive(Error, Debug)]
+pub enum PartialsError {
+    #[error("Failed to read partials from {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Register every `.hbs` and `.md` file beneath `directory` as a partial, named by its path
+/// relative to the directory without the extension, e.g. `shared/header.hbs` as `shared/header`.
+pub fn load_partials(directory: &Path) -> Result<HashMap<String, PromptLibraryRecord>, PartialsError> {
+    let mut partials = HashMap::new();
+    load_partials_into(directory, "", &mut partials)?;
+    Ok(partials)
+}
+
+fn load_partials_into(directory: &Path, prefix: &str, partials: &mut HashMap<String, PromptLibraryRecord>) -> Result<(), PartialsError> {
+    let io_error = |path: &Path, source: std::io::Error| PartialsError::Io {
+        path: path.to_string_lossy().to_string(),
+        source,
+    };
+    let mut entries = directory.read_dir()
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io_error(directory, e))?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+        if path.is_dir() {
+            load_partials_into(&path, &format!("{}{}/", prefix, stem), partials)?;
+            continue;
+        }
+        let is_partial = path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| PARTIAL_EXTENSIONS.contains(&e));
+        if !is_partial {
+            continue;
+        }
+        let name = format!("{}{}", prefix, stem);
+        let template = std::fs::read_to_string(&path).map_err(|e| io_error(&path, e))?;
+        partials.insert(name.clone(), PromptLibraryRecord::new(&name, template));
+    }
+    Ok(())
+}
+
+/// The partials of the templates directory, read on first use and shared by every state of the run
+/// so that cells do not scan the directory on every execution. Edits are picked up by the next run,
+/// or when the directory is reconfigured.
+#[derive(Default)]
+pub struct PartialsCache {
+    loaded: Mutex<Option<(PathBuf, Arc<HashMap<String, PromptLibraryRecord>>)>>,
+}
+
+impl PartialsCache {
+    /// The partials of `templates_dir`, empty when no templates directory is configured.
+    pub fn partials(&self, templates_dir: Option<&Path>) -> Result<Arc<HashMap<String, PromptLibraryRecord>>, PartialsError> {
+        let Some(templates_dir) = templates_dir else {
+            return Ok(Default::default());
+        };
+        let mut loaded = self.loaded.lock().unwrap();
+        match loaded.as_ref() {
+            Some((directory, partials)) if directory == templates_dir => Ok(partials.clone()),
+            _ => {
+                let partials = Arc::new(load_partials(templates_dir)?);
+                *loaded = Some((templates_dir.to_path_buf(), partials.clone()));
+                Ok(partials)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chidori_prompt_format::templating::templates::render_template_prompt;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_partials_are_loaded_by_name() {
+        let dir = std::env::temp_dir().join(format!("chidori_partials_{}", Uuid::now_v7()));
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("persona.hbs"), "You are {{name}}.").unwrap();
+        std::fs::write(dir.join("shared").join("footer.md"), "Be brief.").unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let partials = load_partials(&dir).unwrap();
+        let mut names: Vec<&String> = partials.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["persona", "shared/footer"]);
+        let rendered = render_template_prompt("{{> persona}} {{> shared/footer}}", &json!({"name": "Ada"}), &partials).unwrap();
+        assert_eq!(rendered, "You are Ada. Be brief.");
+
+        // Changes to the directory are picked up when it is loaded again
+        std::fs::write(dir.join("persona.hbs"), "You are {{name}}, a pirate.").unwrap();
+        let partials = load_partials(&dir).unwrap();
+        let rendered = render_template_prompt("{{> persona}}", &json!({"name": "Ada"}), &partials).unwrap();
+        assert_eq!(rendered, "You are Ada, a pirate.");
+
+        let err = render_template_prompt("{{> missing}}", &json!({}), &partials).unwrap_err();
+        assert!(err.to_string().contains("missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_partials_cache_reads_the_directory_once() {
+        let dir = std::env::temp_dir().join(format!("chidori_partials_{}", Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("persona.hbs"), "You are {{name}}.").unwrap();
+
+        let cache = PartialsCache::default();
+        assert!(cache.partials(None).unwrap().is_empty());
+        let partials = cache.partials(Some(&dir)).unwrap();
+        let rendered = render_template_prompt("{{> persona}}", &json!({"name": "Ada"}), &partials).unwrap();
+        assert_eq!(rendered, "You are Ada.");
+
+        // Edits within the run are not read again, a new run reads them
+        std::fs::write(dir.join("persona.hbs"), "You are {{name}}, a pirate.").unwrap();
+        assert!(Arc::ptr_eq(&partials, &cache.partials(Some(&dir)).unwrap()));
+        let partials = PartialsCache::default().partials(Some(&dir)).unwrap();
+        let rendered = render_template_prompt("{{> persona}}", &json!({"name": "Ada"}), &partials).unwrap();
+        assert_eq!(rendered, "You are Ada, a pirate.");
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::env;
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum SecretError {
+    #[error("Secret {0} is not set")]
+    NotFound(String),
+    #[error("Failed to resolve secret {key}: {message}")]
+    Backend {
+        key: String,
+        message: String,
+    },
+}
+
+/// Source of the credentials used to reach model providers, such as api keys. Secrets are resolved
+/// each time a provider is constructed and are never stored on the execution state, so they do
+/// not appear in logs or in the recorded history of a run.
+///
+/// Register a resolver backed by a secret store with `ExecutionState::with_secret_resolver`.
+#[async_trait]
+pub trait SecretResolver: Send + Sync {
+    async fn resolve(&self, key: &str) -> Result<String, SecretError>;
+}
+
+/// Resolves secrets from environment variables of the same name, the default resolver.
+#[derive(Debug, Clone, Default)]
+pub struct EnvSecretResolver;
+
+#[async_trait]
+impl SecretResolver for EnvSecretResolver {
+    async fn resolve(&self, key: &str) -> Result<String, SecretError> {
+        env::var(key).map_err(|_| SecretError::NotFound(key.to_string()))
+    }
+}
+
+/// Resolves secrets from a fixed set of values, for tests and for embedding applications that
+/// obtain their secrets before the run begins.
+#[derive(Clone, Default)]
+pub struct InMemorySecretResolver {
+    secrets: HashMap<String, String>,
+}
+
+impl InMemorySecretResolver {
+    pub fn new(secrets: HashMap<String, String>) -> Self {
+        Self { secrets }
+    }
+}
+
+#[async_trait]
+impl SecretResolver for InMemorySecretResolver {
+    async fn resolve(&self, key: &str) -> Result<String, SecretError> {
+        self.secrets.get(key).cloned().ok_or_else(|| SecretError::NotFound(key.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_resolver() {
+        let resolver = InMemorySecretResolver::new(HashMap::from([("OPENAI_API_KEY".to_string(), "sk-vault".to_string())]));
+        assert_eq!(resolver.resolve("OPENAI_API_KEY").await.unwrap(), "sk-vault");
+        assert!(matches!(resolver.resolve("MISSING").await, Err(SecretError::NotFound(key)) if key == "MISSING"));
+    }
+}
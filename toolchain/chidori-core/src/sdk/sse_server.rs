@@ -0,0 +1,96 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::get;
+use axum::Router;
+use futures_util::stream::{Stream, StreamExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{debug, info};
+
+use crate::execution::execution::progress::ProgressEvent;
+
+/// Number of events buffered per client, clients that fall further behind skip the missed events.
+const CLIENT_BUFFER: usize = 1024;
+
+/// Serve execution progress events as Server-Sent Events at `GET /events` on the given address.
+pub async fn serve_progress_events(receiver: Receiver<ProgressEvent>, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    serve_progress_events_on(receiver, listener).await
+}
+
+/// As `serve_progress_events`, on an already bound listener.
+pub async fn serve_progress_events_on(mut receiver: Receiver<ProgressEvent>, listener: TcpListener) -> anyhow::Result<()> {
+    info!("Serving progress events on {:?}", listener.local_addr()?);
+    let (broadcast_tx, _) = broadcast::channel::<ProgressEvent>(CLIENT_BUFFER);
+
+    // Fan out events to every connected client. Broadcasting never waits on clients,
+    // so a slow or disconnected client cannot hold up the producer.
+    let fan_out_tx = broadcast_tx.clone();
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            // An error only indicates that there are currently no clients
+            let _ = fan_out_tx.send(event);
+        }
+    });
+
+    let app = Router::new()
+        .route("/events", get(events_handler))
+        .with_state(broadcast_tx);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn events_handler(
+    State(broadcast_tx): State<broadcast::Sender<ProgressEvent>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // The stream, and with it the subscription, is dropped when the client disconnects
+    let stream = BroadcastStream::new(broadcast_tx.subscribe()).filter_map(|event| async move {
+        match event {
+            Ok(event) => Event::default()
+                .event(event.event_name())
+                .json_data(&event)
+                .ok()
+                .map(Ok),
+            Err(lagged) => {
+                debug!("Progress event client lagged: {}", lagged);
+                None
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::execution::progress::progress_channel;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_streams_events_to_client() {
+        let (sender, receiver) = progress_channel(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_progress_events_on(receiver, listener));
+
+        let response = reqwest::get(format!("http://{}/events", addr)).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.bytes_stream();
+
+        sender.emit(ProgressEvent::CellStarted {
+            execution_node_id: Uuid::nil(),
+            operation_id: Uuid::nil(),
+            name: Some("example".to_string()),
+        });
+
+        let chunk = body.next().await.unwrap().unwrap();
+        let text = String::from_utf8_lossy(&chunk);
+        assert!(text.contains("event: CellStarted"), "{}", text);
+        assert!(text.contains(r#""name":"example""#), "{}", text);
+    }
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk;
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, PreSampledTracer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::utils::SERVICE_NAME;
+
+/// A layer exporting spans through the given tracer, for services that already configure OpenTelemetry.
+/// Added to the service's subscriber, each executed cell is exported as a `cell` span beneath the span
+/// the run was started within, with attributes for its execution node, operation, duration, provider,
+/// model and tokens. Requests to a model are exported as `llm_request` spans beneath their cell.
+pub fn layer<S, T>(tracer: T) -> OpenTelemetryLayer<S, T>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    T: opentelemetry::trace::Tracer + PreSampledTracer + 'static,
+{
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// A layer exporting spans to the OTLP collector at `exporter_endpoint` over gRPC.
+pub(crate) fn exporter_layer<S>(exporter_endpoint: &str) -> Result<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, Box<dyn Error>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    // Create a gRPC exporter
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(exporter_endpoint);
+
+    // Define a tracer
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            opentelemetry_sdk::trace::config().with_resource(opentelemetry_sdk::Resource::new(vec![KeyValue::new(
+                opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+                SERVICE_NAME.to_string(),
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+
+    Ok(layer(tracer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::Registry;
+    use tracing_subscriber::prelude::*;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use std::sync::{Arc, Mutex};
+    use futures_util::future::BoxFuture;
+    use tracing::Instrument;
+    use crate::execution::execution::ExecutionState;
+    use crate::library::std::ai::llm::mock::{MockChatModel, RequestMatcher};
+    use crate::cells::{CellTypes, LLMPromptCell, SupportedModelProviders, TextRange};
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Default)]
+    struct CollectingExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for CollectingExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cells_export_as_spans() {
+        let exporter = CollectingExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let subscriber = Registry::default().with(layer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let model = Arc::new(MockChatModel::builder().respond_when(RequestMatcher::Any, "Hi!").build());
+        let state = ExecutionState::new_with_random_id().with_chat_model(model);
+        let cell = CellTypes::Prompt(LLMPromptCell::Chat {
+            backing_file_reference: None,
+            is_function_invocation: false,
+            configuration: Default::default(),
+            name: Some("reply".to_string()),
+            provider: Some(SupportedModelProviders::OpenAI),
+            complete_body: "---\nmodel: gpt-4o\n---\nHello".to_string(),
+            req: "Hello".to_string(),
+        }, TextRange::default());
+        let op = state.get_operation_from_cell_type(&cell).unwrap();
+        let (_, state) = state.upsert_operation(op, Uuid::now_v7()).unwrap();
+        state.step_execution().instrument(tracing::info_span!("request")).await.unwrap();
+        provider.force_flush();
+
+        let spans = exporter.0.lock().unwrap().clone();
+        let span = |name: &str| spans.iter().find(|s| s.name == name).unwrap_or_else(|| panic!("no {} span", name)).clone();
+        let attribute = |span: &SpanData, key: &str| span.attributes.iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.to_string());
+        let (request, step, cell, llm) = (span("request"), span("step_execution"), span("cell"), span("llm_request"));
+        assert_eq!(step.parent_span_id, request.span_context.span_id());
+        assert_eq!(cell.parent_span_id, step.span_context.span_id());
+        assert_eq!(llm.parent_span_id, cell.span_context.span_id());
+        assert_eq!(attribute(&cell, "name").as_deref(), Some("reply"));
+        assert_eq!(attribute(&cell, "provider").as_deref(), Some("openai"));
+        assert_eq!(attribute(&cell, "model").as_deref(), Some("gpt-4o"));
+        assert!(attribute(&cell, "total_tokens").is_some());
+        assert!(attribute(&cell, "duration_ms").is_some());
+        assert_eq!(attribute(&llm, "model").as_deref(), Some("gpt-4o"));
+    }
+}
@@ -13,23 +13,19 @@ use chidori_core::utils;
 async fn test_execute_cells_with_global_dependency() -> anyhow::Result<()> {
     let mut env = ChidoriRuntimeInstance::new();
     let (_, op_id_x) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         x = 20
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                        Uuid::now_v7())?;
     let (_, op_id_y) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         y = x + 1
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                        Uuid::now_v7())?;
     // env.resolve_dependencies_from_input_signature();
@@ -56,13 +52,11 @@ async fn test_execute_cells_between_code_and_llm() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     let mut env = ChidoriRuntimeInstance::new();
     let (_, op_id_x) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         x = "Here is a sample string"
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                        Uuid::now_v7())?;
     let (_, op_id_y) = env.upsert_cell(CellTypes::Prompt(LLMPromptCell::Chat {
@@ -73,7 +67,7 @@ async fn test_execute_cells_between_code_and_llm() -> anyhow::Result<()> {
             ..Default::default()
         },
         name: Some("example".into()),
-        provider: SupportedModelProviders::OpenAI,
+        provider: Some(SupportedModelProviders::OpenAI),
         complete_body: "".to_string(),
         req: "\
                       Say only a single word. Give no additional explanation.
@@ -83,13 +77,11 @@ async fn test_execute_cells_between_code_and_llm() -> anyhow::Result<()> {
     }, TextRange::default()),
                                        Uuid::now_v7())?;
     let (_, op_id_z) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         z = await example(x=x)
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                        Uuid::now_v7())?;
 
@@ -129,13 +121,11 @@ async fn test_execute_cells_between_code_and_llm() -> anyhow::Result<()> {
 async fn test_execute_cells_prompts_as_functions() -> anyhow::Result<()> {
     let mut env = ChidoriRuntimeInstance::new();
     let (_, op_id_x) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         y = generate_names(x="John")
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                        Uuid::now_v7())?;
     let (_, op_id_y) = env.upsert_cell(CellTypes::Prompt(LLMPromptCell::Chat {
@@ -143,7 +133,7 @@ async fn test_execute_cells_prompts_as_functions() -> anyhow::Result<()> {
         is_function_invocation: false,
         configuration: LLMPromptCellChatConfiguration::default(),
         name: Some("generate_names".to_string()),
-        provider: SupportedModelProviders::OpenAI,
+        provider: Some(SupportedModelProviders::OpenAI),
         complete_body: "".to_string(),
         req: "\
                       Generate names starting with {{x}}
@@ -169,24 +159,21 @@ async fn test_execute_cells_invoking_a_function() -> anyhow::Result<()> {
     let mut env = ChidoriRuntimeInstance::new();
     env.wait_until_ready().await.unwrap();
     let (_, id_a) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         def add(x, y):
                             return x + y
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                     Uuid::now_v7())?;
     let (_, id_b) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
-        function_invocation: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         y = await add(2, 3)
                         "#}),
+                        metadata: Default::default(),
+        ..Default::default()
     }, TextRange::default()),
                                     Uuid::now_v7())?;
     env.get_state_at_current_execution_head().render_dependency_graph();
@@ -214,24 +201,21 @@ async fn test_execute_inter_runtime_code_plain() -> anyhow::Result<()> {
     let mut env = ChidoriRuntimeInstance::new();
     env.wait_until_ready().await.unwrap();
     let (_, id_a) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
         language: SupportedLanguage::PyO3,
         source_code: String::from(indoc! { r#"
                         def add(x, y):
                             return x + y
                         "#}),
-        function_invocation: None,
+        ..Default::default()
     }, TextRange::default()),
                                     Uuid::now_v7())?;
     let (_, id_b) = env.upsert_cell(CellTypes::Code(CodeCell {
-        backing_file_reference: None,
-        name: None,
-        function_invocation: None,
         language: SupportedLanguage::Deno,
         source_code: String::from(indoc! { r#"
                         const y = await add(2, 3);
                         "#}),
+                        metadata: Default::default(),
+        ..Default::default()
     }, TextRange::default()),
                                     Uuid::now_v7())?;
     env.get_state_at_current_execution_head().render_dependency_graph();
@@ -261,6 +261,8 @@ pub fn editable_chidori_cell_content(
             CellTypes::Template(..) => {
                 render_template_cell(&mut chidori_state, &op_id, ui, cell_holder, exists_in_current_tree);
             }
+            // Transform and embedding cells are not yet editable in the debugger
+            CellTypes::Transform(..) | CellTypes::Embedding(..) => {}
         }
 
         let state_binding = chidori_state.local_cell_state.entry(op_id).or_insert(Arc::new(Mutex::new(CellState::default()))).clone();
@@ -576,7 +578,8 @@ fn render_code_cell(
     let mut local_language = language.clone();
     let language_string = match language {
         SupportedLanguage::PyO3 => "python",
-        SupportedLanguage::Deno => "javascript/typescript"
+        SupportedLanguage::Deno => "javascript/typescript",
+        SupportedLanguage::Lua => "lua"
     };
 
     let report = match language {
@@ -588,13 +591,18 @@ fn render_code_cell(
             let d = chidori_core::chidori_static_analysis::language::javascript::parse::extract_dependencies_js(&source_code);
             d.map(|d| chidori_core::chidori_static_analysis::language::javascript::parse::build_report(&d))
         }
+        SupportedLanguage::Lua => {
+            let d = chidori_core::chidori_static_analysis::language::lua::parse::extract_dependencies_lua(&source_code);
+            d.map(|d| chidori_core::chidori_static_analysis::language::lua::parse::build_report(&d))
+        }
     };
 
     let language_clone =  language.clone();
     let mut layouter = |ui: &egui::Ui, text_string: &str, wrap_width: f32| {
         let syntax_language = match language_clone {
             SupportedLanguage::PyO3 => "py",
-            SupportedLanguage::Deno => "js"
+            SupportedLanguage::Deno => "js",
+            SupportedLanguage::Lua => "lua"
         };
         let mut layout_job =
             egui_extras::syntax_highlighting::highlight(ui.ctx(), &theme, text_string, syntax_language);
@@ -619,7 +627,8 @@ fn render_code_cell(
                 .show_ui(ui, |ui| {
                     let py = ui.selectable_value(&mut local_language, SupportedLanguage::PyO3, "Python");
                     let js = ui.selectable_value(&mut local_language, SupportedLanguage::Deno, "JavaScript");
-                    if py.clicked() || js.clicked() {
+                    let lua = ui.selectable_value(&mut local_language, SupportedLanguage::Lua, "Lua");
+                    if py.clicked() || js.clicked() || lua.clicked() {
                         *language = local_language;
                     }
                 });
@@ -748,11 +757,9 @@ fn render_new_cell_interface(
             let op_id = Uuid::now_v7();
             state.temp_cell = Some(CellHolder {
                 cell: CellTypes::Code(CodeCell {
-                    backing_file_reference: None,
-                    name: None,
                     language: SupportedLanguage::PyO3,
                     source_code: "".to_string(),
-                    function_invocation: None,
+                    ..Default::default()
                 }, TextRange::default()),
                 op_id,
                 applied_at: Default::default(),
@@ -767,7 +774,7 @@ fn render_new_cell_interface(
                     is_function_invocation: false,
                     configuration: Default::default(),
                     name: None,
-                    provider: SupportedModelProviders::OpenAI,
+                    provider: Some(SupportedModelProviders::OpenAI),
                     complete_body: "".to_string(),
                     req: "".to_string(),
                 }, TextRange::default()),
@@ -797,7 +804,7 @@ fn render_new_cell_interface(
                     function_invocation: false,
                     configuration: Default::default(),
                     name: None,
-                    provider: SupportedModelProviders::OpenAI,
+                    provider: Some(SupportedModelProviders::OpenAI),
                     req: "".to_string(),
                     complete_body: "".to_string(),
                 }, TextRange::default()),
@@ -833,6 +840,7 @@ let exists_in_current_tree = false;
             CellTypes::Template(..) => {
                 render_template_cell(&mut chidori_state, &op_id, ui, temp_cell, exists_in_current_tree);
             }
+            CellTypes::Transform(..) | CellTypes::Embedding(..) => {}
         }
 
         if ui.button("Save and Push To Graph").clicked() {
@@ -6,7 +6,7 @@ use egui::{Color32, FontFamily, FontId, Frame, Margin, RichText, Ui};
 use egui_extras::syntax_highlighting;
 use egui_extras::syntax_highlighting::CodeTheme;
 use egui_json_tree::value::{BaseValueType, ExpandableType, JsonTreeValue, ToJsonTreeValue};
-use chidori_core::cells::{CellTypes, CodeCell, LLMCodeGenCell, LLMEmbeddingCell, LLMPromptCell, MemoryCell, SupportedLanguage, TemplateCell, WebserviceCell};
+use chidori_core::cells::{CellTypes, CodeCell, LLMCodeGenCell, LLMEmbeddingCell, LLMPromptCell, MemoryCell, SupportedLanguage, TemplateCell, TransformCell, WebserviceCell};
 use chidori_core::execution::primitives::serialized_value::RkyvSerializedValue;
 
 
@@ -67,9 +67,15 @@ pub fn egui_rkyv(ui: &mut Ui, value: &RkyvSerializedValue, with_clip: bool) {
         RkyvSerializedValue::Number(a) => {
             ui.label(format!("{:?}", a));
         }
+        RkyvSerializedValue::Integer(a) => {
+            ui.label(format!("{:?}", a));
+        }
         RkyvSerializedValue::String(a) => {
             ui.label(format!("{:?}", a));
         }
+        RkyvSerializedValue::Bytes(a) => {
+            ui.label(format!("{} bytes", a.len()));
+        }
         RkyvSerializedValue::Boolean(a) => {
             ui.label(format!("{:?}", a));
         }
@@ -215,6 +221,12 @@ pub fn egui_render_cell_read(ui: &mut Ui, cell: &CellTypes, state: &ExecutionSta
         CellTypes::Template(TemplateCell { name, body, .. }, _) => {
             render_text_cell(ui, name, body, "Prompt", "", &theme);
         }
+        CellTypes::Transform(TransformCell { name, body, .. }, _) => {
+            render_text_cell(ui, name, body, "Transform", "", &theme);
+        }
+        CellTypes::Embedding(LLMEmbeddingCell { name, req, .. }, _) => {
+            render_text_cell(ui, name, req, "Embedding", "", &theme);
+        }
         CellTypes::Prompt(LLMPromptCell::Completion { .. }, _)  => {}
     }
 }
@@ -223,6 +235,7 @@ fn render_code_cell(ui: &mut Ui, name: &Option<String>, source_code: &str, langu
     let (language_string, syntax_language) = match language {
         SupportedLanguage::PyO3 => ("python", "py"),
         SupportedLanguage::Deno => ("javascript/typescript", "js"),
+        SupportedLanguage::Lua => ("lua", "lua"),
     };
 
     render_frame(ui, "Code", Some(language_string), name, source_code, theme, syntax_language);
@@ -402,9 +415,13 @@ pub fn find_matching_strings(
 
 pub fn serialized_value_to_json_value(v: &RkyvSerializedValue) -> serde_json::Value {
     match &v {
-        RkyvSerializedValue::Float(f) => Value::Number(f.to_string().parse().unwrap()),
-        RkyvSerializedValue::Number(n) => Value::Number(n.to_string().parse().unwrap()),
+        RkyvSerializedValue::Float(f) => serde_json::Number::from_f64(*f)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        RkyvSerializedValue::Number(n) => Value::Number((*n).into()),
+        RkyvSerializedValue::Integer(n) => Value::Number((*n).into()),
         RkyvSerializedValue::String(s) => Value::String(s.to_string()),
+        RkyvSerializedValue::Bytes(b) => Value::String(format!("<{} bytes>", b.len())),
         RkyvSerializedValue::Boolean(b) => Value::Bool(*b),
         RkyvSerializedValue::Array(a) => Value::Array(
             a.iter()
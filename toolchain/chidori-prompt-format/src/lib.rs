@@ -43,6 +43,7 @@ pub fn render_template_prompt(
 struct TemplateWithRole {
     role: ChatModelRoles,
     source: String,
+    name: Option<String>,
 }
 
 #[wasm_bindgen]
@@ -50,9 +51,13 @@ pub fn extract_roles_from_template(template: &str) -> JsValue {
     let mut role_blocks = crate::templating::templates::extract_roles_from_template(&template);
     let templates_with_roles: Vec<TemplateWithRole> = role_blocks
         .into_iter()
-        .map(|(a, b)| TemplateWithRole {
-            role: a,
-            source: b.unwrap().source.to_string(),
+        .map(|(a, b)| {
+            let b = b.unwrap();
+            TemplateWithRole {
+                role: a,
+                source: b.source,
+                name: b.name,
+            }
         })
         .collect();
     serde_wasm_bindgen::to_value(&templates_with_roles)
@@ -1 +1,2 @@
 pub mod templates;
+pub mod truncate;
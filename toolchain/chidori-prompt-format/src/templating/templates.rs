@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::value::Map as JsonMap;
 use serde_json::{Map, Value};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::templating::truncate::{Tokenizer, TruncateHelper, TRUNCATE_HELPER};
 use wasm_bindgen::prelude::wasm_bindgen;
 use wasm_bindgen::JsValue;
 
@@ -58,6 +60,26 @@ pub fn referenced_variable_list_to_schema(list: Vec<ReferencedVariable>) -> Sche
         let mut current = &mut schema;
         // Skip params? TODO: we should skip only when they're something that introspects the param
         if el.is_param {
+            // The variable iterated by an each block is an array even if its body refers to no fields
+            if let Some(BlockContextElement::Each(name)) = el.path.last() {
+                if name == &el.name {
+                    for path in &el.path[..el.path.len() - 1] {
+                        let (name, ty) = match path {
+                            BlockContextElement::Partial(name) | BlockContextElement::With(name) => (name, SchemaItemType::Object),
+                            BlockContextElement::Each(name) => (name, SchemaItemType::Array),
+                        };
+                        current = current.items.entry(name.clone()).or_insert_with(|| {
+                            Box::new(SchemaItem { ty, items: HashMap::new() })
+                        });
+                    }
+                    current.items.entry(el.name).or_insert_with(|| {
+                        Box::new(SchemaItem {
+                            ty: SchemaItemType::Array,
+                            items: HashMap::new(),
+                        })
+                    });
+                }
+            }
             continue;
         }
         for path in el.path {
@@ -163,6 +185,8 @@ pub fn analyze_referenced_partials_inner<F>(
                         name: name,
                     });
                 }
+                // Helpers that only read the values of their parameters refer to them as variables
+                let reads_params = matches!(&deref.name, Parameter::Name(n) if n == TRUNCATE_HELPER);
                 for param in &deref.params {
                     let param_names = extract_vars_from_param(param);
                     for param_name in param_names {
@@ -182,7 +206,7 @@ pub fn analyze_referenced_partials_inner<F>(
                         }
                         reference_paths.push(ReferencedVariable {
                             path: block_context.clone(),
-                            is_param: true,
+                            is_param: !reads_params,
                             name: param_name,
                         });
                     }
@@ -275,6 +299,8 @@ pub enum ChatModelRoles {
 pub struct TemplateWithSource {
     pub template: Template,
     pub source: String,
+    /// Participant a role block speaks as, given by its `name` hash, e.g. `{{#user name="alice"}}`.
+    pub name: Option<String>,
 }
 
 pub fn extract_roles_from_template(
@@ -283,6 +309,7 @@ pub fn extract_roles_from_template(
     let temp = TemplateWithSource {
         template: Template::compile(template_string).unwrap(),
         source: template_string.to_string(),
+        name: None,
     };
     let mut role_blocks = extract_roles_from_template_inner(&temp, vec![]);
     if role_blocks.is_empty() {
@@ -309,6 +336,10 @@ fn extract_roles_from_template_inner(
                     TemplateWithSource {
                         template: t,
                         source,
+                        name: match deref.hash.get("name") {
+                            Some(Parameter::Literal(Value::String(name))) => Some(name.clone()),
+                            _ => None,
+                        },
                     }
                 });
                 match &deref.name {
@@ -338,6 +369,7 @@ fn extract_roles_from_template_inner(
                         &TemplateWithSource {
                             template: next_template,
                             source: template_with_source.source.clone(),
+                            name: None,
                         },
                         ctx,
                     );
@@ -373,11 +405,10 @@ fn merge(a: &mut Value, b: Value) {
     *a = b;
 }
 
-// TODO: remove these unwraps
 // TODO: add an argument for passing a set of partials
 // TODO: implement block helpers for User and System prompts
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PromptLibraryRecord {
     template: String,
     name: String,
@@ -385,22 +416,133 @@ pub struct PromptLibraryRecord {
     description: Option<String>,
 }
 
+impl PromptLibraryRecord {
+    pub fn new(name: &str, template: String) -> Self {
+        Self {
+            template,
+            name: name.to_string(),
+            id: name.to_string(),
+            description: None,
+        }
+    }
+}
+
 /// Render a template string, placing in partials (names that map to prompts in the prompt library) and values from the query paths
-/// as records of changes that are made to the event log
+/// as records of changes that are made to the event log. No tokenizer is available to `{{truncate}}`.
 pub fn render_template_prompt(
     template_str: &str,
     json_value: &serde_json::Value,
     partials: &HashMap<String, PromptLibraryRecord>,
 ) -> Result<String> {
-    let mut reg = Handlebars::new();
-    for (name, prompt) in partials.iter() {
-        reg.register_partial(name, prompt.template.as_str())
-            .unwrap();
-    }
-    reg.register_template_string("tpl_1", template_str).unwrap();
-    reg.register_escape_fn(handlebars::no_escape);
-    let render = reg.render("tpl_1", &json_value).unwrap();
-    Ok(render)
+    CompiledTemplate::compile(template_str)?.render(json_value, partials, None)
+}
+
+/// A template parsed once, so that rendering it repeatedly does not parse its source again.
+#[derive(Debug)]
+pub struct CompiledTemplate {
+    template: Template,
+    /// Partials the template was rendered with, keyed by name along with the source they were
+    /// compiled from. A partial is compiled again only when its source changes.
+    partials: Mutex<HashMap<String, (String, Template)>>,
+}
+
+impl CompiledTemplate {
+    pub fn compile(template_str: &str) -> Result<Self> {
+        let template = Template::compile_with_name(template_str, "tpl_1".to_string())
+            .map_err(|e| anyhow::anyhow!("Invalid template: {}", e))?;
+        Ok(Self { template, partials: Mutex::new(HashMap::new()) })
+    }
+
+    /// Render the template as `render_template_prompt` would render its source. `{{truncate}}`
+    /// counts its budget in tokens of `tokenizer`, and is not available without one.
+    pub fn render(
+        &self,
+        json_value: &serde_json::Value,
+        partials: &HashMap<String, PromptLibraryRecord>,
+        tokenizer: Option<&Arc<dyn Tokenizer>>,
+    ) -> Result<String> {
+        let mut reg = Handlebars::new();
+        let mut compiled_partials = self.partials.lock().unwrap();
+        for (name, prompt) in partials.iter() {
+            if !compiled_partials.get(name).is_some_and(|(source, _)| source == &prompt.template) {
+                let partial = Template::compile_with_name(prompt.template.as_str(), name.clone())
+                    .map_err(|e| anyhow::anyhow!("Invalid partial {:?}: {}", name, e))?;
+                compiled_partials.insert(name.clone(), (prompt.template.clone(), partial));
+            }
+            // A registered partial is a template of the partial's name
+            reg.register_template(name, compiled_partials[name].1.clone());
+        }
+        drop(compiled_partials);
+        reg.register_template("tpl_1", self.template.clone());
+        if let Some(tokenizer) = tokenizer {
+            reg.register_helper(TRUNCATE_HELPER, Box::new(TruncateHelper(tokenizer.clone())));
+        }
+        reg.register_escape_fn(handlebars::no_escape);
+        let render = reg.render("tpl_1", &json_value)
+            .map_err(|e| anyhow::anyhow!("Failed to render template: {}", e))?;
+        Ok(render)
+    }
+}
+
+/// Templates compiled on first use, keyed by their source.
+#[derive(Debug, Default)]
+pub struct TemplateCache {
+    templates: Mutex<HashMap<String, Arc<CompiledTemplate>>>,
+}
+
+impl TemplateCache {
+    /// The compiled form of a template, compiling it when the source has not been seen before.
+    pub fn get_or_compile(&self, template_str: &str) -> Result<Arc<CompiledTemplate>> {
+        if let Some(template) = self.templates.lock().unwrap().get(template_str) {
+            return Ok(template.clone());
+        }
+        let template = Arc::new(CompiledTemplate::compile(template_str)?);
+        self.templates.lock().unwrap().insert(template_str.to_string(), template.clone());
+        Ok(template)
+    }
+
+    /// Render a template, parsing its source only the first time it is rendered.
+    pub fn render(
+        &self,
+        template_str: &str,
+        json_value: &serde_json::Value,
+        partials: &HashMap<String, PromptLibraryRecord>,
+        tokenizer: Option<&Arc<dyn Tokenizer>>,
+    ) -> Result<String> {
+        self.get_or_compile(template_str)?.render(json_value, partials, tokenizer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Normalize the whitespace of rendered role content: the indentation common to every line is removed,
+/// trailing whitespace is trimmed, runs of blank lines are collapsed into one and leading and trailing
+/// blank lines are dropped.
+pub fn normalize_whitespace(content: &str) -> String {
+    let lines: Vec<&str> = content.lines().map(|line| line.trim_end()).collect();
+    let indent = lines.iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    let mut normalized: Vec<&str> = vec![];
+    for line in lines {
+        let line = if line.is_empty() { line } else { &line[indent..] };
+        if line.is_empty() && normalized.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        normalized.push(line);
+    }
+    if normalized.last() == Some(&"") {
+        normalized.pop();
+    }
+    normalized.join("\n")
 }
 
 fn get_source_string_from_template(source: &str, template: &Template) -> String {
@@ -419,8 +561,52 @@ mod tests {
     use super::*;
     // use gluesql::core::ast_builder::extract;
     use indoc::indoc;
+    use crate::templating::truncate::ByteTokenizer;
     use serde_json::json;
 
+    #[test]
+    fn test_normalize_whitespace() {
+        let rendered = "\n        You are a helpful assistant.   \n\n\n\n        Answer in:\n          - English\n          - French\n    \n";
+        assert_eq!(
+            normalize_whitespace(rendered),
+            "You are a helpful assistant.\n\nAnswer in:\n  - English\n  - French"
+        );
+        assert_eq!(normalize_whitespace("no change"), "no change");
+        assert_eq!(normalize_whitespace("  \n \n"), "");
+    }
+
+    #[test]
+    fn test_template_cache_compiles_each_source_once() {
+        let cache = TemplateCache::default();
+        let partials = HashMap::new();
+        let template = "Hello, {{name}}!";
+        for _ in 0..3 {
+            assert_eq!(cache.render(template, &json!({"name": "Ada"}), &partials, None).unwrap(), "Hello, Ada!");
+        }
+        assert_eq!(cache.len(), 1);
+
+        assert_eq!(cache.render("Bye, {{name}}.", &json!({"name": "Ada"}), &partials, None).unwrap(), "Bye, Ada.");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.render("{{#if}}", &json!({}), &partials, None).is_err());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_compiled_template_caches_its_partials() {
+        let compiled = CompiledTemplate::compile("{{> greeting}}!").unwrap();
+        let mut partials = HashMap::from([
+            ("greeting".to_string(), PromptLibraryRecord::new("greeting", "Hello, {{name}}".to_string())),
+        ]);
+        assert_eq!(compiled.render(&json!({"name": "Ada"}), &partials, None).unwrap(), "Hello, Ada!");
+        assert_eq!(compiled.render(&json!({"name": "Grace"}), &partials, None).unwrap(), "Hello, Grace!");
+        assert_eq!(compiled.partials.lock().unwrap().len(), 1);
+
+        // An edited partial is compiled again
+        partials.insert("greeting".to_string(), PromptLibraryRecord::new("greeting", "Bye, {{name}}".to_string()));
+        assert_eq!(compiled.render(&json!({"name": "Ada"}), &partials, None).unwrap(), "Bye, Ada!");
+        assert_eq!(compiled.partials.lock().unwrap()["greeting"].0, "Bye, {{name}}");
+    }
+
     // #[test]
     // fn test_template_validation() {
     //     validate_template(
@@ -659,6 +845,26 @@ Summarize content you are provided with for a second-grade student.
         assert_eq!(role_blocks[1].1.clone().unwrap().source, "test".to_string());
         assert_eq!(role_blocks[2].0, ChatModelRoles::Assistant);
         assert_eq!(role_blocks[2].1.clone().unwrap().source, "test".to_string());
+        assert!(role_blocks.iter().all(|(_, block)| block.as_ref().unwrap().name.is_none()));
+    }
+
+    #[test]
+    fn test_extracting_role_names() {
+        let template_string = indoc! {r#"
+                {{#user name="alice"}}Hi Bob.{{/user}}
+                {{#user name="bob"}}Hi Alice.{{/user}}
+                {{#assistant}}Hello both.{{/assistant}}
+            "#};
+        let role_blocks = extract_roles_from_template(&template_string);
+        let names: Vec<_> = role_blocks.iter()
+            .map(|(role, block)| (role.clone(), block.as_ref().unwrap().name.clone()))
+            .collect();
+        assert_eq!(names, vec![
+            (ChatModelRoles::User, Some("alice".to_string())),
+            (ChatModelRoles::User, Some("bob".to_string())),
+            (ChatModelRoles::Assistant, None),
+        ]);
+        assert_eq!(role_blocks[0].1.as_ref().unwrap().source, "Hi Bob.");
     }
 
     #[test]
@@ -713,4 +919,34 @@ Summarize content you are provided with for a second-grade student.
         ]);
         dbg!(&schema);
     }
+
+    #[test]
+    fn test_schema_of_iterated_variable_is_array() {
+        let schema = analyze_referenced_partials("{{#each items}}-{{/each}}").unwrap();
+        assert_eq!(schema.items["items"].ty, SchemaItemType::Array);
+
+        let schema = analyze_referenced_partials("{{#each items}}{{name}}{{/each}}").unwrap();
+        assert_eq!(schema.items["items"].ty, SchemaItemType::Array);
+        assert_eq!(schema.items["items"].items["name"].ty, SchemaItemType::String);
+    }
+
+    #[test]
+    fn test_truncate_helper() {
+        let template = "Summarize: {{truncate document 8}}";
+        let schema = analyze_referenced_partials(template).unwrap();
+        assert_eq!(schema.items["document"].ty, SchemaItemType::String);
+        assert!(!schema.items.contains_key("truncate"));
+
+        let tokenizer: Arc<dyn Tokenizer> = Arc::new(ByteTokenizer);
+        let compiled = CompiledTemplate::compile(template).unwrap();
+        let document = "word ".repeat(100);
+        let rendered = compiled.render(&json!({ "document": document }), &HashMap::new(), Some(&tokenizer)).unwrap();
+        assert_eq!(rendered, "Summarize: word …");
+
+        let rendered = compiled.render(&json!({ "document": "short" }), &HashMap::new(), Some(&tokenizer)).unwrap();
+        assert_eq!(rendered, "Summarize: short");
+
+        // Without a tokenizer there is no budget to truncate to
+        assert!(render_template_prompt(template, &json!({ "document": "short" }), &HashMap::new()).is_err());
+    }
 }
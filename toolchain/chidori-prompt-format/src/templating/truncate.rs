@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderErrorReason};
+use serde_json::Value;
+
+/// Name under which the truncation helper is registered, `{{truncate document 2000}}`.
+pub const TRUNCATE_HELPER: &str = "truncate";
+
+/// Appended to values that were truncated.
+pub const TRUNCATION_MARKER: &str = "…";
+
+/// The tokenizer token budgets are counted in. This crate bundles no tokenizer, the embedding
+/// application supplies that of the model a template is rendered for.
+pub trait Tokenizer: Send + Sync {
+    fn encode(&self, text: &str) -> Vec<usize>;
+
+    /// The text of a sequence of tokens, None when it does not end on a character boundary.
+    fn decode(&self, tokens: &[usize]) -> Option<String>;
+}
+
+/// Truncate text to at most `budget` tokens of `tokenizer`, the marker included. Text within the
+/// budget is returned unchanged.
+pub fn truncate_to_tokens(tokenizer: &dyn Tokenizer, text: &str, budget: usize) -> String {
+    let tokens = tokenizer.encode(text);
+    if tokens.len() <= budget {
+        return text.to_string();
+    }
+    let marker_tokens = tokenizer.encode(TRUNCATION_MARKER).len();
+    let mut kept = budget.saturating_sub(marker_tokens);
+    // A token boundary may fall within a multibyte character, drop tokens until it does not
+    loop {
+        if let Some(prefix) = tokenizer.decode(&tokens[..kept]) {
+            return format!("{}{}", prefix, TRUNCATION_MARKER);
+        }
+        kept -= 1;
+    }
+}
+
+/// `{{truncate value budget}}` renders the value truncated to `budget` tokens of its tokenizer.
+pub struct TruncateHelper(pub Arc<dyn Tokenizer>);
+
+impl HelperDef for TruncateHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let value = h.param(0).ok_or(RenderErrorReason::ParamNotFoundForIndex(TRUNCATE_HELPER, 0))?;
+        let budget = h.param(1)
+            .and_then(|budget| budget.value().as_u64())
+            .ok_or(RenderErrorReason::InvalidParamType("a token budget as the second parameter of truncate"))?;
+        let text = match value.value() {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        out.write(&truncate_to_tokens(self.0.as_ref(), &text, budget as usize))?;
+        Ok(())
+    }
+}
+
+/// A tokenizer of one token per byte, for tests of this crate.
+#[cfg(test)]
+pub(crate) struct ByteTokenizer;
+
+#[cfg(test)]
+impl Tokenizer for ByteTokenizer {
+    fn encode(&self, text: &str) -> Vec<usize> {
+        text.bytes().map(usize::from).collect()
+    }
+
+    fn decode(&self, tokens: &[usize]) -> Option<String> {
+        String::from_utf8(tokens.iter().map(|token| *token as u8).collect()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        assert_eq!(truncate_to_tokens(&ByteTokenizer, "A short document.", 20), "A short document.");
+
+        let long = "The quick brown fox jumps over the lazy dog. ".repeat(50);
+        let truncated = truncate_to_tokens(&ByteTokenizer, &long, 10);
+        assert!(truncated.ends_with(TRUNCATION_MARKER));
+        assert!(long.starts_with(truncated.trim_end_matches(TRUNCATION_MARKER)));
+        assert!(ByteTokenizer.encode(&truncated).len() <= 10);
+
+        // Characters spanning several tokens are never split
+        let truncated = truncate_to_tokens(&ByteTokenizer, &"🦀".repeat(20), 10);
+        assert_eq!(truncated, format!("🦀{}", TRUNCATION_MARKER));
+    }
+}
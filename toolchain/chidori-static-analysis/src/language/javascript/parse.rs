@@ -830,6 +830,11 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                             arguments: vec![],
                             emit_event: vec![],
                             trigger_on: vec![],
+                            argument_defaults: HashMap::new(),
+                            argument_annotations: HashMap::new(),
+                            variadic_args: None,
+                            variadic_kwargs: None,
+                            returned_names: vec![],
                         });
                 }
             }
@@ -853,6 +858,11 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                             arguments: vec![],
                             emit_event: vec![], // Initialize with an empty string or a default value
                             trigger_on: vec![],
+                            argument_defaults: HashMap::new(),
+                            argument_annotations: HashMap::new(),
+                            variadic_args: None,
+                            variadic_kwargs: None,
+                            returned_names: vec![],
                         });
 
                     if attribute_path == vec![&"emitAs".to_string()] {
@@ -884,6 +894,11 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                                     arguments: vec![],
                                     emit_event: vec![], // Initialize with an empty string or a default value
                                     trigger_on: vec![],
+                                    argument_defaults: HashMap::new(),
+                                    argument_annotations: HashMap::new(),
+                                    variadic_args: None,
+                                    variadic_kwargs: None,
+                                    returned_names: vec![],
                                 });
                             x.arguments.push(identifier.clone());
                         }
@@ -1269,6 +1284,11 @@ x = random.randint(0, 10)
                         arguments: vec![],
                         emit_event: vec![],
                         trigger_on: vec![],
+                        argument_defaults: HashMap::new(),
+                        argument_annotations: HashMap::new(),
+                        variadic_args: None,
+                        variadic_kwargs: None,
+                        returned_names: vec![],
                     },
                 );
                 map
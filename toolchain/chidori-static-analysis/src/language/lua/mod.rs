@@ -0,0 +1 @@
+pub mod parse;
\ No newline at end of file
@@ -0,0 +1,444 @@
+use crate::language::{ChidoriStaticAnalysisError, ContextPath, InternalCallGraph, Report, ReportItem, ReportTriggerableFunctions, TextRange};
+use std::collections::{HashMap, HashSet};
+
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+const LUA_BUILT_INS: &[&str] = &[
+    "_G", "_VERSION", "assert", "collectgarbage", "coroutine", "debug", "dofile", "error",
+    "getmetatable", "io", "ipairs", "load", "loadfile", "math", "next", "os", "package", "pairs",
+    "pcall", "print", "rawequal", "rawget", "rawlen", "rawset", "require", "select",
+    "setmetatable", "string", "table", "tonumber", "tostring", "type", "utf8", "xpcall",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String, TextRange),
+    Symbol(String),
+    Literal,
+}
+
+/// Splits Lua source into the identifiers and punctuation that matter for dependency extraction.
+/// Comments, string literals and numbers are collapsed so their contents are never mistaken for references.
+fn tokenize(source_code: &str) -> Result<Vec<Token>, ChidoriStaticAnalysisError> {
+    let chars: Vec<char> = source_code.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    let unterminated = |msg: &str, offset: usize| ChidoriStaticAnalysisError::ParseError {
+        msg: msg.to_string(),
+        offset: offset as u32,
+        source_path: "<embedded>".to_string(),
+        source_code: source_code.to_string(),
+    };
+
+    // Long brackets are of the form [[ ... ]] or [==[ ... ]==], returns the index after the closing bracket
+    let long_bracket_end = |start: usize| -> Option<Option<usize>> {
+        if chars.get(start) != Some(&'[') {
+            return None;
+        }
+        let mut level = 0;
+        let mut j = start + 1;
+        while chars.get(j) == Some(&'=') {
+            level += 1;
+            j += 1;
+        }
+        if chars.get(j) != Some(&'[') {
+            return None;
+        }
+        let closing: Vec<char> = std::iter::once(']')
+            .chain(std::iter::repeat('=').take(level))
+            .chain(std::iter::once(']'))
+            .collect();
+        let mut k = j + 1;
+        while k + closing.len() <= chars.len() {
+            if chars[k..k + closing.len()] == closing[..] {
+                return Some(Some(k + closing.len()));
+            }
+            k += 1;
+        }
+        Some(None)
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            match long_bracket_end(i + 2) {
+                Some(Some(end)) => i = end,
+                Some(None) => return Err(unterminated("unfinished long comment", i)),
+                None => {
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                }
+            }
+        } else if c == '"' || c == '\'' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(unterminated("unfinished string", start));
+            }
+            i += 1;
+            tokens.push(Token::Literal);
+        } else if c == '[' && matches!(chars.get(i + 1), Some('[') | Some('=')) {
+            match long_bracket_end(i) {
+                Some(Some(end)) => {
+                    i = end;
+                    tokens.push(Token::Literal);
+                }
+                Some(None) => return Err(unterminated("unfinished long string", i)),
+                None => {
+                    tokens.push(Token::Symbol("[".to_string()));
+                    i += 1;
+                }
+            }
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Literal);
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let identifier: String = chars[start..i].iter().collect();
+            tokens.push(Token::Identifier(identifier, TextRange { start, end: i }));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["==", "~=", "<=", ">=", "..", "::"].contains(&two.as_str()) {
+                tokens.push(Token::Symbol(two));
+                i += 2;
+            } else {
+                tokens.push(Token::Symbol(c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_symbol(token: Option<&Token>, symbol: &str) -> bool {
+    matches!(token, Some(Token::Symbol(s)) if s == symbol)
+}
+
+fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+    matches!(token, Some(Token::Identifier(s, _)) if s == keyword)
+}
+
+/// Lua has no parser dependency in this crate, so dependencies are extracted from a token scan rather than an AST.
+/// Locals are tracked without block scoping, which errs on the side of treating a name as locally provided.
+pub fn extract_dependencies_lua(source_code: &str) -> Result<Vec<Vec<ContextPath>>, ChidoriStaticAnalysisError> {
+    let tokens = tokenize(source_code)?;
+    let mut context_stack_references = vec![];
+    let mut locals: HashSet<String> = HashSet::new();
+    let mut assigned: HashSet<String> = HashSet::new();
+    let mut block_depth = 0usize;
+    let mut brace_depth = 0usize;
+    // Stack of (function name, block depth the function body opened at) for the functions we are within
+    let mut function_stack: Vec<(String, TextRange, usize)> = vec![];
+
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Symbol(s) => {
+                match s.as_str() {
+                    "{" => brace_depth += 1,
+                    "}" => brace_depth = brace_depth.saturating_sub(1),
+                    _ => {}
+                }
+                i += 1;
+            }
+            Token::Literal => {
+                i += 1;
+            }
+            Token::Identifier(name, range) => {
+                let previous = if i > 0 { tokens.get(i - 1) } else { None };
+                match name.as_str() {
+                    "function" => {
+                        // Collect the (possibly dotted) function name, then its parameter list
+                        let mut j = i + 1;
+                        let mut function_name = None;
+                        if let Some(Token::Identifier(n, r)) = tokens.get(j) {
+                            if !LUA_KEYWORDS.contains(&n.as_str()) {
+                                function_name = Some((n.clone(), r.clone()));
+                                j += 1;
+                                if is_symbol(tokens.get(j), ".") || is_symbol(tokens.get(j), ":") {
+                                    // Methods on tables are not exposed as standalone functions, but depend on the table
+                                    if !locals.contains(n) {
+                                        context_stack_references.push(vec![ContextPath::IdentifierReferredTo {
+                                            name: n.clone(),
+                                            in_scope: assigned.contains(n),
+                                            exposed: false,
+                                        }]);
+                                    }
+                                    function_name = None;
+                                }
+                                while is_symbol(tokens.get(j), ".") || is_symbol(tokens.get(j), ":") {
+                                    j += 2;
+                                }
+                            }
+                        }
+                        let mut arguments = vec![];
+                        if is_symbol(tokens.get(j), "(") {
+                            j += 1;
+                            while let Some(token) = tokens.get(j) {
+                                match token {
+                                    Token::Symbol(s) if s == ")" => break,
+                                    Token::Identifier(arg, _) => arguments.push(arg.clone()),
+                                    _ => {}
+                                }
+                                j += 1;
+                            }
+                        }
+                        let is_local = is_keyword(previous, "local");
+                        block_depth += 1;
+                        if let Some((function_name, function_range)) = function_name {
+                            if is_local || block_depth > 1 {
+                                locals.insert(function_name.clone());
+                            } else {
+                                assigned.insert(function_name.clone());
+                                let mut path = vec![ContextPath::InFunction(function_name.clone(), function_range.clone())];
+                                if arguments.is_empty() {
+                                    context_stack_references.push(path);
+                                } else {
+                                    path.push(ContextPath::FunctionArguments);
+                                    for argument in &arguments {
+                                        let mut argument_path = path.clone();
+                                        argument_path.push(ContextPath::FunctionArgument(argument.clone()));
+                                        context_stack_references.push(argument_path);
+                                    }
+                                }
+                                function_stack.push((function_name, function_range, block_depth));
+                            }
+                        }
+                        locals.extend(arguments);
+                        i = j + 1;
+                    }
+                    "local" => {
+                        let mut j = i + 1;
+                        if !is_keyword(tokens.get(j), "function") {
+                            while let Some(Token::Identifier(n, _)) = tokens.get(j) {
+                                locals.insert(n.clone());
+                                j += 1;
+                                // Skip attributes such as <const>
+                                if is_symbol(tokens.get(j), "<") {
+                                    j += 3;
+                                }
+                                if !is_symbol(tokens.get(j), ",") {
+                                    break;
+                                }
+                                j += 1;
+                            }
+                        }
+                        i = j;
+                    }
+                    "for" => {
+                        let mut j = i + 1;
+                        while let Some(token) = tokens.get(j) {
+                            match token {
+                                Token::Identifier(n, _) if n == "in" => break,
+                                Token::Symbol(s) if s == "=" => break,
+                                Token::Identifier(n, _) => { locals.insert(n.clone()); }
+                                _ => {}
+                            }
+                            j += 1;
+                        }
+                        i = j;
+                    }
+                    "do" | "if" | "repeat" => {
+                        block_depth += 1;
+                        i += 1;
+                    }
+                    "end" | "until" => {
+                        if matches!(function_stack.last(), Some((_, _, depth)) if *depth == block_depth) {
+                            function_stack.pop();
+                        }
+                        block_depth = block_depth.saturating_sub(1);
+                        i += 1;
+                    }
+                    _ if LUA_KEYWORDS.contains(&name.as_str()) => {
+                        i += 1;
+                    }
+                    _ => {
+                        // Field accesses and table constructor keys are not references to globals
+                        let is_field = is_symbol(previous, ".") || is_symbol(previous, ":") || is_symbol(previous, "::") || is_keyword(previous, "goto");
+                        let is_table_key = brace_depth > 0 && is_symbol(tokens.get(i + 1), "=");
+                        if is_field || is_table_key {
+                            i += 1;
+                            continue;
+                        }
+
+                        // Detect the targets of an assignment statement, `a = 1` or `a, b = 1, 2`
+                        let mut targets = vec![(name.clone(), range.clone())];
+                        let mut j = i + 1;
+                        while is_symbol(tokens.get(j), ",") {
+                            if let Some(Token::Identifier(n, r)) = tokens.get(j + 1) {
+                                targets.push((n.clone(), r.clone()));
+                                j += 2;
+                            } else {
+                                break;
+                            }
+                        }
+                        if is_symbol(tokens.get(j), "=") && brace_depth == 0 {
+                            for (target, _) in targets {
+                                if locals.contains(&target) {
+                                    continue;
+                                }
+                                if function_stack.is_empty() && block_depth == 0 {
+                                    context_stack_references.push(vec![
+                                        ContextPath::AssignmentToStatement,
+                                        ContextPath::IdentifierReferredTo { name: target.clone(), in_scope: false, exposed: false },
+                                    ]);
+                                }
+                                assigned.insert(target);
+                            }
+                            i = j + 1;
+                            continue;
+                        }
+
+                        if !locals.contains(name) {
+                            let mut path: Vec<ContextPath> = function_stack
+                                .iter()
+                                .map(|(n, r, _)| ContextPath::InFunction(n.clone(), r.clone()))
+                                .collect();
+                            path.push(ContextPath::IdentifierReferredTo {
+                                name: name.clone(),
+                                in_scope: assigned.contains(name),
+                                exposed: false,
+                            });
+                            context_stack_references.push(path);
+                        }
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(context_stack_references)
+}
+
+pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
+    let mut exposed_values = HashMap::new();
+    let mut depended_values = HashMap::new();
+    let mut triggerable_functions: HashMap<String, ReportTriggerableFunctions> = HashMap::new();
+    for context_path in context_paths {
+        let in_function = context_path.iter().any(|x| matches!(x, ContextPath::InFunction(_, _)));
+        for context_path_unit in context_path {
+            match context_path_unit {
+                ContextPath::InFunction(name, _) => {
+                    triggerable_functions.entry(name.clone()).or_default();
+                }
+                ContextPath::FunctionArgument(argument) => {
+                    if let Some(ContextPath::InFunction(function_name, _)) = context_path.first() {
+                        triggerable_functions.entry(function_name.clone()).or_default().arguments.push(argument.clone());
+                    }
+                }
+                ContextPath::IdentifierReferredTo { name, in_scope: false, .. } => {
+                    if !in_function && context_path.contains(&ContextPath::AssignmentToStatement) {
+                        exposed_values.insert(name.clone(), ReportItem {});
+                    } else if !context_path.contains(&ContextPath::AssignmentToStatement) {
+                        depended_values.insert(name.clone(), ReportItem {});
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Values that are later assigned by this cell are not dependencies of it
+    depended_values.retain(|value, _| {
+        !LUA_BUILT_INS.contains(&value.as_str())
+            && !exposed_values.contains_key(value)
+            && !triggerable_functions.contains_key(value)
+    });
+
+    Report {
+        internal_call_graph: InternalCallGraph {
+            graph: Default::default(),
+        },
+        cell_exposed_values: exposed_values,
+        cell_depended_values: depended_values,
+        triggerable_functions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn report_for(source: &str) -> Report {
+        build_report(&extract_dependencies_lua(source).unwrap())
+    }
+
+    #[test]
+    fn test_assignment_is_exposed() {
+        let report = report_for(indoc! { r#"
+            x = 1
+            y, z = 2, 3
+            local hidden = 4
+            "#});
+        let mut exposed: Vec<_> = report.cell_exposed_values.keys().cloned().collect();
+        exposed.sort();
+        assert_eq!(exposed, vec!["x", "y", "z"]);
+        assert!(report.cell_depended_values.is_empty());
+    }
+
+    #[test]
+    fn test_free_identifiers_are_depended_on() {
+        let report = report_for(indoc! { r#"
+            -- a comment mentioning ignored
+            local label = "not_a_reference"
+            result = input_value + config.offset
+            print(label, string.format("%d", result))
+            "#});
+        let mut depended: Vec<_> = report.cell_depended_values.keys().cloned().collect();
+        depended.sort();
+        assert_eq!(depended, vec!["config", "input_value"]);
+        assert!(report.cell_exposed_values.contains_key("result"));
+    }
+
+    #[test]
+    fn test_top_level_functions_are_triggerable() {
+        let report = report_for(indoc! { r#"
+            function add(a, b)
+                local total = a + b
+                return total + offset
+            end
+
+            local function helper() return 1 end
+
+            function M.method(self) end
+            "#});
+        assert_eq!(report.triggerable_functions.len(), 1);
+        assert_eq!(report.triggerable_functions["add"].arguments, vec!["a", "b"]);
+        assert!(report.cell_depended_values.contains_key("offset"));
+        assert!(report.cell_depended_values.contains_key("M"));
+    }
+
+    #[test]
+    fn test_table_constructor_keys_are_not_references() {
+        let report = report_for(indoc! { r#"
+            data = { name = "x", count = n, [[long string]] }
+            "#});
+        assert!(report.cell_exposed_values.contains_key("data"));
+        let depended: Vec<_> = report.cell_depended_values.keys().cloned().collect();
+        assert_eq!(depended, vec!["n"]);
+    }
+
+    #[test]
+    fn test_unterminated_string_is_a_parse_error() {
+        assert!(extract_dependencies_lua("x = \"abc").is_err());
+    }
+}
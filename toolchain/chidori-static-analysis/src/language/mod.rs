@@ -8,6 +8,7 @@ use thiserror::Error;
 
 pub mod typechecker;
 pub mod javascript;
+pub mod lua;
 pub mod python;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash)]
@@ -27,6 +28,22 @@ pub struct ReportItem {
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReportTriggerableFunctions {
     pub arguments: Vec<String>,
+    /// Source text of the default value of each argument that declares one
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub argument_defaults: HashMap<String, String>,
+    /// Source text of the type annotation of each argument that declares one
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub argument_annotations: HashMap<String, String>,
+    /// Name of the parameter collecting additional positional arguments, `*args`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variadic_args: Option<String>,
+    /// Name of the parameter collecting additional keyword arguments, `**kwargs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variadic_kwargs: Option<String>,
+    /// Names of the values returned by the function, when it returns a tuple of identifiers
+    /// or a dictionary literal with string keys
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub returned_names: Vec<String>,
     // pub context_path: Vec<ContextPath>,
     // TODO: these need their own set of depended values
     // TODO: we need to extract signatures for triggerable functions
@@ -125,6 +142,16 @@ pub enum ContextPath {
     InAnonFunction,
     FunctionArguments,
     FunctionArgument(String),
+    /// An argument's default value, as the argument name and the source text of the default
+    FunctionArgumentDefault(String, String),
+    /// An argument's type annotation, as the argument name and the source text of the annotation
+    FunctionArgumentAnnotation(String, String),
+    /// The `*args` parameter of a function
+    VariadicArgument(String),
+    /// The `**kwargs` parameter of a function
+    VariadicKeywordArgument(String),
+    /// The names of the values returned from a function
+    ReturnedNames(Vec<String>),
     InClass(String),
     InFunctionDecorator(usize),
     InCallExpression,
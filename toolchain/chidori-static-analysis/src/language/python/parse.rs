@@ -1,5 +1,5 @@
 use crate::language::{ChidoriStaticAnalysisError, InternalCallGraph, Report, ReportItem, ReportTriggerableFunctions, TextRange};
-use rustpython_parser::ast::{Constant, Expr, Identifier, Stmt};
+use rustpython_parser::ast::{Constant, Expr, Identifier, Ranged, Stmt};
 use rustpython_parser::{ast, Parse};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -19,6 +19,8 @@ use crate::language::ContextPath;
 /// * `locals`: A set of strings representing local variables defined within the AST.
 /// * `local_contexts`: A vector of sets, where each set represents a separate local context.
 /// * `globals`: A set of strings representing global variables defined within the AST.
+/// * `source_code`: The source being walked, used to capture the text of argument defaults.
+/// * `imported_modules`: The absolute modules imported anywhere within the AST, in the order encountered.
 #[derive(Default)]
 pub struct ASTWalkContext {
     pub context_stack_references: Vec<Vec<ContextPath>>,
@@ -26,6 +28,8 @@ pub struct ASTWalkContext {
     pub locals: HashSet<String>,
     pub local_contexts: Vec<HashSet<String>>,
     pub globals: HashSet<String>,
+    pub source_code: String,
+    pub imported_modules: Vec<String>,
 }
 
 impl ASTWalkContext {
@@ -36,6 +40,8 @@ impl ASTWalkContext {
             locals: HashSet::new(),
             local_contexts: vec![],
             globals: HashSet::new(),
+            source_code: String::new(),
+            imported_modules: vec![],
         }
     }
 
@@ -102,6 +108,42 @@ impl ASTWalkContext {
             .push(self.context_stack.clone());
     }
 
+    fn encounter_argument_default(&mut self, name: &Identifier, source: String) {
+        self.context_stack
+            .push(ContextPath::FunctionArgumentDefault(name.to_string(), source));
+        self.context_stack_references
+            .push(self.context_stack.clone());
+        self.context_stack.pop();
+    }
+
+    fn encounter_argument_annotation(&mut self, name: &Identifier, source: String) {
+        self.context_stack
+            .push(ContextPath::FunctionArgumentAnnotation(name.to_string(), source));
+        self.context_stack_references
+            .push(self.context_stack.clone());
+        self.context_stack.pop();
+    }
+
+    fn encounter_returned_names(&mut self, names: Vec<String>) {
+        self.context_stack
+            .push(ContextPath::ReturnedNames(names));
+        self.context_stack_references
+            .push(self.context_stack.clone());
+        self.context_stack.pop();
+    }
+
+    fn encounter_variadic_argument(&mut self, name: &Identifier, keyword: bool) {
+        self.locals.insert(name.to_string());
+        self.context_stack.push(if keyword {
+            ContextPath::VariadicKeywordArgument(name.to_string())
+        } else {
+            ContextPath::VariadicArgument(name.to_string())
+        });
+        self.context_stack_references
+            .push(self.context_stack.clone());
+        self.context_stack.pop();
+    }
+
     fn enter_decorator_expression(&mut self, idx: &usize) -> usize {
         self.context_stack
             .push(ContextPath::InFunctionDecorator(idx.clone()));
@@ -400,11 +442,44 @@ pub fn extract_dependencies_python(source_code: &str) -> Result<Vec<Vec<ContextP
                 source_code: source_code.to_string(),
             }
         })?;
-    let mut machine = ASTWalkContext::default();
+    let mut machine = ASTWalkContext {
+        source_code: source_code.to_string(),
+        ..ASTWalkContext::default()
+    };
     traverse_statements(&ast, &mut machine);
     Ok(machine.context_stack_references)
 }
 
+/// The imports of the given source that name a denied module or one of its submodules, so that
+/// cells can be rejected before they run.
+pub fn denied_imports_python(source_code: &str, denied_modules: &[String]) -> Result<Vec<String>, ChidoriStaticAnalysisError> {
+    let ast = ast::Suite::parse(source_code, "<embedded>")
+        .map_err(|e| {
+            ChidoriStaticAnalysisError::ParseError {
+                msg: e.error.to_string(),
+                offset: e.offset.to_u32(),
+                source_path: e.source_path,
+                source_code: source_code.to_string(),
+            }
+        })?;
+    let mut machine = ASTWalkContext {
+        source_code: source_code.to_string(),
+        ..ASTWalkContext::default()
+    };
+    traverse_statements(&ast, &mut machine);
+    Ok(machine.imported_modules
+        .into_iter()
+        .filter(|module| is_denied_module(module, denied_modules))
+        .collect())
+}
+
+/// Whether `module` is one of `denied_modules` or a submodule of one.
+pub fn is_denied_module(module: &str, denied_modules: &[String]) -> bool {
+    denied_modules.iter().any(|denied| {
+        module == denied || module.strip_prefix(denied.as_str()).is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
 fn traverse_comprehension(comp: &ast::Comprehension, machine: &mut ASTWalkContext) {
     traverse_expression(&comp.target, machine);
     traverse_expression(&comp.iter, machine);
@@ -413,6 +488,55 @@ fn traverse_comprehension(comp: &ast::Comprehension, machine: &mut ASTWalkContex
     }
 }
 
+/// The names of the values a return statement produces, for a tuple of identifiers or a dictionary
+/// literal keyed by strings.
+fn returned_names(expr: &ast::Expr) -> Option<Vec<String>> {
+    match expr {
+        ast::Expr::Tuple(ast::ExprTuple { elts, .. }) => elts
+            .iter()
+            .map(|elt| match elt {
+                ast::Expr::Name(ast::ExprName { id, .. }) => Some(id.to_string()),
+                _ => None,
+            })
+            .collect(),
+        ast::Expr::Dict(ast::ExprDict { keys, .. }) => keys
+            .iter()
+            .map(|key| match key {
+                Some(ast::Expr::Constant(ast::ExprConstant { value: Constant::Str(s), .. })) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Arguments are referenced in declaration order, followed by the default value and type annotation
+/// of each argument that declares one and the names of the `*args` and `**kwargs` parameters.
+fn traverse_arguments(args: &ast::Arguments, machine: &mut ASTWalkContext) {
+    let declared = args.posonlyargs.iter().chain(&args.args).chain(&args.kwonlyargs);
+    for ast::ArgWithDefault { def, default, .. } in declared {
+        machine.encounter_named_reference(&def.arg);
+        if let Some(default) = default {
+            let source = machine.source_code.get(default.range().start().to_usize()..default.range().end().to_usize())
+                .unwrap_or_default()
+                .to_string();
+            machine.encounter_argument_default(&def.arg, source);
+        }
+        if let Some(annotation) = &def.annotation {
+            let source = machine.source_code.get(annotation.range().start().to_usize()..annotation.range().end().to_usize())
+                .unwrap_or_default()
+                .to_string();
+            machine.encounter_argument_annotation(&def.arg, source);
+        }
+    }
+    if let Some(vararg) = &args.vararg {
+        machine.encounter_variadic_argument(&vararg.arg, false);
+    }
+    if let Some(kwarg) = &args.kwarg {
+        machine.encounter_variadic_argument(&kwarg.arg, true);
+    }
+}
+
 pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContext) {
     for stmt in statements {
         match stmt {
@@ -448,17 +572,7 @@ pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContex
                     machine.pop_until(idx);
                 }
                 let args_idx = machine.enter_arguments();
-                for ast::ArgWithDefault {
-                    range,
-                    def,
-                    default,
-                    ..
-                } in &args.args
-                {
-                    if let ast::Arg { arg, .. } = def {
-                        machine.encounter_named_reference(arg);
-                    }
-                }
+                traverse_arguments(args, machine);
                 machine.pop_until(args_idx);
                 traverse_statements(body, machine);
                 machine.pop_until(idx);
@@ -485,17 +599,7 @@ pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContex
                     machine.pop_until(idx);
                 }
                 let args_idx = machine.enter_arguments();
-                for ast::ArgWithDefault {
-                    range,
-                    def,
-                    default,
-                    ..
-                } in &args.args
-                {
-                    if let ast::Arg { arg, .. } = def {
-                        machine.encounter_named_reference(arg);
-                    }
-                }
+                traverse_arguments(args, machine);
                 machine.pop_until(args_idx);
                 traverse_statements(body, machine);
                 machine.pop_until(idx);
@@ -511,6 +615,9 @@ pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContex
             }
             ast::Stmt::Return(ast::StmtReturn { value, .. }) => {
                 if let Some(expr) = value {
+                    if let Some(names) = returned_names(expr) {
+                        machine.encounter_returned_names(names);
+                    }
                     traverse_expression(expr, machine);
                 }
             }
@@ -642,6 +749,7 @@ pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContex
             }
             ast::Stmt::Import(ast::StmtImport { names, .. }) => {
                 for name in names {
+                    machine.imported_modules.push(name.name.to_string());
                     if let Some(name) = &name.asname {
                         machine.globals.insert(name.to_string());
                     } else {
@@ -650,8 +758,14 @@ pub fn traverse_statements(statements: &[ast::Stmt], machine: &mut ASTWalkContex
                 }
                 // No recursion needed
             }
-            ast::Stmt::ImportFrom(ast::StmtImportFrom { .. }) => {
+            ast::Stmt::ImportFrom(ast::StmtImportFrom { module, level, .. }) => {
                 // TODO: Import
+                // Relative imports refer to the cell's own package rather than a named module
+                if let Some(module) = module {
+                    if level.as_ref().map_or(0, |level| level.to_u32()) == 0 {
+                        machine.imported_modules.push(module.to_string());
+                    }
+                }
                 // No recursion needed
             }
             ast::Stmt::Global(ast::StmtGlobal { .. }) => {
@@ -685,6 +799,21 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
     let mut depended_values = HashMap::new();
     let mut triggerable_functions = HashMap::new();
     for context_path in context_paths {
+        // Decorators of the form @ch.emit_as("name") and @ch.on_event("name") declare the events
+        // a function publishes and the events it is invoked in response to
+        if let [ContextPath::InFunction(function_name, _), ContextPath::InFunctionDecorator(_), ContextPath::InCallExpression, ContextPath::Constant(event), ContextPath::Attribute(decorator), ContextPath::IdentifierReferredTo { name, .. }] = context_path.as_slice() {
+            if name == "ch" {
+                let x = triggerable_functions
+                    .entry(function_name.clone())
+                    .or_insert_with(|| ReportTriggerableFunctions::default());
+                match decorator.as_str() {
+                    "emit_as" => x.emit_event.push(event.clone()),
+                    "on_event" => x.trigger_on.push(event.clone()),
+                    _ => {}
+                }
+            }
+        }
+
         let mut encountered = vec![];
         for (idx, context_path_unit) in context_path.iter().enumerate() {
             // encountered is the reversed order of the context path
@@ -701,6 +830,11 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                             arguments: vec![],
                             emit_event: vec![],
                             trigger_on: vec![],
+                            argument_defaults: HashMap::new(),
+                            argument_annotations: HashMap::new(),
+                            variadic_args: None,
+                            variadic_kwargs: None,
+                            returned_names: vec![],
                         });
                 }
             }
@@ -717,12 +851,45 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                                 arguments: vec![],
                                 emit_event: vec![], // Initialize with an empty string or a default value
                                 trigger_on: vec![],
+                                argument_defaults: HashMap::new(),
+                                argument_annotations: HashMap::new(),
+                                variadic_args: None,
+                                variadic_kwargs: None,
+                                returned_names: vec![],
                             });
                         x.arguments.push(name.clone());
                     }
                 }
             }
 
+            // Defaults and variadic parameters are recorded against the function they are declared by
+            if let ContextPath::FunctionArgumentDefault(_, _)
+                | ContextPath::FunctionArgumentAnnotation(_, _)
+                | ContextPath::VariadicArgument(_)
+                | ContextPath::VariadicKeywordArgument(_)
+                | ContextPath::ReturnedNames(_) = context_path_unit {
+                if let Some(ContextPath::InFunction(function_name, _)) = encountered.iter().rev().find(|x| matches!(x, ContextPath::InFunction(_, _))) {
+                    let x = triggerable_functions
+                        .entry(function_name.clone())
+                        .or_insert_with(|| ReportTriggerableFunctions::default());
+                    match context_path_unit {
+                        ContextPath::FunctionArgumentDefault(name, source) => {
+                            x.argument_defaults.insert(name.clone(), source.clone());
+                        }
+                        ContextPath::FunctionArgumentAnnotation(name, source) => {
+                            x.argument_annotations.insert(name.clone(), source.clone());
+                        }
+                        ContextPath::VariadicArgument(name) => x.variadic_args = Some(name.clone()),
+                        ContextPath::VariadicKeywordArgument(name) => x.variadic_kwargs = Some(name.clone()),
+                        // The first return statement determines the names of the outputs
+                        ContextPath::ReturnedNames(names) if x.returned_names.is_empty() => {
+                            x.returned_names = names.clone();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
             // If an identifier is referred to, and it has not been assigned to earlier during our interpreting
             if let ContextPath::IdentifierReferredTo{name: identifier, exposed: false, in_scope: false} = context_path_unit {
                 // If we encounter both FunctionArguments and InFunction, then this is a function argument
@@ -737,6 +904,11 @@ pub fn build_report(context_paths: &Vec<Vec<ContextPath>>) -> Report {
                                     arguments: vec![],
                                     emit_event: vec![], // Initialize with an empty string or a default value
                                     trigger_on: vec![],
+                                    argument_defaults: HashMap::new(),
+                                    argument_annotations: HashMap::new(),
+                                    variadic_args: None,
+                                    variadic_kwargs: None,
+                                    returned_names: vec![],
                                 });
                             x.arguments.push(identifier.clone());
                         }
@@ -1007,6 +1179,11 @@ mod tests {
                         arguments: vec![],
                         emit_event: vec![],
                         trigger_on: vec![],
+                        argument_defaults: HashMap::new(),
+                        argument_annotations: HashMap::new(),
+                        variadic_args: None,
+                        variadic_kwargs: None,
+                        returned_names: vec![],
                     },
                 );
                 map
@@ -1069,6 +1246,11 @@ x = random.randint(0, 10)
                         arguments: vec![],
                         emit_event: vec![],
                         trigger_on: vec![],
+                        argument_defaults: HashMap::new(),
+                        argument_annotations: HashMap::new(),
+                        variadic_args: None,
+                        variadic_kwargs: None,
+                        returned_names: vec![],
                     },
                 );
                 map
@@ -1109,6 +1291,14 @@ x = random.randint(0, 10)
                         arguments: vec!["a", "b", "c", "d"].into_iter().map(|a| a.to_string()).collect(),
                         emit_event: vec![],
                         trigger_on: vec![],
+                        argument_defaults: HashMap::from([
+                            ("c".to_string(), "2".to_string()),
+                            ("d".to_string(), "3".to_string()),
+                        ]),
+                        argument_annotations: HashMap::new(),
+                        variadic_args: None,
+                        variadic_kwargs: None,
+                        returned_names: vec![],
                     },
                 );
                 map
@@ -1213,6 +1403,11 @@ x = random.randint(0, 10)
                         arguments: vec!["self".to_string()],
                         emit_event: vec![],
                         trigger_on: vec![],
+                        argument_defaults: HashMap::new(),
+                        argument_annotations: HashMap::new(),
+                        variadic_args: None,
+                        variadic_kwargs: None,
+                        returned_names: vec![],
                     },
                 );
                 map
@@ -1222,4 +1417,94 @@ x = random.randint(0, 10)
         assert_eq!(result, report);
         Ok(())
     }
+
+    #[test]
+    fn test_report_generation_argument_defaults_and_variadics() {
+        let python_source = indoc! { r#"
+        def f(x, y=10, *rest, **opts):
+            return x + y + len(rest) + len(opts)
+            "#};
+        let context_stack_references = extract_dependencies_python(python_source).unwrap();
+        let result = build_report(&context_stack_references);
+        let function = &result.triggerable_functions["f"];
+        assert_eq!(function.arguments, vec!["x".to_string(), "y".to_string()]);
+        assert_eq!(function.argument_defaults, HashMap::from([("y".to_string(), "10".to_string())]));
+        assert_eq!(function.variadic_args.as_deref(), Some("rest"));
+        assert_eq!(function.variadic_kwargs.as_deref(), Some("opts"));
+        assert!(!result.cell_depended_values.contains_key("opts"));
+        assert!(!result.cell_depended_values.contains_key("rest"));
+    }
+
+    #[test]
+    fn test_report_generation_argument_annotations() {
+        let python_source = indoc! { r#"
+        def paint(color: Literal["red", "green"], times: int, note=None):
+            return color * times
+            "#};
+        let context_stack_references = extract_dependencies_python(python_source).unwrap();
+        let result = build_report(&context_stack_references);
+        let function = &result.triggerable_functions["paint"];
+        assert_eq!(function.arguments, vec!["color".to_string(), "times".to_string(), "note".to_string()]);
+        assert_eq!(function.argument_annotations, HashMap::from([
+            ("color".to_string(), r#"Literal["red", "green"]"#.to_string()),
+            ("times".to_string(), "int".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn test_report_generation_emitted_events() {
+        let python_source = indoc! { r#"
+        @ch.on_event("new_file")
+        @ch.emit_as("file_created")
+        def create_file(path):
+            return path
+            "#};
+        let context_stack_references = extract_dependencies_python(python_source).unwrap();
+        let result = build_report(&context_stack_references);
+        let function = &result.triggerable_functions["create_file"];
+        assert_eq!(function.emit_event, vec!["file_created".to_string()]);
+        assert_eq!(function.trigger_on, vec!["new_file".to_string()]);
+        assert_eq!(function.arguments, vec!["path".to_string()]);
+    }
+
+    #[test]
+    fn test_report_generation_returned_names() {
+        let python_source = indoc! { r#"
+        def stats(values):
+            total = sum(values)
+            count = len(values)
+            return total, count
+
+        def summary(values):
+            return {"total": sum(values), "count": len(values)}
+
+        def single(values):
+            return values[0]
+            "#};
+        let context_stack_references = extract_dependencies_python(python_source).unwrap();
+        let result = build_report(&context_stack_references);
+        assert_eq!(result.triggerable_functions["stats"].returned_names, vec!["total".to_string(), "count".to_string()]);
+        assert_eq!(result.triggerable_functions["summary"].returned_names, vec!["total".to_string(), "count".to_string()]);
+        assert!(result.triggerable_functions["single"].returned_names.is_empty());
+    }
+
+    #[test]
+    fn test_denied_imports() {
+        let python_source = indoc! { r#"
+        import json
+        import os.path
+        from subprocess import run
+        from . import sibling
+
+        def fetch():
+            import socket
+            return socket
+            "#};
+        let denied = vec!["os".to_string(), "subprocess".to_string(), "socket".to_string(), "sibling".to_string()];
+        assert_eq!(
+            denied_imports_python(python_source, &denied).unwrap(),
+            vec!["os.path".to_string(), "subprocess".to_string(), "socket".to_string()]
+        );
+        assert!(!is_denied_module("osmosis", &denied));
+    }
 }